@@ -1,3 +1,4 @@
+use chrono::{Local, NaiveDateTime, TimeZone};
 use log::*;
 use std::collections::hash_map::Entry;
 use std::collections::HashMap;
@@ -6,46 +7,633 @@ use std::sync::mpsc::{self, Receiver, Sender};
 use std::thread::JoinHandle;
 
 use super::err_house;
+use super::protocol;
 use super::smart_house_tcp_client::TcpClient;
 use super::smart_house_udp_client::UdpClient;
 
 const GET_DEVICES: &str = "get_devs";
-const TURN_ON: &str = "turn_on";
-const TURN_OFF: &str = "turn_off";
-const GET_POWER: &str = "get_power";
-const GET_TEMP: &str = "get_temp";
+const GET_ENERGY: &str = "energy";
 const GET_REPORT: &str = "report";
+const GET_ALERTS: &str = "alerts";
+const BATCH: &str = "batch";
+const BATCH_ALL: &str = "all";
+const BATCH_SEPARATOR: char = ';';
+const UNITS: &str = "units";
+const SCHEDULE: &str = "schedule";
+const SCHEDULE_AT: &str = "schedule_at";
+const SCHEDULES: &str = "schedules";
+const UNSCHEDULE: &str = "unschedule";
+const GET_THERMOSTAT: &str = "thermostat";
+const GROUP: &str = "group";
+const GROUP_DO: &str = "group_do";
+const SCENE: &str = "scene";
+const APPLY_SCENE: &str = "apply";
+const GET_SCENES: &str = "scenes";
+/// Local time of one-shot schedule
+const SCHEDULE_TIME_FORMAT: &str = "%Y-%m-%dT%H:%M";
 
 const EXIT: &str = "exit";
 
+/// Console commands applied to particular device
+const DEV_CMDS: &[(&str, protocol::Cmd, &str)] = &[
+    (
+        "turn_on",
+        protocol::Cmd::TurnOn,
+        "to turn on particular device",
+    ),
+    (
+        "turn_off",
+        protocol::Cmd::TurnOff,
+        "to turn off particular device",
+    ),
+    ("get_power", protocol::Cmd::Power, "to get power of socket"),
+    (
+        "get_temp",
+        protocol::Cmd::Temperature,
+        "to get temperature of thermometer",
+    ),
+    (
+        "get_brightness",
+        protocol::Cmd::Brightness,
+        "to get brightness of lamp",
+    ),
+    ("get_humidity", protocol::Cmd::Humidity, "to get humidity"),
+    ("lock", protocol::Cmd::Lock, "to lock door"),
+    ("unlock", protocol::Cmd::Unlock, "to unlock door"),
+    (
+        "get_lock",
+        protocol::Cmd::LockState,
+        "to get state of door lock",
+    ),
+    (
+        "get_leak",
+        protocol::Cmd::Leak,
+        "to check leak sensor alarm",
+    ),
+    (
+        "reset_alarm",
+        protocol::Cmd::ResetAlarm,
+        "to reset leak sensor alarm",
+    ),
+    (
+        "get_target",
+        protocol::Cmd::Target,
+        "to get target temperature of thermometer",
+    ),
+    (
+        "get_state",
+        protocol::Cmd::GetState,
+        "to get state of device",
+    ),
+    (
+        "get_caps",
+        protocol::Cmd::GetCapabilities,
+        "to get commands and readings supported by device",
+    ),
+];
+
+type ArgParser = fn(&str) -> Option<protocol::Cmd>;
+
+/// Console commands with argument applied to particular device
+const ARG_CMDS: &[(&str, ArgParser, &str)] = &[
+    (
+        "set_level",
+        |arg| arg.parse().ok().map(protocol::Cmd::SetLevel),
+        "\"level\" to set brightness of lamp in % or power limit of socket in W",
+    ),
+    (
+        "set_target",
+        |arg| arg.parse().ok().map(protocol::Cmd::SetTarget),
+        "\"temperature\" to set target temperature of thermometer",
+    ),
+    (
+        "turn_on_for",
+        |arg| arg.parse().ok().map(protocol::Cmd::TurnOnFor),
+        "\"minutes\" to turn on device for given time",
+    ),
+    (
+        "thermostat_mode",
+        |arg| {
+            let mode = match arg {
+                "off" => protocol::ThermostatMode::Off,
+                "heat" => protocol::ThermostatMode::Heat,
+                "auto" => protocol::ThermostatMode::Auto,
+                _ => return None,
+            };
+            Some(protocol::Cmd::SetThermostatMode(mode))
+        },
+        "off|heat|auto to set mode of thermostat, \"dev name\" is name of thermostat",
+    ),
+    (
+        "set_setpoint",
+        |arg| arg.parse().ok().map(protocol::Cmd::SetSetpoint),
+        "\"temperature\" to set temperature thermostat keeps in auto mode",
+    ),
+];
+
+#[derive(Clone)]
 pub enum ConsoleCmd {
-    GetDevs,
-    TurnOn(String),
-    TurnOff(String),
-    GetPower(String),
-    GetTemp(String),
+    Request(protocol::Request),
     Exit,
 }
 
 fn help() {
-    println!("Type \"{}\" to all devices from all servers", GET_DEVICES);
     println!(
-        "Type \"{}\" \"dev name\" to turn on particular device",
-        TURN_ON
+        "Type \"{}\" [type=\"type name\"] [state=on|off|fault] \
+         [prefix=\"name prefix\"] [tag=\"tag\"] [limit=\"count\"] [after=\"cursor\"] \
+         to get devices from all servers",
+        GET_DEVICES
+    );
+    for (name, _, descr) in DEV_CMDS {
+        println!("Type \"{}\" \"dev name\" {}", name, descr);
+    }
+    for (name, _, descr) in ARG_CMDS {
+        println!("Type \"{}\" \"dev name\" {}", name, descr);
+    }
+    println!(
+        "Type \"{}\" [\"dev name\"] to get energy consumption and cost of sockets",
+        GET_ENERGY
+    );
+    println!(
+        "Type \"{}\" [{}] \"command\" {} \"command\" ... to execute several commands at once, \
+         with \"{}\" commands are applied only if all of them succeed",
+        BATCH, BATCH_ALL, BATCH_SEPARATOR, BATCH_ALL
+    );
+    println!(
+        "Type \"{}\" [w|kw] [c|f|k] to set preferred units of readings, \
+         without units readings are shown in units of server",
+        UNITS
+    );
+    println!(
+        "Type \"{}\" [\"dev name\"] to get alerts of devices",
+        GET_ALERTS
+    );
+    println!(
+        "Type \"{}\" \"dev name\" \"minute\" \"hour\" \"day\" \"month\" \"weekday\" \"command\" [argument] \
+         to run command by cron expression, e.g. \"{} sock1 30 6 * * 1-5 turn_on\"",
+        SCHEDULE, SCHEDULE
+    );
+    println!(
+        "Type \"{}\" \"dev name\" \"YYYY-MM-DDTHH:MM\" \"command\" [argument] to run command once",
+        SCHEDULE_AT
+    );
+    println!("Type \"{}\" to get schedules of all servers", SCHEDULES);
+    println!(
+        "Type \"{}\" \"dev name\" \"id\" to remove schedule",
+        UNSCHEDULE
     );
     println!(
-        "Type \"{}\" \"dev name\" to turn off particular device",
-        TURN_OFF
+        "Type \"{}\" [\"thermostat name\"] to get state of thermostats",
+        GET_THERMOSTAT
     );
-    println!("Type \"{}\" \"dev name\" to get power of socket", GET_POWER);
     println!(
-        "Type \"{}\" \"dev name\" to get temperature of thermometer",
-        GET_TEMP
+        "Type \"{}\" \"group name\" [\"dev name\" ...] to define group, without devices group is removed",
+        GROUP
+    );
+    println!(
+        "Type \"{}\" \"group name\" \"command\" [argument] to execute command on all devices of group",
+        GROUP_DO
+    );
+    println!(
+        "Type \"{}\" \"scene name\" [\"dev name\" \"command\" [argument] {} ...] to define scene, \
+         without commands scene is removed",
+        SCENE, BATCH_SEPARATOR
+    );
+    println!("Type \"{}\" \"scene name\" to apply scene", APPLY_SCENE);
+    println!(
+        "Type \"{}\" to get groups and scenes of all servers",
+        GET_SCENES
     );
     println!("Type \"{}\" get report from servers", GET_REPORT);
     println!("Type \"exit\" to exit from smart house app");
 }
 
+/// Parses filters of device listing given as "key=value"
+fn parse_list_query(params: &[String]) -> Option<protocol::ListQuery> {
+    let mut query = protocol::ListQuery::default();
+    for param in params {
+        let (key, value) = param.split_once('=')?;
+        match key {
+            "type" => query.type_dev = Some(value.to_owned()),
+            "state" => {
+                let state = match value {
+                    "on" => protocol::StateFilter::On,
+                    "off" => protocol::StateFilter::Off,
+                    "fault" => protocol::StateFilter::Fault,
+                    _ => return None,
+                };
+                query.state = Some(state);
+            }
+            "prefix" => query.name_prefix = Some(value.to_owned()),
+            "tag" => query.tag = Some(value.to_owned()),
+            "limit" => query.limit = value.parse().ok()?,
+            "after" => query.cursor = Some(value.to_owned()),
+            _ => return None,
+        }
+    }
+    Some(query)
+}
+
+fn parse_units(params: &[String]) -> Option<Vec<protocol::Unit>> {
+    params
+        .iter()
+        .map(|param| match param.to_lowercase().as_str() {
+            "w" => Some(protocol::Unit::Watt),
+            "kw" => Some(protocol::Unit::Kilowatt),
+            "c" => Some(protocol::Unit::Celsius),
+            "f" => Some(protocol::Unit::Fahrenheit),
+            "k" => Some(protocol::Unit::Kelvin),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Parses commands of batch separated by `BATCH_SEPARATOR`
+fn parse_batch(params: &[String]) -> Option<protocol::Cmd> {
+    let (mode, params) = match params.first().map(|param| param.as_str()) {
+        Some(BATCH_ALL) => (protocol::BatchMode::AllOrNothing, &params[1..]),
+        _ => (protocol::BatchMode::BestEffort, params),
+    };
+    let mut items = Vec::new();
+    for item in params.join(" ").split(BATCH_SEPARATOR) {
+        let item_params = item
+            .split_whitespace()
+            .map(|param| param.to_owned())
+            .collect::<Vec<String>>();
+        if item_params.is_empty() || item_params[0] == BATCH {
+            return None;
+        }
+        match parse_cmd(&item_params)? {
+            ConsoleCmd::Request(req) => items.push(req),
+            ConsoleCmd::Exit => return None,
+        }
+    }
+    Some(protocol::Cmd::Batch(items, mode))
+}
+
+fn parse_schedule_time(time: &str) -> Option<protocol::ScheduleTime> {
+    let time = NaiveDateTime::parse_from_str(time, SCHEDULE_TIME_FORMAT).ok()?;
+    let time = Local.from_local_datetime(&time).earliest()?;
+    Some(protocol::ScheduleTime::Once(time.timestamp_millis()))
+}
+
+/// Parses scheduled command given as "command" [argument]
+fn parse_schedule(
+    dev_name: &str,
+    when: protocol::ScheduleTime,
+    cmd_params: &[String],
+) -> Option<protocol::Request> {
+    let (cmd, args) = cmd_params.split_first()?;
+    let mut params = vec![cmd.to_owned(), dev_name.to_owned()];
+    params.extend_from_slice(args);
+    let ConsoleCmd::Request(req) = parse_cmd(&params)? else {
+        return None;
+    };
+    let schedule = protocol::Schedule {
+        when,
+        cmd: Box::new(req.cmd),
+    };
+    Some(protocol::Request::new(
+        protocol::Cmd::AddSchedule(schedule),
+        dev_name.to_owned(),
+    ))
+}
+
+/// Parses command of device given as "command" "dev name" [argument]
+fn parse_dev_cmd(params: &[String]) -> Option<protocol::Cmd> {
+    match parse_cmd(params)? {
+        ConsoleCmd::Request(req) if !req.dev_name.is_empty() => Some(req.cmd),
+        _ => None,
+    }
+}
+
+/// Parses items of scene given as "dev name" "command" [argument] separated by `BATCH_SEPARATOR`
+fn parse_scene(params: &[String]) -> Option<Vec<protocol::SceneItem>> {
+    if params.is_empty() {
+        return Some(Vec::new());
+    }
+    let mut items = Vec::new();
+    for item in params.join(" ").split(BATCH_SEPARATOR) {
+        let mut item_params = item
+            .split_whitespace()
+            .map(|param| param.to_owned())
+            .collect::<Vec<String>>();
+        if item_params.len() < 2 {
+            return None;
+        }
+        item_params.swap(0, 1);
+        items.push(protocol::SceneItem {
+            dev_name: item_params[1].clone(),
+            cmd: parse_dev_cmd(&item_params)?,
+        });
+    }
+    Some(items)
+}
+
+fn parse_cmd(params: &[String]) -> Option<ConsoleCmd> {
+    let req = match (params[0].as_str(), params.len()) {
+        (BATCH, 2..) => protocol::Request::new(parse_batch(&params[1..])?, String::new()),
+        (GET_DEVICES, _) => protocol::Request::new(
+            protocol::Cmd::GetListDevices(parse_list_query(&params[1..])?),
+            String::new(),
+        ),
+        (GET_ENERGY, 1) => protocol::Request::new(protocol::Cmd::Energy, String::new()),
+        (GET_ENERGY, 2) => protocol::Request::new(protocol::Cmd::Energy, params[1].to_owned()),
+        (GET_ALERTS, 1) => protocol::Request::new(protocol::Cmd::GetAlerts, String::new()),
+        (GET_ALERTS, 2) => protocol::Request::new(protocol::Cmd::GetAlerts, params[1].to_owned()),
+        (SCHEDULE, 8..=9) => parse_schedule(
+            &params[1],
+            protocol::ScheduleTime::Cron(params[2..7].join(" ")),
+            &params[7..],
+        )?,
+        (SCHEDULE_AT, 4..=5) => {
+            parse_schedule(&params[1], parse_schedule_time(&params[2])?, &params[3..])?
+        }
+        (SCHEDULES, 1) => protocol::Request::new(protocol::Cmd::ListSchedules, String::new()),
+        (UNSCHEDULE, 3) => protocol::Request::new(
+            protocol::Cmd::RemoveSchedule(params[2].parse().ok()?),
+            params[1].to_owned(),
+        ),
+        (GET_THERMOSTAT, 1) => protocol::Request::new(protocol::Cmd::GetThermostat, String::new()),
+        (GET_THERMOSTAT, 2) => {
+            protocol::Request::new(protocol::Cmd::GetThermostat, params[1].to_owned())
+        }
+        (GROUP, 2..) => protocol::Request::new(
+            protocol::Cmd::DefineGroup(params[2..].to_vec()),
+            params[1].to_owned(),
+        ),
+        (GROUP_DO, 3..=4) => {
+            let mut cmd_params = vec![params[2].to_owned(), params[1].to_owned()];
+            cmd_params.extend_from_slice(&params[3..]);
+            let cmd = parse_dev_cmd(&cmd_params)?;
+            protocol::Request::new(
+                protocol::Cmd::GroupAction(Box::new(cmd)),
+                params[1].to_owned(),
+            )
+        }
+        (SCENE, 2..) => protocol::Request::new(
+            protocol::Cmd::DefineScene(parse_scene(&params[2..])?),
+            params[1].to_owned(),
+        ),
+        (APPLY_SCENE, 2) => protocol::Request::new(protocol::Cmd::ApplyScene, params[1].to_owned()),
+        (GET_SCENES, 1) => protocol::Request::new(protocol::Cmd::GetScenes, String::new()),
+        (EXIT, 1) => return Some(ConsoleCmd::Exit),
+        (name, 2) => {
+            let (_, cmd, _) = DEV_CMDS.iter().find(|(cmd_name, _, _)| *cmd_name == name)?;
+            protocol::Request::new(cmd.clone(), params[1].to_owned())
+        }
+        (name, 3) => {
+            let (_, parse_arg, _) = ARG_CMDS.iter().find(|(cmd_name, _, _)| *cmd_name == name)?;
+            let cmd = match parse_arg(&params[2]) {
+                Some(cmd) => cmd,
+                None => {
+                    println!("Invalid argument: {}", params[2]);
+                    return None;
+                }
+            };
+            protocol::Request::new(cmd, params[1].to_owned())
+        }
+        _ => return None,
+    };
+    Some(ConsoleCmd::Request(req))
+}
+
+/// Name of command typed in console, so capabilities of device
+/// are shown as commands user is able to apply
+fn console_name(cmd: &protocol::Cmd) -> String {
+    if let protocol::Cmd::Energy = cmd {
+        return GET_ENERGY.to_owned();
+    }
+    if let Some((name, _, _)) = DEV_CMDS
+        .iter()
+        .find(|(_, dev_cmd, _)| dev_cmd.same_kind(cmd))
+    {
+        return name.to_string();
+    }
+    // Any valid argument gives command of the same kind
+    if let Some((name, _, _)) = ARG_CMDS
+        .iter()
+        .find(|(_, parse_arg, _)| parse_arg("1").is_some_and(|arg_cmd| arg_cmd.same_kind(cmd)))
+    {
+        return name.to_string();
+    }
+    format!("{:?}", cmd)
+}
+
+/// Command with its argument as typed in console
+fn format_cmd(cmd: &protocol::Cmd) -> String {
+    let name = console_name(cmd);
+    match cmd {
+        protocol::Cmd::SetLevel(arg) | protocol::Cmd::SetTarget(arg) => format!("{name} {arg}"),
+        protocol::Cmd::TurnOnFor(minutes) => format!("{name} {minutes}"),
+        _ => name,
+    }
+}
+
+fn format_schedule(entry: &protocol::ScheduleEntry) -> String {
+    let mut res = format!(
+        "{}: {}: {}, {}",
+        entry.id,
+        entry.dev_name,
+        format_cmd(&entry.schedule.cmd),
+        entry.schedule.when
+    );
+    if let Some(next_run_ms) = entry.next_run_ms {
+        res.push_str(&format!(
+            ", next run at {}",
+            protocol::format_time(next_run_ms)
+        ));
+    }
+    if let Some(last_run_ms) = entry.last_run_ms {
+        res.push_str(&format!(
+            ", last run at {}",
+            protocol::format_time(last_run_ms)
+        ));
+    }
+    res
+}
+
+fn format_reading(reading: &protocol::SuccessKind) -> Option<String> {
+    let res = match reading {
+        protocol::SuccessKind::Power(power) => format!("power: {}", power),
+        protocol::SuccessKind::Temp(temp) => format!("temperature: {}", temp),
+        protocol::SuccessKind::Brightness(level) => format!("brightness: {}%", level),
+        protocol::SuccessKind::Humidity(humidity) => format!("humidity: {}", humidity),
+        protocol::SuccessKind::Lock(state) => format!("lock state: {}", state),
+        protocol::SuccessKind::Leak(is_leak) => {
+            let state = if *is_leak { "LEAK DETECTED" } else { "dry" };
+            format!("leak sensor: {}", state)
+        }
+        _ => return None,
+    };
+    Some(res)
+}
+
+fn format_state(state: &protocol::DeviceState) -> String {
+    let mut res = format!(
+        "{}, fault: {}, uptime: {} s",
+        if state.is_on { "on" } else { "off" },
+        if state.is_fault { "yes" } else { "no" },
+        state.uptime_secs
+    );
+    match state.last_reading.as_deref().and_then(format_reading) {
+        Some(reading) => res.push_str(&format!(", last {}", reading)),
+        None => res.push_str(", no readings yet"),
+    }
+    if let Some(off_in) = state.off_in_secs {
+        res.push_str(&format!(", auto-off in {} s", off_in));
+    }
+    res
+}
+
+fn format_cmds(cmds: &[protocol::Cmd]) -> String {
+    cmds.iter()
+        .map(console_name)
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// Prints response of server, `transport` is prefix for output
+pub fn print_response(transport: &str, resp: &protocol::Response) {
+    print_result(transport, &resp.to_req, &resp.resp_kind);
+}
+
+fn print_result(transport: &str, req: &protocol::Request, resp_kind: &protocol::ResponseKind) {
+    match resp_kind {
+        protocol::ResponseKind::Success(success_kind) => {
+            match success_kind {
+                protocol::SuccessKind::Ack => match &req.cmd {
+                    protocol::Cmd::DefineGroup(members) => {
+                        let action = if members.is_empty() {
+                            "removed"
+                        } else {
+                            "defined"
+                        };
+                        println!("{transport}: Group {} {action}", req.dev_name);
+                    }
+                    protocol::Cmd::DefineScene(items) => {
+                        let action = if items.is_empty() {
+                            "removed"
+                        } else {
+                            "defined"
+                        };
+                        println!("{transport}: Scene {} {action}", req.dev_name);
+                    }
+                    cmd => println!("{transport}: Command: {:?} success", cmd),
+                },
+                protocol::SuccessKind::ListDev(page) => {
+                    println!("{transport}: Count devices in page: {}", page.devices.len());
+                    for dev in page.devices.iter() {
+                        println!("{}: {}", dev.name, dev.type_dev)
+                    }
+                    if let Some(cursor) = &page.next_cursor {
+                        println!("{transport}: More devices available, add \"after={cursor}\" to get them");
+                    }
+                }
+                protocol::SuccessKind::Energy(reports) => {
+                    println!("{transport}: Energy reports: {}", reports.len());
+                    for report in reports.iter() {
+                        println!("{}", report);
+                    }
+                }
+                protocol::SuccessKind::State(state) => {
+                    println!(
+                        "{transport}: Device: {} state: {}",
+                        req.dev_name,
+                        format_state(state)
+                    );
+                }
+                protocol::SuccessKind::Capabilities(caps) => {
+                    println!(
+                        "{transport}: Device: {} commands: {}",
+                        req.dev_name,
+                        format_cmds(&caps.commands)
+                    );
+                    println!(
+                        "{transport}: Device: {} readings: {}",
+                        req.dev_name,
+                        format_cmds(&caps.readings)
+                    );
+                }
+                protocol::SuccessKind::Alerts(alerts) => {
+                    let raised = alerts.iter().filter(|alert| alert.is_raised).count();
+                    println!("{transport}: Alerts: {}, raised: {}", alerts.len(), raised);
+                    for alert in alerts.iter() {
+                        println!("{}", alert);
+                    }
+                }
+                protocol::SuccessKind::ScheduleAdded(id) => {
+                    println!("{transport}: Schedule {} added for {}", id, req.dev_name);
+                }
+                protocol::SuccessKind::Schedules(entries) => {
+                    println!("{transport}: Schedules: {}", entries.len());
+                    for entry in entries.iter() {
+                        println!("{}", format_schedule(entry));
+                    }
+                }
+                protocol::SuccessKind::Thermostats(thermostats) => {
+                    println!("{transport}: Thermostats: {}", thermostats.len());
+                    for thermostat in thermostats.iter() {
+                        println!("{}", thermostat);
+                    }
+                }
+                protocol::SuccessKind::DeviceResults(results) => {
+                    println!("{transport}: {} devices of {}", results.len(), req.dev_name);
+                    let cmd = match &req.cmd {
+                        protocol::Cmd::GroupAction(cmd) => cmd.as_ref(),
+                        cmd => cmd,
+                    };
+                    for res in results.iter() {
+                        let dev_req = protocol::Request::new(cmd.clone(), res.dev_name.clone());
+                        match &res.result {
+                            protocol::ResponseKind::Success(protocol::SuccessKind::Ack) => {
+                                println!("{transport}: Device: {} success", res.dev_name);
+                            }
+                            result => print_result(transport, &dev_req, result),
+                        }
+                    }
+                }
+                protocol::SuccessKind::Scenes(groups, scenes) => {
+                    println!(
+                        "{transport}: Groups: {}, scenes: {}",
+                        groups.len(),
+                        scenes.len()
+                    );
+                    for group in groups.iter() {
+                        println!("group {}: {}", group.name, group.devices.join(", "));
+                    }
+                    for scene in scenes.iter() {
+                        let items: Vec<String> = scene
+                            .items
+                            .iter()
+                            .map(|item| format!("{} {}", item.dev_name, format_cmd(&item.cmd)))
+                            .collect();
+                        println!("scene {}: {}", scene.name, items.join(", "));
+                    }
+                }
+                protocol::SuccessKind::Batch(results) => {
+                    let items: &[protocol::Request] = match &req.cmd {
+                        protocol::Cmd::Batch(items, _) => items,
+                        _ => &[],
+                    };
+                    println!("{transport}: Batch of {} commands executed", results.len());
+                    for (i, (item, result)) in items.iter().zip(results.iter()).enumerate() {
+                        print_result(&format!("{transport} #{}", i + 1), item, result);
+                    }
+                }
+                reading => {
+                    if let Some(reading) = format_reading(reading) {
+                        println!("{transport}: Device: {} {}", req.dev_name, reading);
+                    }
+                }
+            }
+        }
+        protocol::ResponseKind::Err(e) => {
+            println!("{transport}: {}", e);
+        }
+    }
+}
+
 pub trait Service {
     fn start_service(self, rx: Receiver<ConsoleCmd>) -> JoinHandle<()>;
 }
@@ -63,6 +651,8 @@ impl Channel {
 
 pub struct ConsoleServer {
     channels: HashMap<&'static str, Channel>,
+    /// Preferred units added to every request
+    units: Vec<protocol::Unit>,
 }
 
 impl ConsoleServer {
@@ -111,6 +701,7 @@ impl ConsoleServer {
     pub fn new() -> Self {
         Self {
             channels: HashMap::new(),
+            units: Vec::new(),
         }
     }
 
@@ -140,111 +731,209 @@ impl ConsoleServer {
                 continue;
             }
 
-            match params[0].as_str() {
-                GET_DEVICES => {
-                    if let Err(e) = self.send_service_cmd(TcpClient::name(), ConsoleCmd::GetDevs) {
-                        error!("Can't get tcp devices: {e}");
-                        panic!();
-                    }
-
-                    if let Err(e) = self.send_service_cmd(UdpClient::name(), ConsoleCmd::GetDevs) {
-                        error!("Can't get udp devices: {e}");
-                        panic!();
+            if params[0] == UNITS {
+                match parse_units(&params[1..]) {
+                    Some(units) => {
+                        println!("Preferred units: {:?}", units);
+                        self.units = units;
                     }
+                    None => println!("Unknown unit"),
                 }
-                TURN_ON => {
-                    if params.len() != 2 {
-                        println!("Wrong command");
-                        help();
-                        continue;
-                    }
-
-                    self.send_service_cmd(
-                        TcpClient::name(),
-                        ConsoleCmd::TurnOn(params[1].to_owned()),
-                    )
-                    .unwrap();
-                    self.send_service_cmd(
-                        UdpClient::name(),
-                        ConsoleCmd::TurnOn(params[1].to_owned()),
-                    )
-                    .unwrap();
-                }
-                TURN_OFF => {
-                    if params.len() != 2 {
-                        println!("Wrong command");
-                        help();
-                        continue;
-                    }
-
-                    self.send_service_cmd(
-                        TcpClient::name(),
-                        ConsoleCmd::TurnOff(params[1].to_owned()),
-                    )
-                    .unwrap();
-                    self.send_service_cmd(
-                        UdpClient::name(),
-                        ConsoleCmd::TurnOff(params[1].to_owned()),
-                    )
-                    .unwrap();
-                }
-
-                GET_POWER => {
-                    if params.len() != 2 {
-                        println!("Wrong command");
-                        help();
-                        continue;
-                    }
-
-                    self.send_service_cmd(
-                        TcpClient::name(),
-                        ConsoleCmd::GetPower(params[1].to_owned()),
-                    )
-                    .unwrap();
-                    self.send_service_cmd(
-                        UdpClient::name(),
-                        ConsoleCmd::GetPower(params[1].to_owned()),
-                    )
-                    .unwrap();
-                }
-
-                GET_TEMP => {
-                    if params.len() != 2 {
-                        println!("Wrong command");
-                        help();
-                        continue;
-                    }
+                continue;
+            }
 
-                    self.send_service_cmd(
-                        TcpClient::name(),
-                        ConsoleCmd::GetTemp(params[1].to_owned()),
-                    )
-                    .unwrap();
-                    self.send_service_cmd(
-                        UdpClient::name(),
-                        ConsoleCmd::GetTemp(params[1].to_owned()),
-                    )
-                    .unwrap();
-                }
-
-                EXIT => {
-                    if let Err(e) = self.send_service_cmd(TcpClient::name(), ConsoleCmd::Exit) {
-                        error!("Can't stop tcp client: {e}");
-                    }
-                    if let Err(e) = self.send_service_cmd(UdpClient::name(), ConsoleCmd::Exit) {
-                        error!("Can't stop udp server: {e}");
-                    }
-                    info!("Exit from emulator");
-                    println!("Exit from emulator");
-                    break;
+            let cmd = match parse_cmd(&params) {
+                Some(ConsoleCmd::Request(mut req)) => {
+                    req.units = self.units.clone();
+                    ConsoleCmd::Request(req)
                 }
-                _ => {
+                Some(cmd) => cmd,
+                None => {
                     println!("Unexpected command");
                     help();
+                    continue;
+                }
+            };
+
+            if let ConsoleCmd::Exit = cmd {
+                if let Err(e) = self.send_service_cmd(TcpClient::name(), ConsoleCmd::Exit) {
+                    error!("Can't stop tcp client: {e}");
+                }
+                if let Err(e) = self.send_service_cmd(UdpClient::name(), ConsoleCmd::Exit) {
+                    error!("Can't stop udp server: {e}");
                 }
+                info!("Exit from emulator");
+                println!("Exit from emulator");
+                break;
+            }
+
+            if let Err(e) = self.send_service_cmd(TcpClient::name(), cmd.clone()) {
+                error!("Can't send command to tcp client: {e}");
+                panic!();
+            }
+            if let Err(e) = self.send_service_cmd(UdpClient::name(), cmd) {
+                error!("Can't send command to udp client: {e}");
+                panic!();
             }
         }
         self.join();
         println!("All services stopped");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(line: &str) -> Vec<String> {
+        line.split_whitespace()
+            .map(|param| param.to_owned())
+            .collect()
+    }
+
+    /// Commands don't implement PartialEq, so they are compared by debug output
+    fn debug<T: std::fmt::Debug>(value: T) -> String {
+        format!("{:?}", value)
+    }
+
+    fn req(cmd: protocol::Cmd, dev_name: &str) -> protocol::Request {
+        protocol::Request::new(cmd, dev_name.to_owned())
+    }
+
+    #[test]
+    fn test_parse_schedule_time() {
+        let at_7_30 = Local
+            .with_ymd_and_hms(2026, 10, 19, 7, 30, 0)
+            .unwrap()
+            .timestamp_millis();
+        let cases = [
+            (
+                "2026-10-19T07:30",
+                Some(protocol::ScheduleTime::Once(at_7_30)),
+            ),
+            ("2026-10-19 07:30", None),
+            ("2026-10-19T07:30:00", None),
+            ("2026-13-19T07:30", None),
+            ("2026-10-19T25:30", None),
+            ("07:30", None),
+            ("", None),
+        ];
+        for (time, expected) in cases {
+            assert_eq!(debug(parse_schedule_time(time)), debug(expected), "{time}");
+        }
+    }
+
+    #[test]
+    fn test_parse_schedule() {
+        let cron = || protocol::ScheduleTime::Cron("0 7 * * *".to_owned());
+        let schedule = |cmd| {
+            let schedule = protocol::Schedule {
+                when: cron(),
+                cmd: Box::new(cmd),
+            };
+            Some(req(protocol::Cmd::AddSchedule(schedule), "lamp"))
+        };
+        let cases = [
+            ("turn_on", schedule(protocol::Cmd::TurnOn)),
+            ("set_level 30", schedule(protocol::Cmd::SetLevel(30.0))),
+            ("energy", schedule(protocol::Cmd::Energy)),
+            ("set_level thirty", None),
+            ("turn_on 30", None),
+            ("fly", None),
+            ("exit", None),
+            ("get_devs", None),
+            ("", None),
+        ];
+        for (cmd, expected) in cases {
+            let parsed = parse_schedule("lamp", cron(), &params(cmd));
+            assert_eq!(debug(parsed), debug(expected), "{cmd}");
+        }
+
+        // Console forms of schedule
+        let cases = [
+            ("schedule lamp 0 7 * * * turn_on", true),
+            ("schedule lamp 0 7 * * * set_level 30", true),
+            ("schedule lamp 0 7 * * turn_on", false),
+            ("schedule lamp 0 7 * * * set_level 30 40", false),
+            ("schedule_at lamp 2026-10-19T07:30 turn_on", true),
+            ("schedule_at lamp 2026-10-19T07:30 set_level 30", true),
+            ("schedule_at lamp 07:30 turn_on", false),
+            ("schedule_at lamp 2026-10-19T07:30", false),
+        ];
+        for (line, is_valid) in cases {
+            assert_eq!(parse_cmd(&params(line)).is_some(), is_valid, "{line}");
+        }
+    }
+
+    #[test]
+    fn test_parse_scene() {
+        let item = |dev_name: &str, cmd| protocol::SceneItem {
+            dev_name: dev_name.to_owned(),
+            cmd,
+        };
+        let cases = [
+            ("", Some(Vec::new())),
+            (
+                "lamp turn_on",
+                Some(vec![item("lamp", protocol::Cmd::TurnOn)]),
+            ),
+            (
+                "lamp set_level 30 ; socket turn_off",
+                Some(vec![
+                    item("lamp", protocol::Cmd::SetLevel(30.0)),
+                    item("socket", protocol::Cmd::TurnOff),
+                ]),
+            ),
+            ("lamp", None),
+            ("turn_on lamp", None),
+            ("lamp turn_on ;", None),
+            ("lamp turn_on ; ; socket turn_off", None),
+            ("lamp set_level", None),
+            ("lamp set_level bright", None),
+            ("lamp get_devs", None),
+            ("lamp exit", None),
+        ];
+        for (line, expected) in cases {
+            assert_eq!(debug(parse_scene(&params(line))), debug(expected), "{line}");
+        }
+    }
+
+    #[test]
+    fn test_parse_batch() {
+        let batch = |items, mode| Some(protocol::Cmd::Batch(items, mode));
+        let cases = [
+            (
+                "turn_on lamp ; get_power socket",
+                batch(
+                    vec![
+                        req(protocol::Cmd::TurnOn, "lamp"),
+                        req(protocol::Cmd::Power, "socket"),
+                    ],
+                    protocol::BatchMode::BestEffort,
+                ),
+            ),
+            (
+                "all turn_on lamp;set_level lamp 30",
+                batch(
+                    vec![
+                        req(protocol::Cmd::TurnOn, "lamp"),
+                        req(protocol::Cmd::SetLevel(30.0), "lamp"),
+                    ],
+                    protocol::BatchMode::AllOrNothing,
+                ),
+            ),
+            ("all", None),
+            ("turn_on lamp ;", None),
+            ("; turn_on lamp", None),
+            ("turn_on", None),
+            ("turn_on lamp ; fly lamp", None),
+            ("set_level lamp bright", None),
+            ("batch turn_on lamp", None),
+            ("turn_on lamp ; batch turn_off lamp", None),
+            ("turn_on lamp ; exit", None),
+        ];
+        for (line, expected) in cases {
+            assert_eq!(debug(parse_batch(&params(line))), debug(expected), "{line}");
+        }
+    }
+}
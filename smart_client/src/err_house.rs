@@ -10,6 +10,7 @@ pub enum ErrorKind {
     UnknownService,
     DeserializationError,
     UnknownTypePack,
+    PayloadTooLarge,
 }
 
 #[derive(Debug)]
@@ -35,7 +36,7 @@ impl Display for Err {
 impl From<io::Error> for Err {
     fn from(value: io::Error) -> Self {
         match value.kind() {
-            io::ErrorKind::TimedOut => Self::new(ErrorKind::IoTimeOut),
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => Self::new(ErrorKind::IoTimeOut),
             _ => Self::new(ErrorKind::IoError),
         }
     }
@@ -1,26 +1,201 @@
+use chrono::{Local, TimeZone};
 use serde::{Deserialize, Serialize};
 use std::fmt::Display;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Cmd {
-    GetListDevices,
+    GetListDevices(ListQuery),
     TurnOn,
     TurnOff,
     Power,
     Temperature,
+    Energy,
+    Brightness,
+    Humidity,
+    Lock,
+    Unlock,
+    LockState,
+    Leak,
+    ResetAlarm,
+    /// Dim level of lamp in %, power limit of socket in W
+    SetLevel(f64),
+    /// Target temperature of thermometer in C
+    SetTarget(f64),
+    /// Turns device on for given number of minutes
+    TurnOnFor(u32),
+    Target,
+    GetState,
+    GetCapabilities,
+    /// Several requests executed together
+    Batch(Vec<Request>, BatchMode),
+    /// Alerts of device or of all devices if device name is empty
+    GetAlerts,
+    /// Schedules command on device, id of schedule is returned
+    AddSchedule(Schedule),
+    ListSchedules,
+    /// Removes schedule by id, device name of schedule is optional
+    RemoveSchedule(u32),
+    /// State of thermostat or of all thermostats if name is empty
+    GetThermostat,
+    SetThermostatMode(ThermostatMode),
+    /// Temperature thermostat keeps in auto mode, C
+    SetSetpoint(f64),
+    /// Defines group of devices named by device name of request, empty group is removed
+    DefineGroup(Vec<String>),
+    /// Executes command on each device of group
+    GroupAction(Box<Cmd>),
+    /// Defines scene named by device name of request, empty scene is removed
+    DefineScene(Vec<SceneItem>),
+    ApplyScene,
+    /// Groups and scenes of server
+    GetScenes,
+}
+
+impl Cmd {
+    /// Compares commands ignoring their arguments
+    pub fn same_kind(&self, other: &Cmd) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other)
+    }
 }
 
-#[derive(Serialize, Deserialize, Copy, Clone)]
-pub enum TypeDev {
-    SmartSocket,
-    SmartTherm,
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum BatchMode {
+    BestEffort,
+    /// Failed item rolls back all items applied before it
+    AllOrNothing,
 }
 
-impl Display for TypeDev {
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ScheduleTime {
+    /// Cron expression "minute hour day-of-month month day-of-week" in local time of server
+    Cron(String),
+    /// One-shot time, milliseconds since Unix epoch
+    Once(i64),
+}
+
+impl Display for ScheduleTime {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            TypeDev::SmartSocket => write!(f, "Smart Socket"),
-            TypeDev::SmartTherm => write!(f, "Smart Therm"),
+            ScheduleTime::Cron(expr) => write!(f, "cron \"{}\"", expr),
+            ScheduleTime::Once(time_ms) => write!(f, "once at {}", format_time(*time_ms)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Schedule {
+    pub when: ScheduleTime,
+    pub cmd: Box<Cmd>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ScheduleEntry {
+    pub id: u32,
+    pub dev_name: String,
+    pub schedule: Schedule,
+    pub next_run_ms: Option<i64>,
+    pub last_run_ms: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum ThermostatMode {
+    Off,
+    Heat,
+    Auto,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ThermostatState {
+    pub name: String,
+    pub therm: String,
+    pub heater: String,
+    pub mode: ThermostatMode,
+    pub setpoint: f64,
+    pub temperature: Option<f64>,
+    pub is_heating: bool,
+    pub control: String,
+}
+
+impl Display for ThermostatState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {:?} mode, setpoint {:.1} C, ",
+            self.name, self.mode, self.setpoint
+        )?;
+        match self.temperature {
+            Some(temp) => write!(f, "temperature {:.2} C", temp)?,
+            None => write!(f, "no temperature")?,
+        }
+        write!(
+            f,
+            ", heater {} is {}, {} by {}",
+            self.heater,
+            if self.is_heating { "on" } else { "off" },
+            self.control,
+            self.therm
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SceneItem {
+    pub dev_name: String,
+    pub cmd: Cmd,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Group {
+    pub name: String,
+    pub devices: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Scene {
+    pub name: String,
+    pub items: Vec<SceneItem>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DeviceResult {
+    pub dev_name: String,
+    pub result: ResponseKind,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum StateFilter {
+    On,
+    Off,
+    Fault,
+}
+
+/// Filters and page of device listing
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ListQuery {
+    /// Type name from config of server, e.g. "socket"
+    pub type_dev: Option<String>,
+    pub state: Option<StateFilter>,
+    pub name_prefix: Option<String>,
+    pub tag: Option<String>,
+    /// Name of the last device of previous page
+    pub cursor: Option<String>,
+    /// Max count of devices in page, zero means as many as fit in frame
+    pub limit: u8,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum LockState {
+    Locked,
+    Unlocked,
+    Jammed,
+}
+
+impl Display for LockState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LockState::Locked => write!(f, "locked"),
+            LockState::Unlocked => write!(f, "unlocked"),
+            LockState::Jammed => write!(f, "jammed"),
         }
     }
 }
@@ -28,40 +203,251 @@ impl Display for TypeDev {
 #[derive(Serialize, Deserialize)]
 pub struct Device {
     pub name: String,
-    pub type_dev: TypeDev,
+    /// Type name from config of server, e.g. "socket"
+    pub type_dev: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum Unit {
+    Watt,
+    Kilowatt,
+    Celsius,
+    Fahrenheit,
+    Kelvin,
+    Percent,
+}
+
+impl Display for Unit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Unit::Watt => write!(f, "W"),
+            Unit::Kilowatt => write!(f, "kW"),
+            Unit::Celsius => write!(f, "°C"),
+            Unit::Fahrenheit => write!(f, "°F"),
+            Unit::Kelvin => write!(f, "K"),
+            Unit::Percent => write!(f, "%"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum Quality {
+    Fresh,
+    Stale,
+    Simulated,
+}
+
+impl Display for Quality {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Quality::Fresh => write!(f, "fresh"),
+            Quality::Stale => write!(f, "stale"),
+            Quality::Simulated => write!(f, "simulated"),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
+pub struct Measurement {
+    pub value: f64,
+    pub unit: Unit,
+    pub quality: Quality,
+    /// Time of sample, milliseconds since Unix epoch
+    pub timestamp_ms: i64,
+}
+
+impl Display for Measurement {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:.2} {} ({} at {})",
+            self.value,
+            self.unit,
+            self.quality,
+            format_time(self.timestamp_ms)
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Request {
     pub cmd: Cmd,
     pub dev_name: String,
+    /// Preferred units of readings, server units are used if empty
+    pub units: Vec<Unit>,
 }
 
 impl Request {
     pub fn new(cmd: Cmd, dev_name: String) -> Self {
-        Self { cmd, dev_name }
+        Self {
+            cmd,
+            dev_name,
+            units: Vec::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Consumption {
+    pub kwh: f64,
+    pub cost: f64,
+}
+
+impl Display for Consumption {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:.3} kWh, cost {:.2}", self.kwh, self.cost)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct EnergyReport {
+    pub dev_name: String,
+    pub total_kwh: f64,
+    pub day: Consumption,
+    pub week: Consumption,
+    pub month: Consumption,
+}
+
+impl Display for EnergyReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Device: {} total: {:.3} kWh",
+            self.dev_name, self.total_kwh
+        )?;
+        writeln!(f, "  day: {}", self.day)?;
+        writeln!(f, "  week: {}", self.week)?;
+        write!(f, "  month: {}", self.month)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DevicePage {
+    pub devices: Vec<Device>,
+    /// Cursor of the next page, none for the last page
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DeviceState {
+    pub is_on: bool,
+    pub is_fault: bool,
+    pub last_reading: Option<Box<SuccessKind>>,
+    pub uptime_secs: u64,
+    /// Seconds until device turns off by itself, e.g. by timer or safety limit
+    pub off_in_secs: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Capabilities {
+    pub commands: Vec<Cmd>,
+    pub readings: Vec<Cmd>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AlertState {
+    pub dev_name: String,
+    pub rule: String,
+    pub is_raised: bool,
+    pub raised_ms: Option<i64>,
+    pub cleared_ms: Option<i64>,
+    pub last_value: Option<f64>,
+}
+
+pub fn format_time(timestamp_ms: i64) -> String {
+    match Local.timestamp_millis_opt(timestamp_ms).single() {
+        Some(time) => time.format("%Y-%m-%d %H:%M:%S").to_string(),
+        None => "?".to_owned(),
+    }
+}
+
+impl Display for AlertState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let state = if self.is_raised { "RAISED" } else { "clear" };
+        write!(f, "{}: {}: {}", self.dev_name, self.rule, state)?;
+        if let Some(value) = self.last_value {
+            write!(f, ", value {:.2}", value)?;
+        }
+        if let Some(raised_ms) = self.raised_ms {
+            write!(f, ", raised at {}", format_time(raised_ms))?;
+        }
+        if let Some(cleared_ms) = self.cleared_ms {
+            write!(f, ", cleared at {}", format_time(cleared_ms))?;
+        }
+        Ok(())
     }
 }
 
 #[derive(Serialize, Deserialize)]
 pub enum SuccessKind {
     Ack,
-    ListDev(Vec<Device>),
-    Power(f64),
-    Temp(f64),
+    ListDev(DevicePage),
+    Power(Measurement),
+    Temp(Measurement),
+    Energy(Vec<EnergyReport>),
+    Brightness(u8),
+    Humidity(Measurement),
+    Lock(LockState),
+    Leak(bool),
+    State(DeviceState),
+    Capabilities(Capabilities),
+    Batch(Vec<ResponseKind>),
+    Alerts(Vec<AlertState>),
+    ScheduleAdded(u32),
+    Schedules(Vec<ScheduleEntry>),
+    Thermostats(Vec<ThermostatState>),
+    DeviceResults(Vec<DeviceResult>),
+    Scenes(Vec<Group>, Vec<Scene>),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
-pub enum ErrorKind {
-    WrongCmd,
-    DevNotFound,
-    UnknownCmd,
+pub enum ErrorCategory {
+    Validation,
+    NotFound,
+    Permission,
+    Device,
+    Server,
+}
+
+impl Display for ErrorCategory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ErrorCategory::Validation => write!(f, "validation"),
+            ErrorCategory::NotFound => write!(f, "not found"),
+            ErrorCategory::Permission => write!(f, "permission"),
+            ErrorCategory::Device => write!(f, "device"),
+            ErrorCategory::Server => write!(f, "server"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Error {
+    /// Stable code of error, e.g. 102 for argument out of range
+    pub code: u16,
+    pub category: ErrorCategory,
+    pub message: String,
+    pub details: Option<String>,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Error {} ({}): {}",
+            self.code, self.category, self.message
+        )?;
+        if let Some(details) = &self.details {
+            write!(f, ": {}", details)?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 pub enum ResponseKind {
     Success(SuccessKind),
-    Err(ErrorKind),
+    Err(Error),
 }
 
 #[derive(Serialize, Deserialize)]
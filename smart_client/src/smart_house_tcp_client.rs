@@ -2,10 +2,10 @@ use std::io::Write;
 use std::sync::mpsc::TryRecvError;
 use std::time::Duration;
 
-use super::console_server::{ConsoleCmd, Service};
+use super::console_server::{print_response, ConsoleCmd, Service};
 use super::err_house;
 use super::protocol;
-use super::transport_layer::{TranportPack, TypePack};
+use super::transport_layer::TranportPack;
 use log::*;
 use std::net::TcpStream;
 use std::sync::mpsc::Receiver;
@@ -70,21 +70,7 @@ impl TcpClient {
                     continue;
                 };
                 let req = match cmd {
-                    ConsoleCmd::GetDevs => {
-                        protocol::Request::new(protocol::Cmd::GetListDevices, String::new())
-                    }
-                    ConsoleCmd::TurnOn(name) => {
-                        protocol::Request::new(protocol::Cmd::TurnOn, name.to_owned())
-                    }
-                    ConsoleCmd::TurnOff(name) => {
-                        protocol::Request::new(protocol::Cmd::TurnOff, name.to_owned())
-                    }
-                    ConsoleCmd::GetPower(name) => {
-                        protocol::Request::new(protocol::Cmd::Power, name.to_owned())
-                    }
-                    ConsoleCmd::GetTemp(name) => {
-                        protocol::Request::new(protocol::Cmd::Temperature, name.to_owned())
-                    }
+                    ConsoleCmd::Request(req) => req,
                     ConsoleCmd::Exit => {
                         info!("Exit from tcp client");
                         break;
@@ -98,7 +84,13 @@ impl TcpClient {
                         panic!();
                     }
                 };
-                let pack = TranportPack::new(TypePack::Simple, raw_req).serialize();
+                let pack = match TranportPack::from_payload(raw_req).serialize() {
+                    Ok(val) => val,
+                    Err(e) => {
+                        println!("Request is too large: {e}");
+                        continue;
+                    }
+                };
                 if let Err(e) = tcp_stream.write_all(&pack) {
                     info!("Connection closed: {:?}", e);
                     break;
@@ -135,31 +127,7 @@ impl TcpClient {
             }
         };
 
-        match resp.resp_kind {
-            protocol::ResponseKind::Success(success_kind) => match success_kind {
-                protocol::SuccessKind::Ack => {
-                    println!("Tcp: Command: {:?} success", resp.to_req.cmd);
-                }
-                protocol::SuccessKind::ListDev(devices) => {
-                    println!("Tcp: Count connected devices: {}", devices.len());
-                    for dev in devices.iter() {
-                        println!("{}: {}", dev.name, dev.type_dev)
-                    }
-                }
-                protocol::SuccessKind::Power(power) => {
-                    println!("Tcp: Device: {} power: {}", resp.to_req.dev_name, power);
-                }
-                protocol::SuccessKind::Temp(temp) => {
-                    println!(
-                        "Tcp: Device: {} temperature: {}",
-                        resp.to_req.dev_name, temp
-                    );
-                }
-            },
-            protocol::ResponseKind::Err(e) => {
-                println!("Tcp: Error: {:?}", e);
-            }
-        }
+        print_response("Tcp", &resp);
         Ok(())
     }
 }
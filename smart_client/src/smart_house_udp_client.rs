@@ -1,10 +1,10 @@
 use std::sync::mpsc::TryRecvError;
 use std::time::Duration;
 
-use super::console_server::{ConsoleCmd, Service};
+use super::console_server::{print_response, ConsoleCmd, Service};
 use super::err_house;
 use super::protocol;
-use super::transport_layer::{TranportPack, TypePack};
+use super::transport_layer::{TranportPack, MAX_DATAGRAM};
 use log::*;
 use std::io::Cursor;
 use std::net::UdpSocket;
@@ -76,21 +76,7 @@ impl UdpClient {
                     continue;
                 };
                 let req = match cmd {
-                    ConsoleCmd::GetDevs => {
-                        protocol::Request::new(protocol::Cmd::GetListDevices, String::new())
-                    }
-                    ConsoleCmd::TurnOn(name) => {
-                        protocol::Request::new(protocol::Cmd::TurnOn, name.to_owned())
-                    }
-                    ConsoleCmd::TurnOff(name) => {
-                        protocol::Request::new(protocol::Cmd::TurnOff, name.to_owned())
-                    }
-                    ConsoleCmd::GetPower(name) => {
-                        protocol::Request::new(protocol::Cmd::Power, name.to_owned())
-                    }
-                    ConsoleCmd::GetTemp(name) => {
-                        protocol::Request::new(protocol::Cmd::Temperature, name.to_owned())
-                    }
+                    ConsoleCmd::Request(req) => req,
                     ConsoleCmd::Exit => {
                         info!("Exit from udp client");
                         break;
@@ -104,17 +90,25 @@ impl UdpClient {
                         panic!();
                     }
                 };
-                let pack = TranportPack::new(TypePack::Simple, raw_req).serialize();
+                let pack = match TranportPack::from_payload(raw_req).serialize() {
+                    Ok(val) => val,
+                    Err(e) => {
+                        println!("Request is too large: {e}");
+                        continue;
+                    }
+                };
                 if let Err(e) = udp_sock.send(&pack) {
                     info!("Server: {SERVER_ADDR} doesn't respond: {:?}", e);
                     break;
                 }
 
-                let mut resp = vec![0; 1500];
+                let mut resp = vec![0; MAX_DATAGRAM];
                 match udp_sock.recv(&mut resp) {
                     Ok(pack_len) => resp.shrink_to(pack_len),
                     Err(e) => {
-                        if let std::io::ErrorKind::TimedOut = e.kind() {
+                        if let std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock =
+                            e.kind()
+                        {
                             continue;
                         } else {
                             info!("Connection ins't valid: {:?}", e);
@@ -123,9 +117,10 @@ impl UdpClient {
                     }
                 }
 
+                // Other responses are still valid, client keeps working
                 if let Err(e) = self.handle_response(&resp) {
                     error!("Wrong response: {:?}", e);
-                    break;
+                    continue;
                 }
             }
         })
@@ -144,31 +139,7 @@ impl UdpClient {
             }
         };
 
-        match resp.resp_kind {
-            protocol::ResponseKind::Success(success_kind) => match success_kind {
-                protocol::SuccessKind::Ack => {
-                    println!("Udp: Command: {:?} success", resp.to_req.cmd);
-                }
-                protocol::SuccessKind::ListDev(devices) => {
-                    println!("Udp: Count connected devices: {}", devices.len());
-                    for dev in devices.iter() {
-                        println!("{}: {}", dev.name, dev.type_dev)
-                    }
-                }
-                protocol::SuccessKind::Power(power) => {
-                    println!("Udp: Device: {} power: {}", resp.to_req.dev_name, power);
-                }
-                protocol::SuccessKind::Temp(temp) => {
-                    println!(
-                        "Udp: Device: {} temperature: {}",
-                        resp.to_req.dev_name, temp
-                    );
-                }
-            },
-            protocol::ResponseKind::Err(e) => {
-                println!("Udp: Error: {:?}", e);
-            }
-        }
+        print_response("Udp", &resp);
         Ok(())
     }
 }
@@ -3,10 +3,16 @@ use log::*;
 use std::io::Read;
 
 const SIMPLE_PACK: u8 = 0xA2;
+const EXTENDED_PACK: u8 = 0xA3;
+
+/// Size of buffer datagram is received into, server doesn't send longer datagrams
+pub const MAX_DATAGRAM: usize = 1500;
 
 #[derive(Clone, Copy)]
 pub enum TypePack {
     Simple,
+    /// Pack with two bytes length for payloads not fitting simple pack
+    Extended,
     Unknown(u8),
 }
 
@@ -14,6 +20,7 @@ impl From<u8> for TypePack {
     fn from(value: u8) -> Self {
         match value {
             SIMPLE_PACK => Self::Simple,
+            EXTENDED_PACK => Self::Extended,
             _ => Self::Unknown(value),
         }
     }
@@ -23,6 +30,7 @@ impl From<TypePack> for u8 {
     fn from(value: TypePack) -> Self {
         match value {
             TypePack::Simple => SIMPLE_PACK,
+            TypePack::Extended => EXTENDED_PACK,
             TypePack::Unknown(val) => val,
         }
     }
@@ -38,15 +46,35 @@ impl TranportPack {
         Self { type_pack, payload }
     }
 
-    pub fn serialize(&self) -> Vec<u8> {
+    /// Pack of the smallest type able to hold payload
+    pub fn from_payload(payload: Vec<u8>) -> Self {
+        let type_pack = if payload.len() <= u8::MAX as usize {
+            TypePack::Simple
+        } else {
+            TypePack::Extended
+        };
+        Self::new(type_pack, payload)
+    }
+
+    /// Fails if payload is too long for length field of pack
+    pub fn serialize(&self) -> Result<Vec<u8>, err_house::Err> {
         let mut res = Vec::new();
         let type_pack: u8 = self.type_pack.into();
-        let len: u8 = self.payload.len() as u8;
 
         res.push(type_pack);
-        res.push(len);
+        let len = self.payload.len();
+        let too_large = |_| {
+            error!("Payload of {len} bytes doesn't fit pack");
+            err_house::Err::new(err_house::ErrorKind::PayloadTooLarge)
+        };
+        match self.type_pack {
+            TypePack::Extended => {
+                res.extend_from_slice(&u16::try_from(len).map_err(too_large)?.to_be_bytes())
+            }
+            _ => res.push(u8::try_from(len).map_err(too_large)?),
+        }
         res.extend_from_slice(&self.payload);
-        res
+        Ok(res)
     }
 
     pub fn from_reader(reader: &mut impl Read) -> Result<Self, err_house::Err> {
@@ -61,6 +89,13 @@ impl TranportPack {
                 reader.read_exact(&mut payload)?;
                 Ok(Self { type_pack, payload })
             }
+            TypePack::Extended => {
+                let mut len = [0; 2];
+                reader.read_exact(&mut len)?;
+                let mut payload = vec![0; u16::from_be_bytes(len) as usize];
+                reader.read_exact(&mut payload)?;
+                Ok(Self { type_pack, payload })
+            }
             TypePack::Unknown(val) => {
                 error!("Unknown type pack: {val}");
                 Err(err_house::Err::new(err_house::ErrorKind::UnknownTypePack))
@@ -81,7 +116,7 @@ mod tests {
     #[test]
     fn test_pack_to_bytes() {
         let pack = TranportPack::new(TypePack::Simple, vec![0, 0, 0]);
-        let bytes: Vec<u8> = pack.serialize();
+        let bytes: Vec<u8> = pack.serialize().unwrap();
         let expected = vec![SIMPLE_PACK, 3, 0, 0, 0];
         assert_eq!(bytes, expected);
     }
@@ -119,4 +154,28 @@ mod tests {
         let pack = TranportPack::from_reader(&mut stream);
         assert!(pack.is_err());
     }
+
+    #[test]
+    fn test_extended_pack() {
+        let payload: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        let bytes = TranportPack::from_payload(payload.clone())
+            .serialize()
+            .unwrap();
+        assert_eq!(&bytes[..3], &[EXTENDED_PACK, 1, 44]);
+
+        let mut stream = Cursor::new(bytes);
+        let pack = TranportPack::from_reader(&mut stream).unwrap();
+        assert_eq!(pack.payload, payload);
+
+        let bytes = TranportPack::from_payload(vec![1, 2]).serialize().unwrap();
+        assert_eq!(bytes, vec![SIMPLE_PACK, 2, 1, 2]);
+    }
+
+    #[test]
+    fn test_payload_too_large() {
+        let pack = TranportPack::new(TypePack::Simple, vec![0; u8::MAX as usize + 1]);
+        assert!(pack.serialize().is_err());
+        let pack = TranportPack::from_payload(vec![0; u16::MAX as usize + 1]);
+        assert!(pack.serialize().is_err());
+    }
 }
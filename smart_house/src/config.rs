@@ -0,0 +1,31 @@
+use crate::device::{DevType, Transport};
+use anyhow::Result;
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+
+#[derive(Deserialize)]
+pub struct DeviceConfig {
+    pub name: String,
+    pub type_dev: DevType,
+    pub addr: String,
+    pub protocol: Transport,
+}
+
+#[derive(Deserialize)]
+pub struct RoomConfig {
+    pub name: String,
+    pub devices: Vec<DeviceConfig>,
+}
+
+#[derive(Deserialize)]
+pub struct HouseConfig {
+    pub rooms: Vec<RoomConfig>,
+}
+
+impl HouseConfig {
+    pub fn load(path: &Path) -> Result<Self> {
+        let config = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&config)?)
+    }
+}
@@ -0,0 +1,233 @@
+use crate::protocol;
+use crate::transport_layer::{TranportPack, TypePack};
+use log::*;
+use std::time::Instant;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, UdpSocket};
+
+/// Emulated device logic, independent of transport
+pub trait Emulator: Send + 'static {
+    fn name(&self) -> &'static str;
+
+    /// Commands emulator is able to handle
+    fn commands(&self) -> &'static [protocol::Cmd];
+
+    /// Commands reading values of device
+    fn readings(&self) -> &'static [protocol::Cmd];
+
+    fn is_on(&self) -> bool;
+
+    /// Device is unable to work properly, e.g. lock is jammed
+    fn is_fault(&self) -> bool {
+        false
+    }
+
+    fn handle_request(&mut self, req: protocol::Request) -> protocol::Response;
+}
+
+/// State of emulator tracked the same way for all device types
+struct Status {
+    on_since: Option<Instant>,
+    last_reading: Option<protocol::SuccessKind>,
+}
+
+impl Status {
+    fn new(emulator: &impl Emulator) -> Self {
+        let mut status = Self {
+            on_since: None,
+            last_reading: None,
+        };
+        status.sync(emulator);
+        status
+    }
+
+    fn state(&self, emulator: &impl Emulator) -> protocol::DeviceState {
+        protocol::DeviceState {
+            is_on: emulator.is_on(),
+            is_fault: emulator.is_fault(),
+            last_reading: self.last_reading.clone().map(Box::new),
+            uptime_secs: self
+                .on_since
+                .map_or(0, |on_since| on_since.elapsed().as_secs()),
+        }
+    }
+
+    fn update(&mut self, emulator: &impl Emulator, resp: &protocol::Response) {
+        if let protocol::ResponseKind::Success(reading) = &resp.resp_kind {
+            if emulator.readings().contains(&resp.to_req.cmd) {
+                self.last_reading = Some(reading.clone());
+            }
+        }
+        self.sync(emulator);
+    }
+
+    /// Emulator may turn on or off by itself
+    fn sync(&mut self, emulator: &impl Emulator) {
+        match (emulator.is_on(), self.on_since) {
+            (true, None) => self.on_since = Some(Instant::now()),
+            (false, Some(_)) => self.on_since = None,
+            _ => {}
+        }
+    }
+}
+
+fn execute(
+    emulator: &mut impl Emulator,
+    status: &mut Status,
+    req: protocol::Request,
+) -> protocol::Response {
+    let resp = match req.cmd {
+        protocol::Cmd::GetState => {
+            let state = status.state(emulator);
+            protocol::Response::reading_response(req, protocol::SuccessKind::State(state))
+        }
+        protocol::Cmd::GetCapabilities => {
+            let caps = protocol::Capabilities {
+                commands: emulator.commands().to_vec(),
+                readings: emulator.readings().to_vec(),
+            };
+            protocol::Response::reading_response(req, protocol::SuccessKind::Capabilities(caps))
+        }
+        _ => emulator.handle_request(req),
+    };
+    status.update(emulator, &resp);
+    resp
+}
+
+fn handle_payload(
+    emulator: &mut impl Emulator,
+    status: &mut Status,
+    ip_addr: &str,
+    payload: &[u8],
+) -> Option<protocol::Response> {
+    let req: protocol::Request = match bincode::deserialize(payload) {
+        Ok(val) => val,
+        Err(e) => {
+            info!("Invalid request protocol: {:?}", e);
+            return None;
+        }
+    };
+
+    if req.addr != ip_addr {
+        info!(
+            "Invalid address: self: {} but received: {}",
+            ip_addr, req.addr
+        );
+        return None;
+    }
+
+    Some(execute(emulator, status, req))
+}
+
+fn serialize_response(resp: &protocol::Response) -> Option<Vec<u8>> {
+    match bincode::serialize(resp) {
+        Ok(val) => Some(TranportPack::new(TypePack::Simple, val).serialize()),
+        Err(e) => {
+            error!("Can't serialize response: {:?}", e);
+            None
+        }
+    }
+}
+
+pub async fn serve_tcp(ip_addr: String, mut emulator: impl Emulator) {
+    let listener = match TcpListener::bind(&ip_addr).await {
+        Ok(res) => res,
+        Err(e) => {
+            error!(
+                "{}: can't bind to addr: {}, reason: {:?}",
+                emulator.name(),
+                ip_addr,
+                e
+            );
+            return;
+        }
+    };
+
+    let mut status = Status::new(&emulator);
+    loop {
+        let (mut tcp_stream, remote_addr) = match listener.accept().await {
+            Ok(res) => res,
+            Err(e) => {
+                error!(
+                    "{}: can't accept new connection, reason: {:?}",
+                    emulator.name(),
+                    e
+                );
+                return;
+            }
+        };
+
+        loop {
+            let pack = match TranportPack::from_reader(&mut tcp_stream).await {
+                Ok(pack) => pack,
+                Err(e) => {
+                    info!("Connection at address: {remote_addr} closed {:?}", e);
+                    break;
+                }
+            };
+            let resp =
+                match handle_payload(&mut emulator, &mut status, &ip_addr, &pack.into_payload()) {
+                    Some(resp) => resp,
+                    None => continue,
+                };
+            let bin_pack = match serialize_response(&resp) {
+                Some(val) => val,
+                None => return,
+            };
+            if let Err(e) = tcp_stream.write_all(&bin_pack).await {
+                info!("Connection at addr: {} closed {:?}", remote_addr, e);
+                break;
+            }
+        }
+    }
+}
+
+pub async fn serve_udp(ip_addr: String, mut emulator: impl Emulator) {
+    let udp_sock = match UdpSocket::bind(&ip_addr).await {
+        Ok(res) => res,
+        Err(e) => {
+            error!(
+                "{}: can't bind to addr: {}, reason: {:?}",
+                emulator.name(),
+                ip_addr,
+                e
+            );
+            return;
+        }
+    };
+
+    let mut status = Status::new(&emulator);
+    loop {
+        let mut bin_pack = vec![0u8; 1500];
+        let (pack_size, remote_addr) = match udp_sock.recv_from(&mut bin_pack).await {
+            Ok(res) => res,
+            Err(e) => {
+                error!("Error recv udp datagram: {:?}", e);
+                break;
+            }
+        };
+
+        bin_pack.shrink_to(pack_size);
+
+        let pack = match TranportPack::deserialize(&bin_pack) {
+            Ok(pack) => pack,
+            Err(e) => {
+                info!("Connection deserialize pack: {:?}", e);
+                continue;
+            }
+        };
+        let resp = match handle_payload(&mut emulator, &mut status, &ip_addr, &pack.into_payload())
+        {
+            Some(resp) => resp,
+            None => continue,
+        };
+        let bin_pack = match serialize_response(&resp) {
+            Some(val) => val,
+            None => break,
+        };
+        if let Err(e) = udp_sock.send_to(&bin_pack, remote_addr).await {
+            error!("Internal error: {:?}", e);
+            break;
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use super::emulator::Emulator;
+use crate::protocol::{self, Cmd};
+use log::*;
+use rand::prelude::{thread_rng, Rng};
+use rand_distr::StandardNormal;
+
+const AVG_HUMIDITY: f64 = 45.0; // %
+const HUMIDITY_SPREAD: f64 = 5.0; // %
+
+const COMMANDS: &[Cmd] = &[Cmd::TurnOn, Cmd::TurnOff, Cmd::Humidity];
+const READINGS: &[Cmd] = &[Cmd::Humidity];
+
+pub struct HumidityEmulator {
+    is_turned_on: bool,
+}
+
+impl Emulator for HumidityEmulator {
+    fn name(&self) -> &'static str {
+        "HumidityEmulator"
+    }
+
+    fn commands(&self) -> &'static [Cmd] {
+        COMMANDS
+    }
+
+    fn readings(&self) -> &'static [Cmd] {
+        READINGS
+    }
+
+    fn is_on(&self) -> bool {
+        self.is_turned_on
+    }
+
+    fn handle_request(&mut self, req: protocol::Request) -> protocol::Response {
+        match req.cmd {
+            protocol::Cmd::TurnOn => {
+                info!("Humidity sensor is turned on");
+                self.is_turned_on = true;
+                protocol::Response::success_response(req)
+            }
+            protocol::Cmd::TurnOff => {
+                info!("Humidity sensor is turned off");
+                self.is_turned_on = false;
+                protocol::Response::success_response(req)
+            }
+            protocol::Cmd::Humidity => {
+                let humidity = self.get_humidity();
+                protocol::Response::reading_response(req, protocol::SuccessKind::Humidity(humidity))
+            }
+            _ => {
+                info!("Unsupported command for humidity sensor {:?}", req.cmd);
+                protocol::Response::err_response(req, protocol::ErrorKind::UnknownCmd)
+            }
+        }
+    }
+}
+
+impl HumidityEmulator {
+    pub fn new() -> HumidityEmulator {
+        Self { is_turned_on: true }
+    }
+
+    fn get_humidity(&self) -> f64 {
+        if !self.is_turned_on {
+            return 0.0;
+        }
+
+        let noize = thread_rng().sample::<f64, StandardNormal>(StandardNormal);
+        (AVG_HUMIDITY + noize * HUMIDITY_SPREAD).clamp(0.0, 100.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{ResponseKind, SuccessKind};
+
+    fn humidity(sensor: &mut HumidityEmulator) -> f64 {
+        let req = protocol::Request::new("127.0.0.1:4446", Cmd::Humidity);
+        match sensor.handle_request(req).resp_kind {
+            ResponseKind::Success(SuccessKind::Humidity(val)) => val,
+            _ => panic!("humidity isn't read"),
+        }
+    }
+
+    #[test]
+    fn test_humidity_range() {
+        let mut sensor = HumidityEmulator::new();
+        let cnt = 10_000;
+        let samples: Vec<f64> = (0..cnt).map(|_| humidity(&mut sensor)).collect();
+        assert!(samples.iter().all(|val| (0.0..=100.0).contains(val)));
+        let avg = samples.iter().sum::<f64>() / cnt as f64;
+        assert!((avg - AVG_HUMIDITY).abs() < 0.5);
+
+        sensor.handle_request(protocol::Request::new("127.0.0.1:4446", Cmd::TurnOff));
+        assert_eq!(humidity(&mut sensor), 0.0);
+    }
+}
@@ -0,0 +1,142 @@
+use super::emulator::Emulator;
+use crate::protocol::{self, Cmd};
+use log::*;
+
+const MAX_BRIGHTNESS: u8 = 100;
+const MAX_POWER: f64 = 60.0; // 60 W at full brightness
+
+const COMMANDS: &[Cmd] = &[
+    Cmd::TurnOn,
+    Cmd::TurnOff,
+    Cmd::Brightness,
+    Cmd::Power,
+    Cmd::SetLevel(0),
+];
+const READINGS: &[Cmd] = &[Cmd::Brightness, Cmd::Power];
+
+pub struct LampEmulator {
+    is_turned_on: bool,
+    brightness: u8,
+}
+
+impl Emulator for LampEmulator {
+    fn name(&self) -> &'static str {
+        "LampEmulator"
+    }
+
+    fn commands(&self) -> &'static [Cmd] {
+        COMMANDS
+    }
+
+    fn readings(&self) -> &'static [Cmd] {
+        READINGS
+    }
+
+    fn is_on(&self) -> bool {
+        self.is_turned_on
+    }
+
+    fn handle_request(&mut self, req: protocol::Request) -> protocol::Response {
+        match req.cmd {
+            protocol::Cmd::TurnOn => {
+                info!("Lamp is turned on");
+                self.is_turned_on = true;
+                protocol::Response::success_response(req)
+            }
+            protocol::Cmd::TurnOff => {
+                info!("Lamp is turned off");
+                self.is_turned_on = false;
+                protocol::Response::success_response(req)
+            }
+            protocol::Cmd::SetLevel(level) => {
+                if level > MAX_BRIGHTNESS {
+                    info!("Brightness of lamp is out of range: {level}");
+                    let err = protocol::Error::new(protocol::ErrorKind::WrongCmd)
+                        .with_details(&format!("brightness isn't in 0..={MAX_BRIGHTNESS}"));
+                    return protocol::Response::err_response(req, err);
+                }
+                info!("Lamp brightness is set to {level}%");
+                self.brightness = level;
+                protocol::Response::success_response(req)
+            }
+            protocol::Cmd::Brightness => {
+                let brightness = self.get_brightness();
+                protocol::Response::reading_response(
+                    req,
+                    protocol::SuccessKind::Brightness(brightness),
+                )
+            }
+            protocol::Cmd::Power => {
+                let power = MAX_POWER * self.get_brightness() as f64 / MAX_BRIGHTNESS as f64;
+                protocol::Response::reading_response(req, protocol::SuccessKind::Power(power))
+            }
+            _ => {
+                info!("Unsupported command for lamp {:?}", req.cmd);
+                protocol::Response::err_response(req, protocol::ErrorKind::UnknownCmd)
+            }
+        }
+    }
+}
+
+impl LampEmulator {
+    pub fn new() -> LampEmulator {
+        Self {
+            is_turned_on: true,
+            brightness: MAX_BRIGHTNESS,
+        }
+    }
+
+    /// Level is kept while lamp is off and applied on turning on
+    fn get_brightness(&self) -> u8 {
+        if self.is_turned_on {
+            self.brightness
+        } else {
+            0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{ResponseKind, SuccessKind};
+
+    fn execute(lamp: &mut LampEmulator, cmd: Cmd) -> ResponseKind {
+        lamp.handle_request(protocol::Request::new("127.0.0.1:446", cmd))
+            .resp_kind
+    }
+
+    fn reading(lamp: &mut LampEmulator, cmd: Cmd) -> f64 {
+        match execute(lamp, cmd) {
+            ResponseKind::Success(SuccessKind::Brightness(level)) => level as f64,
+            ResponseKind::Success(SuccessKind::Power(power)) => power,
+            _ => panic!("{cmd} isn't read"),
+        }
+    }
+
+    #[test]
+    fn test_dimming() {
+        let mut lamp = LampEmulator::new();
+        assert_eq!(reading(&mut lamp, Cmd::Brightness), 100.0);
+        assert_eq!(reading(&mut lamp, Cmd::Power), MAX_POWER);
+
+        assert!(matches!(
+            execute(&mut lamp, Cmd::SetLevel(40)),
+            ResponseKind::Success(SuccessKind::Ack)
+        ));
+        assert_eq!(reading(&mut lamp, Cmd::Brightness), 40.0);
+        assert_eq!(reading(&mut lamp, Cmd::Power), 24.0);
+
+        assert!(matches!(
+            execute(&mut lamp, Cmd::SetLevel(101)),
+            ResponseKind::Err(_)
+        ));
+        assert_eq!(reading(&mut lamp, Cmd::Brightness), 40.0);
+
+        execute(&mut lamp, Cmd::TurnOff);
+        assert_eq!(reading(&mut lamp, Cmd::Brightness), 0.0);
+        assert_eq!(reading(&mut lamp, Cmd::Power), 0.0);
+        execute(&mut lamp, Cmd::TurnOn);
+        assert_eq!(reading(&mut lamp, Cmd::Brightness), 40.0);
+    }
+}
@@ -0,0 +1,101 @@
+use super::emulator::Emulator;
+use crate::protocol::{self, Cmd};
+use log::*;
+use rand::prelude::{thread_rng, Rng};
+
+const LEAK_PROBABILITY: f64 = 0.01;
+
+const COMMANDS: &[Cmd] = &[Cmd::TurnOn, Cmd::TurnOff, Cmd::Leak, Cmd::ResetAlarm];
+const READINGS: &[Cmd] = &[Cmd::Leak];
+
+pub struct LeakEmulator {
+    is_turned_on: bool,
+    is_alarm: bool,
+}
+
+impl Emulator for LeakEmulator {
+    fn name(&self) -> &'static str {
+        "LeakEmulator"
+    }
+
+    fn commands(&self) -> &'static [Cmd] {
+        COMMANDS
+    }
+
+    fn readings(&self) -> &'static [Cmd] {
+        READINGS
+    }
+
+    fn is_on(&self) -> bool {
+        self.is_turned_on
+    }
+
+    fn handle_request(&mut self, req: protocol::Request) -> protocol::Response {
+        match req.cmd {
+            protocol::Cmd::TurnOn => {
+                info!("Leak sensor is turned on");
+                self.is_turned_on = true;
+                protocol::Response::success_response(req)
+            }
+            protocol::Cmd::TurnOff => {
+                info!("Leak sensor is turned off");
+                self.is_turned_on = false;
+                protocol::Response::success_response(req)
+            }
+            protocol::Cmd::Leak => {
+                let is_leak = self.check_leak();
+                protocol::Response::reading_response(req, protocol::SuccessKind::Leak(is_leak))
+            }
+            protocol::Cmd::ResetAlarm => {
+                info!("Leak sensor alarm is reset");
+                self.is_alarm = false;
+                protocol::Response::success_response(req)
+            }
+            _ => {
+                info!("Unsupported command for leak sensor {:?}", req.cmd);
+                protocol::Response::err_response(req, protocol::ErrorKind::UnknownCmd)
+            }
+        }
+    }
+}
+
+impl LeakEmulator {
+    pub fn new() -> LeakEmulator {
+        Self {
+            is_turned_on: true,
+            is_alarm: false,
+        }
+    }
+
+    /// Alarm stays raised until it's reset explicitly
+    fn check_leak(&mut self) -> bool {
+        if self.is_turned_on && !self.is_alarm && thread_rng().gen_bool(LEAK_PROBABILITY) {
+            warn!("Leak detected");
+            self.is_alarm = true;
+        }
+        self.is_alarm
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_alarm_is_latched() {
+        let mut sensor = LeakEmulator::new();
+        let mut tries = 0;
+        while !sensor.check_leak() {
+            tries += 1;
+            assert!(tries < 10_000);
+        }
+        assert!((0..100).all(|_| sensor.check_leak()));
+
+        sensor.handle_request(protocol::Request::new("127.0.0.1:4447", Cmd::ResetAlarm));
+        assert!(!sensor.is_alarm);
+
+        // Sensor turned off doesn't detect leaks
+        sensor.handle_request(protocol::Request::new("127.0.0.1:4447", Cmd::TurnOff));
+        assert!((0..1000).all(|_| !sensor.check_leak()));
+    }
+}
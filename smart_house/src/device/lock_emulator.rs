@@ -0,0 +1,120 @@
+use super::emulator::Emulator;
+use crate::protocol::{self, Cmd, LockState};
+use log::*;
+use rand::prelude::{thread_rng, Rng};
+
+const JAM_PROBABILITY: f64 = 0.05;
+
+const COMMANDS: &[Cmd] = &[Cmd::Lock, Cmd::Unlock, Cmd::LockState];
+const READINGS: &[Cmd] = &[Cmd::LockState];
+
+pub struct LockEmulator {
+    state: LockState,
+}
+
+impl Emulator for LockEmulator {
+    fn name(&self) -> &'static str {
+        "LockEmulator"
+    }
+
+    fn commands(&self) -> &'static [Cmd] {
+        COMMANDS
+    }
+
+    fn readings(&self) -> &'static [Cmd] {
+        READINGS
+    }
+
+    /// Lock is powered all the time
+    fn is_on(&self) -> bool {
+        true
+    }
+
+    fn is_fault(&self) -> bool {
+        self.state == LockState::Jammed
+    }
+
+    fn handle_request(&mut self, req: protocol::Request) -> protocol::Response {
+        match req.cmd {
+            protocol::Cmd::Lock => self.actuate(req, LockState::Locked),
+            protocol::Cmd::Unlock => self.actuate(req, LockState::Unlocked),
+            protocol::Cmd::LockState => {
+                protocol::Response::reading_response(req, protocol::SuccessKind::Lock(self.state))
+            }
+            _ => {
+                info!("Unsupported command for door lock {:?}", req.cmd);
+                protocol::Response::err_response(req, protocol::ErrorKind::UnknownCmd)
+            }
+        }
+    }
+}
+
+impl LockEmulator {
+    pub fn new() -> LockEmulator {
+        Self {
+            state: LockState::Locked,
+        }
+    }
+
+    /// Every actuation may jam the lock, jammed lock is released
+    /// by the next successful actuation
+    fn actuate(&mut self, req: protocol::Request, target: LockState) -> protocol::Response {
+        if thread_rng().gen_bool(JAM_PROBABILITY) {
+            warn!("Door lock is jammed");
+            self.state = LockState::Jammed;
+            let err = protocol::Error::new(protocol::ErrorKind::DevFault)
+                .with_details("lock is jammed, retry to release it");
+            return protocol::Response::err_response(req, err);
+        }
+        info!("Door lock is {}", target);
+        self.state = target;
+        protocol::Response::success_response(req)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::ResponseKind;
+
+    fn execute(lock: &mut LockEmulator, cmd: Cmd) -> ResponseKind {
+        lock.handle_request(protocol::Request::new("127.0.0.1:447", cmd))
+            .resp_kind
+    }
+
+    #[test]
+    fn test_lock_state() {
+        let mut lock = LockEmulator::new();
+        assert_eq!(lock.state, LockState::Locked);
+        // Retry until actuation succeeds, jam is released by it
+        for (cmd, state) in [
+            (Cmd::Unlock, LockState::Unlocked),
+            (Cmd::Lock, LockState::Locked),
+        ] {
+            let mut tries = 0;
+            while let ResponseKind::Err(e) = execute(&mut lock, cmd) {
+                assert_eq!(e.code, protocol::ErrorKind::DevFault.code());
+                assert!(lock.is_fault());
+                tries += 1;
+                assert!(tries < 100);
+            }
+            assert_eq!(lock.state, state);
+            assert!(!lock.is_fault());
+        }
+        assert!(matches!(
+            execute(&mut lock, Cmd::Humidity),
+            ResponseKind::Err(_)
+        ));
+    }
+
+    #[test]
+    fn test_jam_probability() {
+        let mut lock = LockEmulator::new();
+        let cnt = 10_000;
+        let jams = (0..cnt)
+            .filter(|_| matches!(execute(&mut lock, Cmd::Lock), ResponseKind::Err(_)))
+            .count();
+        let rate = jams as f64 / cnt as f64;
+        assert!((rate - JAM_PROBABILITY).abs() < 0.015, "jam rate {rate}");
+    }
+}
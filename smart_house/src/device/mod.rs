@@ -1,39 +1,77 @@
+mod emulator;
+mod humidity_emulator;
+mod lamp_emulator;
+mod leak_emulator;
+mod lock_emulator;
 mod sock_emulator;
-mod sock_handler;
-mod sock_view;
+mod tcp_handler;
+mod tcp_view;
 mod therm_emulator;
-mod therm_handler;
-mod therm_view;
+mod udp_handler;
+mod udp_view;
 use super::err_house;
 
 use crate::{protocol, DB_TASKS};
 use anyhow::{bail, Result};
+use emulator::{serve_tcp, serve_udp, Emulator};
+use humidity_emulator::HumidityEmulator;
+use lamp_emulator::LampEmulator;
+use leak_emulator::LeakEmulator;
+use lock_emulator::LockEmulator;
+use serde::Deserialize;
 use sock_emulator::SockEmulator;
-use sock_handler::SockHandler;
-use sock_view::SockView;
+use tcp_handler::TcpHandler;
+use tcp_view::TcpView;
 use therm_emulator::ThermEmulator;
-use therm_handler::ThermHandler;
-use therm_view::ThermView;
 use tokio::task::AbortHandle;
+use udp_handler::UdpHandler;
+use udp_view::UdpView;
 
 use std::{borrow::Borrow, hash::Hash};
 
-#[derive(Clone, Copy)]
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
 pub enum DevType {
+    #[serde(rename = "socket")]
     Sock,
     Therm,
+    Lamp,
+    Humidity,
+    Lock,
+    Leak,
+}
+
+impl DevType {
+    /// Command reading main value of device
+    pub fn reading_cmd(&self) -> protocol::Cmd {
+        match self {
+            DevType::Sock => protocol::Cmd::Power,
+            DevType::Therm => protocol::Cmd::Temperature,
+            DevType::Lamp => protocol::Cmd::Brightness,
+            DevType::Humidity => protocol::Cmd::Humidity,
+            DevType::Lock => protocol::Cmd::LockState,
+            DevType::Leak => protocol::Cmd::Leak,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Transport {
+    Tcp,
+    Udp,
 }
 
 pub enum View {
-    SockView(SockView),
-    ThermView(ThermView),
+    TcpView(TcpView),
+    UdpView(UdpView),
 }
 
 impl View {
     pub async fn send_req(&mut self, req: protocol::Request) -> Result<()> {
         match self {
-            View::SockView(val) => val.send_req(req).await,
-            View::ThermView(val) => val.send_req(req).await,
+            View::TcpView(val) => val.send_req(req).await,
+            View::UdpView(val) => val.send_req(req).await,
         }
     }
 }
@@ -42,6 +80,7 @@ pub struct Device {
     name: String,
     ip_addr: String,
     dev_type: DevType,
+    transport: Transport,
     emulator_abort: Option<AbortHandle>,
     handler_abort: Option<AbortHandle>,
     view: Option<View>,
@@ -68,11 +107,12 @@ impl Borrow<str> for Device {
 }
 
 impl Device {
-    pub fn new(name: &str, ip_addr: &str, dev_type: DevType) -> Device {
+    pub fn new(name: &str, ip_addr: &str, dev_type: DevType, transport: Transport) -> Device {
         Device {
             name: name.to_owned(),
             ip_addr: ip_addr.to_owned(),
             dev_type,
+            transport,
             emulator_abort: None,
             handler_abort: None,
             view: None,
@@ -87,30 +127,48 @@ impl Device {
         &self.ip_addr
     }
 
-    pub async fn connect(&mut self, is_use_emulator: bool) -> Result<()> {
+    pub fn get_type(&self) -> DevType {
+        self.dev_type
+    }
+
+    fn start_emulator(&mut self) {
         match self.dev_type {
-            DevType::Sock => {
-                if is_use_emulator {
-                    let mut lock = DB_TASKS.write().unwrap();
-                    let abort_handle = lock.spawn(SockEmulator::new(&self.ip_addr).start());
-                    self.emulator_abort = Some(abort_handle);
-                }
-                let (view, rx) = SockView::connect(&self.ip_addr, 3).await?;
-                self.view = Some(View::SockView(view));
+            DevType::Sock => self.serve_emulator(SockEmulator::new()),
+            DevType::Therm => self.serve_emulator(ThermEmulator::new()),
+            DevType::Lamp => self.serve_emulator(LampEmulator::new()),
+            DevType::Humidity => self.serve_emulator(HumidityEmulator::new()),
+            DevType::Lock => self.serve_emulator(LockEmulator::new()),
+            DevType::Leak => self.serve_emulator(LeakEmulator::new()),
+        }
+    }
+
+    fn serve_emulator(&mut self, emulator: impl Emulator) {
+        let ip_addr = self.ip_addr.clone();
+        let mut lock = DB_TASKS.write().unwrap();
+        let abort_handle = match self.transport {
+            Transport::Tcp => lock.spawn(serve_tcp(ip_addr, emulator)),
+            Transport::Udp => lock.spawn(serve_udp(ip_addr, emulator)),
+        };
+        self.emulator_abort = Some(abort_handle);
+    }
+
+    pub async fn connect(&mut self, is_use_emulator: bool) -> Result<()> {
+        if is_use_emulator {
+            self.start_emulator();
+        }
+        match self.transport {
+            Transport::Tcp => {
+                let (view, rx) = TcpView::connect(&self.ip_addr, 3).await?;
+                self.view = Some(View::TcpView(view));
                 let mut lock = DB_TASKS.write().unwrap();
-                let abort_handle = lock.spawn(SockHandler::new(rx).start());
+                let abort_handle = lock.spawn(TcpHandler::new(rx).start());
                 self.handler_abort = Some(abort_handle);
             }
-            DevType::Therm => {
-                if is_use_emulator {
-                    let mut lock = DB_TASKS.write().unwrap();
-                    let abort_handle = lock.spawn(ThermEmulator::new(&self.ip_addr).start());
-                    self.emulator_abort = Some(abort_handle);
-                }
-                let (view, rx) = ThermView::connect(&self.ip_addr).await?;
-                self.view = Some(View::ThermView(view));
+            Transport::Udp => {
+                let (view, rx) = UdpView::connect(&self.ip_addr).await?;
+                self.view = Some(View::UdpView(view));
                 let mut lock = DB_TASKS.write().unwrap();
-                let abort_handle = lock.spawn(ThermHandler::new(rx).start());
+                let abort_handle = lock.spawn(UdpHandler::new(rx).start());
                 self.handler_abort = Some(abort_handle);
             }
         }
@@ -1,108 +1,61 @@
-use crate::protocol;
-use crate::transport_layer::{TranportPack, TypePack};
+use super::emulator::Emulator;
+use crate::protocol::{self, Cmd};
 use log::*;
 use rand::prelude::{thread_rng, Rng};
 use rand_distr::StandardNormal;
-use tokio::io::AsyncWriteExt;
-use tokio::net::TcpListener;
 
 const AVG_POWER: f64 = 4_000.0; // 4 kW
 const POWER_SPREAD: f64 = 100.0; // 100W
 
+const COMMANDS: &[Cmd] = &[Cmd::TurnOn, Cmd::TurnOff, Cmd::Power];
+const READINGS: &[Cmd] = &[Cmd::Power];
+
 pub struct SockEmulator {
-    ip_addr: String,
     is_turned_on: bool,
 }
 
-impl SockEmulator {
-    pub async fn start(mut self) {
-        let listener = match TcpListener::bind(&self.ip_addr).await {
-            Ok(res) => res,
-            Err(e) => {
-                error!(
-                    "SockEmulator: can't bind to addr: {}, reason: {:?}",
-                    self.ip_addr, e
-                );
-                return;
-            }
-        };
-
-        loop {
-            let (mut tcp_stream, remote_addr) = match listener.accept().await {
-                Ok(res) => res,
-                Err(e) => {
-                    error!("SockEmulator: can't accept new connection, reason: {:?}", e);
-                    return;
-                }
-            };
+impl Emulator for SockEmulator {
+    fn name(&self) -> &'static str {
+        "SockEmulator"
+    }
 
-            loop {
-                let pack = match TranportPack::from_reader(&mut tcp_stream).await {
-                    Ok(pack) => pack,
-                    Err(e) => {
-                        info!("Connection at address: {remote_addr} closed {:?}", e);
-                        break;
-                    }
-                };
-                let payload = pack.into_payload();
-                let req: protocol::Request = match bincode::deserialize(&payload) {
-                    Ok(val) => val,
-                    Err(e) => {
-                        info!("Invalid request protocol: {:?}", e);
-                        break;
-                    }
-                };
+    fn commands(&self) -> &'static [Cmd] {
+        COMMANDS
+    }
 
-                if req.addr != self.ip_addr {
-                    info!(
-                        "Invalid address: self: {} but received: {}",
-                        self.ip_addr, req.addr
-                    );
-                    continue;
-                }
+    fn readings(&self) -> &'static [Cmd] {
+        READINGS
+    }
 
-                let resp = match req.cmd {
-                    protocol::Cmd::TurnOn => {
-                        self.turn_on();
-                        protocol::Response::success_response(req)
-                    }
-                    protocol::Cmd::TurnOff => {
-                        self.turn_off();
-                        protocol::Response::success_response(req)
-                    }
-                    protocol::Cmd::Power => {
-                        let power = self.get_power();
-                        protocol::Response::current_power(req, power)
-                    }
-                    _ => {
-                        info!("Unsupported command for smart socket {:?}", req.cmd);
-                        protocol::Response::err_response(req, protocol::ErrorKind::UnknownCmd)
-                    }
-                };
+    fn is_on(&self) -> bool {
+        self.is_turned_on
+    }
 
-                let bin_resp = match bincode::serialize(&resp) {
-                    Ok(val) => val,
-                    Err(e) => {
-                        error!("Can't serialize response: {:?}", e);
-                        return;
-                    }
-                };
-                let bin_pack = TranportPack::new(TypePack::Simple, bin_resp).serialize();
-                if let Err(e) = tcp_stream.write_all(&bin_pack).await {
-                    info!("Connection at addr: {} closed {:?}", remote_addr, e);
-                    break;
-                }
+    fn handle_request(&mut self, req: protocol::Request) -> protocol::Response {
+        match req.cmd {
+            protocol::Cmd::TurnOn => {
+                self.turn_on();
+                protocol::Response::success_response(req)
+            }
+            protocol::Cmd::TurnOff => {
+                self.turn_off();
+                protocol::Response::success_response(req)
+            }
+            protocol::Cmd::Power => {
+                let power = self.get_power();
+                protocol::Response::reading_response(req, protocol::SuccessKind::Power(power))
+            }
+            _ => {
+                info!("Unsupported command for smart socket {:?}", req.cmd);
+                protocol::Response::err_response(req, protocol::ErrorKind::UnknownCmd)
             }
         }
     }
 }
 
 impl SockEmulator {
-    pub fn new(ip_addr: &str) -> SockEmulator {
-        Self {
-            ip_addr: ip_addr.to_owned(),
-            is_turned_on: true,
-        }
+    pub fn new() -> SockEmulator {
+        Self { is_turned_on: true }
     }
 
     fn turn_on(&mut self) {
@@ -3,11 +3,11 @@ use crate::transport_layer::TranportPack;
 use log::*;
 use tokio::net::tcp::OwnedReadHalf;
 
-pub struct SockHandler {
+pub struct TcpHandler {
     rx_sock: OwnedReadHalf,
 }
 
-impl SockHandler {
+impl TcpHandler {
     pub async fn start(mut self) {
         loop {
             let pack = match TranportPack::from_reader(&mut self.rx_sock).await {
@@ -31,7 +31,7 @@ impl SockHandler {
     }
 }
 
-impl SockHandler {
+impl TcpHandler {
     pub fn new(rx_sock: OwnedReadHalf) -> Self {
         Self { rx_sock }
     }
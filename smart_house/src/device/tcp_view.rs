@@ -9,11 +9,11 @@ use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::TcpStream;
 use tokio::time::{sleep, Duration};
 
-pub struct SockView {
+pub struct TcpView {
     tx_sock: OwnedWriteHalf,
 }
 
-impl SockView {
+impl TcpView {
     pub async fn connect(
         ip_addr: &str,
         cnt_connect_attempts: usize,
@@ -38,9 +38,9 @@ impl SockView {
         };
 
         let (rx_sock, tx_sock) = tcp_stream.into_split();
-        let sock_view = Self { tx_sock };
+        let tcp_view = Self { tx_sock };
 
-        Ok((sock_view, rx_sock))
+        Ok((tcp_view, rx_sock))
     }
 
     pub async fn send_req(&mut self, req: protocol::Request) -> Result<()> {
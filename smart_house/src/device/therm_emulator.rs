@@ -1,108 +1,61 @@
-use crate::protocol;
-use crate::transport_layer::{TranportPack, TypePack};
+use super::emulator::Emulator;
+use crate::protocol::{self, Cmd};
 use log::*;
 use rand::prelude::{thread_rng, Rng};
 use rand_distr::StandardNormal;
-use tokio::net::UdpSocket;
 
 const AVG_TEMP: f64 = 25.0; // C
-const TEMP_SPREAD: f64 = 5.0; // 100W
+const TEMP_SPREAD: f64 = 5.0; // C
+
+const COMMANDS: &[Cmd] = &[Cmd::TurnOn, Cmd::TurnOff, Cmd::Temperature];
+const READINGS: &[Cmd] = &[Cmd::Temperature];
 
 pub struct ThermEmulator {
-    ip_addr: String,
     is_turned_on: bool,
 }
 
-impl ThermEmulator {
-    pub async fn start(mut self) {
-        let udp_sock = match UdpSocket::bind(&self.ip_addr).await {
-            Ok(res) => res,
-            Err(e) => {
-                error!(
-                    "ThermEmulator: can't bind to addr: {}, reason: {:?}",
-                    self.ip_addr, e
-                );
-                return;
-            }
-        };
+impl Emulator for ThermEmulator {
+    fn name(&self) -> &'static str {
+        "ThermEmulator"
+    }
 
-        loop {
-            let mut bin_pack = vec![0u8; 1500];
-            let (pack_size, remote_addr) = match udp_sock.recv_from(&mut bin_pack).await {
-                Ok(res) => res,
-                Err(e) => {
-                    error!("Error recv udp datagram: {:?}", e);
-                    break;
-                }
-            };
+    fn commands(&self) -> &'static [Cmd] {
+        COMMANDS
+    }
 
-            bin_pack.shrink_to(pack_size);
+    fn readings(&self) -> &'static [Cmd] {
+        READINGS
+    }
 
-            let pack = match TranportPack::deserialize(&bin_pack) {
-                Ok(pack) => pack,
-                Err(e) => {
-                    info!("Connection deserialize pack: {:?}", e);
-                    continue;
-                }
-            };
-            let payload = pack.into_payload();
-            let req: protocol::Request = match bincode::deserialize(&payload) {
-                Ok(val) => val,
-                Err(e) => {
-                    info!("Invalid request protocol: {:?}", e);
-                    continue;
-                }
-            };
+    fn is_on(&self) -> bool {
+        self.is_turned_on
+    }
 
-            if req.addr != self.ip_addr {
-                info!(
-                    "Invalid address: self: {} but received: {}",
-                    self.ip_addr, req.addr
-                );
-                continue;
+    fn handle_request(&mut self, req: protocol::Request) -> protocol::Response {
+        match req.cmd {
+            protocol::Cmd::TurnOn => {
+                self.turn_on();
+                protocol::Response::success_response(req)
             }
-
-            let resp = match req.cmd {
-                protocol::Cmd::TurnOn => {
-                    self.turn_on();
-                    protocol::Response::success_response(req)
-                }
-                protocol::Cmd::TurnOff => {
-                    self.turn_off();
-                    protocol::Response::success_response(req)
-                }
-                protocol::Cmd::Power => {
-                    let power = self.get_temperature();
-                    protocol::Response::current_temp(req, power)
-                }
-                _ => {
-                    info!("Unsupported command for smart thermometer {:?}", req.cmd);
-                    protocol::Response::err_response(req, protocol::ErrorKind::UnknownCmd)
-                }
-            };
-
-            let bin_resp = match bincode::serialize(&resp) {
-                Ok(val) => val,
-                Err(e) => {
-                    error!("Can't serialize response: {:?}", e);
-                    break;
-                }
-            };
-            let bin_pack = TranportPack::new(TypePack::Simple, bin_resp).serialize();
-            if let Err(e) = udp_sock.send_to(&bin_pack, remote_addr).await {
-                error!("Internal error: {:?}", e);
-                break;
+            protocol::Cmd::TurnOff => {
+                self.turn_off();
+                protocol::Response::success_response(req)
+            }
+            protocol::Cmd::Power | protocol::Cmd::Temperature => {
+                let temp = self.get_temperature();
+                protocol::Response::reading_response(req, protocol::SuccessKind::Temp(temp))
+            }
+            _ => {
+                info!("Unsupported command for smart thermometer {:?}", req.cmd);
+                protocol::Response::err_response(req, protocol::ErrorKind::UnknownCmd)
             }
         }
     }
 }
 
 impl ThermEmulator {
-    pub fn new(ip_addr: &str) -> ThermEmulator {
-        Self {
-            ip_addr: ip_addr.to_owned(),
-            is_turned_on: true,
-        }
+    pub fn new() -> ThermEmulator {
+        Self { is_turned_on: true }
     }
 
     fn turn_on(&mut self) {
@@ -4,11 +4,11 @@ use log::*;
 use std::sync::Arc;
 use tokio::net::UdpSocket;
 
-pub struct ThermHandler {
+pub struct UdpHandler {
     rx_sock: Arc<UdpSocket>,
 }
 
-impl ThermHandler {
+impl UdpHandler {
     pub async fn start(self) {
         loop {
             let mut buf = vec![0u8; 1500];
@@ -42,7 +42,7 @@ impl ThermHandler {
     }
 }
 
-impl ThermHandler {
+impl UdpHandler {
     pub fn new(rx_sock: Arc<UdpSocket>) -> Self {
         Self { rx_sock }
     }
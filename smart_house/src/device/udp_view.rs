@@ -7,16 +7,16 @@ use log::*;
 use std::sync::Arc;
 use tokio::net::UdpSocket;
 
-pub struct ThermView {
+pub struct UdpView {
     tx_sock: Arc<UdpSocket>,
 }
 
-impl ThermView {
+impl UdpView {
     pub async fn connect(ip_addr: &str) -> Result<(Self, Arc<UdpSocket>)> {
-        let tx_udp_sock = Arc::new(match UdpSocket::bind("127.0.0.1:4450").await {
+        let tx_udp_sock = Arc::new(match UdpSocket::bind("127.0.0.1:0").await {
             Ok(sock) => sock,
             Err(e) => {
-                log::error!("Can't bind therm view udp socket: {:?}", e);
+                log::error!("Can't bind udp view socket: {:?}", e);
                 bail!(err_house::ErrorKind::IoError);
             }
         });
@@ -27,11 +27,11 @@ impl ThermView {
             bail!(err_house::ErrorKind::IoError);
         }
 
-        let therm_view = Self {
+        let udp_view = Self {
             tx_sock: tx_udp_sock,
         };
 
-        Ok((therm_view, rx_udp_sock))
+        Ok((udp_view, rx_udp_sock))
     }
 
     pub async fn send_req(&mut self, req: protocol::Request) -> Result<()> {
@@ -1,3 +1,4 @@
+mod config;
 mod device;
 mod err_house;
 mod protocol;
@@ -5,15 +6,17 @@ mod room;
 mod smart_house;
 mod transport_layer;
 
+use config::HouseConfig;
 use device::*;
 use lazy_static::lazy_static;
 use log::*;
 use log4rs::append::file::FileAppender;
 use log4rs::config::{Appender, Config, Root};
 use log4rs::encode::pattern::PatternEncoder;
-use protocol::*;
-use std::io::Result;
+use room::Room;
+use smart_house::SmartHouse;
 use std::mem::replace;
+use std::path::Path;
 use std::sync::{Arc, RwLock};
 use tokio::task::JoinSet;
 
@@ -43,23 +46,62 @@ fn init_logger() {
 }
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() -> anyhow::Result<()> {
     init_logger();
     info!("Start smart house app");
-    let mut smart_socket = Device::new("Smart Sock", "127.0.0.1:444", DevType::Sock);
-    let mut smart_therm = Device::new("Smart therm", "127.0.0.1:4444", DevType::Therm);
-    if let Err(e) = smart_socket.connect(true).await {
-        error!("Can't connect to remote smart socket: {:?}", e);
-    }
-    if let Err(e) = smart_therm.connect(true).await {
-        error!("Can't connect to remote smart thermometer: {:?}", e);
+    let config = HouseConfig::load(Path::new("Config.json"))?;
+    let mut house = SmartHouse::new();
+    for room_config in config.rooms.iter() {
+        let mut room = Room::new(&room_config.name);
+        for dev_config in room_config.devices.iter() {
+            let mut dev = Device::new(
+                &dev_config.name,
+                &dev_config.addr,
+                dev_config.type_dev,
+                dev_config.protocol,
+            );
+            if let Err(e) = dev.connect(true).await {
+                error!("Can't connect to remote device {}: {:?}", dev.get_name(), e);
+                continue;
+            }
+            let cmds = [
+                protocol::Cmd::GetCapabilities,
+                dev.get_type().reading_cmd(),
+                protocol::Cmd::GetState,
+            ];
+            for cmd in cmds {
+                let req = protocol::Request::new(dev.get_addr(), cmd);
+                if let Err(e) = dev.send_req(req).await {
+                    error!("Can't send request to device {}: {:?}", dev.get_name(), e);
+                }
+            }
+            room.add_device(dev);
+        }
+        house.add_room(room);
     }
 
-    let sock_req = protocol::Request::new(smart_socket.get_addr(), Cmd::Power);
-    smart_socket.send_req(sock_req).await.unwrap();
+    for room_config in config.rooms.iter() {
+        let room = match house.get_room(&room_config.name) {
+            Some(room) => room,
+            None => continue,
+        };
+        println!(
+            "Room: {}, devices: {}",
+            room.get_name(),
+            room.get_devices().count()
+        );
+        for dev_config in room_config.devices.iter() {
+            if let Some(dev) = room.get_device(&dev_config.name) {
+                println!(
+                    "  {}: {:?} at {}",
+                    dev.get_name(),
+                    dev.get_type(),
+                    dev.get_addr()
+                );
+            }
+        }
+    }
 
-    let therm_req = protocol::Request::new(smart_therm.get_addr(), Cmd::Power);
-    smart_therm.send_req(therm_req).await.unwrap();
     let all_tasks = {
         let mut lock = DB_TASKS.write().unwrap();
         replace(&mut *lock, JoinSet::new())
@@ -1,12 +1,23 @@
 use serde::{Deserialize, Serialize};
 use std::fmt::Display;
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
 pub enum Cmd {
     TurnOn,
     TurnOff,
     Power,
     Temperature,
+    Brightness,
+    Humidity,
+    Lock,
+    Unlock,
+    LockState,
+    Leak,
+    ResetAlarm,
+    GetState,
+    GetCapabilities,
+    /// Brightness of lamp in percent
+    SetLevel(u8),
 }
 
 impl Display for Cmd {
@@ -16,6 +27,33 @@ impl Display for Cmd {
             Cmd::TurnOff => write!(f, "Turn Off"),
             Cmd::Temperature => write!(f, "Temperature"),
             Cmd::Power => write!(f, "Power"),
+            Cmd::Brightness => write!(f, "Brightness"),
+            Cmd::Humidity => write!(f, "Humidity"),
+            Cmd::Lock => write!(f, "Lock"),
+            Cmd::Unlock => write!(f, "Unlock"),
+            Cmd::LockState => write!(f, "Lock State"),
+            Cmd::Leak => write!(f, "Leak"),
+            Cmd::ResetAlarm => write!(f, "Reset Alarm"),
+            Cmd::GetState => write!(f, "Get State"),
+            Cmd::GetCapabilities => write!(f, "Get Capabilities"),
+            Cmd::SetLevel(level) => write!(f, "Set Level {}%", level),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum LockState {
+    Locked,
+    Unlocked,
+    Jammed,
+}
+
+impl Display for LockState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LockState::Locked => write!(f, "locked"),
+            LockState::Unlocked => write!(f, "unlocked"),
+            LockState::Jammed => write!(f, "jammed"),
         }
     }
 }
@@ -41,11 +79,67 @@ impl Display for Request {
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DeviceState {
+    pub is_on: bool,
+    pub is_fault: bool,
+    /// Response to the last reading command
+    pub last_reading: Option<Box<SuccessKind>>,
+    /// Seconds since device was turned on, zero if device is off
+    pub uptime_secs: u64,
+}
+
+impl Display for DeviceState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}, fault: {}, uptime: {} s",
+            if self.is_on { "on" } else { "off" },
+            if self.is_fault { "yes" } else { "no" },
+            self.uptime_secs
+        )?;
+        match &self.last_reading {
+            Some(reading) => write!(f, ", last reading: {}", reading),
+            None => write!(f, ", no readings yet"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Capabilities {
+    pub commands: Vec<Cmd>,
+    /// Commands reading values of device
+    pub readings: Vec<Cmd>,
+}
+
+impl Display for Capabilities {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let join = |cmds: &[Cmd]| {
+            cmds.iter()
+                .map(|cmd| cmd.to_string())
+                .collect::<Vec<String>>()
+                .join(", ")
+        };
+        write!(
+            f,
+            "commands: {}; readings: {}",
+            join(&self.commands),
+            join(&self.readings)
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub enum SuccessKind {
     Ack,
     Power(f64),
     Temp(f64),
+    Brightness(u8),
+    Humidity(f64),
+    Lock(LockState),
+    Leak(bool),
+    State(DeviceState),
+    Capabilities(Capabilities),
 }
 
 impl Display for SuccessKind {
@@ -54,15 +148,44 @@ impl Display for SuccessKind {
             SuccessKind::Ack => write!(f, "Success"),
             SuccessKind::Power(val) => write!(f, "Power device: {}", val),
             SuccessKind::Temp(val) => write!(f, "Temp device: {}", val),
+            SuccessKind::Brightness(val) => write!(f, "Brightness device: {}%", val),
+            SuccessKind::Humidity(val) => write!(f, "Humidity device: {:.1}%", val),
+            SuccessKind::Lock(val) => write!(f, "Lock device: {}", val),
+            SuccessKind::Leak(val) => write!(f, "Leak device: {}", val),
+            SuccessKind::State(val) => write!(f, "State device: {}", val),
+            SuccessKind::Capabilities(val) => write!(f, "Capabilities device: {}", val),
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Errors of emulators. Kind itself isn't sent, client gets its stable code.
+#[derive(Debug, Clone, Copy)]
+#[allow(dead_code)]
 pub enum ErrorKind {
     WrongCmd,
     DevNotFound,
     UnknownCmd,
+    DevFault,
+}
+
+impl ErrorKind {
+    /// Codes are shared with smart server protocol and never change
+    pub fn code(self) -> u16 {
+        match self {
+            ErrorKind::WrongCmd => 100,
+            ErrorKind::UnknownCmd => 101,
+            ErrorKind::DevNotFound => 200,
+            ErrorKind::DevFault => 400,
+        }
+    }
+
+    pub fn category(self) -> ErrorCategory {
+        match self {
+            ErrorKind::WrongCmd | ErrorKind::UnknownCmd => ErrorCategory::Validation,
+            ErrorKind::DevNotFound => ErrorCategory::NotFound,
+            ErrorKind::DevFault => ErrorCategory::Device,
+        }
+    }
 }
 
 impl Display for ErrorKind {
@@ -71,14 +194,80 @@ impl Display for ErrorKind {
             ErrorKind::WrongCmd => write!(f, "Wrong command"),
             ErrorKind::DevNotFound => write!(f, "Device not found"),
             ErrorKind::UnknownCmd => write!(f, "Unknown command"),
+            ErrorKind::DevFault => write!(f, "Device fault"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum ErrorCategory {
+    Validation,
+    NotFound,
+    Permission,
+    Device,
+    Server,
+}
+
+impl Display for ErrorCategory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ErrorCategory::Validation => write!(f, "validation"),
+            ErrorCategory::NotFound => write!(f, "not found"),
+            ErrorCategory::Permission => write!(f, "permission"),
+            ErrorCategory::Device => write!(f, "device"),
+            ErrorCategory::Server => write!(f, "server"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Error {
+    pub code: u16,
+    pub category: ErrorCategory,
+    pub message: String,
+    pub details: Option<String>,
+}
+
+impl Error {
+    pub fn new(kind: ErrorKind) -> Self {
+        Self {
+            code: kind.code(),
+            category: kind.category(),
+            message: kind.to_string(),
+            details: None,
+        }
+    }
+
+    pub fn with_details(mut self, details: &str) -> Self {
+        self.details = Some(details.to_owned());
+        self
+    }
+}
+
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Self {
+        Self::new(kind)
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Error {} ({}): {}",
+            self.code, self.category, self.message
+        )?;
+        if let Some(details) = &self.details {
+            write!(f, ": {}", details)?;
         }
+        Ok(())
     }
 }
 
 #[derive(Serialize, Deserialize)]
 pub enum ResponseKind {
     Success(SuccessKind),
-    Err(ErrorKind),
+    Err(Error),
 }
 
 impl Display for ResponseKind {
@@ -103,24 +292,17 @@ impl Response {
             resp_kind: ResponseKind::Success(SuccessKind::Ack),
         }
     }
-    pub fn current_power(req: Request, power: f64) -> Response {
-        Self {
-            to_req: req,
-            resp_kind: ResponseKind::Success(SuccessKind::Power(power)),
-        }
-    }
-
-    pub fn current_temp(req: Request, temp: f64) -> Response {
+    pub fn reading_response(req: Request, reading: SuccessKind) -> Response {
         Self {
             to_req: req,
-            resp_kind: ResponseKind::Success(SuccessKind::Temp(temp)),
+            resp_kind: ResponseKind::Success(reading),
         }
     }
 
-    pub fn err_response(req: Request, err_kind: ErrorKind) -> Response {
+    pub fn err_response(req: Request, err: impl Into<Error>) -> Response {
         Self {
             to_req: req,
-            resp_kind: ResponseKind::Err(err_kind),
+            resp_kind: ResponseKind::Err(err.into()),
         }
     }
 }
@@ -24,7 +24,7 @@ impl Room {
             warn!("Can't add device: device: already exist");
         }
     }
-    pub fn get_device(&mut self, dev_name: &str) -> Option<&Device> {
+    pub fn get_device(&self, dev_name: &str) -> Option<&Device> {
         self.devices.get(dev_name)
     }
     pub fn get_devices(&self) -> impl Iterator<Item = &Device> {
@@ -23,6 +23,7 @@ impl SmartHouse {
         }
     }
 
+    #[allow(dead_code)]
     pub fn delete_room(&mut self, room_name: &str) {
         self.rooms.remove(room_name);
     }
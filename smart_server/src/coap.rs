@@ -0,0 +1,814 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::path::Path;
+use std::sync::mpsc::Receiver;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use log::*;
+
+use super::console_server::{config_section, ConsoleCmd, Service};
+use super::device::{Reading, SharedDevices};
+use super::metrics::SharedMetrics;
+use super::protocol::{self, Cmd, ErrorCategory, ResponseKind, SuccessKind};
+use super::request_handler;
+
+const TRANSPORT: &str = "coap";
+/// Period of endpoint loop
+const POLL_MS: u64 = 20;
+const MAX_DATAGRAM: usize = 1152;
+const MAX_OBSERVERS: usize = 64;
+/// Oldest responses are evicted beyond this many
+const MAX_RESPONSES: usize = 256;
+/// Payload of .well-known/core leaving room for header, token and options
+const MAX_CORE_LEN: usize = MAX_DATAGRAM - 32;
+/// Confirmable notification is sent at most this many times again
+const MAX_RETRANSMIT: u32 = 4;
+const ACK_RANDOM_FACTOR: f64 = 1.5;
+/// Responses are kept this long to answer duplicated requests
+const EXCHANGE_LIFETIME: Duration = Duration::from_secs(247);
+/// Unchanged reading is notified at least this often
+const MAX_AGE: Duration = Duration::from_secs(60);
+
+const VERSION: u8 = 1;
+const PAYLOAD_MARKER: u8 = 0xff;
+
+const OPT_URI_HOST: u16 = 3;
+const OPT_OBSERVE: u16 = 6;
+const OPT_URI_PORT: u16 = 7;
+const OPT_URI_PATH: u16 = 11;
+const OPT_CONTENT_FORMAT: u16 = 12;
+const OPT_URI_QUERY: u16 = 15;
+const OPT_ACCEPT: u16 = 17;
+/// Options understood or safely ignored, other critical options are rejected
+const KNOWN_OPTIONS: [u16; 7] = [OPT_URI_HOST, OPT_OBSERVE, OPT_URI_PORT, OPT_URI_PATH, OPT_CONTENT_FORMAT, OPT_URI_QUERY, OPT_ACCEPT];
+
+const FORMAT_LINK: u32 = 40;
+const FORMAT_JSON: u32 = 50;
+
+const fn code(class: u8, detail: u8) -> u8 {
+    class << 5 | detail
+}
+
+const EMPTY: u8 = code(0, 0);
+const GET: u8 = code(0, 1);
+const POST: u8 = code(0, 2);
+const PUT: u8 = code(0, 3);
+const CHANGED: u8 = code(2, 4);
+const CONTENT: u8 = code(2, 5);
+const BAD_REQUEST: u8 = code(4, 0);
+const BAD_OPTION: u8 = code(4, 2);
+const FORBIDDEN: u8 = code(4, 3);
+const NOT_FOUND: u8 = code(4, 4);
+const METHOD_NOT_ALLOWED: u8 = code(4, 5);
+const SERVICE_UNAVAILABLE: u8 = code(5, 3);
+
+fn default_ack_timeout() -> u64 {
+    2000
+}
+
+fn default_notify_interval() -> u64 {
+    1000
+}
+
+/// Endpoint section of config, e.g. `{"addr": "127.0.0.1:5683"}`
+#[derive(Deserialize, Clone)]
+pub struct CoapConfig {
+    pub addr: String,
+    /// Initial timeout of confirmable notification, it's doubled on every retransmission
+    #[serde(default = "default_ack_timeout")]
+    pub ack_timeout_ms: u64,
+    /// Observed readings are checked for change this often
+    #[serde(default = "default_notify_interval")]
+    pub notify_interval_ms: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Confirmable,
+    NonConfirmable,
+    Ack,
+    Reset,
+}
+
+/// Message of RFC 7252, options are ordered by number
+#[derive(Debug, Clone, PartialEq)]
+struct Message {
+    kind: Kind,
+    code: u8,
+    id: u16,
+    token: Vec<u8>,
+    options: Vec<(u16, Vec<u8>)>,
+    payload: Vec<u8>,
+}
+
+/// Option delta or length with its extended bytes
+fn option_nibble(value: usize, ext: &mut Vec<u8>) -> u8 {
+    match value {
+        0..=12 => value as u8,
+        13..=268 => {
+            ext.push((value - 13) as u8);
+            13
+        }
+        _ => {
+            ext.extend_from_slice(&((value - 269) as u16).to_be_bytes());
+            14
+        }
+    }
+}
+
+fn read_nibble(nibble: u8, data: &[u8], pos: &mut usize) -> Option<usize> {
+    let value = match nibble {
+        0..=12 => nibble as usize,
+        13 => *data.get(*pos)? as usize + 13,
+        14 => u16::from_be_bytes([*data.get(*pos)?, *data.get(*pos + 1)?]) as usize + 269,
+        _ => return None,
+    };
+    *pos += match nibble {
+        13 => 1,
+        14 => 2,
+        _ => 0,
+    };
+    Some(value)
+}
+
+/// Option value of unsigned integer without leading zero bytes
+fn uint_option(value: u32) -> Vec<u8> {
+    value.to_be_bytes().into_iter().skip_while(|byte| *byte == 0).collect()
+}
+
+fn option_uint(value: &[u8]) -> Option<u32> {
+    if value.len() > 4 {
+        return None;
+    }
+    Some(value.iter().fold(0, |res, byte| res << 8 | *byte as u32))
+}
+
+impl Message {
+    fn new(kind: Kind, code: u8, id: u16, token: Vec<u8>) -> Self {
+        Self {
+            kind,
+            code,
+            id,
+            token,
+            options: Vec::new(),
+            payload: Vec::new(),
+        }
+    }
+
+    fn with_option(mut self, number: u16, value: Vec<u8>) -> Self {
+        let pos = self.options.partition_point(|(n, _)| *n <= number);
+        self.options.insert(pos, (number, value));
+        self
+    }
+
+    fn with_payload(self, format: u32, payload: Vec<u8>) -> Self {
+        Self { payload, ..self.with_option(OPT_CONTENT_FORMAT, uint_option(format)) }
+    }
+
+    fn option(&self, number: u16) -> Option<&[u8]> {
+        self.options.iter().find(|(n, _)| *n == number).map(|(_, value)| value.as_slice())
+    }
+
+    fn path(&self) -> Vec<String> {
+        self.options.iter()
+            .filter(|(n, _)| *n == OPT_URI_PATH)
+            .map(|(_, value)| String::from_utf8_lossy(value).into_owned())
+            .collect()
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut res = vec![VERSION << 6 | (self.kind as u8) << 4 | self.token.len() as u8, self.code];
+        res.extend_from_slice(&self.id.to_be_bytes());
+        res.extend_from_slice(&self.token);
+        let mut last = 0;
+        for (number, value) in self.options.iter() {
+            let mut ext = Vec::new();
+            let delta = option_nibble((number - last) as usize, &mut ext);
+            let len = option_nibble(value.len(), &mut ext);
+            res.push(delta << 4 | len);
+            res.extend_from_slice(&ext);
+            res.extend_from_slice(value);
+            last = *number;
+        }
+        if !self.payload.is_empty() {
+            res.push(PAYLOAD_MARKER);
+            res.extend_from_slice(&self.payload);
+        }
+        res
+    }
+
+    fn decode(data: &[u8]) -> Option<Self> {
+        let (header, code, id) = (*data.first()?, *data.get(1)?, u16::from_be_bytes([*data.get(2)?, *data.get(3)?]));
+        let token_len = (header & 0x0f) as usize;
+        if header >> 6 != VERSION || token_len > 8 {
+            return None;
+        }
+        let kind = [Kind::Confirmable, Kind::NonConfirmable, Kind::Ack, Kind::Reset][(header >> 4 & 3) as usize];
+        let mut msg = Message::new(kind, code, id, data.get(4..4 + token_len)?.to_vec());
+        let mut pos = 4 + token_len;
+        let mut number = 0;
+        while let Some(&byte) = data.get(pos) {
+            pos += 1;
+            if byte == PAYLOAD_MARKER {
+                msg.payload = data[pos..].to_vec();
+                if msg.payload.is_empty() {
+                    return None;
+                }
+                break;
+            }
+            number += read_nibble(byte >> 4, data, &mut pos)?;
+            let len = read_nibble(byte & 0x0f, data, &mut pos)?;
+            msg.options.push((u16::try_from(number).ok()?, data.get(pos..pos + len)?.to_vec()));
+            pos += len;
+        }
+        Some(msg)
+    }
+}
+
+fn error_code(category: ErrorCategory) -> u8 {
+    match category {
+        ErrorCategory::Validation => BAD_REQUEST,
+        ErrorCategory::NotFound => NOT_FOUND,
+        ErrorCategory::Permission => FORBIDDEN,
+        ErrorCategory::Device | ErrorCategory::Server => SERVICE_UNAVAILABLE,
+    }
+}
+
+/// Resource of device, `/devices/<name>/<resource>`
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Resource {
+    State,
+    On,
+    Off,
+    Reading(Reading),
+}
+
+impl Resource {
+    fn parse(segment: &str) -> Option<Self> {
+        match segment {
+            "state" => Some(Resource::State),
+            "on" => Some(Resource::On),
+            "off" => Some(Resource::Off),
+            "power" => Some(Resource::Reading(Reading::Power)),
+            "temperature" => Some(Resource::Reading(Reading::Temperature)),
+            _ => None,
+        }
+    }
+
+    /// Command of request, `None` if method isn't allowed
+    fn cmd(self, method: u8, payload: &[u8]) -> Option<Result<Cmd, &'static str>> {
+        let cmd = match (self, method) {
+            (Resource::State, GET) => Cmd::GetState,
+            (Resource::State, PUT) => match String::from_utf8_lossy(payload).trim().to_lowercase().as_str() {
+                "on" => Cmd::TurnOn,
+                "off" => Cmd::TurnOff,
+                _ => return Some(Err("Payload isn't on or off")),
+            },
+            (Resource::On, POST) => Cmd::TurnOn,
+            (Resource::Off, POST) => Cmd::TurnOff,
+            (Resource::Reading(Reading::Power), GET) => Cmd::Power,
+            (Resource::Reading(Reading::Temperature), GET) => Cmd::Temperature,
+            _ => return None,
+        };
+        Some(Ok(cmd))
+    }
+}
+
+/// Payload of notification without time of sample, it changes on every read
+fn observed(payload: &[u8]) -> Value {
+    let mut res: Value = serde_json::from_slice(payload).unwrap_or_default();
+    if let Some(body) = res.as_object_mut() {
+        body.remove("timestamp_ms");
+    }
+    res
+}
+
+/// Confirmable notification waiting for acknowledgement
+struct Pending {
+    msg: Vec<u8>,
+    id: u16,
+    retransmits: u32,
+    timeout: Duration,
+    resend_at: Instant,
+}
+
+/// Client observing reading of device
+struct Observer {
+    addr: SocketAddr,
+    token: Vec<u8>,
+    dev_name: String,
+    reading: Reading,
+    /// Value of observe option, 24 bits are sent
+    seq: u32,
+    /// Reading notified last, time of sample is ignored
+    last: Value,
+    notified_at: Instant,
+    check_at: Instant,
+    pending: Option<Pending>,
+}
+
+/// CoAP endpoint of devices of UDP server. Readings are observable,
+/// notifications are confirmable and retransmitted until acknowledged.
+pub struct CoapServer {
+    config: Option<CoapConfig>,
+    devices: SharedDevices,
+    metrics: SharedMetrics,
+    socket: Option<UdpSocket>,
+    next_id: u16,
+    /// Responses to recent requests by client and message id
+    responses: HashMap<(SocketAddr, u16), (Instant, Vec<u8>)>,
+    observers: Vec<Observer>,
+    rx: Option<Receiver<ConsoleCmd>>,
+}
+
+impl Service for CoapServer {
+    fn start_service(mut self, rx: Receiver<ConsoleCmd>) -> JoinHandle<()> {
+        self.rx = Some(rx);
+        self.start()
+    }
+
+    fn rx(&self) -> &Receiver<ConsoleCmd> {
+        self.rx.as_ref().unwrap()
+    }
+}
+
+impl CoapServer {
+    /// Endpoint is disabled if config has no coap section
+    pub fn new(config_path: &Path, devices: SharedDevices, metrics: SharedMetrics) -> Self {
+        let config = config_section(config_path, "coap");
+        Self {
+            config,
+            devices,
+            metrics,
+            socket: None,
+            next_id: rand::thread_rng().gen(),
+            responses: HashMap::new(),
+            observers: Vec::new(),
+            rx: None,
+        }
+    }
+
+    pub fn name() -> &'static str {
+        "CoapServer"
+    }
+
+    fn bind(config: &CoapConfig) -> UdpSocket {
+        let socket = match UdpSocket::bind(&config.addr) {
+            Ok(res) => res,
+            Err(e) => {
+                error!("Can't bind to {}: {:?}", config.addr, e);
+                panic!();
+            }
+        };
+        if let Err(e) = socket.set_nonblocking(true) {
+            error!("Can't set nonblocking socket: {e}");
+            panic!();
+        }
+        info!("Coap endpoint is served on coap://{}", config.addr);
+        socket
+    }
+
+    fn start(mut self) -> JoinHandle<()> {
+        thread::spawn(move || {
+            self.socket = self.config.as_ref().map(Self::bind);
+            loop {
+                if let Some(ConsoleCmd::Exit) = self.get_cmd() {
+                    break;
+                }
+                if let Err(e) = self.poll(Instant::now()) {
+                    error!("Invalid coap socket: {:?}", e);
+                    break;
+                }
+                thread::sleep(Duration::from_millis(POLL_MS));
+            }
+        })
+    }
+
+    fn next_id(&mut self) -> u16 {
+        self.next_id = self.next_id.wrapping_add(1);
+        self.next_id
+    }
+
+    fn send(&self, addr: SocketAddr, msg: &[u8]) {
+        if let Some(Err(e)) = self.socket.as_ref().map(|socket| socket.send_to(msg, addr)) {
+            info!("Can't send coap message to {addr}: {e}");
+        }
+    }
+
+    /// Handles received messages, then sends due notifications and retransmissions
+    fn poll(&mut self, now: Instant) -> io::Result<()> {
+        let mut buf = [0; MAX_DATAGRAM];
+        loop {
+            let Some(socket) = self.socket.as_ref() else {
+                return Ok(());
+            };
+            match socket.recv_from(&mut buf) {
+                Ok((len, addr)) => self.handle_datagram(&buf[..len], addr, now),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                // Port of previous datagram is unreachable, error is reported on Windows
+                Err(e) if e.kind() == io::ErrorKind::ConnectionReset => {}
+                Err(e) => return Err(e),
+            }
+        }
+        self.responses.retain(|_, (at, _)| now.duration_since(*at) < EXCHANGE_LIFETIME);
+        self.notify(now);
+        Ok(())
+    }
+
+    fn handle_datagram(&mut self, data: &[u8], addr: SocketAddr, now: Instant) {
+        let Some(msg) = Message::decode(data) else {
+            // Malformed confirmable message is rejected
+            if data.len() >= 4 && data[0] >> 4 == VERSION << 2 {
+                let id = u16::from_be_bytes([data[2], data[3]]);
+                self.send(addr, &Message::new(Kind::Reset, EMPTY, id, Vec::new()).encode());
+            }
+            debug!("Invalid coap message from {addr}");
+            return;
+        };
+        match (msg.kind, msg.code) {
+            (Kind::Ack, _) => {
+                for observer in self.observers.iter_mut().filter(|observer| observer.addr == addr) {
+                    if observer.pending.as_ref().is_some_and(|pending| pending.id == msg.id) {
+                        observer.pending = None;
+                    }
+                }
+            }
+            (Kind::Reset, _) => self.observers.retain(|observer| {
+                let is_rejected = observer.addr == addr && observer.pending.as_ref().is_some_and(|pending| pending.id == msg.id);
+                if is_rejected {
+                    info!("Coap client {addr} stopped observing {}", observer.dev_name);
+                }
+                !is_rejected
+            }),
+            // Ping
+            (Kind::Confirmable, EMPTY) => self.send(addr, &Message::new(Kind::Reset, EMPTY, msg.id, Vec::new()).encode()),
+            (_, EMPTY) => {}
+            (kind, _) => {
+                if let Some((_, resp)) = self.responses.get(&(addr, msg.id)) {
+                    debug!("Duplicated coap message {} from {addr}", msg.id);
+                    if kind == Kind::Confirmable {
+                        self.send(addr, resp);
+                    }
+                    return;
+                }
+                let resp = self.handle_request(&msg, addr, now).encode();
+                self.send(addr, &resp);
+                self.cache_response((addr, msg.id), now, resp);
+            }
+        }
+    }
+
+    fn cache_response(&mut self, key: (SocketAddr, u16), now: Instant, resp: Vec<u8>) {
+        if self.responses.len() >= MAX_RESPONSES {
+            if let Some(oldest) = self.responses.iter().min_by_key(|(_, (at, _))| *at).map(|(key, _)| *key) {
+                self.responses.remove(&oldest);
+            }
+        }
+        self.responses.insert(key, (now, resp));
+    }
+
+    /// Confirmable request is answered by piggybacked acknowledgement
+    fn handle_request(&mut self, req: &Message, addr: SocketAddr, now: Instant) -> Message {
+        let resp = match req.kind {
+            Kind::Confirmable => Message::new(Kind::Ack, EMPTY, req.id, req.token.clone()),
+            _ => Message::new(Kind::NonConfirmable, EMPTY, self.next_id(), req.token.clone()),
+        };
+        if let Some((number, _)) = req.options.iter().find(|(n, _)| n % 2 == 1 && !KNOWN_OPTIONS.contains(n)) {
+            info!("Coap request with unsupported option {number}");
+            return Message { code: BAD_OPTION, ..resp };
+        }
+        let path = req.path();
+        let path: Vec<&str> = path.iter().map(String::as_str).collect();
+        let (dev_name, resource) = match path.as_slice() {
+            [".well-known", "core"] if req.code == GET => return Message { code: CONTENT, ..resp.with_payload(FORMAT_LINK, self.core().into_bytes()) },
+            [".well-known", "core"] => return Message { code: METHOD_NOT_ALLOWED, ..resp },
+            ["devices", dev_name, resource] => match Resource::parse(resource) {
+                Some(resource) => (dev_name.to_string(), resource),
+                None => return Message { code: NOT_FOUND, ..resp },
+            },
+            _ => return Message { code: NOT_FOUND, ..resp },
+        };
+        let cmd = match resource.cmd(req.code, &req.payload) {
+            Some(Ok(cmd)) => cmd,
+            Some(Err(e)) => return Message { code: BAD_REQUEST, ..resp.with_payload(FORMAT_JSON, json!({"error": e}).to_string().into_bytes()) },
+            None => return Message { code: METHOD_NOT_ALLOWED, ..resp },
+        };
+        let (code, payload) = self.execute(&dev_name, cmd);
+        let mut resp = Message { code, ..resp.with_payload(FORMAT_JSON, payload.clone()) };
+        let Resource::Reading(reading) = resource else {
+            return resp;
+        };
+        // Observe option of other requests is ignored
+        match req.option(OPT_OBSERVE).and_then(option_uint) {
+            Some(0) if code == CONTENT => {
+                self.observers.retain(|observer| observer.addr != addr || observer.token != req.token);
+                if self.observers.len() >= MAX_OBSERVERS {
+                    info!("Coap client {addr} can't observe {dev_name}, too many observers");
+                    return resp;
+                }
+                info!("Coap client {addr} observes {:?} of {dev_name}", reading);
+                resp = resp.with_option(OPT_OBSERVE, uint_option(0));
+                self.observers.push(Observer {
+                    addr,
+                    token: req.token.clone(),
+                    dev_name,
+                    reading,
+                    seq: 0,
+                    last: observed(&payload),
+                    notified_at: now,
+                    check_at: now + self.notify_interval(),
+                    pending: None,
+                });
+            }
+            Some(1) => self.observers.retain(|observer| observer.addr != addr || observer.token != req.token),
+            _ => {}
+        }
+        resp
+    }
+
+    /// Resources of devices in CoRE link format, observable ones are marked.
+    /// Links of devices that don't fit single datagram are left out.
+    fn core(&self) -> String {
+        let devices = self.devices.lock().unwrap();
+        let mut core = String::new();
+        for (name, dev) in devices.iter() {
+            let mut links = vec![format!("</devices/{name}/state>,</devices/{name}/on>,</devices/{name}/off>")];
+            for (reading, resource) in [(Reading::Power, "power"), (Reading::Temperature, "temperature")] {
+                if dev.readings().contains(&reading) {
+                    links.push(format!("</devices/{name}/{resource}>;obs"));
+                }
+            }
+            let links = links.join(",");
+            if core.len() + links.len() + 1 > MAX_CORE_LEN {
+                info!("Coap resources of {} devices don't fit datagram, rest is left out", devices.len());
+                break;
+            }
+            if !core.is_empty() {
+                core.push(',');
+            }
+            core.push_str(&links);
+        }
+        core
+    }
+
+    /// Code and JSON payload of response of device
+    fn execute(&self, dev_name: &str, cmd: Cmd) -> (u8, Vec<u8>) {
+        let mut devices = self.devices.lock().unwrap();
+        let started = Instant::now();
+        let resp = request_handler::handle_request(&mut devices, protocol::Request::new(cmd, dev_name.to_owned()));
+        self.metrics.lock().unwrap().record(TRANSPORT, &resp, started.elapsed());
+        let (code, body) = match &resp.resp_kind {
+            ResponseKind::Success(SuccessKind::Ack) => (CHANGED, json!({"result": "ok"})),
+            ResponseKind::Success(SuccessKind::State(state)) => (CONTENT, serde_json::to_value(state).unwrap_or_default()),
+            ResponseKind::Success(SuccessKind::Power(measurement) | SuccessKind::Temp(measurement)) => (CONTENT, serde_json::to_value(measurement).unwrap_or_default()),
+            ResponseKind::Success(success) => (CONTENT, serde_json::to_value(success).unwrap_or_default()),
+            ResponseKind::Err(e) => (error_code(e.category), serde_json::to_value(e).unwrap_or_default()),
+        };
+        (code, body.to_string().into_bytes())
+    }
+
+    fn notify_interval(&self) -> Duration {
+        Duration::from_millis(self.config.as_ref().map_or(default_notify_interval(), |config| config.notify_interval_ms))
+    }
+
+    fn ack_timeout(&self) -> Duration {
+        let timeout = self.config.as_ref().map_or(default_ack_timeout(), |config| config.ack_timeout_ms);
+        Duration::from_millis(timeout).mul_f64(rand::thread_rng().gen_range(1.0..ACK_RANDOM_FACTOR))
+    }
+
+    /// Changed readings are notified, a new notification waits for acknowledgement
+    /// of the previous one. Observer is dropped after the last retransmission
+    /// or after error notification.
+    fn notify(&mut self, now: Instant) {
+        let mut observers = std::mem::take(&mut self.observers);
+        observers.retain_mut(|observer| {
+            if let Some(pending) = observer.pending.as_mut() {
+                if now < pending.resend_at {
+                    return true;
+                }
+                if pending.retransmits >= MAX_RETRANSMIT {
+                    info!("Coap client {} doesn't acknowledge notifications of {}", observer.addr, observer.dev_name);
+                    return false;
+                }
+                pending.retransmits += 1;
+                pending.timeout *= 2;
+                pending.resend_at = now + pending.timeout;
+                self.send(observer.addr, &pending.msg);
+                return true;
+            }
+            if now < observer.check_at {
+                return true;
+            }
+            observer.check_at = now + self.notify_interval();
+            let cmd = if observer.reading == Reading::Power { Cmd::Power } else { Cmd::Temperature };
+            let (code, payload) = self.execute(&observer.dev_name, cmd);
+            let reading = observed(&payload);
+            if reading == observer.last && now.duration_since(observer.notified_at) < MAX_AGE {
+                return true;
+            }
+            observer.seq = (observer.seq + 1) & 0xff_ffff;
+            let id = self.next_id();
+            let mut msg = Message { code, ..Message::new(Kind::Confirmable, EMPTY, id, observer.token.clone()) };
+            if code == CONTENT {
+                msg = msg.with_option(OPT_OBSERVE, uint_option(observer.seq));
+            }
+            let msg = msg.with_payload(FORMAT_JSON, payload.clone()).encode();
+            self.send(observer.addr, &msg);
+            let timeout = self.ack_timeout();
+            observer.pending = Some(Pending {
+                msg,
+                id,
+                retransmits: 0,
+                timeout,
+                resend_at: now + timeout,
+            });
+            observer.last = reading;
+            observer.notified_at = now;
+            code == CONTENT
+        });
+        self.observers = observers;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use crate::device::test_devices;
+
+    fn devices() -> SharedDevices {
+        Arc::new(Mutex::new(test_devices("coap", vec![
+            json!({"name": "sock1", "type": "socket", "model": {"kind": "gauss", "mean": 2000.0, "spread": 0.0}}),
+            json!({"name": "lamp1", "type": "lamp"}),
+        ])))
+    }
+
+    fn coap() -> (CoapServer, UdpSocket) {
+        let config = CoapConfig {
+            addr: "127.0.0.1:0".to_owned(),
+            ack_timeout_ms: 100,
+            notify_interval_ms: 50,
+        };
+        let mut coap = CoapServer {
+            config: Some(config.clone()),
+            devices: devices(),
+            metrics: SharedMetrics::default(),
+            socket: Some(CoapServer::bind(&config)),
+            next_id: 0,
+            responses: HashMap::new(),
+            observers: Vec::new(),
+            rx: None,
+        };
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        client.connect(coap.socket.as_ref().unwrap().local_addr().unwrap()).unwrap();
+        coap.poll(Instant::now()).unwrap();
+        (coap, client)
+    }
+
+    fn request(kind: Kind, code: u8, id: u16, path: &str) -> Message {
+        let mut msg = Message::new(kind, code, id, vec![id as u8, 0xab]);
+        for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+            msg = msg.with_option(OPT_URI_PATH, segment.as_bytes().to_vec());
+        }
+        msg
+    }
+
+    fn call(coap: &mut CoapServer, client: &UdpSocket, req: &Message) -> Message {
+        client.send(&req.encode()).unwrap();
+        thread::sleep(Duration::from_millis(20));
+        coap.poll(Instant::now()).unwrap();
+        recv(client)
+    }
+
+    fn recv(client: &UdpSocket) -> Message {
+        let mut buf = [0; MAX_DATAGRAM];
+        let len = client.recv(&mut buf).unwrap();
+        Message::decode(&buf[..len]).unwrap()
+    }
+
+    fn payload(msg: &Message) -> Value {
+        serde_json::from_slice(&msg.payload).unwrap()
+    }
+
+    #[test]
+    fn test_codec() {
+        let msg = Message::new(Kind::Confirmable, GET, 0x1234, vec![1, 2, 3])
+            .with_option(OPT_URI_PATH, b"a-rather-long-device-name".to_vec())
+            .with_option(OPT_URI_PATH, Vec::new())
+            .with_option(OPT_OBSERVE, uint_option(0))
+            .with_option(300, vec![7; 300])
+            .with_payload(FORMAT_JSON, b"{}".to_vec());
+        assert_eq!(Message::decode(&msg.encode()), Some(msg.clone()));
+        assert_eq!(msg.path(), ["a-rather-long-device-name", ""]);
+        assert_eq!(msg.option(OPT_CONTENT_FORMAT).and_then(option_uint), Some(FORMAT_JSON));
+        assert_eq!(uint_option(0x0100), [1, 0]);
+
+        assert_eq!(Message::decode(&[0x40, 1, 0, 1]), Some(Message::new(Kind::Confirmable, GET, 1, Vec::new())));
+        // Payload marker without payload, version 2, truncated token
+        assert_eq!(Message::decode(&[0x40, 1, 0, 1, 0xff]), None);
+        assert_eq!(Message::decode(&[0x80, 1, 0, 1]), None);
+        assert_eq!(Message::decode(&[0x42, 1, 0, 1, 9]), None);
+    }
+
+    #[test]
+    fn test_requests() {
+        let (mut coap, client) = coap();
+
+        let resp = call(&mut coap, &client, &request(Kind::Confirmable, GET, 1, "/devices/sock1/state"));
+        assert_eq!((resp.kind, resp.code, resp.id, resp.token.as_slice()), (Kind::Ack, CONTENT, 1, [1, 0xab].as_slice()));
+        assert_eq!(payload(&resp)["is_on"], json!(false));
+
+        let put = Message { payload: b"ON".to_vec(), ..request(Kind::NonConfirmable, PUT, 2, "/devices/sock1/state") };
+        let resp = call(&mut coap, &client, &put);
+        assert_eq!((resp.kind, resp.code), (Kind::NonConfirmable, CHANGED));
+        assert!(coap.devices.lock().unwrap()["sock1"].is_on());
+
+        // Duplicate is answered by the same response without executing it again
+        let off = request(Kind::Confirmable, POST, 3, "/devices/sock1/off");
+        let resp = call(&mut coap, &client, &off);
+        coap.execute("sock1", Cmd::TurnOn);
+        assert_eq!(call(&mut coap, &client, &off), resp);
+        assert!(coap.devices.lock().unwrap()["sock1"].is_on());
+
+        let resp = call(&mut coap, &client, &request(Kind::Confirmable, GET, 4, "/devices/sock1/power"));
+        assert_eq!((resp.code, payload(&resp)["unit"].clone()), (CONTENT, json!("Watt")));
+        assert_eq!(call(&mut coap, &client, &request(Kind::Confirmable, GET, 5, "/devices/sock9/state")).code, NOT_FOUND);
+        assert_eq!(call(&mut coap, &client, &request(Kind::Confirmable, GET, 6, "/devices/sock1/on")).code, METHOD_NOT_ALLOWED);
+        assert_eq!(call(&mut coap, &client, &request(Kind::Confirmable, GET, 7, "/devices")).code, NOT_FOUND);
+        let bad = Message { payload: b"maybe".to_vec(), ..request(Kind::Confirmable, PUT, 8, "/devices/sock1/state") };
+        assert_eq!(call(&mut coap, &client, &bad).code, BAD_REQUEST);
+        let if_match = request(Kind::Confirmable, GET, 9, "/devices/sock1/state").with_option(1, Vec::new());
+        assert_eq!(call(&mut coap, &client, &if_match).code, BAD_OPTION);
+
+        let core = call(&mut coap, &client, &request(Kind::Confirmable, GET, 10, "/.well-known/core"));
+        assert_eq!((core.code, core.option(OPT_CONTENT_FORMAT).and_then(option_uint)), (CONTENT, Some(FORMAT_LINK)));
+        let core = String::from_utf8(core.payload).unwrap();
+        assert!(core.contains("</devices/sock1/power>;obs") && core.contains("</devices/lamp1/off>"));
+
+        let ping = call(&mut coap, &client, &Message::new(Kind::Confirmable, EMPTY, 11, Vec::new()));
+        assert_eq!((ping.kind, ping.id), (Kind::Reset, 11));
+    }
+
+    #[test]
+    fn test_observe() {
+        let (mut coap, client) = coap();
+        let observe = request(Kind::Confirmable, GET, 1, "/devices/sock1/power").with_option(OPT_OBSERVE, uint_option(0));
+        let resp = call(&mut coap, &client, &observe);
+        assert_eq!((resp.code, resp.option(OPT_OBSERVE)), (CONTENT, Some([].as_slice())));
+
+        // Reading changes when socket is on
+        coap.execute("sock1", Cmd::TurnOn);
+        thread::sleep(Duration::from_millis(60));
+        coap.poll(Instant::now()).unwrap();
+        let notification = recv(&client);
+        assert_eq!((notification.kind, notification.code, notification.token.as_slice()), (Kind::Confirmable, CONTENT, [1, 0xab].as_slice()));
+        assert_eq!(notification.option(OPT_OBSERVE).and_then(option_uint), Some(1));
+        assert_eq!(payload(&notification)["value"], json!(2000.0));
+
+        // Notification isn't acknowledged, it's sent again with the same id
+        thread::sleep(Duration::from_millis(160));
+        coap.poll(Instant::now()).unwrap();
+        assert_eq!(recv(&client), notification);
+        client.send(&Message::new(Kind::Ack, EMPTY, notification.id, Vec::new()).encode()).unwrap();
+        thread::sleep(Duration::from_millis(20));
+        coap.poll(Instant::now()).unwrap();
+        assert!(coap.observers[0].pending.is_none());
+
+        coap.execute("sock1", Cmd::TurnOff);
+        thread::sleep(Duration::from_millis(60));
+        coap.poll(Instant::now()).unwrap();
+        let notification = recv(&client);
+        assert_eq!(notification.option(OPT_OBSERVE).and_then(option_uint), Some(2));
+        client.send(&Message::new(Kind::Reset, EMPTY, notification.id, Vec::new()).encode()).unwrap();
+        thread::sleep(Duration::from_millis(20));
+        coap.poll(Instant::now()).unwrap();
+        assert!(coap.observers.is_empty());
+    }
+
+    #[test]
+    fn test_limits() {
+        let (mut coap, client) = coap();
+        let started = Instant::now();
+        for id in 0..MAX_RESPONSES as u16 {
+            coap.cache_response(("127.0.0.1:9".parse().unwrap(), id), started + Duration::from_millis(id.into()), Vec::new());
+        }
+        let resp = call(&mut coap, &client, &request(Kind::Confirmable, GET, 1, "/devices/sock1/state"));
+        assert_eq!(coap.responses.len(), MAX_RESPONSES);
+        assert!(!coap.responses.contains_key(&("127.0.0.1:9".parse().unwrap(), 0)));
+        assert_eq!(coap.responses[&(client.local_addr().unwrap(), 1)].1, resp.encode());
+
+        let configs = (0..40).map(|i| json!({"name": format!("socket-in-room-{i}"), "type": "socket"})).collect();
+        coap.devices = Arc::new(Mutex::new(test_devices("coap_core", configs)));
+        let core = call(&mut coap, &client, &request(Kind::Confirmable, GET, 2, "/.well-known/core"));
+        assert_eq!(core.code, CONTENT);
+        assert!(core.encode().len() <= MAX_DATAGRAM);
+        let core = String::from_utf8(core.payload).unwrap();
+        assert!(core.ends_with(">;obs") && core.matches("/state>").count() < 40);
+    }
+}
@@ -1,28 +1,122 @@
-use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
 use std::thread::JoinHandle;
 use std::path::Path;
 use std::io::{self, BufRead};
 use std::collections::HashMap;
 use std::collections::hash_map::Entry;
+use std::fs;
+use std::net::TcpListener;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
 use log::*;
 
 use super::err_house;
+use super::device::check_unique_names;
 use super::smart_house_tcp_server::TcpServer;
 use super::smart_house_udp_server::UdpServer;
+use super::rules::RuleEngine;
+use super::power_budget::PowerBudget;
+use super::metrics::{MetricsServer, SharedMetrics};
+use super::influx::InfluxExporter;
+use super::rest::RestGateway;
+use super::ws::WsServer;
+use super::mqtt::MqttBridge;
+use super::modbus::ModbusServer;
+use super::coap::CoapServer;
+use super::grpc::GrpcServer;
 
 const EXIT: &str = "exit";
+const RULES: &str = "rules";
+const DRY_RUN: &str = "dry_run";
+const BUDGET: &str = "budget";
 
 #[derive(Clone, Copy)]
 pub enum ConsoleCmd {
     Exit,
+    ListRules,
+    /// Turns on or off dry run of all rules
+    DryRun(bool),
+    /// Prints total power and sockets shed by budget
+    PowerBudget,
 }
 
 fn help() {
+    println!("Type \"{RULES}\" to list automation rules and their last results");
+    println!("Type \"{DRY_RUN}\" on|off to evaluate rules without executing their actions");
+    println!("Type \"{BUDGET}\" to show power of sockets and sockets shed by power budget");
     println!("Type \"exit\" to exit from emulator");
 }
 
+/// Pushes event of device or service to server console besides log,
+/// e.g. raised alert. Log record keeps location of caller.
+macro_rules! push_event {
+    ($level:expr, $($arg:tt)+) => {{
+        let event = format!($($arg)+);
+        log::log!($level, "{event}");
+        println!("{event}");
+    }};
+}
+pub(crate) use push_event;
+
 pub trait Service {
     fn start_service(self, rx: Receiver<ConsoleCmd>) -> JoinHandle<()>;
+
+    /// Receiver of console commands given on start
+    fn rx(&self) -> &Receiver<ConsoleCmd>;
+
+    fn get_cmd(&self) -> Option<ConsoleCmd> {
+        match self.rx().try_recv() {
+            Ok(cmd) => Some(cmd),
+            Err(TryRecvError::Disconnected) => {
+                error!("Channel disconnected");
+                panic!();
+            }
+            Err(TryRecvError::Empty) => None,
+        }
+    }
+}
+
+pub fn read_config(config_path: &Path) -> Value {
+    match fs::read_to_string(config_path).map(|json| serde_json::from_str(&json)) {
+        Ok(Ok(res)) => res,
+        res => {
+            error!("Can't read config from {:?}: {:?}", config_path, res);
+            panic!();
+        }
+    }
+}
+
+/// Reads section of service from config, service is disabled if section is absent
+pub fn config_section<T: DeserializeOwned>(config_path: &Path, key: &str) -> Option<T> {
+    match read_config(config_path).get(key) {
+        Some(section) => match serde_json::from_value(section.clone()) {
+            Ok(config) => Some(config),
+            Err(e) => {
+                error!("Wrong input config: invalid {key}: {e}");
+                panic!();
+            }
+        },
+        None => {
+            info!("Section {key} not found in config, service is disabled");
+            None
+        }
+    }
+}
+
+/// Binds listener of service, it's polled between console commands
+pub fn bind_listener(addr: &str) -> TcpListener {
+    let listener = match TcpListener::bind(addr) {
+        Ok(res) => res,
+        Err(e) => {
+            error!("Can't bind to {addr}: {:?}", e);
+            panic!();
+        }
+    };
+    if let Err(e) = listener.set_nonblocking(true) {
+        error!("Can't set nonblocking listener: {e}");
+        panic!();
+    }
+    listener
 }
 
 pub struct Channel {
@@ -69,9 +163,9 @@ impl ConsoleServer {
         }
     }
 
-    fn send_service_cmd(&mut self, service_name: &str, cmd: ConsoleCmd) -> Result<(), err_house::Err> {
+    fn send_service_cmd(&self, service_name: &str, cmd: ConsoleCmd) -> Result<(), err_house::Err> {
         if let Some(channel) = self.channels.get(service_name) {
-            if let Err(_) = channel.tx.send(cmd){
+            if channel.tx.send(cmd).is_err(){
                 error!("Service: {service_name} isn't responding");
                 return Err(err_house::Err::new(err_house::ErrorKind::ServiceNotRespond));
             }
@@ -92,11 +186,36 @@ impl ConsoleServer {
         println!("Start server");
         help();
         let std_in = io::stdin();
-        let tcp_server = TcpServer::new(Path::new("Config.txt"));
-        let udp_server = UdpServer::new(Path::new("Config.txt"));
+        let metrics = SharedMetrics::default();
+        let tcp_server = TcpServer::new(Path::new("Config.txt"), metrics.clone());
+        let udp_server = UdpServer::new(Path::new("Config.txt"), metrics.clone());
+        if let Err(reason) = check_unique_names(&[("tcp", tcp_server.devices()), ("udp", udp_server.devices())]) {
+            error!("Wrong input config: {reason}");
+            panic!();
+        }
+        let rule_engine = RuleEngine::new(Path::new("Rules.txt"), vec![tcp_server.devices(), udp_server.devices()]);
+        let power_budget = PowerBudget::new(Path::new("Config.txt"), vec![tcp_server.devices(), udp_server.devices()]);
+        let metrics_server = MetricsServer::new(Path::new("Config.txt"), vec![("tcp", tcp_server.devices()), ("udp", udp_server.devices())], metrics.clone());
+        let rest_gateway = RestGateway::new(Path::new("Config.txt"), vec![("tcp", tcp_server.devices()), ("udp", udp_server.devices())], metrics.clone());
+        let ws_server = WsServer::new(Path::new("Config.txt"), vec![("tcp", tcp_server.devices()), ("udp", udp_server.devices())], metrics.clone());
+        let mqtt_bridge = MqttBridge::new(Path::new("Config.txt"), vec![("tcp", tcp_server.devices()), ("udp", udp_server.devices())], metrics.clone());
+        let modbus_server = ModbusServer::new(Path::new("Config.txt"), vec![("tcp", tcp_server.devices()), ("udp", udp_server.devices())], metrics.clone());
+        let coap_server = CoapServer::new(Path::new("Config.txt"), udp_server.devices(), metrics.clone());
+        let grpc_server = GrpcServer::new(Path::new("Config.txt"), vec![("tcp", tcp_server.devices()), ("udp", udp_server.devices())], metrics);
+        let influx_exporter = InfluxExporter::new(Path::new("Config.txt"), vec![("tcp", tcp_server.devices()), ("udp", udp_server.devices())]);
 
         self.connect_to_service(tcp_server, TcpServer::name());
         self.connect_to_service(udp_server, UdpServer::name());
+        self.connect_to_service(rule_engine, RuleEngine::name());
+        self.connect_to_service(power_budget, PowerBudget::name());
+        self.connect_to_service(metrics_server, MetricsServer::name());
+        self.connect_to_service(influx_exporter, InfluxExporter::name());
+        self.connect_to_service(rest_gateway, RestGateway::name());
+        self.connect_to_service(ws_server, WsServer::name());
+        self.connect_to_service(mqtt_bridge, MqttBridge::name());
+        self.connect_to_service(modbus_server, ModbusServer::name());
+        self.connect_to_service(coap_server, CoapServer::name());
+        self.connect_to_service(grpc_server, GrpcServer::name());
 
         for line in std_in.lock().lines(){
             let cmd =
@@ -107,15 +226,29 @@ impl ConsoleServer {
                     panic!();
                 }
             };
-            match cmd.as_str() {
-                EXIT => {
-                    if let Err(e) = self.send_service_cmd(TcpServer::name(), ConsoleCmd::Exit){
-                        error!("Can't stop tcp server: {e}");
-                        panic!();
+            let params: Vec<&str> = cmd.split_whitespace().collect();
+            match params[..] {
+                [RULES] => {
+                    if let Err(e) = self.send_service_cmd(RuleEngine::name(), ConsoleCmd::ListRules){
+                        error!("Can't list rules: {e}");
+                    }
+                }
+                [DRY_RUN, mode @ ("on" | "off")] => {
+                    if let Err(e) = self.send_service_cmd(RuleEngine::name(), ConsoleCmd::DryRun(mode == "on")){
+                        error!("Can't switch dry run of rules: {e}");
                     }
-                    if let Err(e) = self.send_service_cmd(UdpServer::name(), ConsoleCmd::Exit){
-                        error!("Can't stop udp server: {e}");
-                        panic!();
+                }
+                [BUDGET] => {
+                    if let Err(e) = self.send_service_cmd(PowerBudget::name(), ConsoleCmd::PowerBudget){
+                        error!("Can't show power budget: {e}");
+                    }
+                }
+                [EXIT] => {
+                    for service_name in self.channels.keys() {
+                        if let Err(e) = self.send_service_cmd(service_name, ConsoleCmd::Exit){
+                            error!("Can't stop {service_name}: {e}");
+                            panic!();
+                        }
                     }
                     info!("Exit from emulator");
                     println!("Exit from emulator");
@@ -0,0 +1,215 @@
+use std::fmt::Display;
+
+use chrono::{DateTime, Duration, Local};
+use serde::Deserialize;
+use log::*;
+
+use super::Reading;
+use crate::console_server::push_event;
+use crate::protocol::AlertState;
+
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum AlertRule {
+    /// Value is greater than limit
+    Above { limit: f64 },
+    /// Value is outside of [min, max]
+    OutOfRange { min: f64, max: f64 },
+    /// Value doesn't change more than tolerance, e.g. sensor hangs
+    Stuck {
+        #[serde(default)]
+        tolerance: f64,
+    },
+}
+
+/// Alert rule of device from config, e.g.
+/// `{"reading": "power", "kind": "above", "limit": 3000.0, "for_secs": 10}`
+#[derive(Deserialize, Clone, Copy, Debug)]
+pub struct AlertConfig {
+    pub reading: Reading,
+    #[serde(flatten)]
+    pub rule: AlertRule,
+    /// Condition must hold this long to raise alert
+    #[serde(default)]
+    pub for_secs: u64,
+}
+
+impl Display for AlertConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.rule {
+            AlertRule::Above { limit } => write!(f, "{:?} above {limit}", self.reading)?,
+            AlertRule::OutOfRange { min, max } => write!(f, "{:?} outside [{min}, {max}]", self.reading)?,
+            AlertRule::Stuck { tolerance } => write!(f, "{:?} stuck within {tolerance}", self.reading)?,
+        }
+        if self.for_secs > 0 {
+            write!(f, " for {} s", self.for_secs)?;
+        }
+        Ok(())
+    }
+}
+
+/// Raise and clear of alert
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AlertEvent {
+    Raised,
+    Cleared,
+}
+
+/// State of alert rule evaluated against readings of device
+pub struct Alert {
+    config: AlertConfig,
+    /// Since when condition holds, for stuck rule since when value is the same
+    since: Option<DateTime<Local>>,
+    /// First value of series for stuck rule
+    stuck_value: f64,
+    last_value: Option<f64>,
+    is_raised: bool,
+    raised_at: Option<DateTime<Local>>,
+    cleared_at: Option<DateTime<Local>>,
+}
+
+impl Alert {
+    pub fn new(config: AlertConfig) -> Self {
+        Self {
+            config,
+            since: None,
+            stuck_value: 0.0,
+            last_value: None,
+            is_raised: false,
+            raised_at: None,
+            cleared_at: None,
+        }
+    }
+
+    pub fn reading(&self) -> Reading {
+        self.config.reading
+    }
+
+    fn is_condition(&mut self, value: f64) -> bool {
+        match self.config.rule {
+            AlertRule::Above { limit } => value > limit,
+            AlertRule::OutOfRange { min, max } => !(min..=max).contains(&value),
+            AlertRule::Stuck { tolerance } => {
+                let is_same = self.since.is_some() && (value - self.stuck_value).abs() <= tolerance;
+                if !is_same {
+                    self.stuck_value = value;
+                }
+                is_same
+            }
+        }
+    }
+
+    /// Evaluates sample of reading, returns event if alert changes its state
+    pub fn check(&mut self, value: f64, now: DateTime<Local>) -> Option<AlertEvent> {
+        let is_condition = self.is_condition(value);
+        self.last_value = Some(value);
+        let is_stuck_rule = matches!(self.config.rule, AlertRule::Stuck { .. });
+        match (is_condition, self.since) {
+            (true, None) => self.since = Some(now),
+            // The first sample of series starts it, so stuck rule keeps it
+            (false, _) if is_stuck_rule => self.since = Some(now),
+            (false, _) => self.since = None,
+            _ => {}
+        }
+
+        let is_holding = self.since.is_some_and(|since| {
+            is_condition && now - since >= Duration::seconds(self.config.for_secs as i64)
+        });
+        match (self.is_raised, is_holding) {
+            (false, true) => {
+                self.is_raised = true;
+                self.raised_at = Some(now);
+                Some(AlertEvent::Raised)
+            }
+            (true, false) => {
+                self.is_raised = false;
+                self.cleared_at = Some(now);
+                Some(AlertEvent::Cleared)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn state(&self, dev_name: &str) -> AlertState {
+        AlertState {
+            dev_name: dev_name.to_owned(),
+            rule: self.config.to_string(),
+            is_raised: self.is_raised,
+            raised_ms: self.raised_at.map(|time| time.timestamp_millis()),
+            cleared_ms: self.cleared_at.map(|time| time.timestamp_millis()),
+            last_value: self.last_value,
+        }
+    }
+}
+
+/// Alerts are pushed to server console besides log
+pub fn report(dev_name: &str, alert: &Alert, event: AlertEvent) {
+    let value = alert.last_value.unwrap_or_default();
+    let level = match event {
+        AlertEvent::Raised => Level::Warn,
+        AlertEvent::Cleared => Level::Info,
+    };
+    push_event!(level, "Alert {:?}: {dev_name}: {}, value {value:.2}", event, alert.config);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alert(rule: AlertRule, for_secs: u64) -> Alert {
+        Alert::new(AlertConfig {
+            reading: Reading::Power,
+            rule,
+            for_secs,
+        })
+    }
+
+    #[test]
+    fn test_above_for_duration() {
+        let mut alert = alert(AlertRule::Above { limit: 100.0 }, 10);
+        let start = Local::now();
+        let at = |secs| start + Duration::seconds(secs);
+        assert_eq!(alert.check(150.0, at(0)), None);
+        assert_eq!(alert.check(150.0, at(5)), None);
+        // Condition is interrupted, so duration starts again
+        assert_eq!(alert.check(50.0, at(6)), None);
+        assert_eq!(alert.check(150.0, at(7)), None);
+        assert_eq!(alert.check(150.0, at(17)), Some(AlertEvent::Raised));
+        assert_eq!(alert.check(150.0, at(18)), None);
+        assert_eq!(alert.check(50.0, at(19)), Some(AlertEvent::Cleared));
+        let state = alert.state("sock");
+        assert!(!state.is_raised);
+        assert_eq!(state.raised_ms, Some(at(17).timestamp_millis()));
+        assert_eq!(state.cleared_ms, Some(at(19).timestamp_millis()));
+    }
+
+    #[test]
+    fn test_out_of_range() {
+        let mut alert = alert(AlertRule::OutOfRange { min: 18.0, max: 26.0 }, 0);
+        let now = Local::now();
+        assert_eq!(alert.check(20.0, now), None);
+        assert_eq!(alert.check(17.0, now), Some(AlertEvent::Raised));
+        assert_eq!(alert.check(30.0, now), None);
+        assert_eq!(alert.check(26.0, now), Some(AlertEvent::Cleared));
+    }
+
+    #[test]
+    fn test_stuck() {
+        let mut alert = alert(AlertRule::Stuck { tolerance: 0.5 }, 60);
+        let start = Local::now();
+        let at = |secs| start + Duration::seconds(secs);
+        assert_eq!(alert.check(20.0, at(0)), None);
+        assert_eq!(alert.check(20.3, at(30)), None);
+        assert_eq!(alert.check(21.0, at(50)), None);
+        assert_eq!(alert.check(21.0, at(100)), None);
+        assert_eq!(alert.check(21.2, at(110)), Some(AlertEvent::Raised));
+        assert_eq!(alert.check(25.0, at(120)), Some(AlertEvent::Cleared));
+    }
+
+    #[test]
+    fn test_config() {
+        let config: AlertConfig = serde_json::from_str(
+            r#"{"reading": "temperature", "kind": "out_of_range", "min": 18.0, "max": 26.0, "for_secs": 30}"#).unwrap();
+        assert_eq!(config.to_string(), "Temperature outside [18, 26] for 30 s");
+    }
+}
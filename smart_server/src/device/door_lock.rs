@@ -0,0 +1,190 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use log::*;
+
+use super::{execute_default, DeviceConfig, FactoryContext, Reading, Sample, SmartDevice, Snapshot};
+use crate::err_house;
+use crate::protocol::{Cmd, Error, ErrorKind, LockState, SuccessKind};
+
+pub const TYPE_NAME: &str = "lock";
+
+const COMMANDS: &[Cmd] = &[Cmd::Lock, Cmd::Unlock, Cmd::LockState];
+const READINGS: &[Reading] = &[Reading::LockState];
+
+pub fn create(dev_config: &DeviceConfig, _ctx: &FactoryContext) -> Result<Box<dyn SmartDevice>, err_house::Err> {
+    let jam_probability: f64 = dev_config.param("jam_probability", 0.0)?;
+    if !(0.0..=1.0).contains(&jam_probability) {
+        error!("Jam probability of lock {} is out of range: {jam_probability}", dev_config.name);
+        return Err(err_house::Err::new(err_house::ErrorKind::ParsingError));
+    }
+    let rng = match dev_config.param::<Option<u64>>("seed", None)? {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+    Ok(Box::new(DoorLock {
+        name: dev_config.name.to_owned(),
+        state: LockState::Locked,
+        jam_probability,
+        rng,
+    }))
+}
+
+/// Lock state as numeric reading
+pub fn lock_value(state: LockState) -> f64 {
+    match state {
+        LockState::Locked => 0.0,
+        LockState::Unlocked => 1.0,
+        LockState::Jammed => 2.0,
+    }
+}
+
+pub fn lock_state(value: f64) -> LockState {
+    match value as u8 {
+        0 => LockState::Locked,
+        1 => LockState::Unlocked,
+        _ => LockState::Jammed,
+    }
+}
+
+/// Door lock. Every actuation may jam the lock, jammed lock
+/// is released by the next successful actuation.
+pub struct DoorLock {
+    name: String,
+    state: LockState,
+    jam_probability: f64,
+    rng: StdRng,
+}
+
+impl DoorLock {
+    fn actuate(&mut self, target: LockState) -> Result<SuccessKind, Error> {
+        if self.rng.gen_bool(self.jam_probability) {
+            warn!("Lock {} is jammed", self.name);
+            self.state = LockState::Jammed;
+            return Err(Error::new(ErrorKind::DevFault).with_details("lock is jammed, retry to release it".to_owned()));
+        }
+        info!("Lock {} is {:?}", self.name, target);
+        self.state = target;
+        Ok(SuccessKind::Ack)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct LockSnapshot {
+    state: LockState,
+}
+
+impl SmartDevice for DoorLock {
+    fn type_dev(&self) -> &'static str {
+        TYPE_NAME
+    }
+
+    fn commands(&self) -> &'static [Cmd] {
+        COMMANDS
+    }
+
+    fn readings(&self) -> &'static [Reading] {
+        READINGS
+    }
+
+    fn turn_on(&mut self) {}
+
+    fn turn_off(&mut self) {}
+
+    /// Lock is powered all the time
+    fn is_on(&self) -> bool {
+        true
+    }
+
+    fn is_fault(&self) -> bool {
+        self.state == LockState::Jammed
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Box::new(LockSnapshot {
+            state: self.state,
+        })
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) {
+        let Ok(snapshot) = snapshot.downcast::<LockSnapshot>() else {
+            error!("Invalid snapshot of lock {}", self.name);
+            return;
+        };
+        self.state = snapshot.state;
+    }
+
+    fn read(&mut self, reading: Reading) -> Result<Sample, Error> {
+        match reading {
+            Reading::LockState => Ok(Sample::fresh(lock_value(self.state))),
+            _ => Err(ErrorKind::WrongCmd.into()),
+        }
+    }
+
+    fn execute(&mut self, cmd: &Cmd) -> Result<SuccessKind, Error> {
+        match cmd {
+            Cmd::Lock => self.actuate(LockState::Locked),
+            Cmd::Unlock => self.actuate(LockState::Unlocked),
+            _ => execute_default(self, cmd),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::energy::EnergyConfig;
+    use serde_json::{json, Value};
+
+    fn door_lock(jam_probability: f64) -> DoorLock {
+        DoorLock {
+            name: "lock".to_owned(),
+            state: LockState::Locked,
+            jam_probability,
+            rng: StdRng::seed_from_u64(7),
+        }
+    }
+
+    fn state(lock: &mut DoorLock) -> LockState {
+        lock_state(lock.read(Reading::LockState).unwrap().value)
+    }
+
+    #[test]
+    fn test_lock_state() {
+        let mut lock = door_lock(0.0);
+        assert!(matches!(lock.execute(&Cmd::Unlock), Ok(SuccessKind::Ack)));
+        assert_eq!(state(&mut lock), LockState::Unlocked);
+        assert!(matches!(lock.execute(&Cmd::Lock), Ok(SuccessKind::Ack)));
+        assert_eq!(state(&mut lock), LockState::Locked);
+        assert!(!lock.is_fault());
+
+        // Jammed lock is released by the next successful actuation
+        let mut lock = door_lock(1.0);
+        assert!(lock.execute(&Cmd::Unlock).is_err());
+        assert_eq!(state(&mut lock), LockState::Jammed);
+        assert!(lock.is_fault());
+        lock.jam_probability = 0.0;
+        assert!(matches!(lock.execute(&Cmd::Unlock), Ok(SuccessKind::Ack)));
+        assert_eq!(state(&mut lock), LockState::Unlocked);
+        assert!(!lock.is_fault());
+    }
+
+    #[test]
+    fn test_jam_probability() {
+        let mut lock = door_lock(0.2);
+        let cnt = 10_000;
+        let jams = (0..cnt).filter(|_| lock.execute(&Cmd::Lock).is_err()).count();
+        let rate = jams as f64 / cnt as f64;
+        assert!((rate - 0.2).abs() < 0.02, "jam rate {rate}");
+
+        let ctx = FactoryContext {
+            energy_config: Arc::new(EnergyConfig::from_config(&Value::Null)),
+            transport: "tcp",
+        };
+        for (jam_probability, is_valid) in [(0.0, true), (1.0, true), (-0.1, false), (1.5, false)] {
+            let config: DeviceConfig = serde_json::from_value(json!({"name": "lock", "type": TYPE_NAME, "jam_probability": jam_probability})).unwrap();
+            assert_eq!(create(&config, &ctx).is_ok(), is_valid, "{jam_probability}");
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use chrono::Local;
+use log::*;
+
+use super::simulation::{Model, ModelConfig};
+use super::{switch, DeviceConfig, FactoryContext, Reading, Sample, SmartDevice, Snapshot};
+use crate::err_house;
+use crate::protocol::{Cmd, Error, ErrorKind};
+
+pub const TYPE_NAME: &str = "humidity";
+
+const AVG_HUMIDITY: f64 = 45.0; // %
+const HUMIDITY_SPREAD: f64 = 5.0; // %
+
+const COMMANDS: &[Cmd] = &[Cmd::TurnOn, Cmd::TurnOff, Cmd::Humidity];
+const READINGS: &[Reading] = &[Reading::Humidity];
+
+pub fn create(dev_config: &DeviceConfig, _ctx: &FactoryContext) -> Result<Box<dyn SmartDevice>, err_house::Err> {
+    let model = dev_config.model.unwrap_or(ModelConfig::gauss(AVG_HUMIDITY, HUMIDITY_SPREAD)).build()?;
+    Ok(Box::new(HumiditySensor {
+        is_turn_on: false,
+        model,
+        last_sample: None,
+    }))
+}
+
+/// Relative humidity sensor, readings are in percents
+pub struct HumiditySensor {
+    is_turn_on: bool,
+    model: Box<dyn Model>,
+    /// Humidity reported while sensor is off
+    last_sample: Option<Sample>,
+}
+
+#[derive(Clone, Copy)]
+struct HumiditySnapshot {
+    is_turn_on: bool,
+}
+
+impl SmartDevice for HumiditySensor {
+    fn type_dev(&self) -> &'static str {
+        TYPE_NAME
+    }
+
+    fn commands(&self) -> &'static [Cmd] {
+        COMMANDS
+    }
+
+    fn readings(&self) -> &'static [Reading] {
+        READINGS
+    }
+
+    fn turn_on(&mut self) {
+        info!("Humidity sensor is turned on");
+        self.is_turn_on = true;
+        self.model.reset(Local::now());
+    }
+
+    fn turn_off(&mut self) {
+        info!("Humidity sensor is turned off");
+        self.is_turn_on = false;
+    }
+
+    fn is_on(&self) -> bool {
+        self.is_turn_on
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Box::new(HumiditySnapshot {
+            is_turn_on: self.is_turn_on,
+        })
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) {
+        let Ok(snapshot) = snapshot.downcast::<HumiditySnapshot>() else {
+            error!("Invalid snapshot of humidity sensor");
+            return;
+        };
+        switch(self, snapshot.is_turn_on);
+    }
+
+    fn read(&mut self, reading: Reading) -> Result<Sample, Error> {
+        if reading != Reading::Humidity {
+            return Err(ErrorKind::WrongCmd.into());
+        }
+        if !self.is_turn_on {
+            return Ok(Sample::stale(self.last_sample));
+        }
+
+        let now = Local::now();
+        let sample = Sample::simulated(self.model.sample(now).clamp(0.0, 100.0), now);
+        self.last_sample = Some(sample);
+        Ok(sample)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Quality;
+
+    fn humidity_sensor(mean: f64, spread: f64) -> HumiditySensor {
+        let mut sensor = HumiditySensor {
+            is_turn_on: false,
+            model: ModelConfig::gauss(mean, spread).build().unwrap(),
+            last_sample: None,
+        };
+        sensor.turn_on();
+        sensor
+    }
+
+    #[test]
+    fn test_humidity_range() {
+        let mut sensor = humidity_sensor(AVG_HUMIDITY, HUMIDITY_SPREAD);
+        let cnt = 10_000;
+        let samples: Vec<f64> = (0..cnt).map(|_| sensor.read(Reading::Humidity).unwrap().value).collect();
+        assert!(samples.iter().all(|value| (0.0..=100.0).contains(value)));
+        let avg = samples.iter().sum::<f64>() / cnt as f64;
+        assert!((avg - AVG_HUMIDITY).abs() < 0.5);
+
+        // Model out of physical range is clamped
+        assert_eq!(humidity_sensor(150.0, 0.0).read(Reading::Humidity).unwrap().value, 100.0);
+        assert_eq!(humidity_sensor(-10.0, 0.0).read(Reading::Humidity).unwrap().value, 0.0);
+
+        // Sensor turned off reports the last humidity as stale
+        let last = sensor.read(Reading::Humidity).unwrap().value;
+        sensor.turn_off();
+        let sample = sensor.read(Reading::Humidity).unwrap();
+        assert_eq!(sample.quality, Quality::Stale);
+        assert_eq!(sample.value, last);
+    }
+}
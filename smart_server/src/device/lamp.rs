@@ -0,0 +1,209 @@
+use chrono::{DateTime, Duration, Local};
+use log::*;
+
+use super::{switch, DeviceConfig, FactoryContext, OffTimer, Reading, Sample, SmartDevice, Snapshot};
+use crate::err_house;
+use crate::protocol::{Cmd, Error, ErrorKind};
+
+pub const TYPE_NAME: &str = "lamp";
+
+pub const MAX_BRIGHTNESS: u8 = 100;
+const MAX_POWER: f64 = 60.0; // 60 W at full brightness
+
+const COMMANDS: &[Cmd] = &[
+    Cmd::TurnOn,
+    Cmd::TurnOff,
+    Cmd::Brightness,
+    Cmd::Power,
+    Cmd::SetLevel(0.0),
+    Cmd::TurnOnFor(0),
+];
+const READINGS: &[Reading] = &[Reading::Brightness, Reading::Power];
+
+pub fn create(dev_config: &DeviceConfig, _ctx: &FactoryContext) -> Result<Box<dyn SmartDevice>, err_house::Err> {
+    let brightness = dev_config.param("brightness", MAX_BRIGHTNESS)?;
+    if brightness > MAX_BRIGHTNESS {
+        error!("Brightness of lamp {} is out of range: {brightness}", dev_config.name);
+        return Err(err_house::Err::new(err_house::ErrorKind::ParsingError));
+    }
+    let max_power = dev_config.param("max_power", MAX_POWER)?;
+    Ok(Box::new(Lamp {
+        name: dev_config.name.to_owned(),
+        is_turn_on: false,
+        brightness,
+        max_power,
+        off_timer: OffTimer::default(),
+    }))
+}
+
+/// Dimmable lamp, power is proportional to brightness
+pub struct Lamp {
+    name: String,
+    is_turn_on: bool,
+    brightness: u8,
+    max_power: f64,
+    off_timer: OffTimer,
+}
+
+#[derive(Clone, Copy)]
+struct LampSnapshot {
+    is_turn_on: bool,
+    brightness: u8,
+    off_timer: OffTimer,
+}
+
+impl SmartDevice for Lamp {
+    fn type_dev(&self) -> &'static str {
+        TYPE_NAME
+    }
+
+    fn commands(&self) -> &'static [Cmd] {
+        COMMANDS
+    }
+
+    fn readings(&self) -> &'static [Reading] {
+        READINGS
+    }
+
+    fn turn_on(&mut self) {
+        info!("Lamp {} is turned on", self.name);
+        self.is_turn_on = true;
+        self.off_timer.cancel();
+    }
+
+    fn turn_off(&mut self) {
+        info!("Lamp {} is turned off", self.name);
+        self.is_turn_on = false;
+        self.off_timer.cancel();
+    }
+
+    fn is_on(&self) -> bool {
+        self.is_turn_on
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Box::new(LampSnapshot {
+            is_turn_on: self.is_turn_on,
+            brightness: self.brightness,
+            off_timer: self.off_timer,
+        })
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) {
+        let Ok(snapshot) = snapshot.downcast::<LampSnapshot>() else {
+            error!("Invalid snapshot of lamp {}", self.name);
+            return;
+        };
+        switch(self, snapshot.is_turn_on);
+        self.brightness = snapshot.brightness;
+        self.off_timer = snapshot.off_timer;
+    }
+
+    fn read(&mut self, reading: Reading) -> Result<Sample, Error> {
+        let brightness = if self.is_turn_on { self.brightness as f64 } else { 0.0 };
+        match reading {
+            Reading::Brightness => Ok(Sample::fresh(brightness)),
+            // Lamp has no power meter, power is estimated by brightness
+            Reading::Power => Ok(Sample::simulated(self.max_power * brightness / MAX_BRIGHTNESS as f64, Local::now())),
+            _ => Err(ErrorKind::WrongCmd.into()),
+        }
+    }
+
+    fn set_level(&mut self, level: f64) -> Result<(), Error> {
+        if !(0.0..=MAX_BRIGHTNESS as f64).contains(&level) {
+            return Err(Error::new(ErrorKind::OutOfRange).with_details(format!("{level} isn't in 0..={MAX_BRIGHTNESS}")));
+        }
+        self.brightness = level.round() as u8;
+        info!("Lamp {} brightness is {}%", self.name, self.brightness);
+        Ok(())
+    }
+
+    fn turn_on_for(&mut self, duration: Duration) -> Result<(), Error> {
+        self.turn_on();
+        self.off_timer.start(Local::now(), duration);
+        Ok(())
+    }
+
+    fn update(&mut self, now: DateTime<Local>) {
+        if self.off_timer.is_expired(&now) {
+            info!("Lamp {} on time is over", self.name);
+            self.turn_off();
+        }
+    }
+
+    fn off_in(&self, now: DateTime<Local>) -> Option<Duration> {
+        self.off_timer.remaining(&now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::Device;
+    use crate::protocol::SuccessKind;
+    use serde_json::json;
+
+    fn lamp() -> Lamp {
+        Lamp {
+            name: "lamp".to_owned(),
+            is_turn_on: false,
+            brightness: MAX_BRIGHTNESS,
+            max_power: MAX_POWER,
+            off_timer: OffTimer::default(),
+        }
+    }
+
+    fn config(read_only: bool) -> DeviceConfig {
+        serde_json::from_value(json!({"name": "lamp", "type": TYPE_NAME, "read_only": read_only})).unwrap()
+    }
+
+    #[test]
+    fn test_set_level_range() {
+        let mut lamp = lamp();
+        assert!(matches!(lamp.execute(&Cmd::SetLevel(101.0)), Err(e) if e.is(ErrorKind::OutOfRange)));
+        assert!(matches!(lamp.execute(&Cmd::SetLevel(f64::NAN)), Err(e) if e.is(ErrorKind::OutOfRange)));
+        assert!(matches!(lamp.execute(&Cmd::SetLevel(40.0)), Ok(SuccessKind::Ack)));
+        assert!(matches!(lamp.execute(&Cmd::SetTarget(20.0)), Err(e) if e.is(ErrorKind::WrongCmd)));
+        lamp.turn_on();
+        assert!(matches!(lamp.execute(&Cmd::Brightness), Ok(SuccessKind::Brightness(40))));
+    }
+
+    #[test]
+    fn test_turn_on_for() {
+        let mut lamp = lamp();
+        assert!(matches!(lamp.execute(&Cmd::TurnOnFor(0)), Err(e) if e.is(ErrorKind::OutOfRange)));
+        assert!(matches!(lamp.execute(&Cmd::TurnOnFor(10)), Ok(SuccessKind::Ack)));
+        lamp.update(Local::now() + Duration::minutes(5));
+        assert!(lamp.is_turn_on);
+        lamp.update(Local::now() + Duration::minutes(11));
+        assert!(!lamp.is_turn_on);
+    }
+
+    #[test]
+    fn test_state_and_capabilities() {
+        let mut dev = Device::new(Box::new(lamp()), &config(false));
+        let Ok(SuccessKind::State(state)) = dev.execute(&Cmd::GetState) else { panic!() };
+        assert!(!state.is_on && !state.is_fault && state.last_reading.is_none());
+
+        dev.execute(&Cmd::TurnOn).unwrap();
+        dev.execute(&Cmd::Brightness).unwrap();
+        let Ok(SuccessKind::State(state)) = dev.execute(&Cmd::GetState) else { panic!() };
+        assert!(state.is_on);
+        assert!(matches!(state.last_reading.as_deref(), Some(SuccessKind::Brightness(MAX_BRIGHTNESS))));
+
+        let Ok(SuccessKind::Capabilities(caps)) = dev.execute(&Cmd::GetCapabilities) else { panic!() };
+        assert!(caps.commands.iter().any(|cmd| cmd.same_kind(&Cmd::SetLevel(50.0))));
+        assert_eq!(caps.readings, vec![Cmd::Brightness, Cmd::Power]);
+    }
+
+    #[test]
+    fn test_read_only() {
+        let mut dev = Device::new(Box::new(lamp()), &config(true));
+        let Err(e) = dev.execute(&Cmd::TurnOn) else { panic!() };
+        assert!(e.is(ErrorKind::PermissionDenied));
+        assert_eq!(e.category, crate::protocol::ErrorCategory::Permission);
+        assert!(!dev.is_on());
+        assert!(matches!(dev.execute(&Cmd::Brightness), Ok(SuccessKind::Brightness(0))));
+        assert!(matches!(dev.execute(&Cmd::GetState), Ok(SuccessKind::State(_))));
+    }
+}
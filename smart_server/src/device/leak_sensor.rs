@@ -0,0 +1,136 @@
+use chrono::Local;
+use log::*;
+
+use super::simulation::{Model, ModelConfig};
+use super::{execute_default, switch, DeviceConfig, FactoryContext, Reading, Sample, SmartDevice, Snapshot};
+use crate::err_house;
+use crate::protocol::{Cmd, Error, ErrorKind, SuccessKind};
+
+pub const TYPE_NAME: &str = "leak";
+
+const AVG_MOISTURE: f64 = 10.0; // %
+const MOISTURE_SPREAD: f64 = 5.0; // %
+const LEAK_THRESHOLD: f64 = 30.0; // %
+
+const COMMANDS: &[Cmd] = &[Cmd::TurnOn, Cmd::TurnOff, Cmd::Leak, Cmd::ResetAlarm];
+const READINGS: &[Reading] = &[Reading::Leak];
+
+pub fn create(dev_config: &DeviceConfig, _ctx: &FactoryContext) -> Result<Box<dyn SmartDevice>, err_house::Err> {
+    let model = dev_config.model.unwrap_or(ModelConfig::gauss(AVG_MOISTURE, MOISTURE_SPREAD)).build()?;
+    Ok(Box::new(LeakSensor {
+        name: dev_config.name.to_owned(),
+        is_turn_on: false,
+        is_alarm: false,
+        threshold: dev_config.param("threshold", LEAK_THRESHOLD)?,
+        model,
+    }))
+}
+
+/// Water leak sensor. Alarm is raised when moisture exceeds threshold
+/// and stays raised until it's reset explicitly.
+pub struct LeakSensor {
+    name: String,
+    is_turn_on: bool,
+    is_alarm: bool,
+    threshold: f64,
+    model: Box<dyn Model>,
+}
+
+#[derive(Clone, Copy)]
+struct LeakSnapshot {
+    is_turn_on: bool,
+    is_alarm: bool,
+}
+
+impl SmartDevice for LeakSensor {
+    fn type_dev(&self) -> &'static str {
+        TYPE_NAME
+    }
+
+    fn commands(&self) -> &'static [Cmd] {
+        COMMANDS
+    }
+
+    fn readings(&self) -> &'static [Reading] {
+        READINGS
+    }
+
+    fn turn_on(&mut self) {
+        info!("Leak sensor {} is turned on", self.name);
+        self.is_turn_on = true;
+        self.model.reset(Local::now());
+    }
+
+    fn turn_off(&mut self) {
+        info!("Leak sensor {} is turned off", self.name);
+        self.is_turn_on = false;
+    }
+
+    fn is_on(&self) -> bool {
+        self.is_turn_on
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Box::new(LeakSnapshot {
+            is_turn_on: self.is_turn_on,
+            is_alarm: self.is_alarm,
+        })
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) {
+        let Ok(snapshot) = snapshot.downcast::<LeakSnapshot>() else {
+            error!("Invalid snapshot of leak sensor {}", self.name);
+            return;
+        };
+        switch(self, snapshot.is_turn_on);
+        self.is_alarm = snapshot.is_alarm;
+    }
+
+    fn read(&mut self, reading: Reading) -> Result<Sample, Error> {
+        if reading != Reading::Leak {
+            return Err(ErrorKind::WrongCmd.into());
+        }
+        // Sensor which is off doesn't detect leaks, so it mustn't report dry floor
+        if !self.is_turn_on {
+            return Err(ErrorKind::DevOff.into());
+        }
+        if !self.is_alarm && self.model.sample(Local::now()) > self.threshold {
+            warn!("Leak sensor {} detected leak", self.name);
+            self.is_alarm = true;
+        }
+        Ok(Sample::fresh(if self.is_alarm { 1.0 } else { 0.0 }))
+    }
+
+    fn execute(&mut self, cmd: &Cmd) -> Result<SuccessKind, Error> {
+        match cmd {
+            Cmd::ResetAlarm => {
+                info!("Leak sensor {} alarm is reset", self.name);
+                self.is_alarm = false;
+                Ok(SuccessKind::Ack)
+            }
+            _ => execute_default(self, cmd),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_alarm_is_latched() {
+        let mut sensor = LeakSensor {
+            name: "leak".to_owned(),
+            is_turn_on: true,
+            is_alarm: false,
+            threshold: LEAK_THRESHOLD,
+            model: ModelConfig::gauss(50.0, 0.0).build().unwrap(),
+        };
+        assert!(matches!(sensor.execute(&Cmd::Leak), Ok(SuccessKind::Leak(true))));
+        // Moisture drops but alarm stays raised until reset
+        sensor.model = ModelConfig::gauss(0.0, 0.0).build().unwrap();
+        assert!(matches!(sensor.execute(&Cmd::Leak), Ok(SuccessKind::Leak(true))));
+        assert!(matches!(sensor.execute(&Cmd::ResetAlarm), Ok(SuccessKind::Ack)));
+        assert!(matches!(sensor.execute(&Cmd::Leak), Ok(SuccessKind::Leak(false))));
+    }
+}
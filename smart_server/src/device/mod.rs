@@ -1,48 +1,627 @@
-mod smart_socket;
-mod smart_therm;
+mod simulation;
+mod registry;
+mod alert;
 
-use smart_socket::SmartSocket;
-use smart_therm::SmartTherm;
+use std::any::Any;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Duration, Local};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use alert::{Alert, AlertConfig};
+use simulation::ModelConfig;
 use super::err_house;
-use super::protocol::TypeDev;
+use super::request_handler;
+use super::protocol::{self, AlertState, Capabilities, Cmd, DeviceState, EnergyReport, Error, ErrorKind, Measurement, Quality, SuccessKind, Unit};
 use log::*;
 
-const SOCKET_TYPE: &str = "socket";
-const THERM_TYPE: &str = "therm";
+pub use registry::{DeviceRegistry, FactoryContext};
+
+/// Declares modules of device types and registers them in `DeviceRegistry`.
+/// Module of device type provides `TYPE_NAME` and `create`.
+macro_rules! device_types {
+    ($($module:ident),*) => {
+        $(mod $module;)*
 
-pub enum Device {
-    Socket(SmartSocket),
-    Therm(SmartTherm),
+        fn register_types(registry: &mut DeviceRegistry) {
+            $(registry.register($module::TYPE_NAME, $module::create);)*
+        }
+    };
 }
 
-impl Device {
-    pub fn get_type_device(&self) -> TypeDev {
-        match self {
-            Device::Socket(_) => TypeDev::SmartSocket,
-            Device::Therm(_) => TypeDev::SmartTherm,
+device_types!(smart_socket, smart_therm, lamp, humidity_sensor, door_lock, leak_sensor);
+
+pub use smart_socket::TYPE_NAME as SOCKET_TYPE;
+pub use smart_therm::TYPE_NAME as THERM_TYPE;
+pub use lamp::TYPE_NAME as LAMP_TYPE;
+
+/// Saved state of device, used to roll back all-or-nothing batch
+pub type Snapshot = Box<dyn Any + Send>;
+
+/// Snapshot of device with status tracked by `Device`
+pub struct DeviceSnapshot {
+    dev: Snapshot,
+    is_shed: bool,
+    on_since: Option<DateTime<Local>>,
+}
+
+/// Limit of `Cmd::TurnOnFor`, one day
+const MAX_ON_MINUTES: u32 = 24 * 60;
+
+/// Last reading of device state older than this is reported as stale
+const STALE_SECS: i64 = 60;
+
+/// Period of sampling readings checked by alerts
+const ALERT_CHECK_SECS: i64 = 1;
+
+/// Devices of server by name
+pub type Devices = HashMap<String, Device>;
+
+/// Devices of server shared with services automating them
+pub type SharedDevices = Arc<Mutex<Devices>>;
+
+#[derive(Deserialize)]
+pub struct DeviceConfig {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub dev_type: String,
+    pub model: Option<ModelConfig>,
+    /// Labels to find device in listing, e.g. room name
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Device only reports its state, commands changing it are denied
+    #[serde(default)]
+    pub read_only: bool,
+    /// Rules raising alerts on readings of device
+    #[serde(default)]
+    pub alerts: Vec<AlertConfig>,
+    /// Device specific parameters
+    #[serde(flatten)]
+    pub params: Map<String, Value>,
+}
+
+impl DeviceConfig {
+    /// Returns device specific parameter or default if it's absent in config
+    pub fn param<T: DeserializeOwned>(&self, name: &str, default: T) -> Result<T, err_house::Err> {
+        match self.params.get(name) {
+            Some(value) => serde_json::from_value(value.clone()).map_err(|e| {
+                error!("Invalid parameter {name} of device {}: {:?}", self.name, e);
+                err_house::Err::from(e)
+            }),
+            None => Ok(default),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Reading {
+    Power,
+    Temperature,
+    Brightness,
+    Humidity,
+    LockState,
+    Leak,
+    Target,
+}
+
+impl Reading {
+    pub fn from_cmd(cmd: &Cmd) -> Option<Self> {
+        match cmd {
+            Cmd::Power => Some(Reading::Power),
+            Cmd::Temperature => Some(Reading::Temperature),
+            Cmd::Brightness => Some(Reading::Brightness),
+            Cmd::Humidity => Some(Reading::Humidity),
+            Cmd::LockState => Some(Reading::LockState),
+            Cmd::Leak => Some(Reading::Leak),
+            Cmd::Target => Some(Reading::Target),
+            _ => None,
         }
     }
-    pub fn turn_on(&mut self) {
+
+    pub fn cmd(&self) -> Cmd {
         match self {
-            Device::Socket(sock) => sock.turn_on(),
-            Device::Therm(therm) => therm.turn_on(),
+            Reading::Power => Cmd::Power,
+            Reading::Temperature => Cmd::Temperature,
+            Reading::Brightness => Cmd::Brightness,
+            Reading::Humidity => Cmd::Humidity,
+            Reading::LockState => Cmd::LockState,
+            Reading::Leak => Cmd::Leak,
+            Reading::Target => Cmd::Target,
         }
     }
-    pub fn turn_off(&mut self) {
+
+    fn into_success(self, sample: Sample) -> SuccessKind {
+        let value = sample.value;
         match self {
-            Device::Socket(sock) => sock.turn_off(),
-            Device::Therm(therm) => therm.turn_off(),
+            Reading::Power => SuccessKind::Power(sample.measurement(Unit::Watt)),
+            Reading::Temperature => SuccessKind::Temp(sample.measurement(Unit::Celsius)),
+            Reading::Brightness => SuccessKind::Brightness(value.round() as u8),
+            Reading::Humidity => SuccessKind::Humidity(sample.measurement(Unit::Percent)),
+            Reading::LockState => SuccessKind::Lock(door_lock::lock_state(value)),
+            Reading::Leak => SuccessKind::Leak(value != 0.0),
+            Reading::Target => SuccessKind::Temp(sample.measurement(Unit::Celsius)),
+        }
+    }
+}
+
+/// Value read from device in units of server: W, C and %
+#[derive(Clone, Copy, Debug)]
+pub struct Sample {
+    pub value: f64,
+    pub quality: Quality,
+    pub time: DateTime<Local>,
+}
+
+impl Sample {
+    pub fn fresh(value: f64) -> Self {
+        Self {
+            value,
+            quality: Quality::Fresh,
+            time: Local::now(),
+        }
+    }
+
+    pub fn simulated(value: f64, time: DateTime<Local>) -> Self {
+        Self {
+            value,
+            quality: Quality::Simulated,
+            time,
+        }
+    }
+
+    /// Last sample of sensor which is off now,
+    /// zero if sensor has never been sampled
+    pub fn stale(last: Option<Sample>) -> Self {
+        let last = last.unwrap_or(Sample::fresh(0.0));
+        Self {
+            quality: Quality::Stale,
+            ..last
+        }
+    }
+
+    fn measurement(&self, unit: Unit) -> Measurement {
+        Measurement {
+            value: self.value,
+            unit,
+            quality: self.quality,
+            timestamp_ms: self.time.timestamp_millis(),
+        }
+    }
+}
+
+/// Emulated device. New device type implements this trait in its own module
+/// and adds the module to `device_types!`. Clients get type name as string,
+/// so the type needs no changes of protocol unless it brings a new kind
+/// of command or reading: those are variants of `protocol::Cmd` and
+/// `SuccessKind`, encoded by position and mirrored by client.
+pub trait SmartDevice: Send {
+    /// Type name registered in `DeviceRegistry`, e.g. "socket"
+    fn type_dev(&self) -> &'static str;
+
+    /// Commands device is able to execute, arguments of commands are ignored
+    fn commands(&self) -> &'static [Cmd];
+
+    /// Values device is able to measure
+    fn readings(&self) -> &'static [Reading];
+
+    fn turn_on(&mut self);
+
+    fn turn_off(&mut self);
+
+    fn is_on(&self) -> bool;
+
+    /// Device is unable to work properly, e.g. lock is jammed
+    fn is_fault(&self) -> bool {
+        false
+    }
+
+    fn read(&mut self, reading: Reading) -> Result<Sample, Error>;
+
+    fn energy(&mut self) -> Option<EnergyReport> {
+        None
+    }
+
+    /// Dim level or power limit, device validates range of level
+    fn set_level(&mut self, _level: f64) -> Result<(), Error> {
+        Err(ErrorKind::WrongCmd.into())
+    }
+
+    fn set_target(&mut self, _target: f64) -> Result<(), Error> {
+        Err(ErrorKind::WrongCmd.into())
+    }
+
+    fn turn_on_for(&mut self, _duration: Duration) -> Result<(), Error> {
+        Err(ErrorKind::WrongCmd.into())
+    }
+
+    /// Called by server periodically and before handling requests
+    fn update(&mut self, _now: DateTime<Local>) {}
+
+    /// Time left until device turns off by itself, e.g. by timer
+    fn off_in(&self, _now: DateTime<Local>) -> Option<Duration> {
+        None
+    }
+
+    /// Called before server stops
+    fn store_state(&mut self) {}
+
+    /// State changed by commands
+    fn snapshot(&self) -> Snapshot;
+
+    /// Restores snapshot made by the same device
+    fn restore(&mut self, snapshot: Snapshot);
+
+    fn execute(&mut self, cmd: &Cmd) -> Result<SuccessKind, Error> {
+        execute_default(self, cmd)
+    }
+}
+
+/// Device of server with status tracked independently of device type
+pub struct Device {
+    dev: Box<dyn SmartDevice>,
+    name: String,
+    tags: Vec<String>,
+    read_only: bool,
+    /// Turned off by power budget, automation is unable to turn it on
+    is_shed: bool,
+    on_since: Option<DateTime<Local>>,
+    last_reading: Option<SuccessKind>,
+    alerts: Vec<Alert>,
+    next_alert_check: DateTime<Local>,
+}
+
+impl Device {
+    pub fn new(dev: Box<dyn SmartDevice>, dev_config: &DeviceConfig) -> Self {
+        let alerts = dev_config.alerts.iter()
+            .filter(|alert| {
+                let is_valid = dev.readings().contains(&alert.reading);
+                if !is_valid {
+                    error!("Device {} has no reading {:?} for alert", dev_config.name, alert.reading);
+                }
+                is_valid
+            })
+            .map(|alert| Alert::new(*alert))
+            .collect();
+        let mut dev = Self {
+            dev,
+            name: dev_config.name.clone(),
+            tags: dev_config.tags.clone(),
+            read_only: dev_config.read_only,
+            is_shed: false,
+            on_since: None,
+            last_reading: None,
+            alerts,
+            next_alert_check: Local::now(),
+        };
+        dev.sync_state(Local::now());
+        dev
+    }
+
+    pub fn type_dev(&self) -> &'static str {
+        self.dev.type_dev()
+    }
+
+    pub fn readings(&self) -> &'static [Reading] {
+        self.dev.readings()
+    }
+
+    pub fn tags(&self) -> &[String] {
+        &self.tags
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.dev.is_on()
+    }
+
+    pub fn is_fault(&self) -> bool {
+        self.dev.is_fault()
+    }
+
+    pub fn is_shed(&self) -> bool {
+        self.is_shed
+    }
+
+    /// Turns device off by power budget
+    pub fn shed(&mut self) -> Result<(), Error> {
+        self.execute(&Cmd::TurnOff)?;
+        self.is_shed = true;
+        Ok(())
+    }
+
+    /// Turns device shed by power budget back on
+    pub fn unshed(&mut self) -> Result<(), Error> {
+        self.execute(&Cmd::TurnOn).map(|_| ())
+    }
+
+    pub fn energy(&mut self) -> Option<EnergyReport> {
+        self.dev.energy()
+    }
+
+    pub fn store_state(&mut self) {
+        self.dev.store_state();
+    }
+
+    pub fn snapshot(&self) -> DeviceSnapshot {
+        DeviceSnapshot {
+            dev: self.dev.snapshot(),
+            is_shed: self.is_shed,
+            on_since: self.on_since,
+        }
+    }
+
+    pub fn restore(&mut self, snapshot: DeviceSnapshot) {
+        self.dev.restore(snapshot.dev);
+        self.is_shed = snapshot.is_shed;
+        self.on_since = snapshot.on_since;
+        self.sync_state(Local::now());
+    }
+
+    pub fn update(&mut self, now: DateTime<Local>) {
+        self.dev.update(now);
+        if !self.alerts.is_empty() && now >= self.next_alert_check {
+            self.next_alert_check = now + Duration::seconds(ALERT_CHECK_SECS);
+            self.check_alerts(now);
+        }
+        self.sync_state(now);
+    }
+
+    /// Reads value of device bypassing protocol, e.g. for automation
+    pub fn sample(&mut self, reading: Reading) -> Result<Sample, Error> {
+        if !self.dev.readings().contains(&reading) {
+            return Err(ErrorKind::WrongCmd.into());
+        }
+        let res = self.dev.read(reading);
+        self.sync_state(Local::now());
+        res
+    }
+
+    pub fn alerts(&self) -> Vec<AlertState> {
+        self.alerts.iter().map(|alert| alert.state(&self.name)).collect()
+    }
+
+    /// Samples readings of alerts. Stale values and values of devices
+    /// which are off keep alerts in their states.
+    fn check_alerts(&mut self, now: DateTime<Local>) {
+        let mut samples: Vec<(Reading, Sample)> = Vec::new();
+        for alert in self.alerts.iter_mut() {
+            let reading = alert.reading();
+            let sample = match samples.iter().find(|(sampled, _)| *sampled == reading) {
+                Some((_, sample)) => *sample,
+                None => match self.dev.read(reading) {
+                    Ok(sample) => {
+                        samples.push((reading, sample));
+                        sample
+                    }
+                    Err(_) => continue,
+                },
+            };
+            if sample.quality == Quality::Stale {
+                continue;
+            }
+            if let Some(event) = alert.check(sample.value, now) {
+                alert::report(&self.name, alert, event);
+            }
+        }
+    }
+
+    pub fn execute(&mut self, cmd: &Cmd) -> Result<SuccessKind, Error> {
+        let now = Local::now();
+        let res = match cmd {
+            Cmd::GetState => Ok(SuccessKind::State(self.state(now))),
+            Cmd::GetCapabilities => Ok(SuccessKind::Capabilities(self.capabilities())),
+            Cmd::GetAlerts => Ok(SuccessKind::Alerts(self.alerts())),
+            Cmd::Energy => self.dev.execute(cmd),
+            _ if self.read_only && Reading::from_cmd(cmd).is_none() => {
+                Err(Error::new(ErrorKind::PermissionDenied).with_details(format!("{:?} changes device", cmd)))
+            }
+            _ => self.dev.execute(cmd),
+        };
+        if let (Some(_), Ok(success)) = (Reading::from_cmd(cmd), &res) {
+            self.last_reading = Some(success.clone());
+        }
+        if turns_on(cmd) && res.is_ok() {
+            self.is_shed = false;
+        }
+        self.sync_state(now);
+        res
+    }
+
+    /// Executes command of automation, e.g. schedule or thermostat. Unlike
+    /// client, automation doesn't turn on device shed by power budget.
+    pub fn execute_automated(&mut self, cmd: &Cmd) -> Result<SuccessKind, Error> {
+        if self.is_shed && turns_on(cmd) {
+            return Err(Error::new(ErrorKind::Shed).with_details(format!("{} is shed", self.name)));
+        }
+        self.execute(cmd)
+    }
+
+    pub fn state(&self, now: DateTime<Local>) -> DeviceState {
+        DeviceState {
+            is_on: self.dev.is_on(),
+            is_fault: self.dev.is_fault(),
+            last_reading: self.last_reading.clone().map(|mut reading| {
+                if let Some(measurement) = reading.measurement_mut() {
+                    if now.timestamp_millis() - measurement.timestamp_ms > STALE_SECS * 1000 {
+                        measurement.quality = Quality::Stale;
+                    }
+                }
+                Box::new(reading)
+            }),
+            uptime_secs: self.on_since.map_or(0, |on_since| (now - on_since).num_seconds().max(0) as u64),
+            off_in_secs: self.dev.off_in(now).map(|off_in| off_in.num_seconds().max(0) as u64),
+        }
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            commands: self.dev.commands().to_vec(),
+            readings: self.dev.readings().iter().map(|reading| reading.cmd()).collect(),
+        }
+    }
+
+    /// Device may turn on or off by itself, e.g. by timer
+    fn sync_state(&mut self, now: DateTime<Local>) {
+        match (self.dev.is_on(), self.on_since) {
+            (true, None) => self.on_since = Some(now),
+            (false, Some(_)) => self.on_since = None,
+            _ => {}
         }
     }
 }
 
-pub fn generate_device_emulator(dev_type: &str) -> Result<Device, err_house::Err>{
-    match dev_type {
-        SOCKET_TYPE => Ok(Device::Socket(SmartSocket::default())),
-        THERM_TYPE => Ok(Device::Therm(SmartTherm::default())),
+fn turns_on(cmd: &Cmd) -> bool {
+    matches!(cmd, Cmd::TurnOn | Cmd::TurnOnFor(_))
+}
+
+/// Applies function to device found in any of servers. Servers are locked
+/// one by one, so caller never holds two locks at once.
+pub fn with_device<T>(servers: &[SharedDevices], name: &str, f: impl FnOnce(&mut Device) -> T) -> Option<T> {
+    for devices in servers {
+        let mut devices = devices.lock().unwrap();
+        if let Some(dev) = devices.get_mut(name) {
+            return Some(f(dev));
+        }
+    }
+    None
+}
+
+/// Checks that device names are unique across servers, otherwise
+/// requests routed by name reach only the first server
+pub fn check_unique_names(servers: &[(&'static str, SharedDevices)]) -> Result<(), String> {
+    let mut owners: HashMap<String, &'static str> = HashMap::new();
+    for (transport, devices) in servers {
+        for name in devices.lock().unwrap().keys() {
+            if let Some(owner) = owners.insert(name.clone(), transport) {
+                return Err(format!("device {name} is both on {owner} and {transport} servers"));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Handles request on server owning device of request, servers are locked
+/// one by one like in `with_device`. Servers are given with their transports.
+pub fn handle_on_owner(servers: &[(&'static str, SharedDevices)], req: protocol::Request) -> protocol::Response {
+    for (_, devices) in servers {
+        let mut devices = devices.lock().unwrap();
+        if devices.contains_key(&req.dev_name) {
+            return request_handler::handle_request(&mut devices, req);
+        }
+    }
+    let e = Error::new(ErrorKind::DevNotFound).with_details(req.dev_name.clone());
+    protocol::Response::new_err_response(req, e)
+}
+
+/// Handles commands common for all devices. Devices overriding
+/// `SmartDevice::execute` fall back to it for commands they don't handle.
+pub fn execute_default<D: SmartDevice + ?Sized>(dev: &mut D, cmd: &Cmd) -> Result<SuccessKind, Error> {
+    if !dev.commands().iter().any(|dev_cmd| dev_cmd.same_kind(cmd)) {
+        return Err(Error::new(ErrorKind::WrongCmd).with_details(format!("{:?} isn't supported by {}", cmd, dev.type_dev())));
+    }
+    match cmd {
+        Cmd::TurnOn => {
+            dev.turn_on();
+            Ok(SuccessKind::Ack)
+        }
+        Cmd::TurnOff => {
+            dev.turn_off();
+            Ok(SuccessKind::Ack)
+        }
+        Cmd::SetLevel(level) => dev.set_level(*level).map(|_| SuccessKind::Ack),
+        Cmd::SetTarget(target) => dev.set_target(*target).map(|_| SuccessKind::Ack),
+        Cmd::TurnOnFor(minutes) => {
+            if !(1..=MAX_ON_MINUTES).contains(minutes) {
+                return Err(Error::new(ErrorKind::OutOfRange).with_details(format!("{minutes} min isn't in 1..={MAX_ON_MINUTES}")));
+            }
+            dev.turn_on_for(Duration::minutes(*minutes as i64)).map(|_| SuccessKind::Ack)
+        }
+        Cmd::Energy => dev.energy().map(|report| SuccessKind::Energy(vec![report])).ok_or(ErrorKind::WrongCmd.into()),
         _ => {
-            error!("Invalid device type for generation");
-            Err(err_house::Err::new(err_house::ErrorKind::WrongDevType))
+            let reading = Reading::from_cmd(cmd).ok_or(ErrorKind::UnknownCmd)?;
+            if !dev.readings().contains(&reading) {
+                return Err(ErrorKind::WrongCmd.into());
+            }
+            dev.read(reading).map(|sample| reading.into_success(sample))
         }
     }
-}
\ No newline at end of file
+}
+
+/// Turns device on or off unless it's already in this state
+pub fn switch<D: SmartDevice + ?Sized>(dev: &mut D, is_on: bool) {
+    match (dev.is_on(), is_on) {
+        (false, true) => dev.turn_on(),
+        (true, false) => dev.turn_off(),
+        _ => {}
+    }
+}
+
+/// Deadline of device turned on by `Cmd::TurnOnFor`
+#[derive(Default, Clone, Copy)]
+pub struct OffTimer {
+    deadline: Option<DateTime<Local>>,
+}
+
+impl OffTimer {
+    pub fn start(&mut self, now: DateTime<Local>, duration: Duration) {
+        self.deadline = Some(now + duration);
+    }
+
+    pub fn cancel(&mut self) {
+        self.deadline = None;
+    }
+
+    pub fn remaining(&self, now: &DateTime<Local>) -> Option<Duration> {
+        self.deadline.map(|deadline| deadline - *now)
+    }
+
+    /// Returns true once, when deadline is passed
+    pub fn is_expired(&mut self, now: &DateTime<Local>) -> bool {
+        match self.deadline {
+            Some(deadline) if deadline <= *now => {
+                self.deadline = None;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Devices created from configs for tests. Energy of sockets is stored in
+/// temp dir of the test, so tests running at once don't share meters.
+#[cfg(test)]
+pub fn test_devices(test: &str, configs: Vec<Value>) -> Devices {
+    use crate::energy::EnergyConfig;
+
+    let storage = std::env::temp_dir().join(format!("smart_server_{test}_{}", std::process::id()));
+    let ctx = FactoryContext {
+        energy_config: Arc::new(EnergyConfig::from_config(&serde_json::json!({"energy": {"storage": storage, "default_price": 0.0}}))),
+        transport: "tcp",
+    };
+    let registry = DeviceRegistry::default();
+    configs.into_iter()
+        .map(|config| {
+            let config: DeviceConfig = serde_json::from_value(config).unwrap();
+            (config.name.clone(), Device::new(registry.create(&config, &ctx).unwrap(), &config))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_unique_names() {
+        let shared = |configs| Arc::new(Mutex::new(test_devices("unique_names", configs)));
+        let tcp = shared(vec![json!({"name": "sock1", "type": "socket"}), json!({"name": "lamp1", "type": "lamp"})]);
+        let udp = shared(vec![json!({"name": "lamp2", "type": "lamp"})]);
+        assert!(check_unique_names(&[("tcp", tcp.clone()), ("udp", udp)]).is_ok());
+
+        let udp = shared(vec![json!({"name": "lamp1", "type": "lamp"})]);
+        assert_eq!(check_unique_names(&[("tcp", tcp), ("udp", udp)]).unwrap_err(), "device lamp1 is both on tcp and udp servers");
+    }
+}
@@ -0,0 +1,50 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use log::*;
+
+use super::{register_types, DeviceConfig, SmartDevice};
+use crate::energy::EnergyConfig;
+use crate::err_house;
+
+/// Shared settings available to device factories
+pub struct FactoryContext {
+    pub energy_config: Arc<EnergyConfig>,
+    /// Server owning created devices, e.g. "tcp"
+    pub transport: &'static str,
+}
+
+pub type Factory = fn(&DeviceConfig, &FactoryContext) -> Result<Box<dyn SmartDevice>, err_house::Err>;
+
+/// Maps device type from config to factory creating device emulator
+pub struct DeviceRegistry {
+    factories: HashMap<&'static str, Factory>,
+}
+
+impl Default for DeviceRegistry {
+    fn default() -> Self {
+        let mut registry = Self {
+            factories: HashMap::new(),
+        };
+        register_types(&mut registry);
+        registry
+    }
+}
+
+impl DeviceRegistry {
+    pub fn register(&mut self, dev_type: &'static str, factory: Factory) {
+        if self.factories.insert(dev_type, factory).is_some() {
+            warn!("Factory for device type {dev_type} replaced");
+        }
+    }
+
+    pub fn create(&self, dev_config: &DeviceConfig, ctx: &FactoryContext) -> Result<Box<dyn SmartDevice>, err_house::Err> {
+        match self.factories.get(dev_config.dev_type.as_str()) {
+            Some(factory) => factory(dev_config, ctx),
+            None => {
+                error!("Invalid device type for generation: {}", dev_config.dev_type);
+                Err(err_house::Err::new(err_house::ErrorKind::WrongDevType))
+            }
+        }
+    }
+}
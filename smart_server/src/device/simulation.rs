@@ -0,0 +1,360 @@
+use std::f64::consts::PI;
+
+use chrono::{DateTime, Local, Timelike};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use rand_distr::{Distribution, Normal};
+use serde::Deserialize;
+use log::*;
+
+use crate::err_house;
+
+const HOURS_IN_DAY: f64 = 24.0;
+
+/// Source of device readings. Models get time explicitly,
+/// so with fixed seed they return the same values for the same times.
+pub trait Model: Send {
+    /// Called when device is turned on.
+    fn reset(&mut self, now: DateTime<Local>);
+    fn sample(&mut self, now: DateTime<Local>) -> f64;
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ModelKind {
+    /// Normally distributed value around mean
+    Gauss { mean: f64, spread: f64 },
+    /// Temperature following daily sinusoidal cycle with thermal inertia
+    Thermal {
+        base: f64,
+        amplitude: f64,
+        peak_hour: f64,
+        inertia_secs: f64,
+        noise: f64,
+    },
+    /// Load switching on and off periodically, e.g. fridge compressor
+    DutyCycle {
+        on_power: f64,
+        idle_power: f64,
+        period_secs: f64,
+        duty: f64,
+        noise: f64,
+    },
+    /// Load drawing high power for short time after turning on, e.g. kettle
+    Burst {
+        power: f64,
+        standby_power: f64,
+        burst_secs: f64,
+        noise: f64,
+    },
+}
+
+impl ModelKind {
+    fn validate(&self) -> Result<(), err_house::Err> {
+        match *self {
+            ModelKind::Gauss { spread, .. } => check(spread >= 0.0, "spread is negative"),
+            ModelKind::Thermal { inertia_secs, noise, .. } => {
+                check(inertia_secs >= 0.0, "inertia_secs is negative")?;
+                check(noise >= 0.0, "noise is negative")
+            }
+            ModelKind::DutyCycle { period_secs, duty, noise, .. } => {
+                check(period_secs > 0.0, "period_secs isn't positive")?;
+                check((0.0..=1.0).contains(&duty), "duty is out of 0..=1")?;
+                check(noise >= 0.0, "noise is negative")
+            }
+            ModelKind::Burst { burst_secs, noise, .. } => {
+                check(burst_secs >= 0.0, "burst_secs is negative")?;
+                check(noise >= 0.0, "noise is negative")
+            }
+        }
+    }
+}
+
+fn check(is_valid: bool, reason: &str) -> Result<(), err_house::Err> {
+    if is_valid {
+        Ok(())
+    }else{
+        error!("Invalid simulation model: {reason}");
+        Err(err_house::Err::new(err_house::ErrorKind::ParsingError))
+    }
+}
+
+#[derive(Deserialize, Clone, Copy)]
+pub struct ModelConfig {
+    #[serde(flatten)]
+    pub kind: ModelKind,
+    pub seed: Option<u64>,
+}
+
+impl ModelConfig {
+    pub fn gauss(mean: f64, spread: f64) -> Self {
+        Self {
+            kind: ModelKind::Gauss { mean, spread },
+            seed: None,
+        }
+    }
+
+    pub fn build(&self) -> Result<Box<dyn Model>, err_house::Err> {
+        self.kind.validate()?;
+        let rng = match self.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        let model: Box<dyn Model> =
+        match self.kind {
+            ModelKind::Gauss { mean, spread } => Box::new(Gauss {
+                noise: Noise::new(rng, spread),
+                mean,
+            }),
+            ModelKind::Thermal { base, amplitude, peak_hour, inertia_secs, noise } => Box::new(Thermal {
+                noise: Noise::new(rng, noise),
+                base,
+                amplitude,
+                peak_hour,
+                inertia_secs,
+                state: None,
+            }),
+            ModelKind::DutyCycle { on_power, idle_power, period_secs, duty, noise } => Box::new(DutyCycle {
+                noise: Noise::new(rng, noise),
+                on_power,
+                idle_power,
+                period_secs,
+                duty,
+                started: None,
+            }),
+            ModelKind::Burst { power, standby_power, burst_secs, noise } => Box::new(Burst {
+                noise: Noise::new(rng, noise),
+                power,
+                standby_power,
+                burst_secs,
+                started: None,
+            }),
+        };
+        Ok(model)
+    }
+}
+
+struct Noise {
+    rng: StdRng,
+    dist: Option<Normal<f64>>,
+}
+
+impl Noise {
+    fn new(rng: StdRng, spread: f64) -> Self {
+        let dist = match Normal::new(0.0, spread) {
+            Ok(dist) => Some(dist),
+            Err(e) => {
+                warn!("Invalid noise spread {spread}: {:?}, noise is disabled", e);
+                None
+            }
+        };
+        Self { rng, dist }
+    }
+
+    fn sample(&mut self) -> f64 {
+        match self.dist {
+            Some(dist) => dist.sample(&mut self.rng),
+            None => 0.0,
+        }
+    }
+}
+
+fn secs_between(from: DateTime<Local>, to: DateTime<Local>) -> f64 {
+    (to - from).num_milliseconds() as f64 / 1000.0
+}
+
+struct Gauss {
+    noise: Noise,
+    mean: f64,
+}
+
+impl Model for Gauss {
+    fn reset(&mut self, _now: DateTime<Local>) {}
+
+    fn sample(&mut self, _now: DateTime<Local>) -> f64 {
+        self.mean + self.noise.sample()
+    }
+}
+
+struct Thermal {
+    noise: Noise,
+    base: f64,
+    amplitude: f64,
+    peak_hour: f64,
+    inertia_secs: f64,
+    state: Option<(DateTime<Local>, f64)>,
+}
+
+impl Thermal {
+    fn ambient(&self, now: &DateTime<Local>) -> f64 {
+        let hour = now.hour() as f64 + now.minute() as f64 / 60.0 + now.second() as f64 / 3600.0;
+        let phase = 2.0 * PI * (hour - self.peak_hour) / HOURS_IN_DAY;
+        self.base + self.amplitude * phase.cos()
+    }
+}
+
+impl Model for Thermal {
+    fn reset(&mut self, _now: DateTime<Local>) {}
+
+    fn sample(&mut self, now: DateTime<Local>) -> f64 {
+        let ambient = self.ambient(&now);
+        let temp = match self.state {
+            Some((last, temp)) if self.inertia_secs > 0.0 => {
+                let dt = secs_between(last, now).max(0.0);
+                temp + (ambient - temp) * (1.0 - (-dt / self.inertia_secs).exp())
+            }
+            _ => ambient,
+        };
+        self.state = Some((now, temp));
+        temp + self.noise.sample()
+    }
+}
+
+struct DutyCycle {
+    noise: Noise,
+    on_power: f64,
+    idle_power: f64,
+    period_secs: f64,
+    duty: f64,
+    started: Option<DateTime<Local>>,
+}
+
+impl Model for DutyCycle {
+    fn reset(&mut self, now: DateTime<Local>) {
+        self.started = Some(now);
+    }
+
+    fn sample(&mut self, now: DateTime<Local>) -> f64 {
+        let started = *self.started.get_or_insert(now);
+        let phase = secs_between(started, now).rem_euclid(self.period_secs);
+        let power = if phase < self.duty * self.period_secs {
+            self.on_power
+        } else {
+            self.idle_power
+        };
+        (power + self.noise.sample()).max(0.0)
+    }
+}
+
+struct Burst {
+    noise: Noise,
+    power: f64,
+    standby_power: f64,
+    burst_secs: f64,
+    started: Option<DateTime<Local>>,
+}
+
+impl Model for Burst {
+    fn reset(&mut self, now: DateTime<Local>) {
+        self.started = Some(now);
+    }
+
+    fn sample(&mut self, now: DateTime<Local>) -> f64 {
+        let started = *self.started.get_or_insert(now);
+        let power = if secs_between(started, now) < self.burst_secs {
+            self.power
+        } else {
+            self.standby_power
+        };
+        (power + self.noise.sample()).max(0.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, TimeZone};
+
+    fn start() -> DateTime<Local> {
+        Local.with_ymd_and_hms(2024, 6, 1, 4, 0, 0).unwrap()
+    }
+
+    fn parse(json: &str) -> ModelConfig {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn test_seeded_model_is_reproducible() {
+        let config = parse(r#"{"kind": "gauss", "mean": 25.0, "spread": 5.0, "seed": 7}"#);
+        let mut first = config.build().unwrap();
+        let mut second = config.build().unwrap();
+        for i in 0..10 {
+            let now = start() + Duration::seconds(i);
+            assert_eq!(first.sample(now), second.sample(now));
+        }
+    }
+
+    #[test]
+    fn test_gauss_is_unbiased() {
+        let mut model = parse(r#"{"kind": "gauss", "mean": 4000.0, "spread": 100.0, "seed": 1}"#).build().unwrap();
+        let cnt = 10_000;
+        let avg = (0..cnt).map(|_| model.sample(start())).sum::<f64>() / cnt as f64;
+        assert!((avg - 4000.0).abs() < 5.0);
+    }
+
+    #[test]
+    fn test_thermal_inertia() {
+        let mut model = parse(
+            r#"{"kind": "thermal", "base": 20.0, "amplitude": 5.0, "peak_hour": 16.0,
+                "inertia_secs": 3600.0, "noise": 0.0}"#,
+        )
+        .build()
+        .unwrap();
+        let first = model.sample(start());
+        // Ambient temperature is low at night and rises by the afternoon,
+        // inertia keeps reading behind ambient
+        let afternoon = start() + Duration::hours(12);
+        let second = model.sample(afternoon);
+        assert!(second > first);
+        assert!(second < 25.0);
+        // Small step doesn't change temperature much
+        let third = model.sample(afternoon + Duration::seconds(1));
+        assert!((third - second).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_duty_cycle_and_burst() {
+        let mut fridge = parse(
+            r#"{"kind": "duty_cycle", "on_power": 150.0, "idle_power": 5.0,
+                "period_secs": 600.0, "duty": 0.3, "noise": 0.0}"#,
+        )
+        .build()
+        .unwrap();
+        fridge.reset(start());
+        assert_eq!(fridge.sample(start() + Duration::seconds(60)), 150.0);
+        assert_eq!(fridge.sample(start() + Duration::seconds(300)), 5.0);
+        assert_eq!(fridge.sample(start() + Duration::seconds(660)), 150.0);
+
+        let mut kettle = parse(
+            r#"{"kind": "burst", "power": 2000.0, "standby_power": 1.0,
+                "burst_secs": 180.0, "noise": 0.0}"#,
+        )
+        .build()
+        .unwrap();
+        kettle.reset(start());
+        assert_eq!(kettle.sample(start() + Duration::seconds(10)), 2000.0);
+        assert_eq!(kettle.sample(start() + Duration::seconds(200)), 1.0);
+    }
+
+    #[test]
+    fn test_invalid_params() {
+        let invalid = [
+            r#"{"kind": "gauss", "mean": 25.0, "spread": -1.0}"#,
+            r#"{"kind": "thermal", "base": 20.0, "amplitude": 5.0, "peak_hour": 16.0, "inertia_secs": -1.0, "noise": 0.0}"#,
+            r#"{"kind": "thermal", "base": 20.0, "amplitude": 5.0, "peak_hour": 16.0, "inertia_secs": 0.0, "noise": -0.5}"#,
+            r#"{"kind": "duty_cycle", "on_power": 150.0, "idle_power": 5.0, "period_secs": 0.0, "duty": 0.3, "noise": 0.0}"#,
+            r#"{"kind": "duty_cycle", "on_power": 150.0, "idle_power": 5.0, "period_secs": -600.0, "duty": 0.3, "noise": 0.0}"#,
+            r#"{"kind": "duty_cycle", "on_power": 150.0, "idle_power": 5.0, "period_secs": 600.0, "duty": 1.5, "noise": 0.0}"#,
+            r#"{"kind": "duty_cycle", "on_power": 150.0, "idle_power": 5.0, "period_secs": 600.0, "duty": -0.1, "noise": 0.0}"#,
+            r#"{"kind": "duty_cycle", "on_power": 150.0, "idle_power": 5.0, "period_secs": 600.0, "duty": 0.3, "noise": -1.0}"#,
+            r#"{"kind": "burst", "power": 2000.0, "standby_power": 1.0, "burst_secs": -1.0, "noise": 0.0}"#,
+            r#"{"kind": "burst", "power": 2000.0, "standby_power": 1.0, "burst_secs": 180.0, "noise": -1.0}"#,
+        ];
+        for json in invalid {
+            assert!(parse(json).build().is_err(), "{json}");
+        }
+        // Bounds are allowed
+        assert!(parse(r#"{"kind": "duty_cycle", "on_power": 150.0, "idle_power": 5.0, "period_secs": 600.0, "duty": 1.0, "noise": 0.0}"#).build().is_ok());
+        assert!(parse(r#"{"kind": "gauss", "mean": 25.0, "spread": 0.0}"#).build().is_ok());
+    }
+}
@@ -1,34 +1,423 @@
-use rand::prelude::{thread_rng, Rng};
-use rand_distr::StandardNormal;
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use chrono::{DateTime, Duration, Local};
 use log::*;
+use serde::Deserialize;
+
+use super::simulation::{Model, ModelConfig};
+use super::{switch, DeviceConfig, FactoryContext, OffTimer, Reading, Sample, SmartDevice, Snapshot};
+use crate::console_server::push_event;
+use crate::energy::{EnergyConfig, EnergyMeter};
+use crate::err_house;
+use crate::protocol::{Cmd, EnergyReport, Error, ErrorKind};
+
+pub const TYPE_NAME: &str = "socket";
 
 const AVG_POWER: f64 = 4_000.0; // 4 kW
 const POWER_SPREAD: f64 = 100.0; // 100W
+const MAX_POWER_LIMIT: f64 = 10_000.0; // 10 kW
+const POWER_CHECK_PERIOD_SECS: i64 = 1;
+
+const COMMANDS: &[Cmd] = &[
+    Cmd::TurnOn,
+    Cmd::TurnOff,
+    Cmd::Power,
+    Cmd::Energy,
+    Cmd::SetLevel(0.0),
+    Cmd::TurnOnFor(0),
+];
+const READINGS: &[Reading] = &[Reading::Power];
+
+pub fn create(dev_config: &DeviceConfig, ctx: &FactoryContext) -> Result<Box<dyn SmartDevice>, err_house::Err> {
+    let model = dev_config.model.unwrap_or(ModelConfig::gauss(AVG_POWER, POWER_SPREAD)).build()?;
+    let mut socket = SmartSocket::new(&dev_config.name, ctx.transport, model, ctx.energy_config.clone());
+    if let Some(limit) = dev_config.param("power_limit", None)? {
+        if socket.set_level(limit).is_err() {
+            error!("Power limit of socket {} is out of range: {limit}", dev_config.name);
+            return Err(err_house::Err::new(err_house::ErrorKind::ParsingError));
+        }
+    }
+    if let Some(minutes) = dev_config.param::<Option<f64>>("max_on_minutes", None)? {
+        if !(minutes > 0.0 && minutes.is_finite()) {
+            error!("Max on-time of socket {} isn't positive: {minutes}", dev_config.name);
+            return Err(err_house::Err::new(err_house::ErrorKind::ParsingError));
+        }
+        socket.max_on = Some(minutes_to_duration(minutes));
+    }
+    socket.idle_off = dev_config.param("idle_off", None)?;
+    if let Some(idle_off) = socket.idle_off {
+        if !(idle_off.below_watts > 0.0 && idle_off.for_minutes > 0.0) {
+            error!("Idle auto-off of socket {} isn't positive: {:?}", dev_config.name, idle_off);
+            return Err(err_house::Err::new(err_house::ErrorKind::ParsingError));
+        }
+    }
+    Ok(Box::new(socket))
+}
+
+fn minutes_to_duration(minutes: f64) -> Duration {
+    Duration::milliseconds((minutes * 60_000.0) as i64)
+}
+
+/// Socket turns off when its power stays below `below_watts` for `for_minutes`,
+/// e.g. `{"below_watts": 5.0, "for_minutes": 10.0}`
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+pub struct IdleOff {
+    pub below_watts: f64,
+    pub for_minutes: f64,
+}
+
+/// Reason of turning socket off by server
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum AutoOff {
+    MaxOnTime(Duration),
+    Idle(IdleOff),
+    Overload { power: f64, limit: f64 },
+}
+
+impl Display for AutoOff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AutoOff::MaxOnTime(max_on) => write!(f, "on longer than {} min", max_on.num_seconds() as f64 / 60.0),
+            AutoOff::Idle(idle_off) => write!(f, "power below {} W for {} min", idle_off.below_watts, idle_off.for_minutes),
+            AutoOff::Overload { power, limit } => write!(f, "power {power:.0} W exceeds limit {limit} W"),
+        }
+    }
+}
 
-#[derive(Default)]
 pub struct SmartSocket {
+    name: String,
     is_turn_on: bool,
+    model: Box<dyn Model>,
+    energy_config: Arc<EnergyConfig>,
+    meter_path: PathBuf,
+    meter: EnergyMeter,
+    /// Socket turns off when power exceeds limit
+    power_limit: Option<f64>,
+    off_timer: OffTimer,
+    /// Safety limit of continuous on-time
+    max_on: Option<Duration>,
+    max_on_timer: OffTimer,
+    idle_off: Option<IdleOff>,
+    /// Power is below idle limit since then
+    idle_since: Option<DateTime<Local>>,
+    next_power_check: DateTime<Local>,
 }
 
 impl SmartSocket {
-    pub fn turn_on(&mut self) {
-        info!("Socket is turned on");
+    /// Meter is stored per server, so devices with the same name on different servers don't share it
+    pub fn new(name: &str, transport: &str, model: Box<dyn Model>, energy_config: Arc<EnergyConfig>) -> Self {
+        let meter_path = energy_config.storage.join(transport).join(format!("{name}.json"));
+        let meter = EnergyMeter::load(&meter_path);
+        Self {
+            name: name.to_owned(),
+            is_turn_on: false,
+            model,
+            energy_config,
+            meter_path,
+            meter,
+            power_limit: None,
+            off_timer: OffTimer::default(),
+            max_on: None,
+            max_on_timer: OffTimer::default(),
+            idle_off: None,
+            idle_since: None,
+            next_power_check: Local::now(),
+        }
+    }
+
+    fn meter_power(&mut self) -> Option<f64> {
+        if self.is_turn_on {
+            Some(self.sample_power())
+        } else {
+            None
+        }
+    }
+
+    fn update_meter(&mut self, power: Option<f64>, force_save: bool) {
+        let now = Local::now();
+        self.meter.sample(now, power, &self.energy_config);
+        if force_save || self.meter.is_save_needed(&now) {
+            self.meter.save(&self.meter_path, now);
+        }
+    }
+
+    fn sample_power(&mut self) -> f64 {
+        self.model.sample(Local::now())
+    }
+
+    /// Auto-off is pushed to server console besides log
+    fn auto_off(&mut self, reason: AutoOff) {
+        push_event!(Level::Warn, "Auto-off: socket {} is turned off, {reason}", self.name);
+        self.turn_off();
+    }
+
+    /// Meters power of socket which is on and turns it off by power limit or idle
+    fn check_power(&mut self, now: DateTime<Local>) {
+        if now < self.next_power_check {
+            return;
+        }
+        self.next_power_check = now + Duration::seconds(POWER_CHECK_PERIOD_SECS);
+        let power = self.model.sample(now);
+        self.update_meter(Some(power), false);
+        if let Some(limit) = self.power_limit.filter(|limit| power > *limit) {
+            self.auto_off(AutoOff::Overload { power, limit });
+            return;
+        }
+        if let Some(idle_off) = self.idle_off {
+            self.check_idle(idle_off, power, now);
+        }
+    }
+
+    fn check_idle(&mut self, idle_off: IdleOff, power: f64, now: DateTime<Local>) {
+        if power >= idle_off.below_watts {
+            self.idle_since = None;
+            return;
+        }
+        let idle_since = *self.idle_since.get_or_insert(now);
+        if now - idle_since >= minutes_to_duration(idle_off.for_minutes) {
+            self.auto_off(AutoOff::Idle(idle_off));
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct SocketSnapshot {
+    is_turn_on: bool,
+    power_limit: Option<f64>,
+    off_timer: OffTimer,
+    max_on_timer: OffTimer,
+}
+
+impl SmartDevice for SmartSocket {
+    fn type_dev(&self) -> &'static str {
+        TYPE_NAME
+    }
+
+    fn commands(&self) -> &'static [Cmd] {
+        COMMANDS
+    }
+
+    fn readings(&self) -> &'static [Reading] {
+        READINGS
+    }
+
+    /// Socket already on keeps its on-time and idle tracking, only timer
+    /// of `Cmd::TurnOnFor` is cancelled
+    fn turn_on(&mut self) {
+        self.off_timer.cancel();
+        if self.is_turn_on {
+            return;
+        }
+        info!("Socket {} is turned on", self.name);
         self.is_turn_on = true;
+        self.idle_since = None;
+        let now = Local::now();
+        if let Some(max_on) = self.max_on {
+            self.max_on_timer.start(now, max_on);
+        }
+        self.model.reset(now);
+        let power = self.sample_power();
+        self.update_meter(Some(power), true);
     }
 
-    pub fn turn_off(&mut self) {
-        info!("Socket is turned off");
+    fn turn_off(&mut self) {
+        info!("Socket {} is turned off", self.name);
         self.is_turn_on = false;
+        self.off_timer.cancel();
+        self.max_on_timer.cancel();
+        self.idle_since = None;
+        self.update_meter(None, true);
     }
 
-    pub fn get_power(&mut self) -> f64 {
+    fn is_on(&self) -> bool {
+        self.is_turn_on
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Box::new(SocketSnapshot {
+            is_turn_on: self.is_turn_on,
+            power_limit: self.power_limit,
+            off_timer: self.off_timer,
+            max_on_timer: self.max_on_timer,
+        })
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) {
+        let Ok(snapshot) = snapshot.downcast::<SocketSnapshot>() else {
+            error!("Invalid snapshot of socket {}", self.name);
+            return;
+        };
+        switch(self, snapshot.is_turn_on);
+        self.power_limit = snapshot.power_limit;
+        self.off_timer = snapshot.off_timer;
+        self.max_on_timer = snapshot.max_on_timer;
+    }
+
+    fn read(&mut self, reading: Reading) -> Result<Sample, Error> {
+        if reading != Reading::Power {
+            return Err(ErrorKind::WrongCmd.into());
+        }
         if !self.is_turn_on {
-            return 0.0;
+            return Ok(Sample::fresh(0.0));
         }
 
-        let noize = thread_rng().sample::<f64, StandardNormal>(StandardNormal) - 0.5;
-        let scalied_noize = noize * POWER_SPREAD as f64;
-        let res = AVG_POWER + scalied_noize;
-        res
+        let now = Local::now();
+        Ok(Sample::simulated(self.model.sample(now), now))
+    }
+
+    fn set_level(&mut self, level: f64) -> Result<(), Error> {
+        if !(level > 0.0 && level <= MAX_POWER_LIMIT) {
+            return Err(Error::new(ErrorKind::OutOfRange).with_details(format!("limit {level} W isn't in (0, {MAX_POWER_LIMIT}]")));
+        }
+        info!("Socket {} power limit is {level}", self.name);
+        self.power_limit = Some(level);
+        Ok(())
+    }
+
+    fn turn_on_for(&mut self, duration: Duration) -> Result<(), Error> {
+        self.turn_on();
+        self.off_timer.start(Local::now(), duration);
+        Ok(())
+    }
+
+    fn update(&mut self, now: DateTime<Local>) {
+        if self.off_timer.is_expired(&now) {
+            info!("Socket {} on time is over", self.name);
+            self.turn_off();
+        }
+        if self.max_on_timer.is_expired(&now) {
+            if let Some(max_on) = self.max_on {
+                self.auto_off(AutoOff::MaxOnTime(max_on));
+            }
+        }
+        if self.is_turn_on {
+            self.check_power(now);
+        }
+    }
+
+    fn off_in(&self, now: DateTime<Local>) -> Option<Duration> {
+        let idle_off_in = match (self.idle_since, self.idle_off) {
+            (Some(idle_since), Some(idle_off)) => Some(idle_since + minutes_to_duration(idle_off.for_minutes) - now),
+            _ => None,
+        };
+        [self.off_timer.remaining(&now), self.max_on_timer.remaining(&now), idle_off_in]
+            .into_iter()
+            .flatten()
+            .min()
     }
-}
\ No newline at end of file
+
+    fn energy(&mut self) -> Option<EnergyReport> {
+        let power = self.meter_power();
+        self.update_meter(power, false);
+        Some(self.meter.report(&self.name, &Local::now()))
+    }
+
+    /// Saves energy counter, so it isn't lost on restart.
+    fn store_state(&mut self) {
+        let power = self.meter_power();
+        self.update_meter(power, true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::{test_devices, Device};
+    use serde_json::{json, Value};
+
+    fn socket(params: Value) -> Device {
+        let mut config = json!({"name": "sock", "type": TYPE_NAME, "model": {"kind": "gauss", "mean": 3.0, "spread": 0.0}});
+        config.as_object_mut().unwrap().extend(params.as_object().unwrap().clone());
+        test_devices("socket", vec![config]).remove("sock").unwrap()
+    }
+
+    #[test]
+    fn test_max_on_time() {
+        let mut sock = socket(json!({"max_on_minutes": 2.0}));
+        sock.execute(&Cmd::TurnOnFor(300)).unwrap();
+        let now = Local::now();
+        let off_in = sock.state(now).off_in_secs.unwrap();
+        assert!((119..=120).contains(&off_in));
+
+        sock.update(now + Duration::seconds(60));
+        assert!(sock.is_on());
+        sock.update(now + Duration::seconds(121));
+        assert!(!sock.is_on());
+        assert_eq!(sock.state(now).off_in_secs, None);
+    }
+
+    #[test]
+    fn test_turn_on_again_keeps_max_on_time() {
+        let storage = std::env::temp_dir().join(format!("smart_server_socket_again_{}", std::process::id()));
+        let energy_config = Arc::new(EnergyConfig::from_config(&json!({"energy": {"storage": storage, "default_price": 0.0}})));
+        let mut sock = SmartSocket::new("sock", "tcp", ModelConfig::gauss(3.0, 0.0).build().unwrap(), energy_config);
+        sock.max_on = Some(Duration::minutes(2));
+        sock.execute(&Cmd::TurnOn).unwrap();
+        // Socket is on for a minute when TurnOn is sent again
+        let start = Local::now() - Duration::seconds(60);
+        sock.max_on_timer.start(start, Duration::minutes(2));
+        sock.execute(&Cmd::TurnOn).unwrap();
+
+        sock.update(start + Duration::seconds(60));
+        assert!(sock.is_on());
+        sock.update(start + Duration::seconds(121));
+        assert!(!sock.is_on());
+        let _ = std::fs::remove_dir_all(storage);
+    }
+
+    #[test]
+    fn test_idle_off() {
+        let mut sock = socket(json!({"idle_off": {"below_watts": 5.0, "for_minutes": 1.0}}));
+        sock.execute(&Cmd::TurnOn).unwrap();
+        let now = Local::now();
+        assert_eq!(sock.state(now).off_in_secs, None);
+
+        sock.update(now);
+        assert_eq!(sock.state(now + Duration::seconds(20)).off_in_secs, Some(40));
+        sock.update(now + Duration::seconds(30));
+        assert!(sock.is_on());
+        sock.update(now + Duration::seconds(60));
+        assert!(!sock.is_on());
+
+        // Power above limit keeps socket on
+        let mut sock = socket(json!({"idle_off": {"below_watts": 2.0, "for_minutes": 1.0}}));
+        sock.execute(&Cmd::TurnOn).unwrap();
+        sock.update(now);
+        sock.update(now + Duration::seconds(120));
+        assert!(sock.is_on());
+        assert_eq!(sock.state(now).off_in_secs, None);
+    }
+
+    #[test]
+    fn test_power_limit() {
+        let mut sock = socket(json!({"power_limit": 2.0}));
+        sock.execute(&Cmd::TurnOn).unwrap();
+        // Reading power doesn't change socket, limit is checked by update
+        assert_eq!(sock.sample(Reading::Power).unwrap().value, 3.0);
+        assert!(sock.is_on());
+        sock.update(Local::now());
+        assert!(!sock.is_on());
+
+        let mut sock = socket(json!({"power_limit": 5.0}));
+        sock.execute(&Cmd::TurnOn).unwrap();
+        sock.update(Local::now());
+        assert!(sock.is_on());
+    }
+
+    #[test]
+    fn test_meter_per_transport() {
+        let storage = std::env::temp_dir().join(format!("smart_server_meter_{}", std::process::id()));
+        let energy_config = Arc::new(EnergyConfig::from_config(&json!({"energy": {"storage": storage, "default_price": 0.0}})));
+        let mut tcp_sock = SmartSocket::new("sock", "tcp", ModelConfig::gauss(3.0, 0.0).build().unwrap(), energy_config.clone());
+        tcp_sock.store_state();
+        assert!(storage.join("tcp").join("sock.json").exists());
+        assert!(!storage.join("udp").join("sock.json").exists());
+
+        let mut udp_sock = SmartSocket::new("sock", "udp", ModelConfig::gauss(3.0, 0.0).build().unwrap(), energy_config);
+        udp_sock.store_state();
+        assert!(storage.join("udp").join("sock.json").exists());
+        let _ = std::fs::remove_dir_all(storage);
+    }
+}
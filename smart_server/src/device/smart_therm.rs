@@ -1,34 +1,122 @@
-use rand::prelude::{thread_rng, Rng};
-use rand_distr::StandardNormal;
+use chrono::Local;
 use log::*;
 
+use super::simulation::{Model, ModelConfig};
+use super::{switch, DeviceConfig, FactoryContext, Reading, Sample, SmartDevice, Snapshot};
+use crate::err_house;
+use crate::protocol::{Cmd, Error, ErrorKind};
+
+pub const TYPE_NAME: &str = "therm";
+
 const AVG_TEMP: f64 = 25.0; // C
-const TEMP_SPREAD: f64 = 5.0; // 100W
+const TEMP_SPREAD: f64 = 5.0; // C
+const DEFAULT_TARGET: f64 = 22.0; // C
+const MIN_TARGET: f64 = 5.0; // C
+const MAX_TARGET: f64 = 35.0; // C
+
+const COMMANDS: &[Cmd] = &[Cmd::TurnOn, Cmd::TurnOff, Cmd::Temperature, Cmd::Target, Cmd::SetTarget(0.0)];
+const READINGS: &[Reading] = &[Reading::Temperature, Reading::Target];
+
+pub fn create(dev_config: &DeviceConfig, _ctx: &FactoryContext) -> Result<Box<dyn SmartDevice>, err_house::Err> {
+    let model = dev_config.model.unwrap_or(ModelConfig::gauss(AVG_TEMP, TEMP_SPREAD)).build()?;
+    let mut therm = SmartTherm::new(model);
+    let target = dev_config.param("target", DEFAULT_TARGET)?;
+    if therm.set_target(target).is_err() {
+        error!("Target temperature of therm {} is out of range: {target}", dev_config.name);
+        return Err(err_house::Err::new(err_house::ErrorKind::ParsingError));
+    }
+    Ok(Box::new(therm))
+}
 
-#[derive(Default)]
 pub struct SmartTherm {
     is_turn_on: bool,
+    model: Box<dyn Model>,
+    target: f64,
+    /// Temperature reported while therm is off
+    last_sample: Option<Sample>,
 }
 
 impl SmartTherm {
-    pub fn turn_on(&mut self) {
+    pub fn new(model: Box<dyn Model>) -> Self {
+        Self {
+            is_turn_on: false,
+            model,
+            target: DEFAULT_TARGET,
+            last_sample: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct ThermSnapshot {
+    is_turn_on: bool,
+    target: f64,
+}
+
+impl SmartDevice for SmartTherm {
+    fn type_dev(&self) -> &'static str {
+        TYPE_NAME
+    }
+
+    fn commands(&self) -> &'static [Cmd] {
+        COMMANDS
+    }
+
+    fn readings(&self) -> &'static [Reading] {
+        READINGS
+    }
+
+    fn turn_on(&mut self) {
         info!("Therm is turned on");
         self.is_turn_on = true;
+        self.model.reset(Local::now());
     }
 
-    pub fn turn_off(&mut self) {
+    fn turn_off(&mut self) {
         info!("Therm is turned off");
         self.is_turn_on = false;
     }
 
-    pub fn get_temperature(&mut self) -> f64 {
-        if !self.is_turn_on {
-            return 0.0;
+    fn is_on(&self) -> bool {
+        self.is_turn_on
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Box::new(ThermSnapshot {
+            is_turn_on: self.is_turn_on,
+            target: self.target,
+        })
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) {
+        let Ok(snapshot) = snapshot.downcast::<ThermSnapshot>() else {
+            error!("Invalid snapshot of therm");
+            return;
+        };
+        switch(self, snapshot.is_turn_on);
+        self.target = snapshot.target;
+    }
+
+    fn read(&mut self, reading: Reading) -> Result<Sample, Error> {
+        match reading {
+            Reading::Temperature if self.is_turn_on => {
+                let now = Local::now();
+                let sample = Sample::simulated(self.model.sample(now), now);
+                self.last_sample = Some(sample);
+                Ok(sample)
+            }
+            Reading::Temperature => Ok(Sample::stale(self.last_sample)),
+            Reading::Target => Ok(Sample::fresh(self.target)),
+            _ => Err(ErrorKind::WrongCmd.into()),
         }
+    }
 
-        let noize = thread_rng().sample::<f64, StandardNormal>(StandardNormal) - 0.5;
-        let scalied_noize = noize * TEMP_SPREAD as f64;
-        let res = AVG_TEMP + scalied_noize;
-        res
+    fn set_target(&mut self, target: f64) -> Result<(), Error> {
+        if !(MIN_TARGET..=MAX_TARGET).contains(&target) {
+            return Err(Error::new(ErrorKind::OutOfRange).with_details(format!("{target} isn't in {MIN_TARGET}..={MAX_TARGET}")));
+        }
+        info!("Therm target temperature is {target}");
+        self.target = target;
+        Ok(())
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,280 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+use chrono::{DateTime, Local, TimeZone, Timelike};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use log::*;
+
+use super::protocol::{Consumption, EnergyReport};
+
+const SECS_IN_HOUR: i64 = 3600;
+const WATTS_IN_KILOWATT: f64 = 1_000.0;
+const DAY_HOURS: i64 = 24;
+const WEEK_HOURS: i64 = 7 * DAY_HOURS;
+const MONTH_HOURS: i64 = 30 * DAY_HOURS;
+const SAVE_PERIOD_SECS: i64 = 60;
+
+/// Price per kWh applied between `from` and `to` hours of local time.
+/// Window may wrap over midnight, e.g. from 23 to 7.
+#[derive(Deserialize, Clone)]
+pub struct Tariff {
+    pub from: u32,
+    pub to: u32,
+    pub price: f64,
+}
+
+impl Tariff {
+    /// Hours are of 0..=23, window of zero length is rejected
+    fn validate(&self) -> Result<(), String> {
+        if self.from > 23 || self.to > 23 {
+            return Err(format!("hours {}..{} are out of 0..=23", self.from, self.to));
+        }
+        if self.from == self.to {
+            return Err(format!("window from {} to {} is empty", self.from, self.to));
+        }
+        Ok(())
+    }
+
+    fn contains(&self, hour: u32) -> bool {
+        if self.from <= self.to {
+            hour >= self.from && hour < self.to
+        } else {
+            hour >= self.from || hour < self.to
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct EnergyConfig {
+    pub storage: PathBuf,
+    pub default_price: f64,
+    #[serde(default)]
+    pub tariffs: Vec<Tariff>,
+}
+
+impl Default for EnergyConfig {
+    fn default() -> Self {
+        Self {
+            storage: PathBuf::from("energy"),
+            default_price: 0.0,
+            tariffs: Vec::new(),
+        }
+    }
+}
+
+impl EnergyConfig {
+    pub fn from_config(config_json: &Value) -> Self {
+        match config_json.get("energy") {
+            Some(energy) => {
+                let config: Self = serde_json::from_value(energy.clone()).expect("Wrong input config: invalid energy section");
+                for tariff in config.tariffs.iter() {
+                    if let Err(reason) = tariff.validate() {
+                        error!("Wrong input config: invalid tariff: {reason}");
+                        panic!();
+                    }
+                }
+                config
+            }
+            None => {
+                info!("Energy section not found in config, tariffs aren't applied");
+                Self::default()
+            }
+        }
+    }
+
+    pub fn price_at(&self, time: &DateTime<Local>) -> f64 {
+        let hour = time.hour();
+        self.tariffs
+            .iter()
+            .find(|tariff| tariff.contains(hour))
+            .map(|tariff| tariff.price)
+            .unwrap_or(self.default_price)
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Copy)]
+struct HourBucket {
+    kwh: f64,
+    cost: f64,
+}
+
+/// Integrates power of device over time into hourly kWh buckets.
+/// Buckets are kept for the last month, total counter is kept for all time.
+#[derive(Serialize, Deserialize, Default)]
+pub struct EnergyMeter {
+    total_kwh: f64,
+    hours: BTreeMap<i64, HourBucket>,
+    #[serde(skip)]
+    last_sample: Option<(DateTime<Local>, f64)>,
+    #[serde(skip)]
+    last_save: Option<DateTime<Local>>,
+}
+
+impl EnergyMeter {
+    pub fn load(path: &PathBuf) -> Self {
+        let data =
+        match fs::read_to_string(path) {
+            Ok(res) => res,
+            Err(_) => {
+                info!("Energy meter {:?} not found, start from zero", path);
+                return Self::default();
+            }
+        };
+        match serde_json::from_str(&data) {
+            Ok(res) => res,
+            Err(e) => {
+                error!("Can't parse energy meter {:?}: {:?}", path, e);
+                Self::default()
+            }
+        }
+    }
+
+    pub fn save(&mut self, path: &PathBuf, now: DateTime<Local>) {
+        if let Some(dir) = path.parent() {
+            if let Err(e) = fs::create_dir_all(dir) {
+                error!("Can't create energy storage {:?}: {:?}", dir, e);
+                return;
+            }
+        }
+        let data =
+        match serde_json::to_string(self) {
+            Ok(res) => res,
+            Err(e) => {
+                error!("Can't serialize energy meter: {:?}", e);
+                return;
+            }
+        };
+        if let Err(e) = fs::write(path, data) {
+            error!("Can't save energy meter to {:?}: {:?}", path, e);
+            return;
+        }
+        self.last_save = Some(now);
+    }
+
+    pub fn is_save_needed(&self, now: &DateTime<Local>) -> bool {
+        match self.last_save {
+            Some(last) => (*now - last).num_seconds() >= SAVE_PERIOD_SECS,
+            None => true,
+        }
+    }
+
+    /// Accounts energy consumed since previous sample with previous power
+    /// and remembers new power. `None` power means device is off.
+    pub fn sample(&mut self, now: DateTime<Local>, power: Option<f64>, config: &EnergyConfig) {
+        if let Some((mut start, watts)) = self.last_sample.take() {
+            while start < now {
+                let hour_start = start.timestamp() - start.timestamp().rem_euclid(SECS_IN_HOUR);
+                let next_hour = local_time(hour_start + SECS_IN_HOUR);
+                let end = if next_hour < now { next_hour } else { now };
+                let hours = (end - start).num_milliseconds() as f64 / (SECS_IN_HOUR * 1000) as f64;
+                let kwh = watts * hours / WATTS_IN_KILOWATT;
+
+                let bucket = self.hours.entry(hour_start).or_default();
+                bucket.kwh += kwh;
+                bucket.cost += kwh * config.price_at(&start);
+                self.total_kwh += kwh;
+                start = end;
+            }
+        }
+        self.last_sample = power.map(|watts| (now, watts));
+
+        let oldest = now.timestamp() - MONTH_HOURS * SECS_IN_HOUR;
+        self.hours = self.hours.split_off(&(oldest - oldest.rem_euclid(SECS_IN_HOUR)));
+    }
+
+    fn consumption(&self, now: &DateTime<Local>, period_hours: i64) -> Consumption {
+        let from = now.timestamp() - period_hours * SECS_IN_HOUR;
+        let from = from - from.rem_euclid(SECS_IN_HOUR);
+        self.hours
+            .range(from..)
+            .fold(Consumption::default(), |acc, (_, bucket)| Consumption {
+                kwh: acc.kwh + bucket.kwh,
+                cost: acc.cost + bucket.cost,
+            })
+    }
+
+    pub fn report(&self, dev_name: &str, now: &DateTime<Local>) -> EnergyReport {
+        EnergyReport {
+            dev_name: dev_name.to_owned(),
+            total_kwh: self.total_kwh,
+            day: self.consumption(now, DAY_HOURS),
+            week: self.consumption(now, WEEK_HOURS),
+            month: self.consumption(now, MONTH_HOURS),
+        }
+    }
+}
+
+fn local_time(timestamp: i64) -> DateTime<Local> {
+    Local.timestamp_opt(timestamp, 0).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn config() -> EnergyConfig {
+        EnergyConfig {
+            storage: PathBuf::new(),
+            default_price: 5.0,
+            tariffs: vec![Tariff {
+                from: 23,
+                to: 7,
+                price: 2.0,
+            }],
+        }
+    }
+
+    #[test]
+    fn test_tariff_window() {
+        let config = config();
+        let day = Local.with_ymd_and_hms(2024, 3, 10, 12, 0, 0).unwrap();
+        let late = Local.with_ymd_and_hms(2024, 3, 10, 23, 30, 0).unwrap();
+        let early = Local.with_ymd_and_hms(2024, 3, 11, 6, 59, 0).unwrap();
+        assert_eq!(config.price_at(&day), 5.0);
+        assert_eq!(config.price_at(&late), 2.0);
+        assert_eq!(config.price_at(&early), 2.0);
+    }
+
+    #[test]
+    fn test_tariff_validation() {
+        let tariff = |from, to| Tariff {
+            from,
+            to,
+            price: 1.0,
+        };
+        assert!(tariff(23, 7).validate().is_ok());
+        assert!(tariff(0, 23).validate().is_ok());
+        assert!(tariff(7, 24).validate().is_err());
+        assert!(tariff(25, 7).validate().is_err());
+        assert!(tariff(7, 7).validate().is_err());
+    }
+
+    #[test]
+    fn test_meter_integrates_power() {
+        let config = config();
+        let mut meter = EnergyMeter::default();
+        let start = Local.with_ymd_and_hms(2024, 3, 10, 22, 30, 0).unwrap();
+        meter.sample(start, Some(2_000.0), &config);
+        let end = start + Duration::hours(1);
+        meter.sample(end, None, &config);
+
+        assert!((meter.total_kwh - 2.0).abs() < 1e-9);
+        let report = meter.report("sock", &end);
+        assert!((report.day.kwh - 2.0).abs() < 1e-9);
+        // Half an hour by day price and half an hour by night price
+        assert!((report.day.cost - (1.0 * 5.0 + 1.0 * 2.0)).abs() < 1e-9);
+
+        // Device is off, nothing accumulated
+        meter.sample(end + Duration::hours(2), None, &config);
+        assert!((meter.total_kwh - 2.0).abs() < 1e-9);
+
+        let later = end + Duration::days(2);
+        let report = meter.report("sock", &later);
+        assert_eq!(report.day.kwh, 0.0);
+        assert!((report.week.kwh - 2.0).abs() < 1e-9);
+        assert!((report.month.kwh - 2.0).abs() < 1e-9);
+    }
+}
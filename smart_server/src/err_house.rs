@@ -1,6 +1,5 @@
 use std::fmt::Display;
 use std::io;
-use serde_json;
 
 #[derive(Debug, Clone, Copy)]
 pub enum ErrorKind {
@@ -12,6 +11,7 @@ pub enum ErrorKind {
     SerializationError,
     UnknownTypePack,
     WrongDevType,
+    PayloadTooLarge,
 }
 
 #[derive(Debug)]
@@ -39,7 +39,7 @@ impl Display for Err {
 impl From<io::Error> for Err {
     fn from(value: io::Error) -> Self {
         match value.kind() {
-            io::ErrorKind::TimedOut => {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => {
                 Self::new(ErrorKind::IoTimeOut)
             }
             _ => {
@@ -0,0 +1,453 @@
+use std::collections::HashMap;
+use std::net::TcpListener;
+use std::path::Path;
+use std::sync::mpsc::Receiver;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use chrono::Local;
+use serde::Deserialize;
+use tokio::sync::{mpsc, watch};
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::{Request, Response, Status};
+use log::*;
+
+use super::console_server::{bind_listener, config_section, ConsoleCmd, Service};
+use super::device::{handle_on_owner, Reading, SharedDevices};
+use super::metrics::SharedMetrics;
+use super::protocol::{self, Cmd, ErrorCategory, ListQuery, ResponseKind, SuccessKind};
+use super::request_handler;
+
+pub mod pb {
+    tonic::include_proto!("smart_house");
+}
+
+use pb::smart_house_server::{SmartHouse, SmartHouseServer};
+
+const TRANSPORT: &str = "grpc";
+/// Readings aren't pushed more often
+const MIN_INTERVAL_MS: u64 = 100;
+/// Events queued for slow subscriber, subscription waits when queue is full
+const EVENTS_CAP: usize = 64;
+
+/// Service section of config, e.g. `{"addr": "127.0.0.1:50051"}`
+#[derive(Deserialize, Clone)]
+pub struct GrpcConfig {
+    pub addr: String,
+}
+
+fn status(e: &protocol::Error) -> Status {
+    let message = match e.details.as_ref() {
+        Some(details) => format!("{}: {details}", e.message),
+        None => e.message.clone(),
+    };
+    match e.category {
+        ErrorCategory::Validation => Status::invalid_argument(message),
+        ErrorCategory::NotFound => Status::not_found(message),
+        ErrorCategory::Permission => Status::permission_denied(message),
+        ErrorCategory::Device => Status::failed_precondition(message),
+        ErrorCategory::Server => Status::unavailable(message),
+    }
+}
+
+fn unit(unit: protocol::Unit) -> pb::Unit {
+    match unit {
+        protocol::Unit::Watt => pb::Unit::Watt,
+        protocol::Unit::Kilowatt => pb::Unit::Kilowatt,
+        protocol::Unit::Celsius => pb::Unit::Celsius,
+        protocol::Unit::Fahrenheit => pb::Unit::Fahrenheit,
+        protocol::Unit::Kelvin => pb::Unit::Kelvin,
+        protocol::Unit::Percent => pb::Unit::Percent,
+    }
+}
+
+fn quality(quality: protocol::Quality) -> pb::Quality {
+    match quality {
+        protocol::Quality::Fresh => pb::Quality::Fresh,
+        protocol::Quality::Stale => pb::Quality::Stale,
+        protocol::Quality::Simulated => pb::Quality::Simulated,
+    }
+}
+
+fn measurement(measurement: &protocol::Measurement) -> pb::Measurement {
+    pb::Measurement {
+        value: measurement.value,
+        unit: unit(measurement.unit) as i32,
+        quality: quality(measurement.quality) as i32,
+        timestamp_ms: measurement.timestamp_ms,
+    }
+}
+
+fn state(state: &protocol::DeviceState) -> pb::DeviceState {
+    let last_measurement = match state.last_reading.as_deref() {
+        Some(SuccessKind::Power(last) | SuccessKind::Temp(last)) => Some(measurement(last)),
+        _ => None,
+    };
+    pb::DeviceState {
+        is_on: state.is_on,
+        is_fault: state.is_fault,
+        uptime_secs: state.uptime_secs,
+        off_in_secs: state.off_in_secs,
+        last_measurement,
+    }
+}
+
+/// Implementation of generated service on devices of all servers
+#[derive(Clone)]
+struct SmartHouseApi {
+    servers: Vec<(&'static str, SharedDevices)>,
+    metrics: SharedMetrics,
+    /// Changed on exit, subscriptions are closed to let server stop
+    closed: watch::Receiver<bool>,
+}
+
+impl SmartHouseApi {
+    /// Executes command on server owning device
+    fn execute(&self, name: &str, cmd: Cmd) -> Result<SuccessKind, protocol::Error> {
+        let started = Instant::now();
+        let resp = handle_on_owner(&self.servers, protocol::Request::new(cmd, name.to_owned()));
+        self.metrics.lock().unwrap().record(TRANSPORT, &resp, started.elapsed());
+        match resp.resp_kind {
+            ResponseKind::Success(success) => Ok(success),
+            ResponseKind::Err(e) => Err(e),
+        }
+    }
+
+    /// Readings of subscribed devices and states changed since the previous call,
+    /// stale readings are skipped
+    fn events(&self, names: &[String], states: &mut HashMap<String, (bool, bool)>) -> Vec<pb::DeviceEvent> {
+        let now = Local::now();
+        let mut events = Vec::new();
+        for (transport, devices) in self.servers.iter() {
+            let mut devices = devices.lock().unwrap();
+            let mut subscribed: Vec<String> = devices.keys()
+                .filter(|name| names.is_empty() || names.contains(*name))
+                .cloned()
+                .collect();
+            subscribed.sort();
+            for name in subscribed {
+                let dev = devices.get_mut(&name).unwrap();
+                for reading in [Reading::Power, Reading::Temperature] {
+                    if !dev.readings().contains(&reading) {
+                        continue;
+                    }
+                    let sample = match dev.sample(reading) {
+                        Ok(sample) if sample.quality != protocol::Quality::Stale => sample,
+                        Ok(_) => continue,
+                        Err(e) => {
+                            debug!("Reading {:?} of {name} is unavailable: {:?}", reading, e);
+                            continue;
+                        }
+                    };
+                    let (unit, event): (_, fn(_) -> _) = match reading {
+                        Reading::Power => (pb::Unit::Watt, pb::device_event::Event::Power),
+                        _ => (pb::Unit::Celsius, pb::device_event::Event::Temperature),
+                    };
+                    events.push(pb::DeviceEvent {
+                        name: name.clone(),
+                        transport: transport.to_string(),
+                        event: Some(event(pb::Measurement {
+                            value: sample.value,
+                            unit: unit as i32,
+                            quality: quality(sample.quality) as i32,
+                            timestamp_ms: sample.time.timestamp_millis(),
+                        })),
+                    });
+                }
+                let dev_state = dev.state(now);
+                let key = (dev_state.is_on, dev_state.is_fault);
+                if states.insert(name.clone(), key) != Some(key) {
+                    events.push(pb::DeviceEvent {
+                        name,
+                        transport: transport.to_string(),
+                        event: Some(pb::device_event::Event::State(state(&dev_state))),
+                    });
+                }
+            }
+        }
+        events
+    }
+}
+
+#[tonic::async_trait]
+impl SmartHouse for SmartHouseApi {
+    type SubscribeStream = ReceiverStream<Result<pb::DeviceEvent, Status>>;
+
+    /// Devices ordered by server then by name, pages are joined
+    async fn list_devices(&self, _req: Request<pb::ListDevicesRequest>) -> Result<Response<pb::ListDevicesReply>, Status> {
+        let mut reply = pb::ListDevicesReply::default();
+        for (transport, devices) in self.servers.iter() {
+            let mut devices = devices.lock().unwrap();
+            let mut query = ListQuery::default();
+            loop {
+                let started = Instant::now();
+                let resp = request_handler::handle_request(&mut devices, protocol::Request::new(Cmd::GetListDevices(query.clone()), String::new()));
+                self.metrics.lock().unwrap().record(TRANSPORT, &resp, started.elapsed());
+                let page = match resp.resp_kind {
+                    ResponseKind::Success(SuccessKind::ListDev(page)) => page,
+                    ResponseKind::Err(e) => return Err(status(&e)),
+                    _ => break,
+                };
+                for dev in page.devices {
+                    reply.devices.push(pb::Device {
+                        name: dev.name,
+                        r#type: dev.type_dev,
+                        transport: transport.to_string(),
+                    });
+                }
+                match page.next_cursor {
+                    Some(cursor) => query.cursor = Some(cursor),
+                    None => break,
+                }
+            }
+        }
+        Ok(Response::new(reply))
+    }
+
+    async fn turn_on(&self, req: Request<pb::DeviceRequest>) -> Result<Response<pb::Ack>, Status> {
+        match self.execute(&req.into_inner().name, Cmd::TurnOn) {
+            Ok(_) => Ok(Response::new(pb::Ack {})),
+            Err(e) => Err(status(&e)),
+        }
+    }
+
+    async fn turn_off(&self, req: Request<pb::DeviceRequest>) -> Result<Response<pb::Ack>, Status> {
+        match self.execute(&req.into_inner().name, Cmd::TurnOff) {
+            Ok(_) => Ok(Response::new(pb::Ack {})),
+            Err(e) => Err(status(&e)),
+        }
+    }
+
+    async fn get_power(&self, req: Request<pb::DeviceRequest>) -> Result<Response<pb::Measurement>, Status> {
+        match self.execute(&req.into_inner().name, Cmd::Power) {
+            Ok(SuccessKind::Power(res)) => Ok(Response::new(measurement(&res))),
+            Ok(_) => Err(Status::internal("Unexpected response")),
+            Err(e) => Err(status(&e)),
+        }
+    }
+
+    async fn get_temperature(&self, req: Request<pb::DeviceRequest>) -> Result<Response<pb::Measurement>, Status> {
+        match self.execute(&req.into_inner().name, Cmd::Temperature) {
+            Ok(SuccessKind::Temp(res)) => Ok(Response::new(measurement(&res))),
+            Ok(_) => Err(Status::internal("Unexpected response")),
+            Err(e) => Err(status(&e)),
+        }
+    }
+
+    async fn get_state(&self, req: Request<pb::DeviceRequest>) -> Result<Response<pb::DeviceState>, Status> {
+        match self.execute(&req.into_inner().name, Cmd::GetState) {
+            Ok(SuccessKind::State(res)) => Ok(Response::new(state(&res))),
+            Ok(_) => Err(Status::internal("Unexpected response")),
+            Err(e) => Err(status(&e)),
+        }
+    }
+
+    /// Subscription lasts until client drops stream
+    async fn subscribe(&self, req: Request<pb::SubscribeRequest>) -> Result<Response<Self::SubscribeStream>, Status> {
+        let req = req.into_inner();
+        for name in req.devices.iter() {
+            if !self.servers.iter().any(|(_, devices)| devices.lock().unwrap().contains_key(name)) {
+                return Err(status(&protocol::Error::new(protocol::ErrorKind::DevNotFound).with_details(name.clone())));
+            }
+        }
+        let interval = Duration::from_millis((req.interval_ms as u64).max(MIN_INTERVAL_MS));
+        info!("Grpc subscription to {:?} every {} ms", req.devices, interval.as_millis());
+        let (tx, rx) = mpsc::channel(EVENTS_CAP);
+        let api = self.clone();
+        tokio::spawn(async move {
+            let mut states = HashMap::new();
+            let mut ticker = tokio::time::interval(interval);
+            let mut closed = api.closed.clone();
+            loop {
+                tokio::select! {
+                    _ = ticker.tick() => {}
+                    _ = closed.changed() => return,
+                }
+                for event in api.events(&req.devices, &mut states) {
+                    if tx.send(Ok(event)).await.is_err() {
+                        info!("Grpc subscription to {:?} is closed", req.devices);
+                        return;
+                    }
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+/// Serves gRPC API of `proto/smart_house.proto` on its own runtime
+pub struct GrpcServer {
+    config: Option<GrpcConfig>,
+    servers: Vec<(&'static str, SharedDevices)>,
+    metrics: SharedMetrics,
+    rx: Option<Receiver<ConsoleCmd>>,
+}
+
+impl Service for GrpcServer {
+    fn start_service(mut self, rx: Receiver<ConsoleCmd>) -> JoinHandle<()> {
+        self.rx = Some(rx);
+        self.start()
+    }
+
+    fn rx(&self) -> &Receiver<ConsoleCmd> {
+        self.rx.as_ref().unwrap()
+    }
+}
+
+impl GrpcServer {
+    /// Service is disabled if config has no grpc section
+    pub fn new(config_path: &Path, servers: Vec<(&'static str, SharedDevices)>, metrics: SharedMetrics) -> Self {
+        let config = config_section(config_path, "grpc");
+        Self {
+            config,
+            servers,
+            metrics,
+            rx: None,
+        }
+    }
+
+    pub fn name() -> &'static str {
+        "GrpcServer"
+    }
+
+    fn bind(config: &GrpcConfig) -> TcpListener {
+        let listener = bind_listener(&config.addr);
+        info!("Grpc service is served on {}", config.addr);
+        listener
+    }
+
+    fn start(self) -> JoinHandle<()> {
+        thread::spawn(move || {
+            let listener = self.config.as_ref().map(Self::bind);
+            let runtime = match tokio::runtime::Builder::new_current_thread().enable_all().build() {
+                Ok(res) => res,
+                Err(e) => {
+                    error!("Can't create grpc runtime: {e}");
+                    panic!();
+                }
+            };
+            runtime.block_on(self.serve(listener));
+        })
+    }
+
+    async fn serve(&self, listener: Option<TcpListener>) {
+        let (closed_tx, closed) = watch::channel(false);
+        let exit = async move {
+            while !matches!(self.get_cmd(), Some(ConsoleCmd::Exit)) {
+                tokio::time::sleep(Duration::from_millis(MIN_INTERVAL_MS)).await;
+            }
+            let _ = closed_tx.send(true);
+        };
+        let Some(listener) = listener else {
+            return exit.await;
+        };
+        let listener = match tokio::net::TcpListener::from_std(listener) {
+            Ok(res) => res,
+            Err(e) => {
+                error!("Invalid listener: {:?}", e);
+                return exit.await;
+            }
+        };
+        let api = SmartHouseApi {
+            servers: self.servers.clone(),
+            metrics: self.metrics.clone(),
+            closed,
+        };
+        let res = tonic::transport::Server::builder()
+            .add_service(SmartHouseServer::new(api))
+            .serve_with_incoming_shutdown(TcpListenerStream::new(listener), exit)
+            .await;
+        if let Err(e) = res {
+            error!("Grpc service failed: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{mpsc as std_mpsc, Arc, Mutex};
+    use crate::device::test_devices;
+    use pb::smart_house_client::SmartHouseClient;
+    use serde_json::{json, Value};
+    use tonic::Code;
+
+    fn server(configs: Vec<Value>) -> SharedDevices {
+        Arc::new(Mutex::new(test_devices("grpc", configs)))
+    }
+
+    /// On state of the next state event and power read in the same round before it
+    async fn next_state(events: &mut tonic::Streaming<pb::DeviceEvent>) -> (bool, Option<f64>) {
+        let mut power = None;
+        loop {
+            match events.message().await.unwrap().unwrap().event {
+                Some(pb::device_event::Event::Power(reading)) => power = Some(reading.value),
+                Some(pb::device_event::Event::State(state)) => return (state.is_on, power),
+                event => panic!("Unexpected event {:?}", event),
+            }
+        }
+    }
+
+    /// Generated client calls service running on loopback like in emulator
+    #[test]
+    fn test_client() {
+        let tcp = server(vec![json!({"name": "sock1", "type": "socket", "model": {"kind": "gauss", "mean": 2000.0, "spread": 0.0}})]);
+        let udp = server(vec![json!({"name": "therm1", "type": "therm"}), json!({"name": "therm2", "type": "therm", "read_only": true})]);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = std_mpsc::channel();
+        let grpc = GrpcServer {
+            config: Some(GrpcConfig { addr: addr.to_string() }),
+            servers: vec![("tcp", tcp), ("udp", udp)],
+            metrics: SharedMetrics::default(),
+            rx: Some(rx),
+        };
+        let metrics = grpc.metrics.clone();
+        listener.set_nonblocking(true).unwrap();
+        let handle = thread::spawn(move || {
+            tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(grpc.serve(Some(listener)));
+        });
+
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        runtime.block_on(async {
+            let mut client = SmartHouseClient::connect(format!("http://{addr}")).await.unwrap();
+            let dev = |name: &str| pb::DeviceRequest { name: name.to_owned() };
+
+            let devices = client.list_devices(pb::ListDevicesRequest {}).await.unwrap().into_inner().devices;
+            let devices: Vec<_> = devices.iter().map(|dev| (dev.name.as_str(), dev.r#type.as_str(), dev.transport.as_str())).collect();
+            assert_eq!(devices, [
+                ("sock1", "socket", "tcp"),
+                ("therm1", "therm", "udp"),
+                ("therm2", "therm", "udp"),
+            ]);
+
+            let mut events = client.subscribe(pb::SubscribeRequest { devices: vec!["sock1".to_owned()], interval_ms: 0 }).await.unwrap().into_inner();
+            assert_eq!(next_state(&mut events).await, (false, Some(0.0)));
+
+            client.turn_on(dev("sock1")).await.unwrap();
+            let power = client.get_power(dev("sock1")).await.unwrap().into_inner();
+            assert_eq!((power.value, power.unit(), power.quality()), (2000.0, pb::Unit::Watt, pb::Quality::Simulated));
+            let state = client.get_state(dev("sock1")).await.unwrap().into_inner();
+            assert!(state.is_on);
+            assert_eq!(state.last_measurement.map(|last| last.value), Some(2000.0));
+            assert_eq!(next_state(&mut events).await, (true, Some(2000.0)));
+            drop(events);
+
+            assert_eq!(client.turn_on(dev("therm2")).await.unwrap_err().code(), Code::PermissionDenied);
+            assert_eq!(client.get_state(dev("sock9")).await.unwrap_err().code(), Code::NotFound);
+            assert_eq!(client.get_temperature(dev("sock1")).await.unwrap_err().code(), Code::InvalidArgument);
+            let unknown = pb::SubscribeRequest { devices: vec!["sock9".to_owned()], interval_ms: 100 };
+            assert_eq!(client.subscribe(unknown).await.unwrap_err().code(), Code::NotFound);
+        });
+
+        // Subscription of other client is closed on exit
+        let mut client = runtime.block_on(SmartHouseClient::connect(format!("http://{addr}"))).unwrap();
+        let mut events = runtime.block_on(client.subscribe(pb::SubscribeRequest::default())).unwrap().into_inner();
+        tx.send(ConsoleCmd::Exit).unwrap();
+        while runtime.block_on(events.message()).is_ok_and(|event| event.is_some()) {}
+        handle.join().unwrap();
+        let mut out = String::new();
+        metrics.lock().unwrap().render(&mut out, Local::now());
+        assert!(out.contains("transport=\"grpc\""));
+    }
+}
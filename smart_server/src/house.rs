@@ -0,0 +1,104 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use serde::Deserialize;
+use chrono::Local;
+use log::*;
+
+use super::device::{Device, DeviceConfig, DeviceRegistry, Devices, FactoryContext, SharedDevices};
+use super::energy::EnergyConfig;
+use super::console_server::{config_section, read_config};
+use super::protocol::{self, Group, Scene};
+use super::request_handler;
+use super::scheduler::{Scheduler, SystemClock};
+use super::thermostat::{ThermostatConfig, Thermostats};
+use super::scene::Scenes;
+
+/// Section of tcp or udp server in config
+#[derive(Deserialize)]
+struct HouseConfig {
+    devices: Vec<DeviceConfig>,
+    #[serde(default)]
+    thermostats: Vec<ThermostatConfig>,
+    #[serde(default)]
+    groups: Vec<Group>,
+    #[serde(default)]
+    scenes: Vec<Scene>,
+    schedules: Option<PathBuf>,
+}
+
+/// Devices of tcp or udp server with schedules, thermostats and scenes
+/// automating them. Servers differ only in transport of requests.
+pub struct House {
+    devices: SharedDevices,
+    scheduler: Scheduler,
+    thermostats: Thermostats,
+    scenes: Scenes,
+}
+
+impl House {
+    /// Creates devices from config section of server, e.g. "tcp"
+    pub fn new(config_path: &Path, transport: &'static str) -> Self {
+        let config: HouseConfig = match config_section(config_path, transport) {
+            Some(config) => config,
+            None => {
+                error!("Wrong input config: section {transport} not found");
+                panic!();
+            }
+        };
+
+        let ctx = FactoryContext {
+            energy_config: Arc::new(EnergyConfig::from_config(&read_config(config_path))),
+            transport,
+        };
+        let registry = DeviceRegistry::default();
+        let mut devices = Devices::new();
+        for dev_config in config.devices.iter() {
+            devices.insert(dev_config.name.to_owned(), Device::new(registry.create(dev_config, &ctx).expect("Wrong input config: unknown device type"), dev_config));
+        }
+        let thermostats = Thermostats::new(config.thermostats, &devices);
+        let scenes = Scenes::new(config.groups, config.scenes, &devices);
+        let schedules_path = config.schedules.unwrap_or_else(|| PathBuf::from(format!("schedules/{transport}.json")));
+        Self {
+            devices: Arc::new(Mutex::new(devices)),
+            scheduler: Scheduler::new(schedules_path, Box::new(SystemClock)),
+            thermostats,
+            scenes,
+        }
+    }
+
+    /// Devices shared with services automating them
+    pub fn devices(&self) -> SharedDevices {
+        self.devices.clone()
+    }
+
+    /// Applies time based changes of devices, runs due schedules and thermostats
+    pub fn update(&mut self) {
+        let mut devices = self.devices.lock().unwrap();
+        request_handler::update_devices(&mut devices);
+        self.scheduler.run(&mut devices);
+        self.thermostats.update(&mut devices, Local::now());
+    }
+
+    /// Passes commands of schedules, thermostats and scenes to their owners,
+    /// other commands are executed on devices
+    pub fn handle_request(&mut self, req: protocol::Request) -> protocol::Response {
+        let mut devices = self.devices.lock().unwrap();
+        if Scheduler::is_schedule_cmd(&req.cmd) {
+            self.scheduler.handle_request(&devices, req)
+        }else if Thermostats::is_thermostat_cmd(&req.cmd) {
+            self.thermostats.handle_request(&mut devices, req)
+        }else if Scenes::is_scene_cmd(&req.cmd) {
+            self.scenes.handle_request(&mut devices, req)
+        }else{
+            request_handler::handle_request(&mut devices, req)
+        }
+    }
+
+    /// Saves state of devices, e.g. energy counters, when server stops
+    pub fn store_state(&self) {
+        for dev in self.devices.lock().unwrap().values_mut() {
+            dev.store_state();
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use log::*;
+
+use super::err_house;
+
+/// Longest request line or header accepted
+const MAX_LINE: usize = 8 * 1024;
+const MAX_HEADERS: usize = 64;
+const READ_TIMEOUT_MS: u64 = 1000;
+
+/// Minimal HTTP/1.1 request, connection is closed after response
+pub struct Request {
+    pub method: String,
+    /// Path without query
+    pub path: String,
+}
+
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16, content_type: &'static str, body: impl Into<Vec<u8>>) -> Self {
+        Self {
+            status,
+            content_type,
+            body: body.into(),
+        }
+    }
+
+    pub fn text(status: u16, body: impl Into<Vec<u8>>) -> Self {
+        Self::new(status, "text/plain; charset=utf-8", body)
+    }
+
+    pub fn json(status: u16, body: &serde_json::Value) -> Self {
+        Self::new(status, "application/json", body.to_string())
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    }
+}
+
+fn read_line(reader: &mut impl BufRead) -> Result<String, err_house::Err> {
+    let mut line = String::new();
+    reader.take(MAX_LINE as u64).read_line(&mut line)?;
+    if !line.ends_with('\n') {
+        return Err(err_house::Err::new(err_house::ErrorKind::ParsingError));
+    }
+    Ok(line.trim_end().to_owned())
+}
+
+pub fn read_request(reader: &mut impl BufRead) -> Result<Request, err_house::Err> {
+    let line = read_line(reader)?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target), Some(_version)) = (parts.next(), parts.next(), parts.next()) else {
+        info!("Invalid http request line: {line}");
+        return Err(err_house::Err::new(err_house::ErrorKind::ParsingError));
+    };
+    let request = Request {
+        method: method.to_owned(),
+        path: target.split('?').next().unwrap_or_default().to_owned(),
+    };
+    for _ in 0..MAX_HEADERS {
+        if read_line(reader)?.is_empty() {
+            return Ok(request);
+        }
+    }
+    info!("Http request has too many headers");
+    Err(err_house::Err::new(err_house::ErrorKind::ParsingError))
+}
+
+pub fn write_response(writer: &mut impl Write, resp: &Response) -> io::Result<()> {
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        resp.status, reason(resp.status), resp.content_type, resp.body.len()
+    );
+    writer.write_all(head.as_bytes())?;
+    writer.write_all(&resp.body)?;
+    writer.flush()
+}
+
+/// Decodes percent-encoded segment of path, e.g. `sock%201` is `sock 1`
+pub fn decode_segment(segment: &str) -> Option<String> {
+    let bytes = segment.as_bytes();
+    let mut res = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            res.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            res.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(res).ok()
+}
+
+/// Serves single request of accepted connection
+pub fn serve(stream: TcpStream, handler: impl FnOnce(&Request) -> Response) {
+    if let Err(e) = stream.set_nonblocking(false).and_then(|_| stream.set_read_timeout(Some(Duration::from_millis(READ_TIMEOUT_MS)))) {
+        error!("Can't set up http stream: {e}");
+        return;
+    }
+    let mut reader = BufReader::new(&stream);
+    let resp = match read_request(&mut reader) {
+        Ok(req) => handler(&req),
+        Err(e) => {
+            info!("Invalid http request: {e}");
+            Response::text(400, "Bad request\n")
+        }
+    };
+    if let Err(e) = write_response(&mut &stream, &resp) {
+        info!("Http connection closed: {:?}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_read_request() {
+        let mut reader = Cursor::new(b"GET /metrics?x=1 HTTP/1.1\r\nHost: localhost\r\nAccept: */*\r\n\r\n".to_vec());
+        let req = read_request(&mut reader).unwrap();
+        assert_eq!((req.method.as_str(), req.path.as_str()), ("GET", "/metrics"));
+
+        assert!(read_request(&mut Cursor::new(b"GET /metrics\r\n\r\n".to_vec())).is_err());
+        assert!(read_request(&mut Cursor::new(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n".to_vec())).is_err());
+
+        let mut out = Vec::new();
+        write_response(&mut out, &Response::text(404, "no")).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(out.ends_with("Content-Length: 2\r\nConnection: close\r\n\r\nno"));
+
+        assert_eq!(decode_segment("sock%201").as_deref(), Some("sock 1"));
+        assert_eq!(decode_segment("sock%2"), None);
+    }
+}
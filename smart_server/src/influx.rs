@@ -0,0 +1,355 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::net::UdpSocket;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Receiver;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use chrono::{DateTime, Local};
+use serde::Deserialize;
+use log::*;
+
+use super::console_server::{config_section, ConsoleCmd, Service};
+use super::device::{Reading, SharedDevices, SOCKET_TYPE, THERM_TYPE};
+use super::protocol::Quality;
+
+/// Lines are packed into datagrams not exceeding this size
+const MAX_DATAGRAM: usize = 1400;
+
+fn default_interval() -> u64 {
+    10
+}
+
+fn default_power_measurement() -> String {
+    "socket_power".to_owned()
+}
+
+fn default_temperature_measurement() -> String {
+    "therm_temperature".to_owned()
+}
+
+fn default_tags() -> Vec<Tag> {
+    vec![Tag::Device, Tag::Type, Tag::Transport]
+}
+
+fn default_max_bytes() -> u64 {
+    1024 * 1024
+}
+
+fn default_keep() -> usize {
+    3
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum InfluxTarget {
+    /// UDP listener of database, e.g. `{"kind": "udp", "addr": "127.0.0.1:8089"}`
+    Udp { addr: String },
+    /// File is renamed to `<path>.1` when it grows over `max_bytes`, older ones are shifted
+    /// up to `<path>.<keep>`, e.g. `{"kind": "file", "path": "influx/readings.lp"}`
+    File {
+        path: PathBuf,
+        #[serde(default = "default_max_bytes")]
+        max_bytes: u64,
+        #[serde(default = "default_keep")]
+        keep: usize,
+    },
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Tag {
+    Device,
+    Type,
+    Transport,
+}
+
+/// Exporter section of config, e.g.
+/// `{"target": {"kind": "udp", "addr": "127.0.0.1:8089"}, "interval_secs": 10, "tags": ["device", "transport"]}`
+#[derive(Deserialize, Clone, Debug)]
+pub struct InfluxConfig {
+    pub target: InfluxTarget,
+    #[serde(default = "default_interval")]
+    pub interval_secs: u64,
+    #[serde(default = "default_power_measurement")]
+    pub power_measurement: String,
+    #[serde(default = "default_temperature_measurement")]
+    pub temperature_measurement: String,
+    #[serde(default = "default_tags")]
+    pub tags: Vec<Tag>,
+}
+
+/// Escapes commas, spaces and equal signs of tag keys and values
+fn escape_tag(value: &str) -> String {
+    value.replace('\\', "\\\\").replace(',', "\\,").replace('=', "\\=").replace(' ', "\\ ")
+}
+
+fn escape_measurement(value: &str) -> String {
+    value.replace('\\', "\\\\").replace(',', "\\,").replace(' ', "\\ ")
+}
+
+enum Sink {
+    Udp { sock: UdpSocket, addr: String },
+    File { path: PathBuf, max_bytes: u64, keep: usize },
+}
+
+impl Sink {
+    fn open(target: &InfluxTarget) -> std::io::Result<Self> {
+        match target {
+            InfluxTarget::Udp { addr } => Ok(Sink::Udp {
+                sock: UdpSocket::bind("0.0.0.0:0")?,
+                addr: addr.clone(),
+            }),
+            InfluxTarget::File { path, max_bytes, keep } => {
+                if let Some(dir) = path.parent() {
+                    fs::create_dir_all(dir)?;
+                }
+                Ok(Sink::File {
+                    path: path.clone(),
+                    max_bytes: *max_bytes,
+                    keep: *keep,
+                })
+            }
+        }
+    }
+
+    fn write(&self, lines: &[String]) -> std::io::Result<()> {
+        match self {
+            Sink::Udp { sock, addr } => {
+                let mut datagram = String::new();
+                for line in lines {
+                    if !datagram.is_empty() && datagram.len() + line.len() + 1 > MAX_DATAGRAM {
+                        sock.send_to(datagram.as_bytes(), addr)?;
+                        datagram.clear();
+                    }
+                    datagram.push_str(line);
+                    datagram.push('\n');
+                }
+                if !datagram.is_empty() {
+                    sock.send_to(datagram.as_bytes(), addr)?;
+                }
+                Ok(())
+            }
+            Sink::File { path, max_bytes, keep } => {
+                let size = fs::metadata(path).map_or(0, |meta| meta.len());
+                if size > 0 && size >= *max_bytes {
+                    rotate(path, *keep)?;
+                }
+                let mut file: File = OpenOptions::new().create(true).append(true).open(path)?;
+                let mut data = lines.join("\n");
+                data.push('\n');
+                file.write_all(data.as_bytes())
+            }
+        }
+    }
+}
+
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{n}"));
+    PathBuf::from(name)
+}
+
+/// Shifts `<path>.N` to `<path>.N+1`, the oldest file is dropped
+fn rotate(path: &Path, keep: usize) -> std::io::Result<()> {
+    if keep == 0 {
+        return fs::remove_file(path);
+    }
+    let _ = fs::remove_file(rotated(path, keep));
+    for n in (1..keep).rev() {
+        let from = rotated(path, n);
+        if from.exists() {
+            fs::rename(from, rotated(path, n + 1))?;
+        }
+    }
+    info!("Influx file {:?} is rotated", path);
+    fs::rename(path, rotated(path, 1))
+}
+
+/// Periodically pushes power of sockets and temperature of thermometers of
+/// all servers as InfluxDB line protocol.
+pub struct InfluxExporter {
+    config: Option<InfluxConfig>,
+    servers: Vec<(&'static str, SharedDevices)>,
+    rx: Option<Receiver<ConsoleCmd>>,
+}
+
+impl Service for InfluxExporter {
+    fn start_service(mut self, rx: Receiver<ConsoleCmd>) -> JoinHandle<()> {
+        self.rx = Some(rx);
+        self.start()
+    }
+
+    fn rx(&self) -> &Receiver<ConsoleCmd> {
+        self.rx.as_ref().unwrap()
+    }
+}
+
+impl InfluxExporter {
+    /// Exporter is disabled if config has no influx section
+    pub fn new(config_path: &Path, servers: Vec<(&'static str, SharedDevices)>) -> Self {
+        let config = config_section(config_path, "influx");
+        Self::from_config(config, servers)
+    }
+
+    pub fn from_config(config: Option<InfluxConfig>, servers: Vec<(&'static str, SharedDevices)>) -> Self {
+        Self {
+            config,
+            servers,
+            rx: None,
+        }
+    }
+
+    pub fn name() -> &'static str {
+        "InfluxExporter"
+    }
+
+    fn start(self) -> JoinHandle<()> {
+        thread::spawn(move || {
+            let sink = self.config.as_ref().map(|config| match Sink::open(&config.target) {
+                Ok(sink) => sink,
+                Err(e) => {
+                    error!("Can't open influx target {:?}: {:?}", config.target, e);
+                    panic!();
+                }
+            });
+            let mut next_export = Local::now();
+            loop {
+                if let Some(ConsoleCmd::Exit) = self.get_cmd() {
+                    break;
+                }
+                let now = Local::now();
+                if let (Some(config), Some(sink)) = (self.config.as_ref(), sink.as_ref()) {
+                    if now >= next_export {
+                        next_export = now + chrono::Duration::seconds(config.interval_secs as i64);
+                        self.export(config, sink);
+                    }
+                }
+                thread::sleep(Duration::from_millis(100));
+            }
+        })
+    }
+
+    fn export(&self, config: &InfluxConfig, sink: &Sink) {
+        let lines = self.lines(config);
+        if lines.is_empty() {
+            return;
+        }
+        if let Err(e) = sink.write(&lines) {
+            warn!("Can't export {} readings to influx: {:?}", lines.len(), e);
+        }
+    }
+
+    /// Readings of sensors turned off are skipped, they have no actual value.
+    fn lines(&self, config: &InfluxConfig) -> Vec<String> {
+        let mut lines = Vec::new();
+        for (transport, devices) in self.servers.iter() {
+            let mut devices = devices.lock().unwrap();
+            let mut names: Vec<String> = devices.keys().cloned().collect();
+            names.sort();
+            for name in names {
+                let dev = devices.get_mut(&name).unwrap();
+                let type_dev = dev.type_dev();
+                let (reading, measurement) = match type_dev {
+                    SOCKET_TYPE => (Reading::Power, &config.power_measurement),
+                    THERM_TYPE => (Reading::Temperature, &config.temperature_measurement),
+                    _ => continue,
+                };
+                let sample = match dev.sample(reading) {
+                    Ok(sample) if sample.quality != Quality::Stale => sample,
+                    Ok(_) => continue,
+                    Err(e) => {
+                        debug!("Reading {:?} of {name} is unavailable: {:?}", reading, e);
+                        continue;
+                    }
+                };
+                let mut line = escape_measurement(measurement);
+                for tag in config.tags.iter() {
+                    let (key, value) = match tag {
+                        Tag::Device => ("device", name.clone()),
+                        Tag::Type => ("type", type_dev.to_owned()),
+                        Tag::Transport => ("transport", transport.to_string()),
+                    };
+                    line.push_str(&format!(",{key}={}", escape_tag(&value)));
+                }
+                line.push_str(&format!(" value={} {}", sample.value, timestamp(sample.time)));
+                lines.push(line);
+            }
+        }
+        lines
+    }
+}
+
+fn timestamp(time: DateTime<Local>) -> i64 {
+    time.timestamp_nanos_opt().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use crate::device::test_devices;
+    use crate::protocol::Cmd;
+    use serde_json::json;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("smart_server_influx_{name}_{}", std::process::id()))
+    }
+
+    fn server() -> SharedDevices {
+        let mut devices = test_devices("influx", vec![
+            json!({"name": "sock 1", "type": "socket", "model": {"kind": "gauss", "mean": 1500.0, "spread": 0.0}}),
+            json!({"name": "therm1", "type": "therm"}),
+            json!({"name": "lamp1", "type": "lamp"}),
+        ]);
+        for dev in devices.values_mut() {
+            dev.execute(&Cmd::TurnOn).unwrap();
+        }
+        Arc::new(Mutex::new(devices))
+    }
+
+    #[test]
+    fn test_udp_export() {
+        let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+        listener.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let config: InfluxConfig = serde_json::from_value(json!({
+            "target": {"kind": "udp", "addr": listener.local_addr().unwrap().to_string()},
+            "power_measurement": "power",
+            "tags": ["device", "transport"]
+        })).unwrap();
+        let exporter = InfluxExporter::from_config(Some(config.clone()), vec![("tcp", server())]);
+        exporter.export(&config, &Sink::open(&config.target).unwrap());
+
+        let mut buf = [0; MAX_DATAGRAM];
+        let len = listener.recv(&mut buf).unwrap();
+        let lines: Vec<&str> = std::str::from_utf8(&buf[..len]).unwrap().lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("power,device=sock\\ 1,transport=tcp value=1500 "));
+        assert!(lines[1].starts_with("therm_temperature,device=therm1,transport=tcp value="));
+        let timestamp: i64 = lines[0].rsplit(' ').next().unwrap().parse().unwrap();
+        assert!((Local::now().timestamp_nanos_opt().unwrap() - timestamp).abs() < 10_000_000_000);
+    }
+
+    #[test]
+    fn test_file_rotation() {
+        let dir = temp_dir("rotation");
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.join("readings.lp");
+        let config: InfluxConfig = serde_json::from_value(json!({
+            "target": {"kind": "file", "path": path, "max_bytes": 100, "keep": 2}
+        })).unwrap();
+        let exporter = InfluxExporter::from_config(Some(config.clone()), vec![("udp", server())]);
+        let sink = Sink::open(&config.target).unwrap();
+        for _ in 0..4 {
+            exporter.export(&config, &sink);
+        }
+
+        let content = fs::read_to_string(&path).unwrap();
+        assert_eq!(content.lines().count(), 2);
+        assert!(content.lines().all(|line| line.contains(",type=") && line.contains(",transport=udp ")));
+        assert!(rotated(&path, 1).exists() && rotated(&path, 2).exists());
+        assert!(!rotated(&path, 3).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -5,6 +5,23 @@ mod err_house;
 mod device;
 mod console_server;
 mod protocol;
+mod energy;
+mod request_handler;
+mod rules;
+mod power_budget;
+mod scheduler;
+mod thermostat;
+mod scene;
+mod house;
+mod http;
+mod metrics;
+mod influx;
+mod rest;
+mod ws;
+mod mqtt;
+mod modbus;
+mod coap;
+mod grpc;
 
 use console_server::ConsoleServer;
 use log4rs::append::file::FileAppender;
@@ -0,0 +1,292 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, TcpListener};
+use std::path::Path;
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use chrono::{DateTime, Local};
+use serde::Deserialize;
+use log::*;
+
+use super::console_server::{bind_listener, config_section, ConsoleCmd, Service};
+use super::device::{Reading, SharedDevices};
+use super::http;
+use super::protocol::{self, Cmd, Quality, ResponseKind};
+
+/// Upper bounds of latency buckets in seconds
+const LATENCY_BUCKETS: &[f64] = &[0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1];
+/// Client without connection, e.g. udp one, is counted while it sends requests this often
+const CLIENT_TIMEOUT_SECS: i64 = 60;
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+pub type SharedMetrics = Arc<Mutex<Metrics>>;
+
+/// Metrics section of config, e.g. `{"addr": "127.0.0.1:9898"}`
+#[derive(Deserialize, Clone)]
+pub struct MetricsConfig {
+    pub addr: String,
+}
+
+#[derive(Default)]
+struct Histogram {
+    /// Count of observations in every bucket, not cumulative
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if let Some(pos) = LATENCY_BUCKETS.iter().position(|bound| value <= *bound) {
+            self.buckets[pos] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// Request statistics of servers, labeled by transport
+#[derive(Default)]
+pub struct Metrics {
+    requests: BTreeMap<(&'static str, String), u64>,
+    errors: BTreeMap<(&'static str, String), u64>,
+    latency: BTreeMap<&'static str, Histogram>,
+    connected: BTreeMap<&'static str, usize>,
+    last_seen: HashMap<SocketAddr, (&'static str, DateTime<Local>)>,
+}
+
+/// Name of command without arguments, e.g. `SetLevel`
+fn cmd_label(cmd: &Cmd) -> String {
+    let name = format!("{:?}", cmd);
+    name.split(['(', ' ', '{']).next().unwrap_or_default().to_owned()
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+impl Metrics {
+    /// Transport labels request in metrics, e.g. "tcp"
+    pub fn record(&mut self, transport: &'static str, resp: &protocol::Response, elapsed: Duration) {
+        *self.requests.entry((transport, cmd_label(&resp.to_req.cmd))).or_default() += 1;
+        if let ResponseKind::Err(e) = &resp.resp_kind {
+            let kind = protocol::ErrorKind::from_code(e.code).map_or_else(|| e.code.to_string(), |kind| format!("{:?}", kind));
+            *self.errors.entry((transport, kind)).or_default() += 1;
+        }
+        self.latency.entry(transport).or_default().observe(elapsed.as_secs_f64());
+    }
+
+    /// Count of clients of transport keeping connection
+    pub fn set_connected(&mut self, transport: &'static str, count: usize) {
+        self.connected.insert(transport, count);
+    }
+
+    /// Request of client of transport without connection
+    pub fn client_seen(&mut self, transport: &'static str, addr: SocketAddr, now: DateTime<Local>) {
+        self.last_seen.insert(addr, (transport, now));
+    }
+
+    pub fn render(&mut self, out: &mut String, now: DateTime<Local>) {
+        write_header(out, "smart_server_requests_total", "counter", "Requests handled by command and transport");
+        for ((transport, cmd), count) in self.requests.iter() {
+            let _ = writeln!(out, "smart_server_requests_total{{transport=\"{transport}\",cmd=\"{cmd}\"}} {count}");
+        }
+
+        write_header(out, "smart_server_errors_total", "counter", "Error responses by kind and transport");
+        for ((transport, kind), count) in self.errors.iter() {
+            let _ = writeln!(out, "smart_server_errors_total{{transport=\"{transport}\",kind=\"{kind}\"}} {count}");
+        }
+
+        write_header(out, "smart_server_request_duration_seconds", "histogram", "Time of handling request");
+        for (transport, histogram) in self.latency.iter() {
+            let mut cumulative = 0;
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+                cumulative += count;
+                let _ = writeln!(out, "smart_server_request_duration_seconds_bucket{{transport=\"{transport}\",le=\"{bound}\"}} {cumulative}");
+            }
+            let _ = writeln!(out, "smart_server_request_duration_seconds_bucket{{transport=\"{transport}\",le=\"+Inf\"}} {}", histogram.count);
+            let _ = writeln!(out, "smart_server_request_duration_seconds_sum{{transport=\"{transport}\"}} {}", histogram.sum);
+            let _ = writeln!(out, "smart_server_request_duration_seconds_count{{transport=\"{transport}\"}} {}", histogram.count);
+        }
+
+        self.last_seen.retain(|_, (_, at)| (now - *at).num_seconds() < CLIENT_TIMEOUT_SECS);
+        let mut connected = self.connected.clone();
+        for (transport, _) in self.last_seen.values() {
+            *connected.entry(transport).or_default() += 1;
+        }
+        write_header(out, "smart_server_connected_clients", "gauge", "Clients connected or recently seen");
+        for (transport, count) in connected {
+            let _ = writeln!(out, "smart_server_connected_clients{{transport=\"{transport}\"}} {count}");
+        }
+    }
+}
+
+/// Serves `/metrics` in Prometheus text format: device readings of all
+/// servers and request statistics they collect.
+pub struct MetricsServer {
+    config: Option<MetricsConfig>,
+    servers: Vec<(&'static str, SharedDevices)>,
+    metrics: SharedMetrics,
+    rx: Option<Receiver<ConsoleCmd>>,
+}
+
+impl Service for MetricsServer {
+    fn start_service(mut self, rx: Receiver<ConsoleCmd>) -> JoinHandle<()> {
+        self.rx = Some(rx);
+        self.start()
+    }
+
+    fn rx(&self) -> &Receiver<ConsoleCmd> {
+        self.rx.as_ref().unwrap()
+    }
+}
+
+impl MetricsServer {
+    /// Server is disabled if config has no metrics section
+    pub fn new(config_path: &Path, servers: Vec<(&'static str, SharedDevices)>, metrics: SharedMetrics) -> Self {
+        let config = config_section(config_path, "metrics");
+        Self {
+            config,
+            servers,
+            metrics,
+            rx: None,
+        }
+    }
+
+    pub fn name() -> &'static str {
+        "MetricsServer"
+    }
+
+    fn bind(config: &MetricsConfig) -> TcpListener {
+        let listener = bind_listener(&config.addr);
+        info!("Metrics are served on http://{}/metrics", config.addr);
+        listener
+    }
+
+    fn start(self) -> JoinHandle<()> {
+        thread::spawn(move || {
+            let listener = self.config.as_ref().map(Self::bind);
+            loop {
+                if let Some(ConsoleCmd::Exit) = self.get_cmd() {
+                    break;
+                }
+                match listener.as_ref().map(|listener| listener.accept()) {
+                    Some(Ok((stream, _))) => http::serve(stream, |req| self.handle(req)),
+                    Some(Err(e)) if e.kind() != ErrorKind::WouldBlock => {
+                        error!("Invalid listener: {:?}", e);
+                        break;
+                    }
+                    _ => thread::sleep(Duration::from_millis(100)),
+                }
+            }
+        })
+    }
+
+    fn handle(&self, req: &http::Request) -> http::Response {
+        match (req.method.as_str(), req.path.as_str()) {
+            ("GET", "/metrics") => http::Response::new(200, CONTENT_TYPE, self.render(Local::now())),
+            (_, "/metrics") => http::Response::text(405, "Only GET is supported\n"),
+            _ => http::Response::text(404, "Not found\n"),
+        }
+    }
+
+    /// Readings are sampled on scrape
+    fn render(&self, now: DateTime<Local>) -> String {
+        let (mut states, mut power, mut temperature) = (String::new(), String::new(), String::new());
+        for (transport, devices) in self.servers.iter() {
+            let mut devices = devices.lock().unwrap();
+            let mut names: Vec<String> = devices.keys().cloned().collect();
+            names.sort();
+            for name in names {
+                let dev = devices.get_mut(&name).unwrap();
+                let labels = format!("device=\"{}\",type=\"{}\",transport=\"{transport}\"", escape(&name), dev.type_dev());
+                let _ = writeln!(states, "smart_server_device_on{{{labels}}} {}", dev.is_on() as u8);
+                for (reading, out) in [(Reading::Power, &mut power), (Reading::Temperature, &mut temperature)] {
+                    if !dev.readings().contains(&reading) {
+                        continue;
+                    }
+                    let metric = if reading == Reading::Power { "smart_server_power_watts" } else { "smart_server_temperature_celsius" };
+                    // Sensor turned off has no actual value, it's left out of scrape
+                    match dev.sample(reading) {
+                        Ok(sample) if sample.quality == Quality::Stale => {}
+                        Ok(sample) => {
+                            let _ = writeln!(out, "{metric}{{{labels}}} {}", sample.value);
+                        }
+                        Err(e) => debug!("Reading {:?} of {name} is unavailable: {:?}", reading, e),
+                    }
+                }
+            }
+        }
+
+        let mut out = String::new();
+        write_header(&mut out, "smart_server_device_on", "gauge", "Device is turned on");
+        out.push_str(&states);
+        write_header(&mut out, "smart_server_power_watts", "gauge", "Power of socket");
+        out.push_str(&power);
+        write_header(&mut out, "smart_server_temperature_celsius", "gauge", "Temperature of thermometer");
+        out.push_str(&temperature);
+        self.metrics.lock().unwrap().render(&mut out, now);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::test_devices;
+    use serde_json::json;
+
+    #[test]
+    fn test_render() {
+        let mut devices = test_devices("metrics", vec![
+            json!({"name": "sock1", "type": "socket", "model": {"kind": "gauss", "mean": 1500.0, "spread": 0.0}}),
+            json!({"name": "lamp1", "type": "lamp"}),
+            json!({"name": "therm1", "type": "therm"}),
+        ]);
+        devices.get_mut("sock1").unwrap().execute(&Cmd::TurnOn).unwrap();
+
+        let metrics = SharedMetrics::default();
+        let server = MetricsServer {
+            config: None,
+            servers: vec![("tcp", Arc::new(Mutex::new(devices)))],
+            metrics: metrics.clone(),
+            rx: None,
+        };
+        let now = Local::now();
+        {
+            let mut metrics = metrics.lock().unwrap();
+            let req = protocol::Request::new(Cmd::SetLevel(40.0), "lamp1".to_owned());
+            metrics.record("tcp", &protocol::Response::new_success_response(req.clone(), protocol::SuccessKind::Ack), Duration::from_micros(300));
+            metrics.record("tcp", &protocol::Response::new_err_response(req, protocol::ErrorKind::DevOff.into()), Duration::from_millis(1));
+            metrics.set_connected("tcp", 1);
+            metrics.client_seen("udp", "127.0.0.1:5000".parse().unwrap(), now - chrono::Duration::seconds(10));
+            metrics.client_seen("udp", "127.0.0.1:5001".parse().unwrap(), now - chrono::Duration::seconds(100));
+        }
+
+        let out = server.render(now);
+        for line in [
+            "smart_server_device_on{device=\"lamp1\",type=\"lamp\",transport=\"tcp\"} 0",
+            "smart_server_device_on{device=\"sock1\",type=\"socket\",transport=\"tcp\"} 1",
+            "smart_server_power_watts{device=\"sock1\",type=\"socket\",transport=\"tcp\"} 1500",
+            "smart_server_requests_total{transport=\"tcp\",cmd=\"SetLevel\"} 2",
+            "smart_server_errors_total{transport=\"tcp\",kind=\"DevOff\"} 1",
+            "smart_server_request_duration_seconds_bucket{transport=\"tcp\",le=\"0.0005\"} 1",
+            "smart_server_request_duration_seconds_bucket{transport=\"tcp\",le=\"0.001\"} 2",
+            "smart_server_request_duration_seconds_bucket{transport=\"tcp\",le=\"+Inf\"} 2",
+            "smart_server_connected_clients{transport=\"tcp\"} 1",
+            "smart_server_connected_clients{transport=\"udp\"} 1",
+        ] {
+            assert!(out.lines().any(|l| l == line), "{line} not found in:\n{out}");
+        }
+        assert!(!out.contains("temperature_celsius{"));
+    }
+}
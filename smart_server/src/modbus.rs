@@ -0,0 +1,499 @@
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::mpsc::Receiver;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use log::*;
+
+use super::console_server::{bind_listener, config_section, ConsoleCmd, Service};
+use super::device::{handle_on_owner, Reading, SharedDevices};
+use super::metrics::SharedMetrics;
+use super::protocol::{self, Cmd, Quality, ResponseKind, SuccessKind, Unit};
+
+const TRANSPORT: &str = "modbus";
+/// Period of server loop, requests of clients are answered within it
+const POLL_MS: u64 = 20;
+const MAX_CLIENTS: usize = 16;
+/// Transaction id, protocol id, length and unit id
+const MBAP_LEN: usize = 7;
+/// Longest PDU allowed by specification
+const MAX_PDU: usize = 253;
+const MAX_READ_COILS: u16 = 2000;
+const MAX_READ_REGISTERS: u16 = 125;
+const MAX_WRITE_COILS: u16 = 1968;
+
+const READ_COILS: u8 = 0x01;
+const READ_INPUT_REGISTERS: u8 = 0x04;
+const WRITE_SINGLE_COIL: u8 = 0x05;
+const WRITE_MULTIPLE_COILS: u8 = 0x0f;
+
+/// Exception codes of Modbus
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Exception {
+    IllegalFunction = 0x01,
+    IllegalDataAddress = 0x02,
+    IllegalDataValue = 0x03,
+    ServerDeviceFailure = 0x04,
+}
+
+/// Scale keeping reading of usual range within 16-bit register: power
+/// of socket up to 32 kW in watts, temperature in tenths of degree
+fn default_scale(reading: Reading) -> f64 {
+    match reading {
+        Reading::Temperature => 10.0,
+        _ => 1.0,
+    }
+}
+
+/// Input register holding reading multiplied by scale as signed 16-bit integer,
+/// e.g. 21.5 °C is 215 with scale 10
+#[derive(Deserialize, Clone)]
+pub struct RegisterConfig {
+    pub register: u16,
+    /// Default scale depends on reading, see `default_scale`
+    pub scale: Option<f64>,
+}
+
+/// Registers of device, e.g. `{"name": "sock1", "coil": 0, "power": {"register": 0}}`
+#[derive(Deserialize, Clone)]
+pub struct DeviceMap {
+    pub name: String,
+    /// Coil turning device on and off
+    pub coil: Option<u16>,
+    /// Power in watts
+    pub power: Option<RegisterConfig>,
+    /// Temperature in degrees Celsius
+    pub temperature: Option<RegisterConfig>,
+}
+
+/// Server section of config, e.g. `{"addr": "127.0.0.1:5020", "devices": [...]}`
+#[derive(Deserialize, Clone)]
+pub struct ModbusConfig {
+    pub addr: String,
+    pub devices: Vec<DeviceMap>,
+}
+
+struct InputRegister {
+    dev_name: String,
+    reading: Reading,
+    scale: f64,
+}
+
+/// Addresses of register map
+#[derive(Default)]
+struct RegisterMap {
+    coils: BTreeMap<u16, String>,
+    inputs: BTreeMap<u16, InputRegister>,
+}
+
+impl RegisterMap {
+    /// Panics on address used twice
+    fn new(devices: &[DeviceMap]) -> Self {
+        let mut map = Self::default();
+        for dev in devices {
+            if let Some(coil) = dev.coil {
+                if map.coils.insert(coil, dev.name.clone()).is_some() {
+                    panic!("Wrong input config: modbus coil {coil} is used twice");
+                }
+            }
+            for (reading, register) in [(Reading::Power, &dev.power), (Reading::Temperature, &dev.temperature)] {
+                let Some(register) = register else {
+                    continue;
+                };
+                let scale = register.scale.unwrap_or(default_scale(reading));
+                assert!(scale > 0.0 && scale.is_finite(), "Wrong input config: modbus scale of {} isn't positive", dev.name);
+                let input = InputRegister {
+                    dev_name: dev.name.clone(),
+                    reading,
+                    scale,
+                };
+                if map.inputs.insert(register.register, input).is_some() {
+                    panic!("Wrong input config: modbus input register {} is used twice", register.register);
+                }
+            }
+        }
+        map
+    }
+}
+
+fn u16_at(data: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([*data.get(pos)?, *data.get(pos + 1)?]))
+}
+
+/// Start address and quantity of request, quantity must be in `1..=max`
+fn range(data: &[u8], max: u16) -> Result<(u16, u16), Exception> {
+    let (Some(start), Some(quantity)) = (u16_at(data, 0), u16_at(data, 2)) else {
+        return Err(Exception::IllegalDataValue);
+    };
+    if quantity == 0 || quantity > max {
+        return Err(Exception::IllegalDataValue);
+    }
+    if start.checked_add(quantity - 1).is_none() {
+        return Err(Exception::IllegalDataAddress);
+    }
+    Ok((start, quantity))
+}
+
+/// Bits packed from the lowest bit of the first byte
+fn pack_bits(bits: &[bool]) -> Vec<u8> {
+    let mut res = vec![0; bits.len().div_ceil(8)];
+    for (i, _) in bits.iter().enumerate().filter(|(_, bit)| **bit) {
+        res[i / 8] |= 1 << (i % 8);
+    }
+    res
+}
+
+/// Reading in register, out of range values are saturated
+fn scaled(value: f64, scale: f64) -> u16 {
+    (value * scale).round().clamp(i16::MIN as f64, i16::MAX as f64) as i16 as u16
+}
+
+struct Client {
+    stream: TcpStream,
+    addr: SocketAddr,
+    /// Received bytes of incomplete frame
+    buf: Vec<u8>,
+}
+
+/// Serves Modbus TCP: coils turn devices on and off, input registers hold
+/// power and temperature. Registers are mapped to devices by config.
+pub struct ModbusServer {
+    config: Option<ModbusConfig>,
+    map: RegisterMap,
+    servers: Vec<(&'static str, SharedDevices)>,
+    metrics: SharedMetrics,
+    clients: Vec<Client>,
+    rx: Option<Receiver<ConsoleCmd>>,
+}
+
+impl Service for ModbusServer {
+    fn start_service(mut self, rx: Receiver<ConsoleCmd>) -> JoinHandle<()> {
+        self.rx = Some(rx);
+        self.start()
+    }
+
+    fn rx(&self) -> &Receiver<ConsoleCmd> {
+        self.rx.as_ref().unwrap()
+    }
+}
+
+impl ModbusServer {
+    /// Server is disabled if config has no modbus section
+    pub fn new(config_path: &Path, servers: Vec<(&'static str, SharedDevices)>, metrics: SharedMetrics) -> Self {
+        let config = config_section(config_path, "modbus");
+        Self::from_config(config, servers, metrics)
+    }
+
+    pub fn from_config(config: Option<ModbusConfig>, servers: Vec<(&'static str, SharedDevices)>, metrics: SharedMetrics) -> Self {
+        let map = config.as_ref().map_or_else(RegisterMap::default, |config| RegisterMap::new(&config.devices));
+        for dev_name in map.coils.values().chain(map.inputs.values().map(|input| &input.dev_name)) {
+            if !servers.iter().any(|(_, devices)| devices.lock().unwrap().contains_key(dev_name)) {
+                warn!("Modbus: device {dev_name} of register map not found");
+            }
+        }
+        Self {
+            config,
+            map,
+            servers,
+            metrics,
+            clients: Vec::new(),
+            rx: None,
+        }
+    }
+
+    pub fn name() -> &'static str {
+        "ModbusServer"
+    }
+
+    fn bind(config: &ModbusConfig) -> TcpListener {
+        let listener = bind_listener(&config.addr);
+        info!("Modbus server is served on {}", config.addr);
+        listener
+    }
+
+    fn start(mut self) -> JoinHandle<()> {
+        thread::spawn(move || {
+            let listener = self.config.as_ref().map(Self::bind);
+            loop {
+                if let Some(ConsoleCmd::Exit) = self.get_cmd() {
+                    break;
+                }
+                if let Some(listener) = listener.as_ref() {
+                    match listener.accept() {
+                        Ok((stream, addr)) => self.accept(stream, addr),
+                        Err(e) if e.kind() != io::ErrorKind::WouldBlock => {
+                            error!("Invalid listener: {:?}", e);
+                            break;
+                        }
+                        Err(_) => {}
+                    }
+                }
+                self.poll();
+                thread::sleep(Duration::from_millis(POLL_MS));
+            }
+        })
+    }
+
+    fn accept(&mut self, stream: TcpStream, addr: SocketAddr) {
+        if self.clients.len() >= MAX_CLIENTS {
+            info!("Modbus client {addr} is rejected, too many clients");
+            return;
+        }
+        if let Err(e) = stream.set_nonblocking(true) {
+            error!("Can't set nonblocking modbus stream: {e}");
+            return;
+        }
+        info!("Modbus client {addr} connected");
+        self.clients.push(Client {
+            stream,
+            addr,
+            buf: Vec::new(),
+        });
+        self.metrics.lock().unwrap().set_connected(TRANSPORT, self.clients.len());
+    }
+
+    /// Answers complete frames of clients, closed clients are dropped
+    fn poll(&mut self) {
+        let mut clients = std::mem::take(&mut self.clients);
+        clients.retain_mut(|client| match self.poll_client(client) {
+            Ok(()) => true,
+            Err(e) => {
+                info!("Modbus client {} disconnected: {e}", client.addr);
+                false
+            }
+        });
+        self.clients = clients;
+        self.metrics.lock().unwrap().set_connected(TRANSPORT, self.clients.len());
+    }
+
+    fn poll_client(&self, client: &mut Client) -> io::Result<()> {
+        let mut chunk = [0; MBAP_LEN + MAX_PDU];
+        loop {
+            match client.stream.read(&mut chunk) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(len) => client.buf.extend_from_slice(&chunk[..len]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        while client.buf.len() >= MBAP_LEN {
+            let (Some(protocol_id), Some(len)) = (u16_at(&client.buf, 2), u16_at(&client.buf, 4)) else {
+                break;
+            };
+            let len = len as usize;
+            if protocol_id != 0 || !(2..=MAX_PDU + 1).contains(&len) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid modbus header"));
+            }
+            if client.buf.len() < 6 + len {
+                break;
+            }
+            let frame: Vec<u8> = client.buf.drain(..6 + len).collect();
+            let pdu = self.handle_pdu(&frame[MBAP_LEN..]);
+            let mut resp = frame[..4].to_vec();
+            resp.extend_from_slice(&(pdu.len() as u16 + 1).to_be_bytes());
+            resp.push(frame[6]);
+            resp.extend_from_slice(&pdu);
+            // Responses are short, stream is blocking while they are written
+            client.stream.set_nonblocking(false)?;
+            client.stream.write_all(&resp)?;
+            client.stream.set_nonblocking(true)?;
+        }
+        Ok(())
+    }
+
+    /// Response PDU of request PDU, exception response on error
+    fn handle_pdu(&self, pdu: &[u8]) -> Vec<u8> {
+        let function = pdu[0];
+        let res = match function {
+            READ_COILS => self.read_coils(&pdu[1..]),
+            READ_INPUT_REGISTERS => self.read_input_registers(&pdu[1..]),
+            WRITE_SINGLE_COIL => self.write_single_coil(&pdu[1..]),
+            WRITE_MULTIPLE_COILS => self.write_multiple_coils(&pdu[1..]),
+            _ => Err(Exception::IllegalFunction),
+        };
+        match res {
+            Ok(data) => [&[function], data.as_slice()].concat(),
+            Err(e) => {
+                debug!("Modbus function {function:#04x} failed: {:?}", e);
+                vec![function | 0x80, e as u8]
+            }
+        }
+    }
+
+    fn read_coils(&self, data: &[u8]) -> Result<Vec<u8>, Exception> {
+        let (start, quantity) = range(data, MAX_READ_COILS)?;
+        let mut bits = Vec::with_capacity(quantity as usize);
+        for addr in start..=start + (quantity - 1) {
+            let dev_name = self.map.coils.get(&addr).ok_or(Exception::IllegalDataAddress)?;
+            match self.execute(dev_name, Cmd::GetState)? {
+                SuccessKind::State(state) => bits.push(state.is_on),
+                _ => return Err(Exception::ServerDeviceFailure),
+            }
+        }
+        let bytes = pack_bits(&bits);
+        Ok([&[bytes.len() as u8], bytes.as_slice()].concat())
+    }
+
+    /// Readings of devices which are off are zero
+    fn read_input_registers(&self, data: &[u8]) -> Result<Vec<u8>, Exception> {
+        let (start, quantity) = range(data, MAX_READ_REGISTERS)?;
+        let mut res = vec![(quantity * 2) as u8];
+        for addr in start..=start + (quantity - 1) {
+            let input = self.map.inputs.get(&addr).ok_or(Exception::IllegalDataAddress)?;
+            let cmd = match input.reading {
+                Reading::Power => Cmd::Power,
+                _ => Cmd::Temperature,
+            };
+            let measurement = match self.execute(&input.dev_name, cmd)? {
+                SuccessKind::Power(measurement) | SuccessKind::Temp(measurement) => measurement,
+                _ => return Err(Exception::ServerDeviceFailure),
+            };
+            let value = if measurement.quality == Quality::Stale { 0.0 } else { measurement.value };
+            res.extend_from_slice(&scaled(value, input.scale).to_be_bytes());
+        }
+        Ok(res)
+    }
+
+    fn write_single_coil(&self, data: &[u8]) -> Result<Vec<u8>, Exception> {
+        let (Some(addr), Some(value)) = (u16_at(data, 0), u16_at(data, 2)) else {
+            return Err(Exception::IllegalDataValue);
+        };
+        let is_on = match value {
+            0xff00 => true,
+            0x0000 => false,
+            _ => return Err(Exception::IllegalDataValue),
+        };
+        let dev_name = self.map.coils.get(&addr).ok_or(Exception::IllegalDataAddress)?;
+        self.switch(dev_name, is_on)?;
+        Ok(data[..4].to_vec())
+    }
+
+    /// Coils are written in order, writing stops at the first failed device
+    fn write_multiple_coils(&self, data: &[u8]) -> Result<Vec<u8>, Exception> {
+        let (start, quantity) = range(data, MAX_WRITE_COILS)?;
+        let bytes = data.get(5..).ok_or(Exception::IllegalDataValue)?;
+        if data[4] as usize != (quantity as usize).div_ceil(8) || bytes.len() != data[4] as usize {
+            return Err(Exception::IllegalDataValue);
+        }
+        let mut dev_names = Vec::with_capacity(quantity as usize);
+        for addr in start..=start + (quantity - 1) {
+            dev_names.push(self.map.coils.get(&addr).ok_or(Exception::IllegalDataAddress)?);
+        }
+        for (i, dev_name) in dev_names.into_iter().enumerate() {
+            self.switch(dev_name, bytes[i / 8] & (1 << (i % 8)) != 0)?;
+        }
+        Ok(data[..4].to_vec())
+    }
+
+    fn switch(&self, dev_name: &str, is_on: bool) -> Result<(), Exception> {
+        self.execute(dev_name, if is_on { Cmd::TurnOn } else { Cmd::TurnOff }).map(|_| ())
+    }
+
+    /// Executes command on server owning device in watts and degrees Celsius
+    fn execute(&self, dev_name: &str, cmd: Cmd) -> Result<SuccessKind, Exception> {
+        let mut req = protocol::Request::new(cmd, dev_name.to_owned());
+        req.units = vec![Unit::Watt, Unit::Celsius];
+        let started = Instant::now();
+        let resp = handle_on_owner(&self.servers, req);
+        self.metrics.lock().unwrap().record(TRANSPORT, &resp, started.elapsed());
+        match resp.resp_kind {
+            ResponseKind::Success(success) => Ok(success),
+            ResponseKind::Err(e) => {
+                info!("Modbus: command of {dev_name} failed: {:?}", e);
+                Err(Exception::ServerDeviceFailure)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use crate::device::test_devices;
+    use serde_json::json;
+
+    fn server() -> SharedDevices {
+        Arc::new(Mutex::new(test_devices("modbus", vec![
+            json!({"name": "sock1", "type": "socket", "model": {"kind": "gauss", "mean": 2000.0, "spread": 0.0}}),
+            json!({"name": "lamp1", "type": "lamp"}),
+            json!({"name": "therm1", "type": "therm"}),
+        ])))
+    }
+
+    fn modbus() -> ModbusServer {
+        let config: ModbusConfig = serde_json::from_value(json!({
+            "addr": "127.0.0.1:0",
+            "devices": [
+                {"name": "sock1", "coil": 0, "power": {"register": 0}},
+                {"name": "lamp1", "coil": 1, "power": {"register": 1, "scale": 1.0}},
+                {"name": "therm1", "temperature": {"register": 2}},
+            ]
+        })).unwrap();
+        ModbusServer::from_config(Some(config), vec![("tcp", server())], SharedMetrics::default())
+    }
+
+    #[test]
+    fn test_functions() {
+        let modbus = modbus();
+        assert_eq!(modbus.handle_pdu(&[READ_COILS, 0, 0, 0, 2]), [READ_COILS, 1, 0b00]);
+        assert_eq!(modbus.handle_pdu(&[WRITE_SINGLE_COIL, 0, 0, 0xff, 0]), [WRITE_SINGLE_COIL, 0, 0, 0xff, 0]);
+        assert_eq!(modbus.handle_pdu(&[READ_COILS, 0, 0, 0, 2]), [READ_COILS, 1, 0b01]);
+        assert_eq!(modbus.handle_pdu(&[WRITE_MULTIPLE_COILS, 0, 0, 0, 2, 1, 0b10]), [WRITE_MULTIPLE_COILS, 0, 0, 0, 2]);
+        assert_eq!(modbus.handle_pdu(&[READ_COILS, 0, 0, 0, 2]), [READ_COILS, 1, 0b10]);
+
+        // Socket is off, thermometer is off
+        assert_eq!(modbus.handle_pdu(&[READ_INPUT_REGISTERS, 0, 0, 0, 1]), [READ_INPUT_REGISTERS, 2, 0, 0]);
+        assert_eq!(modbus.handle_pdu(&[READ_INPUT_REGISTERS, 0, 2, 0, 1]), [READ_INPUT_REGISTERS, 2, 0, 0]);
+        modbus.handle_pdu(&[WRITE_SINGLE_COIL, 0, 0, 0xff, 0]);
+        // Power is in watts and temperature in tenths of degree by default
+        assert_eq!(modbus.handle_pdu(&[READ_INPUT_REGISTERS, 0, 0, 0, 1]), [READ_INPUT_REGISTERS, 2, 0x07, 0xd0]);
+        let registers = RegisterMap::new(&modbus.config.as_ref().unwrap().devices);
+        let scales: Vec<f64> = registers.inputs.values().map(|input| input.scale).collect();
+        assert_eq!(scales, [1.0, 1.0, 10.0]);
+
+        assert_eq!(modbus.handle_pdu(&[0x03, 0, 0, 0, 1]), [0x83, Exception::IllegalFunction as u8]);
+        assert_eq!(modbus.handle_pdu(&[READ_COILS, 0, 1, 0, 2]), [0x81, Exception::IllegalDataAddress as u8]);
+        assert_eq!(modbus.handle_pdu(&[READ_COILS, 0, 0, 0, 0]), [0x81, Exception::IllegalDataValue as u8]);
+        assert_eq!(modbus.handle_pdu(&[WRITE_SINGLE_COIL, 0, 0, 0x12, 0]), [0x85, Exception::IllegalDataValue as u8]);
+        assert_eq!(modbus.handle_pdu(&[WRITE_MULTIPLE_COILS, 0, 0, 0, 2, 1]), [0x8f, Exception::IllegalDataValue as u8]);
+
+        assert_eq!(scaled(-21.54, 10.0), (-215i16) as u16);
+        assert_eq!(scaled(1e6, 10.0), i16::MAX as u16);
+    }
+
+    #[test]
+    fn test_frames() {
+        let mut modbus = modbus();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let (stream, addr) = listener.accept().unwrap();
+        modbus.accept(stream, addr);
+
+        // Two requests, the second one is split between writes
+        client.write_all(&[0, 1, 0, 0, 0, 6, 7, WRITE_SINGLE_COIL, 0, 1, 0xff, 0, 0, 2, 0, 0]).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        modbus.poll();
+        client.write_all(&[0, 6, 9, READ_COILS, 0, 0, 0, 2]).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        modbus.poll();
+
+        let mut resp = [0; 12 + 10];
+        client.read_exact(&mut resp).unwrap();
+        assert_eq!(resp[..12], [0, 1, 0, 0, 0, 6, 7, WRITE_SINGLE_COIL, 0, 1, 0xff, 0]);
+        assert_eq!(resp[12..], [0, 2, 0, 0, 0, 4, 9, READ_COILS, 1, 0b10]);
+        let mut metrics = String::new();
+        modbus.metrics.lock().unwrap().render(&mut metrics, chrono::Local::now());
+        assert!(metrics.contains("transport=\"modbus\""));
+
+        drop(client);
+        thread::sleep(Duration::from_millis(50));
+        modbus.poll();
+        assert!(modbus.clients.is_empty());
+    }
+}
@@ -0,0 +1,561 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::mpsc::Receiver;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Local};
+use rumqttc::{Client, Connection, Event, MqttOptions, Packet, QoS, RecvTimeoutError};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use log::*;
+
+use super::console_server::{config_section, ConsoleCmd, Service};
+use super::device::{handle_on_owner, Reading, SharedDevices, LAMP_TYPE, SOCKET_TYPE};
+use super::metrics::SharedMetrics;
+use super::protocol::{self, Cmd, Quality, ResponseKind};
+
+const TRANSPORT: &str = "mqtt";
+/// Event loop is polled this long, it's also period of service loop
+const POLL_MS: u64 = 100;
+/// Requests queued while broker is unavailable
+const REQUESTS_CAP: usize = 256;
+const DEV: &str = "{dev}";
+
+fn default_port() -> u16 {
+    1883
+}
+
+fn default_client_id() -> String {
+    "smart_server".to_owned()
+}
+
+fn default_qos() -> u8 {
+    1
+}
+
+fn default_publish_interval() -> u64 {
+    10
+}
+
+fn default_reconnect_delay() -> u64 {
+    5
+}
+
+fn default_discovery_prefix() -> String {
+    "homeassistant".to_owned()
+}
+
+/// Topic templates, `{dev}` is replaced by device name
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Topics {
+    pub state: String,
+    pub power: String,
+    pub temperature: String,
+    /// Payload `ON` or `OFF` turns device on or off
+    pub set: String,
+}
+
+impl Default for Topics {
+    fn default() -> Self {
+        Self {
+            state: "house/{dev}/state".to_owned(),
+            power: "house/{dev}/power".to_owned(),
+            temperature: "house/{dev}/temperature".to_owned(),
+            set: "house/{dev}/set".to_owned(),
+        }
+    }
+}
+
+/// Home Assistant discovery, configs are published under `<prefix>/<component>/<node_id>/...`
+#[derive(Deserialize, Clone)]
+pub struct Discovery {
+    #[serde(default = "default_discovery_prefix")]
+    pub prefix: String,
+    #[serde(default = "default_client_id")]
+    pub node_id: String,
+}
+
+/// Bridge section of config, e.g. `{"host": "127.0.0.1", "qos": 1, "discovery": {}}`
+#[derive(Deserialize, Clone)]
+pub struct MqttConfig {
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    #[serde(default = "default_client_id")]
+    pub client_id: String,
+    #[serde(default)]
+    pub topics: Topics,
+    #[serde(default = "default_qos")]
+    pub qos: u8,
+    #[serde(default = "default_publish_interval")]
+    pub publish_interval_secs: u64,
+    #[serde(default = "default_reconnect_delay")]
+    pub reconnect_delay_secs: u64,
+    pub discovery: Option<Discovery>,
+}
+
+fn topic(template: &str, dev_name: &str) -> String {
+    template.replace(DEV, dev_name)
+}
+
+/// Name of device in topic made by template
+fn dev_of<'a>(template: &str, topic: &'a str) -> Option<&'a str> {
+    let (prefix, suffix) = template.split_once(DEV)?;
+    topic.strip_prefix(prefix)?
+        .strip_suffix(suffix)
+        .filter(|name| !name.is_empty() && !name.contains(['/', '+', '#']))
+}
+
+/// Id of Home Assistant entity may contain only letters, digits, `_` and `-`
+fn object_id(name: &str) -> String {
+    name.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' }).collect()
+}
+
+fn on_off(is_on: bool) -> &'static str {
+    if is_on { "ON" } else { "OFF" }
+}
+
+/// Publishes state and readings of devices of all servers as retained
+/// messages and turns devices on and off by messages of `set` topic.
+/// Broker is reconnected after delay when connection is lost.
+pub struct MqttBridge {
+    config: Option<MqttConfig>,
+    qos: QoS,
+    servers: Vec<(&'static str, SharedDevices)>,
+    metrics: SharedMetrics,
+    link: Option<(Client, Connection)>,
+    is_connected: bool,
+    retry_at: Option<DateTime<Local>>,
+    next_publish: DateTime<Local>,
+    /// On state of devices published last
+    states: HashMap<String, bool>,
+    rx: Option<Receiver<ConsoleCmd>>,
+}
+
+impl Service for MqttBridge {
+    fn start_service(mut self, rx: Receiver<ConsoleCmd>) -> JoinHandle<()> {
+        self.rx = Some(rx);
+        self.start()
+    }
+
+    fn rx(&self) -> &Receiver<ConsoleCmd> {
+        self.rx.as_ref().unwrap()
+    }
+}
+
+impl MqttBridge {
+    /// Bridge is disabled if config has no mqtt section
+    pub fn new(config_path: &Path, servers: Vec<(&'static str, SharedDevices)>, metrics: SharedMetrics) -> Self {
+        let config = config_section(config_path, "mqtt");
+        Self::from_config(config, servers, metrics)
+    }
+
+    pub fn from_config(config: Option<MqttConfig>, servers: Vec<(&'static str, SharedDevices)>, metrics: SharedMetrics) -> Self {
+        let qos = config.as_ref().map_or(QoS::AtMostOnce, |config| rumqttc::qos(config.qos).expect("Wrong input config: mqtt qos isn't 0, 1 or 2"));
+        if let Some(config) = config.as_ref() {
+            let topics = &config.topics;
+            for template in [&topics.state, &topics.power, &topics.temperature, &topics.set] {
+                assert!(template.contains(DEV), "Wrong input config: mqtt topic {template} has no {DEV}");
+            }
+        }
+        Self {
+            config,
+            qos,
+            servers,
+            metrics,
+            link: None,
+            is_connected: false,
+            retry_at: None,
+            next_publish: Local::now(),
+            states: HashMap::new(),
+            rx: None,
+        }
+    }
+
+    pub fn name() -> &'static str {
+        "MqttBridge"
+    }
+
+    fn start(mut self) -> JoinHandle<()> {
+        thread::spawn(move || {
+            self.connect();
+            loop {
+                if let Some(ConsoleCmd::Exit) = self.get_cmd() {
+                    break;
+                }
+                if self.link.is_some() {
+                    self.poll(Local::now());
+                } else {
+                    thread::sleep(Duration::from_millis(POLL_MS));
+                }
+            }
+            if let Some((client, mut connection)) = self.link.take() {
+                if self.is_connected && client.try_disconnect().is_ok() {
+                    let _ = connection.recv_timeout(Duration::from_millis(POLL_MS));
+                }
+            }
+        })
+    }
+
+    /// Connection is made by event loop on the first poll
+    fn connect(&mut self) {
+        let Some(config) = self.config.as_ref() else {
+            return;
+        };
+        let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+        options.set_keep_alive(Duration::from_secs(30));
+        info!("Mqtt bridge connects to {}:{}", config.host, config.port);
+        self.link = Some(Client::new(options, REQUESTS_CAP));
+    }
+
+    /// Drives event loop for a while, then publishes changes
+    fn poll(&mut self, now: DateTime<Local>) {
+        if self.retry_at.is_some_and(|retry_at| now < retry_at) {
+            thread::sleep(Duration::from_millis(POLL_MS));
+            return;
+        }
+        self.retry_at = None;
+        let Some((client, mut connection)) = self.link.take() else {
+            return;
+        };
+        let deadline = Instant::now() + Duration::from_millis(POLL_MS);
+        while let Some(timeout) = deadline.checked_duration_since(Instant::now()) {
+            match connection.recv_timeout(timeout) {
+                Ok(Ok(Event::Incoming(Packet::ConnAck(_)))) => self.on_connected(&client),
+                Ok(Ok(Event::Incoming(Packet::Publish(publish)))) => {
+                    self.on_publish(&client, &publish.topic, &String::from_utf8_lossy(&publish.payload));
+                }
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => {
+                    let delay = self.config.as_ref().map_or(0, |config| config.reconnect_delay_secs);
+                    if self.is_connected {
+                        warn!("Mqtt connection lost: {e}, reconnect in {delay} s");
+                    } else {
+                        info!("Mqtt broker is unavailable: {e}, reconnect in {delay} s");
+                    }
+                    self.is_connected = false;
+                    self.retry_at = Some(now + chrono::Duration::seconds(delay as i64));
+                    break;
+                }
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => {
+                    error!("Mqtt requests channel is closed");
+                    break;
+                }
+            }
+        }
+        if self.is_connected {
+            self.publish(&client, now);
+        }
+        self.link = Some((client, connection));
+    }
+
+    /// Broker doesn't keep session, so subscription and all topics are renewed
+    fn on_connected(&mut self, client: &Client) {
+        let Some(config) = self.config.as_ref() else {
+            return;
+        };
+        info!("Mqtt bridge is connected");
+        self.is_connected = true;
+        self.states.clear();
+        self.next_publish = Local::now();
+        if let Err(e) = client.try_subscribe(topic(&config.topics.set, "+"), self.qos) {
+            warn!("Can't subscribe to mqtt commands: {e}");
+        }
+        if config.discovery.is_some() {
+            for (topic, payload) in self.discovery_configs() {
+                self.send(client, topic, payload.to_string());
+            }
+        }
+    }
+
+    fn on_publish(&mut self, client: &Client, topic: &str, payload: &str) {
+        let Some(config) = self.config.as_ref() else {
+            return;
+        };
+        let Some(dev_name) = dev_of(&config.topics.set, topic) else {
+            debug!("Mqtt message of unexpected topic {topic}");
+            return;
+        };
+        let cmd = match payload.trim().to_uppercase().as_str() {
+            "ON" => Cmd::TurnOn,
+            "OFF" => Cmd::TurnOff,
+            _ => {
+                warn!("Mqtt command {payload} of {dev_name} isn't ON or OFF");
+                return;
+            }
+        };
+        let dev_name = dev_name.to_owned();
+        match self.execute(&dev_name, cmd) {
+            ResponseKind::Success(_) => self.publish(client, Local::now()),
+            ResponseKind::Err(e) => warn!("Mqtt command {payload} of {dev_name} failed: {:?}", e),
+        }
+    }
+
+    /// Executes command on server owning device, the same way as request of protocol
+    fn execute(&self, dev_name: &str, cmd: Cmd) -> ResponseKind {
+        let started = Instant::now();
+        let resp = handle_on_owner(&self.servers, protocol::Request::new(cmd, dev_name.to_owned()));
+        self.metrics.lock().unwrap().record(TRANSPORT, &resp, started.elapsed());
+        resp.resp_kind
+    }
+
+    fn send(&self, client: &Client, topic: String, payload: String) {
+        if let Err(e) = client.try_publish(topic.as_str(), self.qos, true, payload) {
+            debug!("Can't publish mqtt message to {topic}: {e}");
+        }
+    }
+
+    /// Changed states are published every poll, readings once in interval.
+    fn publish(&mut self, client: &Client, now: DateTime<Local>) {
+        let Some(config) = self.config.as_ref() else {
+            return;
+        };
+        let is_readings_due = now >= self.next_publish;
+        if is_readings_due {
+            self.next_publish = now + chrono::Duration::seconds(config.publish_interval_secs as i64);
+        }
+        let mut messages = Vec::new();
+        for (_, devices) in self.servers.iter() {
+            let mut devices = devices.lock().unwrap();
+            for (name, dev) in devices.iter_mut() {
+                if self.states.insert(name.clone(), dev.is_on()) != Some(dev.is_on()) {
+                    messages.push((topic(&config.topics.state, name), on_off(dev.is_on()).to_owned()));
+                }
+                if !is_readings_due {
+                    continue;
+                }
+                for (reading, template) in [(Reading::Power, &config.topics.power), (Reading::Temperature, &config.topics.temperature)] {
+                    if !dev.readings().contains(&reading) {
+                        continue;
+                    }
+                    match dev.sample(reading) {
+                        Ok(sample) if sample.quality != Quality::Stale => messages.push((topic(template, name), format!("{:.2}", sample.value))),
+                        Ok(_) => {}
+                        Err(e) => debug!("Reading {:?} of {name} is unavailable: {:?}", reading, e),
+                    }
+                }
+            }
+        }
+        for (topic, payload) in messages {
+            self.send(client, topic, payload);
+        }
+    }
+
+    /// Switch for every socket and lamp, sensor for every power and temperature reading
+    fn discovery_configs(&self) -> Vec<(String, Value)> {
+        let (Some(config), Some(discovery)) = (self.config.as_ref(), self.config.as_ref().and_then(|config| config.discovery.as_ref())) else {
+            return Vec::new();
+        };
+        let node_id = object_id(&discovery.node_id);
+        let mut configs = Vec::new();
+        for (_, devices) in self.servers.iter() {
+            let devices = devices.lock().unwrap();
+            for (name, dev) in devices.iter() {
+                let id = object_id(name);
+                if matches!(dev.type_dev(), SOCKET_TYPE | LAMP_TYPE) {
+                    configs.push((format!("{}/switch/{node_id}/{id}/config", discovery.prefix), json!({
+                        "name": name,
+                        "unique_id": format!("{node_id}_{id}"),
+                        "state_topic": topic(&config.topics.state, name),
+                        "command_topic": topic(&config.topics.set, name),
+                        "payload_on": "ON",
+                        "payload_off": "OFF",
+                    })));
+                }
+                for (reading, template, unit, class) in [
+                    (Reading::Power, &config.topics.power, "W", "power"),
+                    (Reading::Temperature, &config.topics.temperature, "°C", "temperature"),
+                ] {
+                    if !dev.readings().contains(&reading) {
+                        continue;
+                    }
+                    configs.push((format!("{}/sensor/{node_id}/{id}_{class}/config", discovery.prefix), json!({
+                        "name": format!("{name} {class}"),
+                        "unique_id": format!("{node_id}_{id}_{class}"),
+                        "state_topic": topic(template, name),
+                        "unit_of_measurement": unit,
+                        "device_class": class,
+                        "state_class": "measurement",
+                    })));
+                }
+            }
+        }
+        configs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+    use crate::device::test_devices;
+
+    /// Embedded broker serving one client at a time: keeps retained messages
+    /// and sends queued messages to client
+    struct Broker {
+        retained: Arc<Mutex<HashMap<String, String>>>,
+        outgoing: Arc<Mutex<Vec<(String, String)>>>,
+        connects: Arc<Mutex<usize>>,
+        port: u16,
+    }
+
+    fn read_len(stream: &mut TcpStream) -> Option<usize> {
+        let (mut len, mut shift) = (0, 0);
+        loop {
+            let mut byte = [0];
+            stream.read_exact(&mut byte).ok()?;
+            len += ((byte[0] & 0x7f) as usize) << shift;
+            if byte[0] & 0x80 == 0 {
+                return Some(len);
+            }
+            shift += 7;
+        }
+    }
+
+    fn packet(header: u8, body: &[u8]) -> Vec<u8> {
+        let mut res = vec![header];
+        let mut len = body.len();
+        loop {
+            let byte = (len % 128) as u8;
+            len /= 128;
+            res.push(if len > 0 { byte | 0x80 } else { byte });
+            if len == 0 {
+                break;
+            }
+        }
+        res.extend_from_slice(body);
+        res
+    }
+
+    fn string(s: &str) -> Vec<u8> {
+        let mut res = (s.len() as u16).to_be_bytes().to_vec();
+        res.extend_from_slice(s.as_bytes());
+        res
+    }
+
+    impl Broker {
+        /// Every client connection is closed after `session_ms`
+        fn start(session_ms: u64) -> Broker {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let broker = Broker {
+                retained: Arc::default(),
+                outgoing: Arc::default(),
+                connects: Arc::default(),
+                port: listener.local_addr().unwrap().port(),
+            };
+            let (retained, outgoing, connects) = (broker.retained.clone(), broker.outgoing.clone(), broker.connects.clone());
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let mut stream = stream.unwrap();
+                    stream.set_read_timeout(Some(Duration::from_millis(20))).unwrap();
+                    let closed_at = Instant::now() + Duration::from_millis(session_ms);
+                    while Instant::now() < closed_at {
+                        for (topic, payload) in outgoing.lock().unwrap().drain(..) {
+                            let mut body = string(&topic);
+                            body.extend_from_slice(payload.as_bytes());
+                            stream.write_all(&packet(0x30, &body)).unwrap();
+                        }
+                        let mut header = [0];
+                        if stream.read_exact(&mut header).is_err() {
+                            continue;
+                        }
+                        stream.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+                        let Some(len) = read_len(&mut stream) else {
+                            break;
+                        };
+                        let mut body = vec![0; len];
+                        stream.read_exact(&mut body).unwrap();
+                        stream.set_read_timeout(Some(Duration::from_millis(20))).unwrap();
+                        match header[0] >> 4 {
+                            1 => {
+                                *connects.lock().unwrap() += 1;
+                                stream.write_all(&packet(0x20, &[0, 0])).unwrap();
+                            }
+                            3 => {
+                                let topic_len = u16::from_be_bytes([body[0], body[1]]) as usize;
+                                let topic = String::from_utf8(body[2..2 + topic_len].to_vec()).unwrap();
+                                let qos = (header[0] >> 1) & 3;
+                                let payload_at = 2 + topic_len + if qos > 0 { 2 } else { 0 };
+                                if qos > 0 {
+                                    stream.write_all(&packet(0x40, &body[2 + topic_len..payload_at])).unwrap();
+                                }
+                                retained.lock().unwrap().insert(topic, String::from_utf8(body[payload_at..].to_vec()).unwrap());
+                            }
+                            8 => stream.write_all(&packet(0x90, &[body[0], body[1], 1])).unwrap(),
+                            12 => stream.write_all(&packet(0xd0, &[])).unwrap(),
+                            _ => {}
+                        }
+                    }
+                }
+            });
+            broker
+        }
+
+        fn retained(&self, topic: &str) -> Option<String> {
+            self.retained.lock().unwrap().get(topic).cloned()
+        }
+    }
+
+    fn server() -> SharedDevices {
+        Arc::new(Mutex::new(test_devices("mqtt", vec![
+            json!({"name": "sock 1", "type": "socket", "model": {"kind": "gauss", "mean": 2000.0, "spread": 0.0}}),
+            json!({"name": "therm1", "type": "therm"}),
+        ])))
+    }
+
+    fn poll_until(bridge: &mut MqttBridge, is_done: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !is_done() {
+            assert!(Instant::now() < deadline, "timed out");
+            bridge.poll(Local::now());
+        }
+    }
+
+    #[test]
+    fn test_topics() {
+        let topics = Topics::default();
+        assert_eq!(topic(&topics.power, "sock1"), "house/sock1/power");
+        assert_eq!(dev_of(&topics.set, "house/sock 1/set"), Some("sock 1"));
+        assert_eq!(dev_of(&topics.set, "house/a/b/set"), None);
+        assert_eq!(dev_of(&topics.set, "house//set"), None);
+        assert_eq!(dev_of(&topics.set, "house/sock1/state"), None);
+    }
+
+    #[test]
+    fn test_bridge() {
+        let broker = Broker::start(1500);
+        let config: MqttConfig = serde_json::from_value(json!({
+            "host": "127.0.0.1", "port": broker.port, "reconnect_delay_secs": 0, "publish_interval_secs": 0,
+            "topics": {"state": "test/{dev}/state"},
+            "discovery": {"node_id": "house 1"}
+        })).unwrap();
+        let mut bridge = MqttBridge::from_config(Some(config), vec![("tcp", server())], SharedMetrics::default());
+        bridge.connect();
+
+        poll_until(&mut bridge, || broker.retained("test/sock 1/state").is_some());
+        assert_eq!(broker.retained("test/sock 1/state").as_deref(), Some("OFF"));
+        // Devices are off, they have no actual readings
+        assert_eq!(broker.retained("house/sock 1/power"), None);
+        assert_eq!(broker.retained("house/therm1/temperature"), None);
+        let switch: Value = serde_json::from_str(&broker.retained("homeassistant/switch/house_1/sock_1/config").unwrap()).unwrap();
+        assert_eq!((&switch["command_topic"], &switch["unique_id"]), (&json!("house/sock 1/set"), &json!("house_1_sock_1")));
+        assert!(broker.retained("homeassistant/sensor/house_1/therm1_temperature/config").is_some());
+        assert!(broker.retained("homeassistant/switch/house_1/therm1/config").is_none());
+
+        broker.outgoing.lock().unwrap().push(("house/sock 1/set".to_owned(), "on".to_owned()));
+        poll_until(&mut bridge, || broker.retained("test/sock 1/state").as_deref() == Some("ON"));
+        assert!(bridge.servers[0].1.lock().unwrap()["sock 1"].is_on());
+        poll_until(&mut bridge, || broker.retained("house/sock 1/power").is_some());
+        assert_eq!(broker.retained("house/sock 1/power").as_deref(), Some("2000.00"));
+
+        // Broker drops connection, bridge connects again and renews retained state
+        broker.retained.lock().unwrap().clear();
+        poll_until(&mut bridge, || *broker.connects.lock().unwrap() == 2 && broker.retained("test/sock 1/state").is_some());
+        assert_eq!(broker.retained("test/sock 1/state").as_deref(), Some("ON"));
+    }
+}
@@ -0,0 +1,343 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::path::Path;
+use std::sync::mpsc::Receiver;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use chrono::{DateTime, Local};
+use serde::Deserialize;
+use log::*;
+
+use super::console_server::{config_section, push_event, ConsoleCmd, Service};
+use super::device::{with_device, Reading, SharedDevices, SOCKET_TYPE};
+
+const DEFAULT_PERIOD_SECS: u64 = 1;
+
+fn default_period() -> u64 {
+    DEFAULT_PERIOD_SECS
+}
+
+/// Budget of the whole house from config, e.g.
+/// `{"limit_watts": 10000.0, "restore_margin_watts": 1000.0, "restore_delay_secs": 30,
+///   "priorities": {"heater1": 3, "sock1": 2}}`
+#[derive(Deserialize, Clone)]
+pub struct BudgetConfig {
+    pub limit_watts: f64,
+    /// Socket is restored only if total power stays this much below limit
+    #[serde(default)]
+    pub restore_margin_watts: f64,
+    /// Sockets aren't restored sooner after the last shed or restore
+    #[serde(default)]
+    pub restore_delay_secs: i64,
+    #[serde(default = "default_period")]
+    pub period_secs: u64,
+    /// Sockets of lower priority are shed first, absent sockets have priority 0
+    #[serde(default)]
+    pub priorities: HashMap<String, u8>,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum BudgetEvent {
+    Shed,
+    Restored,
+}
+
+impl Display for BudgetEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BudgetEvent::Shed => write!(f, "shed"),
+            BudgetEvent::Restored => write!(f, "restored"),
+        }
+    }
+}
+
+/// Socket turned off by budget
+struct Shed {
+    name: String,
+    priority: u8,
+    /// Power before shed, socket is expected to take it again after restore
+    power: f64,
+    at: DateTime<Local>,
+}
+
+/// Socket turned on with its power
+struct Load {
+    name: String,
+    priority: u8,
+    power: f64,
+}
+
+/// Keeps summed power of sockets of all servers within limit. Sockets of the
+/// lowest priority are turned off when limit is exceeded, they are turned
+/// back on one by one when there is room for them.
+pub struct PowerBudget {
+    config: Option<BudgetConfig>,
+    servers: Vec<SharedDevices>,
+    shed: Vec<Shed>,
+    total_power: f64,
+    last_action: Option<DateTime<Local>>,
+    rx: Option<Receiver<ConsoleCmd>>,
+}
+
+impl Service for PowerBudget {
+    fn start_service(mut self, rx: Receiver<ConsoleCmd>) -> JoinHandle<()> {
+        self.rx = Some(rx);
+        self.start()
+    }
+
+    fn rx(&self) -> &Receiver<ConsoleCmd> {
+        self.rx.as_ref().unwrap()
+    }
+}
+
+impl PowerBudget {
+    /// Budget is disabled if config has no power_budget section
+    pub fn new(config_path: &Path, servers: Vec<SharedDevices>) -> Self {
+        let config = config_section(config_path, "power_budget");
+        Self::from_config(config, servers)
+    }
+
+    pub fn from_config(config: Option<BudgetConfig>, servers: Vec<SharedDevices>) -> Self {
+        Self {
+            config,
+            servers,
+            shed: Vec::new(),
+            total_power: 0.0,
+            last_action: None,
+            rx: None,
+        }
+    }
+
+    pub fn name() -> &'static str {
+        "PowerBudget"
+    }
+
+    fn start(mut self) -> JoinHandle<()> {
+        thread::spawn(move || {
+            let period = chrono::Duration::seconds(self.config.as_ref().map_or(DEFAULT_PERIOD_SECS, |config| config.period_secs) as i64);
+            let mut next_evaluation = Local::now();
+            loop {
+                match self.get_cmd() {
+                    Some(ConsoleCmd::Exit) => break,
+                    Some(ConsoleCmd::PowerBudget) => self.print_budget(),
+                    _ => {}
+                }
+                let now = Local::now();
+                if now >= next_evaluation {
+                    next_evaluation = now + period;
+                    self.evaluate(now);
+                }
+                thread::sleep(Duration::from_millis(100));
+            }
+        })
+    }
+
+    /// Power of sockets turned on
+    fn loads(&self, config: &BudgetConfig) -> Vec<Load> {
+        let mut loads = Vec::new();
+        for devices in self.servers.iter() {
+            let mut devices = devices.lock().unwrap();
+            for (name, dev) in devices.iter_mut() {
+                if dev.type_dev() != SOCKET_TYPE || !dev.is_on() {
+                    continue;
+                }
+                match dev.sample(Reading::Power) {
+                    Ok(sample) => loads.push(Load {
+                        name: name.clone(),
+                        priority: config.priorities.get(name).copied().unwrap_or_default(),
+                        power: sample.value,
+                    }),
+                    Err(e) => info!("Power of {name} is unknown: {:?}", e),
+                }
+            }
+        }
+        loads
+    }
+
+    pub fn evaluate(&mut self, now: DateTime<Local>) {
+        let Some(config) = self.config.clone() else {
+            return;
+        };
+        let mut loads = self.loads(&config);
+        // Socket turned on by client isn't restored by budget
+        let servers = &self.servers;
+        self.shed.retain(|shed| with_device(servers, &shed.name, |dev| dev.is_shed()) == Some(true));
+        self.total_power = loads.iter().map(|load| load.power).sum();
+
+        if self.total_power > config.limit_watts {
+            loads.sort_by(|a, b| a.priority.cmp(&b.priority).then(b.power.total_cmp(&a.power)));
+            for load in loads {
+                if self.total_power <= config.limit_watts {
+                    break;
+                }
+                match with_device(&self.servers, &load.name, |dev| dev.shed()) {
+                    Some(Ok(_)) => {
+                        self.total_power -= load.power;
+                        self.last_action = Some(now);
+                        report(BudgetEvent::Shed, &load.name, load.power, self.total_power, config.limit_watts);
+                        self.shed.push(Shed {
+                            name: load.name,
+                            priority: load.priority,
+                            power: load.power,
+                            at: now,
+                        });
+                    }
+                    res => warn!("Power budget is unable to shed {}: {:?}", load.name, res),
+                }
+            }
+            return;
+        }
+
+        let is_delayed = self.last_action.is_some_and(|last| (now - last).num_seconds() < config.restore_delay_secs);
+        // The most important socket goes first, of equal ones the longest shed
+        let Some(pos) = self.shed.iter().enumerate()
+            .max_by(|(_, a), (_, b)| a.priority.cmp(&b.priority).then(b.at.cmp(&a.at)))
+            .map(|(pos, _)| pos) else {
+            return;
+        };
+        let shed = &self.shed[pos];
+        if is_delayed || self.total_power + shed.power > config.limit_watts - config.restore_margin_watts {
+            return;
+        }
+        self.last_action = Some(now);
+        // Socket failed to turn on stays shed and is retried after delay
+        match with_device(&self.servers, &shed.name, |dev| dev.unshed()) {
+            Some(Ok(_)) => {
+                let shed = self.shed.remove(pos);
+                self.total_power += shed.power;
+                report(BudgetEvent::Restored, &shed.name, shed.power, self.total_power, config.limit_watts);
+            }
+            res => warn!("Power budget is unable to restore {}: {:?}", shed.name, res),
+        }
+    }
+
+    fn print_budget(&self) {
+        let Some(config) = self.config.as_ref() else {
+            println!("Power budget is disabled");
+            return;
+        };
+        println!("Power budget: {:.0} W of {:.0} W, shed sockets: {}", self.total_power, config.limit_watts, self.shed.len());
+        for shed in self.shed.iter() {
+            println!("  {}: priority {}, {:.0} W, shed at {}", shed.name, shed.priority, shed.power, shed.at.format("%H:%M:%S"));
+        }
+    }
+}
+
+/// Shed and restore are pushed to server console besides log
+fn report(event: BudgetEvent, name: &str, power: f64, total_power: f64, limit: f64) {
+    push_event!(Level::Warn, "Power budget: {name} {event}, {power:.0} W, total {total_power:.0} W of {limit:.0} W");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use crate::device::test_devices;
+    use crate::protocol::{Cmd, ErrorKind};
+    use crate::thermostat::Thermostats;
+    use serde_json::json;
+
+    /// Sockets of 4000 W turned on
+    fn server(names: &[&str]) -> SharedDevices {
+        let configs = names.iter()
+            .map(|name| json!({"name": name, "type": "socket", "model": {"kind": "gauss", "mean": 4000.0, "spread": 0.0}}))
+            .collect();
+        let mut devices = test_devices("budget", configs);
+        for dev in devices.values_mut() {
+            dev.execute(&Cmd::TurnOn).unwrap();
+        }
+        Arc::new(Mutex::new(devices))
+    }
+
+    fn is_on(server: &SharedDevices, name: &str) -> bool {
+        server.lock().unwrap()[name].is_on()
+    }
+
+    #[test]
+    fn test_shed_and_restore() {
+        let (tcp, udp) = (server(&["sock1", "sock2"]), server(&["heater1"]));
+        let config: BudgetConfig = serde_json::from_value(json!({
+            "limit_watts": 10000.0, "restore_margin_watts": 500.0, "restore_delay_secs": 30,
+            "priorities": {"heater1": 3, "sock1": 2}
+        })).unwrap();
+        let mut budget = PowerBudget::from_config(Some(config), vec![tcp.clone(), udp.clone()]);
+        let start = Local::now();
+        let at = |secs| start + chrono::Duration::seconds(secs);
+
+        budget.evaluate(at(0));
+        assert!(!is_on(&tcp, "sock2") && is_on(&tcp, "sock1") && is_on(&udp, "heater1"));
+        assert_eq!(budget.total_power, 8000.0);
+
+        // Socket is restored after delay only if it fits below limit with margin
+        budget.config.as_mut().unwrap().limit_watts = 12400.0;
+        budget.evaluate(at(10));
+        assert!(!is_on(&tcp, "sock2"));
+        budget.evaluate(at(40));
+        assert!(!is_on(&tcp, "sock2"));
+        budget.config.as_mut().unwrap().limit_watts = 12500.0;
+        budget.evaluate(at(41));
+        assert!(is_on(&tcp, "sock2"));
+        assert!(budget.shed.is_empty());
+
+        // Socket turned on by user is forgotten by budget
+        budget.config.as_mut().unwrap().limit_watts = 7000.0;
+        budget.evaluate(at(42));
+        assert!(!is_on(&tcp, "sock2") && !is_on(&tcp, "sock1") && is_on(&udp, "heater1"));
+        tcp.lock().unwrap().get_mut("sock1").unwrap().execute(&Cmd::TurnOn).unwrap();
+        budget.config.as_mut().unwrap().limit_watts = 20000.0;
+        budget.evaluate(at(43));
+        assert_eq!(budget.shed.len(), 1);
+        assert_eq!(budget.shed[0].name, "sock2");
+    }
+
+    #[test]
+    fn test_automation_keeps_shed() {
+        let server = Arc::new(Mutex::new(test_devices("budget", vec![
+            json!({"name": "therm1", "type": "therm", "model": {"kind": "gauss", "mean": 18.0, "spread": 0.0}}),
+            json!({"name": "heater1", "type": "socket", "model": {"kind": "gauss", "mean": 4000.0, "spread": 0.0}}),
+        ])));
+        let thermostat = serde_json::from_value(json!({
+            "name": "living", "therm": "therm1", "heater": "heater1", "setpoint": 21.0,
+            "control": {"kind": "hysteresis", "band": 1.0}
+        })).unwrap();
+        let mut thermostats = Thermostats::new(vec![thermostat], &server.lock().unwrap());
+        let config: BudgetConfig = serde_json::from_value(json!({"limit_watts": 3000.0, "restore_delay_secs": 30})).unwrap();
+        let mut budget = PowerBudget::from_config(Some(config), vec![server.clone()]);
+        let start = Local::now();
+        let at = |secs| start + chrono::Duration::seconds(secs);
+
+        server.lock().unwrap().get_mut("therm1").unwrap().execute(&Cmd::TurnOn).unwrap();
+        thermostats.update(&mut server.lock().unwrap(), at(0));
+        assert!(is_on(&server, "heater1"));
+        budget.evaluate(at(0));
+        assert!(!is_on(&server, "heater1") && server.lock().unwrap()["heater1"].is_shed());
+
+        // Thermostat is unable to turn shed heater on, so budget keeps it shed
+        for secs in 1..5 {
+            thermostats.update(&mut server.lock().unwrap(), at(secs * 10));
+            budget.evaluate(at(secs * 10));
+            assert!(!is_on(&server, "heater1"));
+            assert_eq!(budget.shed.len(), 1);
+        }
+        let res = server.lock().unwrap().get_mut("heater1").unwrap().execute_automated(&Cmd::TurnOn);
+        assert!(matches!(res, Err(e) if e.is(ErrorKind::Shed)));
+
+        // Budget restores heater when there is room for it
+        budget.config.as_mut().unwrap().limit_watts = 5000.0;
+        budget.evaluate(at(60));
+        assert!(is_on(&server, "heater1") && !server.lock().unwrap()["heater1"].is_shed());
+        assert!(budget.shed.is_empty());
+
+        // Client turns shed heater on explicitly
+        budget.config.as_mut().unwrap().limit_watts = 3000.0;
+        budget.evaluate(at(61));
+        assert!(!is_on(&server, "heater1"));
+        server.lock().unwrap().get_mut("heater1").unwrap().execute(&Cmd::TurnOn).unwrap();
+        assert!(!server.lock().unwrap()["heater1"].is_shed());
+        thermostats.update(&mut server.lock().unwrap(), at(62));
+        budget.config.as_mut().unwrap().limit_watts = 5000.0;
+        budget.evaluate(at(62));
+        assert!(is_on(&server, "heater1") && budget.shed.is_empty());
+    }
+}
@@ -1,28 +1,181 @@
 use serde::{Serialize, Deserialize};
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
 pub enum Cmd {
-  GetListDevices,
+  GetListDevices(ListQuery),
   TurnOn,
   TurnOff,
   Power,
   Temperature,
+  Energy,
+  Brightness,
+  Humidity,
+  Lock,
+  Unlock,
+  LockState,
+  Leak,
+  ResetAlarm,
+  /// Dim level of lamp in %, power limit of socket in W
+  SetLevel(f64),
+  /// Target temperature of thermometer in C
+  SetTarget(f64),
+  /// Turns device on for given number of minutes, then device turns off
+  TurnOnFor(u32),
+  Target,
+  GetState,
+  GetCapabilities,
+  /// Several requests executed together, result of each is returned
+  Batch(Vec<Request>, BatchMode),
+  /// Alerts of device or of all devices if device name is empty
+  GetAlerts,
+  /// Schedules command on device, id of schedule is returned
+  AddSchedule(Schedule),
+  ListSchedules,
+  /// Removes schedule by id, device name of schedule is optional
+  RemoveSchedule(u32),
+  /// State of thermostat or of all thermostats if name is empty
+  GetThermostat,
+  SetThermostatMode(ThermostatMode),
+  /// Temperature thermostat keeps in auto mode, C
+  SetSetpoint(f64),
+  /// Defines group of devices named by device name of request, empty group is removed
+  DefineGroup(Vec<String>),
+  /// Executes command on each device of group
+  GroupAction(Box<Cmd>),
+  /// Defines scene named by device name of request, empty scene is removed
+  DefineScene(Vec<SceneItem>),
+  ApplyScene,
+  /// Groups and scenes of server
+  GetScenes,
 }
 
-#[derive(Serialize, Deserialize)]
-pub enum TypeDev {
-  SmartSocket,
-  SmartTherm,
+impl Cmd {
+  /// Compares commands ignoring their arguments
+  pub fn same_kind(&self, other: &Cmd) -> bool {
+    std::mem::discriminant(self) == std::mem::discriminant(other)
+  }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum BatchMode {
+  /// Every item is executed regardless of results of other items
+  BestEffort,
+  /// Failed item rolls back all items applied before it
+  AllOrNothing,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum ScheduleTime {
+  /// Cron expression "minute hour day-of-month month day-of-week" in local time of server
+  Cron(String),
+  /// One-shot time, milliseconds since Unix epoch
+  Once(i64),
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Schedule {
+  pub when: ScheduleTime,
+  pub cmd: Box<Cmd>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct ScheduleEntry {
+  pub id: u32,
+  pub dev_name: String,
+  pub schedule: Schedule,
+  /// Milliseconds since Unix epoch
+  pub next_run_ms: Option<i64>,
+  pub last_run_ms: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum ThermostatMode {
+  /// Heater is kept off
+  Off,
+  /// Heater is kept on regardless of temperature
+  Heat,
+  /// Heater is switched to keep setpoint
+  Auto,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct ThermostatState {
+  pub name: String,
+  pub therm: String,
+  pub heater: String,
+  pub mode: ThermostatMode,
+  pub setpoint: f64,
+  /// The last fresh temperature, none if therm doesn't measure
+  pub temperature: Option<f64>,
+  pub is_heating: bool,
+  /// Controller from config, e.g. "hysteresis 1"
+  pub control: String,
+}
+
+/// Command of scene, e.g. `{"dev_name": "lamp1", "cmd": {"SetLevel": 30.0}}`
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct SceneItem {
+  pub dev_name: String,
+  pub cmd: Cmd,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Group {
+  pub name: String,
+  pub devices: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Scene {
+  pub name: String,
+  /// Items are applied in order
+  pub items: Vec<SceneItem>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DeviceResult {
+  pub dev_name: String,
+  pub result: ResponseKind,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum StateFilter {
+  On,
+  Off,
+  Fault,
+}
+
+/// Filters and page of device listing. Default query requests
+/// the first page of all devices.
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, Debug)]
+pub struct ListQuery {
+  /// Type name from config, e.g. "socket"
+  pub type_dev: Option<String>,
+  pub state: Option<StateFilter>,
+  pub name_prefix: Option<String>,
+  pub tag: Option<String>,
+  /// Name of the last device of previous page
+  pub cursor: Option<String>,
+  /// Max count of devices in page, zero means as many as fit in frame
+  pub limit: u8,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum LockState {
+  Locked,
+  Unlocked,
+  Jammed,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Device {
   pub name: String,
-  pub type_dev: TypeDev,
+  /// Type name from config, e.g. "socket"
+  pub type_dev: String,
 }
 
 impl Device {
-  pub fn new(name: String, type_dev: TypeDev) -> Self{
+  pub fn new(name: String, type_dev: String) -> Self{
     Self {
       name,
       type_dev,
@@ -30,10 +183,91 @@ impl Device {
   }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum Unit {
+  Watt,
+  Kilowatt,
+  Celsius,
+  Fahrenheit,
+  Kelvin,
+  Percent,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Quantity {
+  Power,
+  Temperature,
+  Ratio,
+}
+
+impl Unit {
+  fn quantity(self) -> Quantity {
+    match self {
+      Unit::Watt | Unit::Kilowatt => Quantity::Power,
+      Unit::Celsius | Unit::Fahrenheit | Unit::Kelvin => Quantity::Temperature,
+      Unit::Percent => Quantity::Ratio,
+    }
+  }
+
+  /// Value in W, C or % converted from this unit
+  fn base_value(self, value: f64) -> f64 {
+    match self {
+      Unit::Kilowatt => value * 1000.0,
+      Unit::Fahrenheit => (value - 32.0) * 5.0 / 9.0,
+      Unit::Kelvin => value - 273.15,
+      Unit::Watt | Unit::Celsius | Unit::Percent => value,
+    }
+  }
+
+  /// Value in this unit converted from W, C or %
+  fn unit_value(self, value: f64) -> f64 {
+    match self {
+      Unit::Kilowatt => value / 1000.0,
+      Unit::Fahrenheit => value * 9.0 / 5.0 + 32.0,
+      Unit::Kelvin => value + 273.15,
+      Unit::Watt | Unit::Celsius | Unit::Percent => value,
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum Quality {
+  /// Measured when request is handled
+  Fresh,
+  /// Measured earlier, e.g. sensor is off now
+  Stale,
+  /// Produced by emulation model instead of real sensor
+  Simulated,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub struct Measurement {
+  pub value: f64,
+  pub unit: Unit,
+  pub quality: Quality,
+  /// Time of sample, milliseconds since Unix epoch
+  pub timestamp_ms: i64,
+}
+
+impl Measurement {
+  /// Converts value to the first of preferred units measuring the same quantity,
+  /// value stays as is if there is no such unit
+  pub fn convert(&mut self, units: &[Unit]) {
+    let Some(unit) = units.iter().find(|unit| unit.quantity() == self.unit.quantity()) else {
+      return;
+    };
+    self.value = unit.unit_value(self.unit.base_value(self.value));
+    self.unit = *unit;
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
 pub struct Request {
   pub cmd: Cmd,
   pub dev_name: String,
+  /// Preferred units of readings, server units are used if empty
+  #[serde(default)]
+  pub units: Vec<Unit>,
 }
 
 impl Request {
@@ -41,29 +275,257 @@ impl Request {
     Self {
       cmd,
       dev_name,
+      units: Vec::new(),
     }
   }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Default, Clone, Copy)]
+pub struct Consumption {
+  pub kwh: f64,
+  pub cost: f64,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct EnergyReport {
+  pub dev_name: String,
+  pub total_kwh: f64,
+  pub day: Consumption,
+  pub week: Consumption,
+  pub month: Consumption,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DevicePage {
+  pub devices: Vec<Device>,
+  /// Cursor of the next page, none for the last page
+  pub next_cursor: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DeviceState {
+  pub is_on: bool,
+  pub is_fault: bool,
+  /// Response to the last reading command
+  pub last_reading: Option<Box<SuccessKind>>,
+  /// Seconds since device was turned on, zero if device is off
+  pub uptime_secs: u64,
+  /// Seconds until device turns off by itself, e.g. by timer or safety limit
+  pub off_in_secs: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Capabilities {
+  pub commands: Vec<Cmd>,
+  /// Commands reading values of device
+  pub readings: Vec<Cmd>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AlertState {
+  pub dev_name: String,
+  /// Rule of alert from config, e.g. "Power above 3000 for 10 s"
+  pub rule: String,
+  pub is_raised: bool,
+  /// Time of the last raise, milliseconds since Unix epoch
+  pub raised_ms: Option<i64>,
+  /// Time of the last clear, milliseconds since Unix epoch
+  pub cleared_ms: Option<i64>,
+  /// The last value alert is checked against
+  pub last_value: Option<f64>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub enum SuccessKind {
   Ack,
-  ListDev(Vec<Device>),
-  Power(f64),
-  Temp(f64),
+  ListDev(DevicePage),
+  Power(Measurement),
+  Temp(Measurement),
+  Energy(Vec<EnergyReport>),
+  Brightness(u8),
+  Humidity(Measurement),
+  Lock(LockState),
+  Leak(bool),
+  State(DeviceState),
+  Capabilities(Capabilities),
+  /// Results of batch items in order of requests
+  Batch(Vec<ResponseKind>),
+  Alerts(Vec<AlertState>),
+  ScheduleAdded(u32),
+  Schedules(Vec<ScheduleEntry>),
+  Thermostats(Vec<ThermostatState>),
+  /// Result of each device of group or scene
+  DeviceResults(Vec<DeviceResult>),
+  Scenes(Vec<Group>, Vec<Scene>),
 }
 
-#[derive(Serialize, Deserialize)]
+impl SuccessKind {
+  pub fn measurement_mut(&mut self) -> Option<&mut Measurement> {
+    match self {
+      SuccessKind::Power(measurement) | SuccessKind::Temp(measurement) | SuccessKind::Humidity(measurement) => Some(measurement),
+      _ => None,
+    }
+  }
+
+  /// Converts readings including the last reading of device state.
+  /// Items of batch are converted by their own requests.
+  pub fn convert(&mut self, units: &[Unit]) {
+    if let SuccessKind::State(state) = self {
+      if let Some(reading) = state.last_reading.as_deref_mut() {
+        reading.convert(units);
+      }
+    }
+    if let Some(measurement) = self.measurement_mut() {
+      measurement.convert(units);
+    }
+  }
+}
+
+/// Errors of server. Kind itself isn't sent, client gets its stable code.
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum ErrorKind {
   WrongCmd,
   DevNotFound,
   UnknownCmd,
+  DevFault,
+  /// Argument of command is out of range supported by device
+  OutOfRange,
+  /// Item of all-or-nothing batch isn't applied, because another item failed
+  Aborted,
+  /// Device is unable to execute command while it's off
+  DevOff,
+  /// Load exceeds limit of device
+  Overload,
+  /// Device is read only
+  PermissionDenied,
+  /// Server is serving another client
+  Busy,
+  /// Argument of command is malformed, e.g. cron expression
+  InvalidArgument,
+  ScheduleNotFound,
+  SceneNotFound,
+  /// Response doesn't fit frame of transport, e.g. datagram
+  ResponseTooLarge,
+  /// Device is turned off by power budget, only client or budget turns it on
+  Shed,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum ErrorCategory {
+  /// Request is invalid, resending it doesn't help
+  Validation,
+  NotFound,
+  Permission,
+  /// Device is unable to execute valid request
+  Device,
+  /// Request may succeed later
+  Server,
+}
+
+impl ErrorKind {
+  pub const ALL: &'static [ErrorKind] = &[
+    ErrorKind::WrongCmd, ErrorKind::DevNotFound, ErrorKind::UnknownCmd, ErrorKind::DevFault, ErrorKind::OutOfRange,
+    ErrorKind::Aborted, ErrorKind::DevOff, ErrorKind::Overload, ErrorKind::PermissionDenied, ErrorKind::Busy,
+    ErrorKind::InvalidArgument, ErrorKind::ScheduleNotFound, ErrorKind::SceneNotFound, ErrorKind::ResponseTooLarge,
+    ErrorKind::Shed,
+  ];
+
+  pub fn from_code(code: u16) -> Option<Self> {
+    Self::ALL.iter().copied().find(|kind| kind.code() == code)
+  }
+
+  /// Code is a part of protocol, it never changes for the same kind
+  pub fn code(self) -> u16 {
+    match self {
+      ErrorKind::WrongCmd => 100,
+      ErrorKind::UnknownCmd => 101,
+      ErrorKind::OutOfRange => 102,
+      ErrorKind::InvalidArgument => 103,
+      ErrorKind::ResponseTooLarge => 104,
+      ErrorKind::DevNotFound => 200,
+      ErrorKind::ScheduleNotFound => 201,
+      ErrorKind::SceneNotFound => 202,
+      ErrorKind::PermissionDenied => 300,
+      ErrorKind::DevFault => 400,
+      ErrorKind::DevOff => 401,
+      ErrorKind::Overload => 402,
+      ErrorKind::Shed => 403,
+      ErrorKind::Busy => 500,
+      ErrorKind::Aborted => 501,
+    }
+  }
+
+  pub fn category(self) -> ErrorCategory {
+    match self {
+      ErrorKind::WrongCmd | ErrorKind::UnknownCmd | ErrorKind::OutOfRange | ErrorKind::InvalidArgument | ErrorKind::ResponseTooLarge => ErrorCategory::Validation,
+      ErrorKind::DevNotFound | ErrorKind::ScheduleNotFound | ErrorKind::SceneNotFound => ErrorCategory::NotFound,
+      ErrorKind::PermissionDenied => ErrorCategory::Permission,
+      ErrorKind::DevFault | ErrorKind::DevOff | ErrorKind::Overload | ErrorKind::Shed => ErrorCategory::Device,
+      ErrorKind::Busy | ErrorKind::Aborted => ErrorCategory::Server,
+    }
+  }
+
+  fn message(self) -> &'static str {
+    match self {
+      ErrorKind::WrongCmd => "Command isn't supported by device",
+      ErrorKind::UnknownCmd => "Unknown command",
+      ErrorKind::OutOfRange => "Argument is out of range",
+      ErrorKind::InvalidArgument => "Argument is invalid",
+      ErrorKind::ResponseTooLarge => "Response is too large, request less data",
+      ErrorKind::DevNotFound => "Device not found",
+      ErrorKind::ScheduleNotFound => "Schedule not found",
+      ErrorKind::SceneNotFound => "Group or scene not found",
+      ErrorKind::PermissionDenied => "Device is read only",
+      ErrorKind::DevFault => "Device fault",
+      ErrorKind::DevOff => "Device is off",
+      ErrorKind::Overload => "Device is overloaded",
+      ErrorKind::Shed => "Device is shed by power budget",
+      ErrorKind::Busy => "Server is busy",
+      ErrorKind::Aborted => "Batch is rolled back",
+    }
+  }
+}
+
+/// Error sent to client. Messages are short, so batch of errors fits datagram.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Error {
+  pub code: u16,
+  pub category: ErrorCategory,
+  pub message: String,
+  pub details: Option<String>,
+}
+
+impl Error {
+  pub fn new(kind: ErrorKind) -> Self {
+    Self {
+      code: kind.code(),
+      category: kind.category(),
+      message: kind.message().to_owned(),
+      details: None,
+    }
+  }
+
+  pub fn with_details(mut self, details: String) -> Self {
+    self.details = Some(details);
+    self
+  }
+
+  #[cfg(test)]
+  pub fn is(&self, kind: ErrorKind) -> bool {
+    self.code == kind.code()
+  }
+}
+
+impl From<ErrorKind> for Error {
+  fn from(kind: ErrorKind) -> Self {
+    Self::new(kind)
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub enum ResponseKind {
   Success(SuccessKind),
-  Err(ErrorKind),
+  Err(Error),
 }
 
 #[derive(Serialize, Deserialize)]
@@ -79,10 +541,46 @@ impl Response {
       resp_kind: ResponseKind::Success(success_kind),
     }
   }
-  pub fn new_err_response(to_req: Request, err_kind: ErrorKind) -> Self {
+  pub fn new_err_response(to_req: Request, err: Error) -> Self {
     Self {
       to_req,
-      resp_kind: ResponseKind::Err(err_kind),
+      resp_kind: ResponseKind::Err(err),
     }
   }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn measurement(value: f64, unit: Unit) -> Measurement {
+    Measurement {
+      value,
+      unit,
+      quality: Quality::Fresh,
+      timestamp_ms: 0,
+    }
+  }
+
+  #[test]
+  fn test_convert_units() {
+    let units = [Unit::Percent, Unit::Fahrenheit, Unit::Kilowatt];
+    let mut temp = measurement(100.0, Unit::Celsius);
+    temp.convert(&units);
+    assert_eq!(temp.unit, Unit::Fahrenheit);
+    assert!((temp.value - 212.0).abs() < 1e-9);
+
+    let mut power = measurement(1500.0, Unit::Watt);
+    power.convert(&units);
+    assert_eq!(power, measurement(1.5, Unit::Kilowatt));
+
+    let mut temp = measurement(0.0, Unit::Celsius);
+    temp.convert(&[Unit::Kelvin]);
+    assert!((temp.value - 273.15).abs() < 1e-9);
+
+    // No unit of the same quantity
+    let mut power = measurement(1500.0, Unit::Watt);
+    power.convert(&[Unit::Kelvin]);
+    assert_eq!(power, measurement(1500.0, Unit::Watt));
+  }
+}
@@ -0,0 +1,396 @@
+use chrono::Local;
+use log::*;
+
+use super::device::{Device, DeviceSnapshot, Devices};
+use super::protocol::{self, BatchMode, Cmd, DevicePage, Error, ErrorKind, ListQuery, ResponseKind, StateFilter, SuccessKind, Unit};
+use super::transport_layer::MAX_PAYLOAD;
+
+/// Lets devices apply time based changes, e.g. turn off by timer
+pub fn update_devices(devices: &mut Devices) {
+    let now = Local::now();
+    for dev in devices.values_mut() {
+        dev.update(now);
+    }
+}
+
+/// Max count of requests in batch. Response to batch of the largest
+/// results still fits datagram, see `test_batch_fits_datagram`.
+const MAX_BATCH_LEN: usize = 10;
+
+/// Executes request on devices of server. Shared by all transports.
+/// Server handles requests one by one, so batch is applied as a whole
+/// before the next request is handled.
+pub fn handle_request(devices: &mut Devices, req: protocol::Request) -> protocol::Response {
+    update_devices(devices);
+    match execute(devices, &req) {
+        ResponseKind::Success(success) => protocol::Response::new_success_response(req, success),
+        ResponseKind::Err(e) => protocol::Response::new_err_response(req, e),
+    }
+}
+
+/// Replaces response not fitting frame of transport by error, so client
+/// gets error instead of truncated frame. Lists without pagination,
+/// e.g. alerts of all devices, may grow beyond datagram.
+pub fn bound_response(resp: protocol::Response, max_size: usize) -> protocol::Response {
+    let size = bincode::serialized_size(&resp).map_or(usize::MAX, |size| size as usize);
+    if size <= max_size {
+        return resp;
+    }
+    warn!("Response of {size} bytes to {:?} exceeds frame of {max_size} bytes", resp.to_req.cmd);
+    let details = format!("response is {size} bytes, frame is {max_size} bytes");
+    protocol::Response::new_err_response(resp.to_req, Error::new(ErrorKind::ResponseTooLarge).with_details(details))
+}
+
+fn execute(devices: &mut Devices, req: &protocol::Request) -> ResponseKind {
+    match &req.cmd {
+        Cmd::GetListDevices(query) => ResponseKind::Success(SuccessKind::ListDev(list_devices(devices, req, query))),
+        Cmd::Energy if req.dev_name.is_empty() => {
+            let reports = devices.values_mut().filter_map(|dev| dev.energy()).collect();
+            ResponseKind::Success(SuccessKind::Energy(reports))
+        }
+        Cmd::Batch(items, mode) => execute_batch(devices, items, *mode, &req.units),
+        Cmd::GetAlerts if req.dev_name.is_empty() => {
+            let mut alerts: Vec<protocol::AlertState> = devices.values().flat_map(|dev| dev.alerts()).collect();
+            alerts.sort_by(|a, b| a.dev_name.cmp(&b.dev_name));
+            ResponseKind::Success(SuccessKind::Alerts(alerts))
+        }
+        cmd => {
+            let dev =
+            match devices.get_mut(&req.dev_name) {
+                Some(dev) => dev,
+                None => {
+                    info!("Device: {} not found", req.dev_name);
+                    return ResponseKind::Err(Error::new(ErrorKind::DevNotFound).with_details(req.dev_name.clone()));
+                }
+            };
+            match dev.execute(cmd) {
+                Ok(mut success) => {
+                    info!("Device: {} executed {:?}", req.dev_name, cmd);
+                    success.convert(&req.units);
+                    ResponseKind::Success(success)
+                }
+                Err(e) => {
+                    info!("Device: {} unable to execute {:?}: {:?}", req.dev_name, cmd, e);
+                    ResponseKind::Err(e)
+                }
+            }
+        }
+    }
+}
+
+/// Executes items in order. In all-or-nothing mode the first failed item
+/// rolls back devices changed by previous items, the rest items are skipped.
+/// Items without preferred units get units of batch request.
+fn execute_batch(devices: &mut Devices, items: &[protocol::Request], mode: BatchMode, units: &[Unit]) -> ResponseKind {
+    if items.len() > MAX_BATCH_LEN {
+        info!("Batch of {} requests is too long", items.len());
+        return ResponseKind::Err(Error::new(ErrorKind::OutOfRange).with_details(format!("{} items, max {MAX_BATCH_LEN}", items.len())));
+    }
+    let mut snapshots: Vec<(&str, DeviceSnapshot)> = Vec::new();
+    let mut results = Vec::with_capacity(items.len());
+    for item in items {
+        if mode == BatchMode::AllOrNothing && snapshots.iter().all(|(name, _)| *name != item.dev_name) {
+            if let Some(dev) = devices.get(&item.dev_name) {
+                snapshots.push((&item.dev_name, dev.snapshot()));
+            }
+        }
+        let mut result = match item.cmd {
+            Cmd::Batch(..) => ResponseKind::Err(Error::new(ErrorKind::WrongCmd).with_details("nested batch".to_owned())),
+            _ => execute(devices, item),
+        };
+        if let (true, ResponseKind::Success(success)) = (item.units.is_empty(), &mut result) {
+            success.convert(units);
+        }
+        let is_failed = matches!(result, ResponseKind::Err(_));
+        results.push(result);
+
+        if mode == BatchMode::AllOrNothing && is_failed {
+            info!("Batch item {} failed, batch is rolled back", results.len() - 1);
+            for (name, snapshot) in snapshots.into_iter().rev() {
+                if let Some(dev) = devices.get_mut(name) {
+                    dev.restore(snapshot);
+                }
+            }
+            let failed = results.len() - 1;
+            for (i, result) in results.iter_mut().enumerate() {
+                if i != failed {
+                    *result = ResponseKind::Err(ErrorKind::Aborted.into());
+                }
+            }
+            results.resize(items.len(), ResponseKind::Err(ErrorKind::Aborted.into()));
+            break;
+        }
+    }
+    ResponseKind::Success(SuccessKind::Batch(results))
+}
+
+fn is_match(query: &ListQuery, name: &str, dev: &Device) -> bool {
+    query.type_dev.as_deref().is_none_or(|type_dev| type_dev == dev.type_dev())
+        && query.state.is_none_or(|state| match state {
+            StateFilter::On => dev.is_on(),
+            StateFilter::Off => !dev.is_on(),
+            StateFilter::Fault => dev.is_fault(),
+        })
+        && query.name_prefix.as_ref().is_none_or(|prefix| name.starts_with(prefix.as_str()))
+        && query.tag.as_ref().is_none_or(|tag| dev.tags().contains(tag))
+}
+
+fn response_size(req: &protocol::Request, page: &DevicePage) -> usize {
+    let resp = protocol::Response::new_success_response(req.clone(), SuccessKind::ListDev(page.clone()));
+    bincode::serialized_size(&resp).map_or(usize::MAX, |size| size as usize)
+}
+
+/// Devices matching query ordered by name. Page is limited by size of
+/// transport frame, the rest of devices is requested with the next cursor.
+fn list_devices(devices: &Devices, req: &protocol::Request, query: &ListQuery) -> DevicePage {
+    let mut names: Vec<&String> = devices.iter()
+        .filter(|(name, dev)| is_match(query, name, dev))
+        .map(|(name, _)| name)
+        .collect();
+    names.sort();
+    let limit = if query.limit == 0 { usize::MAX } else { query.limit as usize };
+
+    let mut page = DevicePage {
+        devices: Vec::new(),
+        next_cursor: None,
+    };
+    let mut names = names.into_iter()
+        .filter(|name| query.cursor.as_ref().is_none_or(|cursor| *name > cursor))
+        .peekable();
+    while let Some(name) = names.next() {
+        page.devices.push(protocol::Device::new(name.to_owned(), devices[name].type_dev().to_owned()));
+        page.next_cursor = names.peek().map(|_| name.to_owned());
+        if page.devices.len() > 1 && response_size(req, &page) > MAX_PAYLOAD {
+            page.devices.pop();
+            page.next_cursor = page.devices.last().map(|dev| dev.name.to_owned());
+            break;
+        }
+        if page.devices.len() == limit {
+            break;
+        }
+    }
+    page
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::test_devices;
+    use crate::transport_layer::MAX_DATAGRAM_PAYLOAD;
+    use serde_json::json;
+
+    fn devices() -> Devices {
+        let configs = (0..40)
+            .map(|i| {
+                let (dev_type, tag) = if i % 2 == 0 { ("lamp", "kitchen") } else { ("humidity", "bath") };
+                json!({"name": format!("{dev_type}{i:02}"), "type": dev_type, "tags": [tag]})
+            })
+            .collect();
+        test_devices("request_handler", configs)
+    }
+
+    fn list(devices: &mut Devices, query: ListQuery) -> DevicePage {
+        let req = protocol::Request::new(Cmd::GetListDevices(query), String::new());
+        let resp = handle_request(devices, req);
+        assert!(bincode::serialized_size(&resp).unwrap() as usize <= MAX_PAYLOAD);
+        match resp.resp_kind {
+            protocol::ResponseKind::Success(protocol::SuccessKind::ListDev(page)) => page,
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_pages_fit_frame() {
+        let mut devices = devices();
+        let mut query = ListQuery::default();
+        let mut names = Vec::new();
+        loop {
+            let page = list(&mut devices, query.clone());
+            assert!(!page.devices.is_empty());
+            names.extend(page.devices.into_iter().map(|dev| dev.name));
+            match page.next_cursor {
+                Some(cursor) => query.cursor = Some(cursor),
+                None => break,
+            }
+        }
+        let mut expected: Vec<String> = devices.keys().cloned().collect();
+        expected.sort();
+        assert_eq!(names, expected);
+    }
+
+    #[test]
+    fn test_filters() {
+        let mut devices = devices();
+        devices.get_mut("lamp04").unwrap().execute(&Cmd::TurnOn).unwrap();
+
+        let page = list(&mut devices, ListQuery { state: Some(StateFilter::On), ..Default::default() });
+        assert_eq!(page.devices.len(), 1);
+        assert_eq!(page.devices[0].name, "lamp04");
+
+        let page = list(&mut devices, ListQuery { name_prefix: Some("humidity1".to_owned()), ..Default::default() });
+        assert_eq!(page.devices.len(), 5);
+        assert!(page.next_cursor.is_none());
+
+        let query = ListQuery {
+            type_dev: Some("lamp".to_owned()),
+            tag: Some("kitchen".to_owned()),
+            limit: 3,
+            ..Default::default()
+        };
+        let page = list(&mut devices, query);
+        let names: Vec<&str> = page.devices.iter().map(|dev| dev.name.as_str()).collect();
+        assert_eq!(names, ["lamp00", "lamp02", "lamp04"]);
+        assert_eq!(page.next_cursor.as_deref(), Some("lamp04"));
+
+        let page = list(&mut devices, ListQuery { tag: Some("garage".to_owned()), ..Default::default() });
+        assert!(page.devices.is_empty() && page.next_cursor.is_none());
+    }
+
+    fn batch(devices: &mut Devices, items: Vec<protocol::Request>, mode: BatchMode) -> Vec<ResponseKind> {
+        let req = protocol::Request::new(Cmd::Batch(items, mode), String::new());
+        match handle_request(devices, req).resp_kind {
+            ResponseKind::Success(SuccessKind::Batch(results)) => results,
+            _ => panic!(),
+        }
+    }
+
+    fn brightness(devices: &mut Devices, name: &str) -> u8 {
+        let req = protocol::Request::new(Cmd::Brightness, name.to_owned());
+        match handle_request(devices, req).resp_kind {
+            ResponseKind::Success(SuccessKind::Brightness(level)) => level,
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_batch() {
+        let mut devices = devices();
+        let items = vec![
+            protocol::Request::new(Cmd::TurnOn, "lamp00".to_owned()),
+            protocol::Request::new(Cmd::SetLevel(30.0), "lamp00".to_owned()),
+            protocol::Request::new(Cmd::TurnOn, "lamp02".to_owned()),
+            protocol::Request::new(Cmd::SetLevel(500.0), "lamp02".to_owned()),
+            protocol::Request::new(Cmd::TurnOn, "lamp04".to_owned()),
+        ];
+
+        let results = batch(&mut devices, items.clone(), BatchMode::AllOrNothing);
+        let errors: Vec<Option<u16>> = results.iter().map(|res| match res {
+            ResponseKind::Err(e) => Some(e.code),
+            _ => None,
+        }).collect();
+        let (aborted, out_of_range) = (Some(ErrorKind::Aborted.code()), Some(ErrorKind::OutOfRange.code()));
+        assert_eq!(errors, [aborted, aborted, aborted, out_of_range, aborted]);
+        assert!(!devices["lamp00"].is_on() && !devices["lamp02"].is_on() && !devices["lamp04"].is_on());
+        devices.get_mut("lamp00").unwrap().execute(&Cmd::TurnOn).unwrap();
+        assert_eq!(brightness(&mut devices, "lamp00"), 100);
+        devices.get_mut("lamp00").unwrap().execute(&Cmd::TurnOff).unwrap();
+
+        let results = batch(&mut devices, items, BatchMode::BestEffort);
+        assert_eq!(results.iter().filter(|res| matches!(res, ResponseKind::Err(e) if e.is(ErrorKind::OutOfRange))).count(), 1);
+        assert!(devices["lamp00"].is_on() && devices["lamp02"].is_on() && devices["lamp04"].is_on());
+        assert_eq!(brightness(&mut devices, "lamp00"), 30);
+    }
+
+    #[test]
+    fn test_batch_rollback_keeps_shed() {
+        let mut devices = devices();
+        devices.get_mut("lamp00").unwrap().shed().unwrap();
+        let items = vec![
+            protocol::Request::new(Cmd::TurnOn, "lamp00".to_owned()),
+            protocol::Request::new(Cmd::SetLevel(500.0), "lamp02".to_owned()),
+        ];
+
+        batch(&mut devices, items, BatchMode::AllOrNothing);
+        assert!(!devices["lamp00"].is_on() && devices["lamp00"].is_shed());
+    }
+
+    #[test]
+    fn test_batch_fits_datagram() {
+        // Names are as long as names of devices in config usually are
+        let names: Vec<String> = ["socket", "therm", "lamp", "humidity", "lock", "leak"].iter()
+            .map(|dev_type| format!("{dev_type:_<16}"))
+            .collect();
+        let configs = names.iter()
+            .map(|name| json!({"name": name, "type": name.trim_end_matches('_')}))
+            .collect();
+        let mut devices = test_devices("batch_datagram", configs);
+        for dev in devices.values_mut() {
+            let _ = dev.execute(&Cmd::TurnOn);
+        }
+
+        for cmd in [Cmd::GetCapabilities, Cmd::GetState, Cmd::Energy, Cmd::GetAlerts] {
+            for name in names.iter() {
+                let items = vec![protocol::Request::new(cmd.clone(), name.to_owned()); MAX_BATCH_LEN];
+                let req = protocol::Request::new(Cmd::Batch(items, BatchMode::BestEffort), String::new());
+                let resp = handle_request(&mut devices, req);
+                let size = bincode::serialized_size(&resp).unwrap() as usize;
+                assert!(size <= MAX_DATAGRAM_PAYLOAD, "batch of {cmd:?} to {name} is {size} bytes");
+            }
+        }
+    }
+
+    #[test]
+    fn test_bound_response() {
+        let mut devices = devices();
+        let req = protocol::Request::new(Cmd::GetCapabilities, "lamp00".to_owned());
+        let resp = handle_request(&mut devices, req);
+        let size = bincode::serialized_size(&resp).unwrap() as usize;
+
+        let resp = bound_response(resp, size);
+        assert!(matches!(resp.resp_kind, ResponseKind::Success(SuccessKind::Capabilities(_))));
+        let resp = bound_response(resp, size - 1);
+        assert!(matches!(resp.resp_kind, ResponseKind::Err(ref e) if e.is(ErrorKind::ResponseTooLarge)));
+        assert!(matches!(resp.to_req.cmd, Cmd::GetCapabilities));
+    }
+
+    fn measurement(resp_kind: &ResponseKind) -> protocol::Measurement {
+        match resp_kind {
+            ResponseKind::Success(SuccessKind::Power(measurement) | SuccessKind::Humidity(measurement)) => *measurement,
+            ResponseKind::Success(SuccessKind::State(state)) => {
+                let mut reading = state.last_reading.as_deref().unwrap().clone();
+                *reading.measurement_mut().unwrap()
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_units_and_quality() {
+        let mut devices = devices();
+        let mut power = protocol::Request::new(Cmd::Power, "lamp00".to_owned());
+        power.units = vec![protocol::Unit::Kilowatt];
+        let items = vec![
+            protocol::Request::new(Cmd::TurnOn, "lamp00".to_owned()),
+            power.clone(),
+            protocol::Request::new(Cmd::Power, "lamp00".to_owned()),
+            protocol::Request::new(Cmd::TurnOn, "humidity01".to_owned()),
+            protocol::Request::new(Cmd::Humidity, "humidity01".to_owned()),
+            protocol::Request::new(Cmd::TurnOff, "humidity01".to_owned()),
+            protocol::Request::new(Cmd::Humidity, "humidity01".to_owned()),
+        ];
+        let mut req = protocol::Request::new(Cmd::Batch(items, BatchMode::BestEffort), String::new());
+        req.units = vec![protocol::Unit::Kelvin];
+        let results = match handle_request(&mut devices, req).resp_kind {
+            ResponseKind::Success(SuccessKind::Batch(results)) => results,
+            _ => panic!(),
+        };
+
+        // Item units take precedence, units of other quantities are ignored
+        let kw = measurement(&results[1]);
+        let watt = measurement(&results[2]);
+        assert_eq!((kw.unit, watt.unit), (protocol::Unit::Kilowatt, protocol::Unit::Watt));
+        assert_eq!(kw.quality, protocol::Quality::Simulated);
+        assert!((kw.value * 1000.0 - watt.value).abs() < 1e-9);
+
+        let on = measurement(&results[4]);
+        let off = measurement(&results[6]);
+        assert_eq!(off.quality, protocol::Quality::Stale);
+        assert_eq!((off.value, off.timestamp_ms), (on.value, on.timestamp_ms));
+
+        let mut state = protocol::Request::new(Cmd::GetState, "lamp00".to_owned());
+        state.units = vec![protocol::Unit::Watt];
+        let last = measurement(&handle_request(&mut devices, state).resp_kind);
+        assert_eq!(last.unit, protocol::Unit::Watt);
+        assert!((last.value - watt.value).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,361 @@
+use std::io::ErrorKind;
+use std::net::TcpListener;
+use std::path::Path;
+use std::sync::mpsc::Receiver;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use log::*;
+
+use super::console_server::{bind_listener, config_section, ConsoleCmd, Service};
+use super::device::{handle_on_owner, SharedDevices};
+use super::http;
+use super::metrics::SharedMetrics;
+use super::protocol::{self, Cmd, ErrorCategory, ListQuery, ResponseKind, SuccessKind};
+use super::request_handler;
+
+const TRANSPORT: &str = "rest";
+const OPENAPI_PATH: &str = "/openapi.json";
+
+/// Gateway section of config, e.g. `{"addr": "127.0.0.1:8080"}`
+#[derive(Deserialize, Clone)]
+pub struct RestConfig {
+    pub addr: String,
+}
+
+#[derive(Clone, Copy)]
+enum Action {
+    ListDevices,
+    /// Command executed on device named by path
+    Device(fn() -> Cmd),
+    OpenApi,
+}
+
+struct Route {
+    method: &'static str,
+    /// Segment `{name}` matches name of device
+    path: &'static str,
+    summary: &'static str,
+    /// Schema of success response in OpenAPI document
+    schema: &'static str,
+    action: Action,
+}
+
+/// Routes of gateway, OpenAPI document is generated from them
+const ROUTES: &[Route] = &[
+    Route { method: "GET", path: "/devices", summary: "List devices of all servers", schema: "DeviceList", action: Action::ListDevices },
+    Route { method: "GET", path: "/devices/{name}", summary: "Get state of device", schema: "DeviceState", action: Action::Device(|| Cmd::GetState) },
+    Route { method: "POST", path: "/devices/{name}/on", summary: "Turn device on", schema: "Ack", action: Action::Device(|| Cmd::TurnOn) },
+    Route { method: "POST", path: "/devices/{name}/off", summary: "Turn device off", schema: "Ack", action: Action::Device(|| Cmd::TurnOff) },
+    Route { method: "GET", path: "/devices/{name}/power", summary: "Read power of socket or lamp", schema: "Measurement", action: Action::Device(|| Cmd::Power) },
+    Route { method: "GET", path: "/devices/{name}/temperature", summary: "Read temperature of thermometer", schema: "Measurement", action: Action::Device(|| Cmd::Temperature) },
+    Route { method: "GET", path: OPENAPI_PATH, summary: "Get this document", schema: "OpenApi", action: Action::OpenApi },
+];
+
+impl Route {
+    /// Name of device if path matches route
+    fn matches(&self, path: &str) -> Option<Option<String>> {
+        let mut name = None;
+        let mut segments = path.trim_end_matches('/').split('/');
+        for pattern in self.path.split('/') {
+            let segment = segments.next()?;
+            if pattern == "{name}" {
+                name = Some(http::decode_segment(segment).filter(|name| !name.is_empty())?);
+            } else if pattern != segment {
+                return None;
+            }
+        }
+        segments.next().is_none().then_some(name)
+    }
+
+    fn operation_id(&self) -> String {
+        let mut id = self.method.to_lowercase();
+        for segment in self.path.split(['/', '.']).filter(|segment| !segment.is_empty()) {
+            let segment = segment.trim_matches(['{', '}']);
+            id.push_str(&segment[..1].to_uppercase());
+            id.push_str(&segment[1..]);
+        }
+        id
+    }
+}
+
+fn status(category: ErrorCategory) -> u16 {
+    match category {
+        ErrorCategory::Validation => 400,
+        ErrorCategory::NotFound => 404,
+        ErrorCategory::Permission => 403,
+        ErrorCategory::Device => 409,
+        ErrorCategory::Server => 503,
+    }
+}
+
+fn error_response(e: &protocol::Error) -> http::Response {
+    http::Response::json(status(e.category), &serde_json::to_value(e).unwrap_or_default())
+}
+
+fn success_body(success: &SuccessKind) -> Value {
+    match success {
+        SuccessKind::Ack => json!({"result": "ok"}),
+        SuccessKind::State(state) => serde_json::to_value(state).unwrap_or_default(),
+        SuccessKind::Power(measurement) | SuccessKind::Temp(measurement) => serde_json::to_value(measurement).unwrap_or_default(),
+        success => serde_json::to_value(success).unwrap_or_default(),
+    }
+}
+
+fn schemas() -> Value {
+    json!({
+        "Ack": {"type": "object", "properties": {"result": {"type": "string"}}},
+        "DeviceList": {"type": "array", "items": {"type": "object", "properties": {
+            "name": {"type": "string"},
+            "type_dev": {"type": "string"},
+            "transport": {"type": "string"},
+        }}},
+        "DeviceState": {"type": "object", "properties": {
+            "is_on": {"type": "boolean"},
+            "is_fault": {"type": "boolean"},
+            "last_reading": {"type": "object", "nullable": true},
+            "uptime_secs": {"type": "integer"},
+            "off_in_secs": {"type": "integer", "nullable": true},
+        }},
+        "Measurement": {"type": "object", "properties": {
+            "value": {"type": "number"},
+            "unit": {"type": "string"},
+            "quality": {"type": "string"},
+            "timestamp_ms": {"type": "integer"},
+        }},
+        "Error": {"type": "object", "properties": {
+            "code": {"type": "integer"},
+            "category": {"type": "string"},
+            "message": {"type": "string"},
+            "details": {"type": "string", "nullable": true},
+        }},
+        "OpenApi": {"type": "object"},
+    })
+}
+
+/// OpenAPI 3 document of routes
+fn openapi() -> Value {
+    let mut paths = Map::new();
+    for route in ROUTES {
+        let mut operation = json!({
+            "summary": route.summary,
+            "operationId": route.operation_id(),
+            "responses": {
+                "200": {
+                    "description": "Success",
+                    "content": {"application/json": {"schema": {"$ref": format!("#/components/schemas/{}", route.schema)}}},
+                },
+                "default": {
+                    "description": "Error of server or device",
+                    "content": {"application/json": {"schema": {"$ref": "#/components/schemas/Error"}}},
+                },
+            },
+        });
+        if route.path.contains("{name}") {
+            operation["parameters"] = json!([{"name": "name", "in": "path", "required": true, "schema": {"type": "string"}}]);
+        }
+        let item = paths.entry(route.path).or_insert_with(|| json!({}));
+        item[route.method.to_lowercase()] = operation;
+    }
+    json!({
+        "openapi": "3.0.3",
+        "info": {"title": "Smart house REST gateway", "version": env!("CARGO_PKG_VERSION")},
+        "paths": paths,
+        "components": {"schemas": schemas()},
+    })
+}
+
+/// JSON gateway to devices of all servers. Device commands are handled
+/// the same way as requests of tcp and udp clients.
+pub struct RestGateway {
+    config: Option<RestConfig>,
+    servers: Vec<(&'static str, SharedDevices)>,
+    metrics: SharedMetrics,
+    rx: Option<Receiver<ConsoleCmd>>,
+}
+
+impl Service for RestGateway {
+    fn start_service(mut self, rx: Receiver<ConsoleCmd>) -> JoinHandle<()> {
+        self.rx = Some(rx);
+        self.start()
+    }
+
+    fn rx(&self) -> &Receiver<ConsoleCmd> {
+        self.rx.as_ref().unwrap()
+    }
+}
+
+impl RestGateway {
+    /// Gateway is disabled if config has no rest section
+    pub fn new(config_path: &Path, servers: Vec<(&'static str, SharedDevices)>, metrics: SharedMetrics) -> Self {
+        let config = config_section(config_path, "rest");
+        Self {
+            config,
+            servers,
+            metrics,
+            rx: None,
+        }
+    }
+
+    pub fn name() -> &'static str {
+        "RestGateway"
+    }
+
+    fn bind(config: &RestConfig) -> TcpListener {
+        let listener = bind_listener(&config.addr);
+        info!("Rest gateway is served on http://{}", config.addr);
+        listener
+    }
+
+    fn start(self) -> JoinHandle<()> {
+        thread::spawn(move || {
+            let listener = self.config.as_ref().map(Self::bind);
+            loop {
+                if let Some(ConsoleCmd::Exit) = self.get_cmd() {
+                    break;
+                }
+                match listener.as_ref().map(|listener| listener.accept()) {
+                    Some(Ok((stream, _))) => http::serve(stream, |req| self.handle(req)),
+                    Some(Err(e)) if e.kind() != ErrorKind::WouldBlock => {
+                        error!("Invalid listener: {:?}", e);
+                        break;
+                    }
+                    _ => thread::sleep(Duration::from_millis(100)),
+                }
+            }
+        })
+    }
+
+    fn handle(&self, req: &http::Request) -> http::Response {
+        let mut is_path_known = false;
+        for route in ROUTES {
+            let Some(name) = route.matches(&req.path) else {
+                continue;
+            };
+            is_path_known = true;
+            if route.method != req.method {
+                continue;
+            }
+            return match (route.action, name) {
+                (Action::ListDevices, _) => self.list_devices(),
+                (Action::Device(cmd), Some(name)) => self.execute(&name, cmd()),
+                (Action::OpenApi, _) => http::Response::json(200, &openapi()),
+                (Action::Device(_), None) => http::Response::text(404, "Not found\n"),
+            };
+        }
+        if is_path_known {
+            http::Response::text(405, "Method isn't allowed\n")
+        } else {
+            http::Response::text(404, "Not found\n")
+        }
+    }
+
+    /// Executes command on server owning device
+    fn execute(&self, name: &str, cmd: Cmd) -> http::Response {
+        let started = Instant::now();
+        let resp = handle_on_owner(&self.servers, protocol::Request::new(cmd, name.to_owned()));
+        self.metrics.lock().unwrap().record(TRANSPORT, &resp, started.elapsed());
+        match &resp.resp_kind {
+            ResponseKind::Success(success) => http::Response::json(200, &success_body(success)),
+            ResponseKind::Err(e) => error_response(e),
+        }
+    }
+
+    /// Devices of all servers ordered by server then by name, pages are joined
+    fn list_devices(&self) -> http::Response {
+        let mut list = Vec::new();
+        for (transport, devices) in self.servers.iter() {
+            let mut devices = devices.lock().unwrap();
+            let mut query = ListQuery::default();
+            loop {
+                let started = Instant::now();
+                let resp = request_handler::handle_request(&mut devices, protocol::Request::new(Cmd::GetListDevices(query.clone()), String::new()));
+                self.metrics.lock().unwrap().record(TRANSPORT, &resp, started.elapsed());
+                let page = match resp.resp_kind {
+                    ResponseKind::Success(SuccessKind::ListDev(page)) => page,
+                    ResponseKind::Err(e) => return error_response(&e),
+                    _ => break,
+                };
+                for dev in page.devices {
+                    list.push(json!({"name": dev.name, "type_dev": dev.type_dev, "transport": transport}));
+                }
+                match page.next_cursor {
+                    Some(cursor) => query.cursor = Some(cursor),
+                    None => break,
+                }
+            }
+        }
+        http::Response::json(200, &Value::Array(list))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use crate::device::test_devices;
+
+    fn server(configs: Vec<Value>) -> SharedDevices {
+        Arc::new(Mutex::new(test_devices("rest", configs)))
+    }
+
+    fn gateway() -> RestGateway {
+        let tcp = server(vec![json!({"name": "sock 1", "type": "socket", "model": {"kind": "gauss", "mean": 1000.0, "spread": 0.0}})]);
+        let udp = server(vec![json!({"name": "therm1", "type": "therm"}), json!({"name": "leak1", "type": "leak"})]);
+        RestGateway {
+            config: None,
+            servers: vec![("tcp", tcp), ("udp", udp)],
+            metrics: SharedMetrics::default(),
+            rx: None,
+        }
+    }
+
+    fn call(gateway: &RestGateway, method: &str, path: &str) -> (u16, Value) {
+        let resp = gateway.handle(&http::Request {
+            method: method.to_owned(),
+            path: path.to_owned(),
+        });
+        (resp.status, serde_json::from_slice(&resp.body).unwrap_or_default())
+    }
+
+    #[test]
+    fn test_routes() {
+        let gateway = gateway();
+        let (status, list) = call(&gateway, "GET", "/devices");
+        assert_eq!(status, 200);
+        let names: Vec<&str> = list.as_array().unwrap().iter().map(|dev| dev["name"].as_str().unwrap()).collect();
+        assert_eq!(names, ["sock 1", "leak1", "therm1"]);
+        assert_eq!(list[2]["transport"], "udp");
+
+        assert_eq!(call(&gateway, "POST", "/devices/sock%201/on"), (200, json!({"result": "ok"})));
+        let (status, state) = call(&gateway, "GET", "/devices/sock%201/");
+        assert_eq!((status, &state["is_on"]), (200, &json!(true)));
+        let (status, power) = call(&gateway, "GET", "/devices/sock%201/power");
+        assert_eq!((status, &power["value"], &power["unit"]), (200, &json!(1000.0), &json!("Watt")));
+
+        let (status, error) = call(&gateway, "GET", "/devices/leak1/power");
+        assert_eq!((status, &error["code"]), (400, &json!(protocol::ErrorKind::WrongCmd.code())));
+        let (status, error) = call(&gateway, "POST", "/devices/lamp9/off");
+        assert_eq!((status, &error["code"]), (404, &json!(protocol::ErrorKind::DevNotFound.code())));
+        assert_eq!(call(&gateway, "GET", "/devices/therm1/on").0, 405);
+        assert_eq!(call(&gateway, "GET", "/devices/therm1/humidity").0, 404);
+        assert_eq!(call(&gateway, "GET", "/devices//power").0, 404);
+    }
+
+    #[test]
+    fn test_openapi() {
+        let (status, doc) = call(&gateway(), "GET", OPENAPI_PATH);
+        assert_eq!(status, 200);
+        let paths = doc["paths"].as_object().unwrap();
+        for route in ROUTES {
+            let operation = &paths[route.path][route.method.to_lowercase()];
+            assert_eq!(operation["summary"], route.summary);
+            let schema = operation["responses"]["200"]["content"]["application/json"]["schema"]["$ref"].as_str().unwrap();
+            assert!(doc["components"]["schemas"].get(schema.rsplit('/').next().unwrap()).is_some());
+        }
+        assert_eq!(paths["/devices/{name}/on"]["post"]["operationId"], "postDevicesNameOn");
+        assert_eq!(paths["/devices/{name}"]["get"]["parameters"][0]["in"], "path");
+    }
+}
@@ -0,0 +1,351 @@
+use std::fmt::Display;
+use std::fs;
+use std::path::Path;
+use std::sync::mpsc::Receiver;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use chrono::{DateTime, Local};
+use serde::Deserialize;
+use log::*;
+
+use super::console_server::{ConsoleCmd, Service};
+use super::device::{with_device, Reading, SharedDevices};
+use super::protocol::{Cmd, Quality};
+
+const DEFAULT_PERIOD_SECS: u64 = 1;
+
+fn default_period() -> u64 {
+    DEFAULT_PERIOD_SECS
+}
+
+/// Condition on reading of device, at least one bound is required
+#[derive(Deserialize, Clone)]
+pub struct Condition {
+    pub device: String,
+    pub reading: Reading,
+    pub above: Option<f64>,
+    pub below: Option<f64>,
+}
+
+impl Condition {
+    fn holds(&self, value: f64) -> bool {
+        self.above.is_none_or(|above| value > above) && self.below.is_none_or(|below| value < below)
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct Action {
+    pub device: String,
+    pub cmd: Cmd,
+}
+
+/// Rule from rules file, e.g.
+/// `{"name": "fan", "when": {"device": "therm1", "reading": "temperature", "above": 28.0},
+/// "then": {"device": "sock2", "cmd": "TurnOn"}}`
+#[derive(Deserialize, Clone)]
+pub struct Rule {
+    pub name: String,
+    pub when: Condition,
+    pub then: Action,
+    /// Rule is evaluated, but its action isn't executed
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+impl Display for Rule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "when {} {:?}", self.when.device, self.when.reading)?;
+        if let Some(above) = self.when.above {
+            write!(f, " > {above}")?;
+        }
+        if let Some(below) = self.when.below {
+            write!(f, " < {below}")?;
+        }
+        write!(f, " then {:?} {}", self.then.cmd, self.then.device)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RulesConfig {
+    /// No rule executes its action
+    #[serde(default)]
+    pub dry_run: bool,
+    #[serde(default = "default_period")]
+    pub period_secs: u64,
+    pub rules: Vec<Rule>,
+}
+
+/// Result of the last evaluation of rule
+#[derive(Clone, PartialEq, Debug)]
+pub enum Outcome {
+    NotEvaluated,
+    /// Reading isn't available, e.g. device is off
+    Unavailable(String),
+    Idle(f64),
+    /// Condition still holds after firing, rule fires again when it holds next time
+    Holds(f64),
+    Fired(f64, Result<(), String>),
+    DryRun(f64),
+}
+
+impl Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Outcome::NotEvaluated => write!(f, "not evaluated yet"),
+            Outcome::Unavailable(reason) => write!(f, "reading unavailable: {reason}"),
+            Outcome::Idle(value) => write!(f, "condition is false, value {value:.2}"),
+            Outcome::Holds(value) => write!(f, "condition holds, value {value:.2}, already fired"),
+            Outcome::Fired(value, Ok(())) => write!(f, "fired, value {value:.2}"),
+            Outcome::Fired(value, Err(e)) => write!(f, "fired, value {value:.2}, action failed: {e}"),
+            Outcome::DryRun(value) => write!(f, "fired in dry run, value {value:.2}"),
+        }
+    }
+}
+
+struct RuleState {
+    rule: Rule,
+    is_active: bool,
+    outcome: Outcome,
+    evaluated_at: Option<DateTime<Local>>,
+    fired_at: Option<DateTime<Local>>,
+}
+
+/// Evaluates rules against devices of all servers. Rule fires when its
+/// condition becomes true, so action isn't repeated while condition holds.
+pub struct RuleEngine {
+    rules: Vec<RuleState>,
+    dry_run: bool,
+    period: chrono::Duration,
+    servers: Vec<SharedDevices>,
+    rx: Option<Receiver<ConsoleCmd>>,
+}
+
+impl Service for RuleEngine {
+    fn start_service(mut self, rx: Receiver<ConsoleCmd>) -> JoinHandle<()> {
+        self.rx = Some(rx);
+        self.start()
+    }
+
+    fn rx(&self) -> &Receiver<ConsoleCmd> {
+        self.rx.as_ref().unwrap()
+    }
+}
+
+impl RuleEngine {
+    /// Server works without rules if rules file is absent
+    pub fn new(rules_path: &Path, servers: Vec<SharedDevices>) -> Self {
+        let config = match fs::read_to_string(rules_path) {
+            Ok(json) => match serde_json::from_str::<RulesConfig>(&json) {
+                Ok(config) => config,
+                Err(e) => {
+                    error!("Can't parse rules {:?}: {:?}", rules_path, e);
+                    panic!();
+                }
+            },
+            Err(e) => {
+                warn!("Can't read rules from {:?}: {:?}", rules_path, e);
+                RulesConfig {
+                    dry_run: false,
+                    period_secs: DEFAULT_PERIOD_SECS,
+                    rules: Vec::new(),
+                }
+            }
+        };
+        info!("RuleEngine created with {} rules", config.rules.len());
+        Self::from_config(config, servers)
+    }
+
+    pub fn from_config(config: RulesConfig, servers: Vec<SharedDevices>) -> Self {
+        let rules = config.rules.into_iter()
+            .filter(|rule| {
+                let is_valid = rule.when.above.is_some() || rule.when.below.is_some();
+                if !is_valid {
+                    error!("Rule {} has no bounds of condition", rule.name);
+                }
+                is_valid
+            })
+            .map(|rule| RuleState {
+                rule,
+                is_active: false,
+                outcome: Outcome::NotEvaluated,
+                evaluated_at: None,
+                fired_at: None,
+            })
+            .collect();
+        Self {
+            rules,
+            dry_run: config.dry_run,
+            period: chrono::Duration::seconds(config.period_secs as i64),
+            servers,
+            rx: None,
+        }
+    }
+
+    pub fn name() -> &'static str {
+        "RuleEngine"
+    }
+
+    fn start(mut self) -> JoinHandle<()> {
+        thread::spawn(move || {
+            let mut next_evaluation = Local::now();
+            loop {
+                match self.get_cmd() {
+                    Some(ConsoleCmd::Exit) => break,
+                    Some(ConsoleCmd::ListRules) => self.print_rules(),
+                    Some(ConsoleCmd::DryRun(dry_run)) => {
+                        info!("Dry run of rules: {dry_run}");
+                        self.dry_run = dry_run;
+                    }
+                    Some(ConsoleCmd::PowerBudget) | None => {}
+                }
+                let now = Local::now();
+                if now >= next_evaluation {
+                    next_evaluation = now + self.period;
+                    self.evaluate(now);
+                }
+                thread::sleep(Duration::from_millis(100));
+            }
+        })
+    }
+
+    pub fn evaluate(&mut self, now: DateTime<Local>) {
+        for state in self.rules.iter_mut() {
+            Self::evaluate_rule(&self.servers, state, self.dry_run, now);
+        }
+    }
+
+    fn evaluate_rule(servers: &[SharedDevices], state: &mut RuleState, dry_run: bool, now: DateTime<Local>) {
+        let rule = &state.rule;
+        state.evaluated_at = Some(now);
+        let value = match with_device(servers, &rule.when.device, |dev| dev.sample(rule.when.reading)) {
+            Some(Ok(sample)) if sample.quality != Quality::Stale => sample.value,
+            Some(Ok(_)) => {
+                state.outcome = Outcome::Unavailable("value is stale".to_owned());
+                return;
+            }
+            Some(Err(e)) => {
+                state.outcome = Outcome::Unavailable(e.message);
+                return;
+            }
+            None => {
+                state.outcome = Outcome::Unavailable("device not found".to_owned());
+                return;
+            }
+        };
+        if !rule.when.holds(value) {
+            state.is_active = false;
+            state.outcome = Outcome::Idle(value);
+            return;
+        }
+        if state.is_active {
+            state.outcome = Outcome::Holds(value);
+            return;
+        }
+
+        state.is_active = true;
+        state.fired_at = Some(now);
+        if dry_run || rule.dry_run {
+            info!("Rule {} fired in dry run, value {value}: {}", rule.name, rule);
+            state.outcome = Outcome::DryRun(value);
+            return;
+        }
+        let res = match with_device(servers, &rule.then.device, |dev| dev.execute_automated(&rule.then.cmd)) {
+            Some(Ok(_)) => Ok(()),
+            Some(Err(e)) => Err(e.message),
+            None => Err("device not found".to_owned()),
+        };
+        match &res {
+            Ok(()) => info!("Rule {} fired, value {value}: {}", rule.name, rule),
+            Err(e) => warn!("Rule {} fired, value {value}: {}, action failed: {e}", rule.name, rule),
+        }
+        state.outcome = Outcome::Fired(value, res);
+    }
+
+    fn print_rules(&self) {
+        let mode = if self.dry_run { ", dry run" } else { "" };
+        println!("Rules: {}{mode}", self.rules.len());
+        for state in self.rules.iter() {
+            let dry_run = if state.rule.dry_run { " (dry run)" } else { "" };
+            println!("{}{dry_run}: {}", state.rule.name, state.rule);
+            let time = |time: Option<DateTime<Local>>| time.map_or("never".to_owned(), |time| time.format("%H:%M:%S").to_string());
+            println!("  last result: {}, evaluated: {}, fired: {}",
+                state.outcome, time(state.evaluated_at), time(state.fired_at));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use crate::device::test_devices;
+    use serde_json::json;
+
+    fn devices(names: &[&str]) -> SharedDevices {
+        let configs = names.iter().map(|name| json!({"name": name, "type": "lamp"})).collect();
+        Arc::new(Mutex::new(test_devices("rules", configs)))
+    }
+
+    fn engine(dry_run: bool, servers: Vec<SharedDevices>) -> RuleEngine {
+        let config: RulesConfig = serde_json::from_value(json!({
+            "dry_run": dry_run,
+            "rules": [
+                {
+                    "name": "power_guard",
+                    "when": {"device": "lamp1", "reading": "power", "above": 30.0},
+                    "then": {"device": "lamp2", "cmd": "TurnOff"}
+                },
+                {
+                    "name": "no_bounds",
+                    "when": {"device": "lamp1", "reading": "power"},
+                    "then": {"device": "lamp2", "cmd": "TurnOn"}
+                }
+            ]
+        })).unwrap();
+        RuleEngine::from_config(config, servers)
+    }
+
+    fn execute(servers: &[SharedDevices], name: &str, cmd: Cmd) {
+        with_device(servers, name, |dev| dev.execute(&cmd)).unwrap().unwrap();
+    }
+
+    fn is_on(servers: &[SharedDevices], name: &str) -> bool {
+        with_device(servers, name, |dev| dev.is_on()).unwrap()
+    }
+
+    #[test]
+    fn test_rule_fires_once() {
+        // Devices of condition and action belong to different servers
+        let servers = vec![devices(&["lamp1"]), devices(&["lamp2"])];
+        let mut engine = engine(false, servers.clone());
+        assert_eq!(engine.rules.len(), 1);
+        execute(&servers, "lamp2", Cmd::TurnOn);
+
+        engine.evaluate(Local::now());
+        assert_eq!(engine.rules[0].outcome, Outcome::Idle(0.0));
+
+        execute(&servers, "lamp1", Cmd::TurnOn);
+        engine.evaluate(Local::now());
+        assert!(matches!(engine.rules[0].outcome, Outcome::Fired(_, Ok(()))));
+        assert!(!is_on(&servers, "lamp2"));
+
+        // Rule doesn't fire again while condition holds
+        execute(&servers, "lamp2", Cmd::TurnOn);
+        engine.evaluate(Local::now());
+        assert!(matches!(engine.rules[0].outcome, Outcome::Holds(_)));
+        assert!(is_on(&servers, "lamp2"));
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let servers = vec![devices(&["lamp1", "lamp2"])];
+        let mut engine = engine(true, servers.clone());
+        execute(&servers, "lamp1", Cmd::TurnOn);
+        execute(&servers, "lamp2", Cmd::TurnOn);
+        engine.evaluate(Local::now());
+        assert!(matches!(engine.rules[0].outcome, Outcome::DryRun(_)));
+        assert!(engine.rules[0].fired_at.is_some());
+        assert!(is_on(&servers, "lamp2"));
+    }
+}
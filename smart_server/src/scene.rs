@@ -0,0 +1,241 @@
+use log::*;
+
+use super::device::Devices;
+use super::protocol::{self, Cmd, DeviceResult, Error, ErrorKind, Group, ResponseKind, Scene, SceneItem, SuccessKind, Unit};
+
+/// Executes commands on devices one by one, every device gets its own result
+fn apply<'a>(devices: &mut Devices, items: impl Iterator<Item = (&'a String, &'a Cmd)>, units: &[Unit]) -> Vec<DeviceResult> {
+    items
+        .map(|(dev_name, cmd)| {
+            let result = match devices.get_mut(dev_name).map(|dev| dev.execute(cmd)) {
+                Some(Ok(mut success)) => {
+                    success.convert(units);
+                    ResponseKind::Success(success)
+                }
+                Some(Err(e)) => ResponseKind::Err(e),
+                None => ResponseKind::Err(Error::new(ErrorKind::DevNotFound).with_details(dev_name.clone())),
+            };
+            DeviceResult {
+                dev_name: dev_name.clone(),
+                result,
+            }
+        })
+        .collect()
+}
+
+/// Groups and scenes of server. Client sends definition to all servers,
+/// so each server keeps only its own devices. Groups and scenes defined
+/// over protocol live until server stops, config ones are defined on start.
+pub struct Scenes {
+    groups: Vec<Group>,
+    scenes: Vec<Scene>,
+}
+
+impl Scenes {
+    pub fn new(groups: Vec<Group>, scenes: Vec<Scene>, devices: &Devices) -> Self {
+        let groups = groups.into_iter()
+            .map(|mut group| {
+                group.devices.retain(|name| {
+                    let is_valid = devices.contains_key(name);
+                    if !is_valid {
+                        error!("Group {} has unknown device {name}", group.name);
+                    }
+                    is_valid
+                });
+                group
+            })
+            .collect();
+        let scenes = scenes.into_iter()
+            .map(|mut scene| {
+                scene.items.retain(|item| {
+                    let is_valid = devices.contains_key(&item.dev_name);
+                    if !is_valid {
+                        error!("Scene {} has unknown device {}", scene.name, item.dev_name);
+                    }
+                    is_valid
+                });
+                scene
+            })
+            .collect();
+        Self { groups, scenes }
+    }
+
+    pub fn is_scene_cmd(cmd: &Cmd) -> bool {
+        matches!(cmd, Cmd::DefineGroup(_) | Cmd::GroupAction(_) | Cmd::DefineScene(_) | Cmd::ApplyScene | Cmd::GetScenes)
+    }
+
+    fn define_group(&mut self, name: &str, members: &[String], devices: &Devices) -> Result<(), Error> {
+        let pos = self.groups.iter().position(|group| group.name == name);
+        let local: Vec<String> = members.iter().filter(|name| devices.contains_key(*name)).cloned().collect();
+        match (pos, members.is_empty(), local.is_empty()) {
+            (Some(pos), true, _) => {
+                info!("Group {name} removed");
+                self.groups.remove(pos);
+            }
+            (None, true, _) => return Err(Error::new(ErrorKind::SceneNotFound).with_details(name.to_owned())),
+            (_, false, true) => return Err(Error::new(ErrorKind::DevNotFound).with_details(members.join(", "))),
+            (pos, false, false) => {
+                info!("Group {name} defined: {:?}", local);
+                let group = Group {
+                    name: name.to_owned(),
+                    devices: local,
+                };
+                match pos {
+                    Some(pos) => self.groups[pos] = group,
+                    None => self.groups.push(group),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn define_scene(&mut self, name: &str, items: &[SceneItem], devices: &Devices) -> Result<(), Error> {
+        let pos = self.scenes.iter().position(|scene| scene.name == name);
+        let local: Vec<SceneItem> = items.iter().filter(|item| devices.contains_key(&item.dev_name)).cloned().collect();
+        match (pos, items.is_empty(), local.is_empty()) {
+            (Some(pos), true, _) => {
+                info!("Scene {name} removed");
+                self.scenes.remove(pos);
+            }
+            (None, true, _) => return Err(Error::new(ErrorKind::SceneNotFound).with_details(name.to_owned())),
+            (_, false, true) => {
+                let names: Vec<&str> = items.iter().map(|item| item.dev_name.as_str()).collect();
+                return Err(Error::new(ErrorKind::DevNotFound).with_details(names.join(", ")));
+            }
+            (pos, false, false) => {
+                info!("Scene {name} defined with {} commands", local.len());
+                let scene = Scene {
+                    name: name.to_owned(),
+                    items: local,
+                };
+                match pos {
+                    Some(pos) => self.scenes[pos] = scene,
+                    None => self.scenes.push(scene),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn execute(&mut self, devices: &mut Devices, req: &protocol::Request) -> Result<SuccessKind, Error> {
+        if req.dev_name.is_empty() && !matches!(req.cmd, Cmd::GetScenes) {
+            return Err(Error::new(ErrorKind::InvalidArgument).with_details("name of group or scene is empty".to_owned()));
+        }
+        let not_found = || Error::new(ErrorKind::SceneNotFound).with_details(req.dev_name.clone());
+        match &req.cmd {
+            Cmd::DefineGroup(members) => self.define_group(&req.dev_name, members, devices).map(|_| SuccessKind::Ack),
+            Cmd::DefineScene(items) => self.define_scene(&req.dev_name, items, devices).map(|_| SuccessKind::Ack),
+            Cmd::GroupAction(cmd) => {
+                let group = self.groups.iter().find(|group| group.name == req.dev_name).ok_or_else(not_found)?;
+                info!("Group {} executes {:?}", group.name, cmd);
+                let results = apply(devices, group.devices.iter().map(|name| (name, cmd.as_ref())), &req.units);
+                Ok(SuccessKind::DeviceResults(results))
+            }
+            Cmd::ApplyScene => {
+                let scene = self.scenes.iter().find(|scene| scene.name == req.dev_name).ok_or_else(not_found)?;
+                info!("Scene {} is applied", scene.name);
+                let results = apply(devices, scene.items.iter().map(|item| (&item.dev_name, &item.cmd)), &req.units);
+                Ok(SuccessKind::DeviceResults(results))
+            }
+            Cmd::GetScenes => Ok(SuccessKind::Scenes(self.groups.clone(), self.scenes.clone())),
+            cmd => Err(Error::new(ErrorKind::WrongCmd).with_details(format!("{:?} isn't scene command", cmd))),
+        }
+    }
+
+    pub fn handle_request(&mut self, devices: &mut Devices, req: protocol::Request) -> protocol::Response {
+        match self.execute(devices, &req) {
+            Ok(success) => protocol::Response::new_success_response(req, success),
+            Err(e) => {
+                info!("Scene request {:?} failed: {:?}", req.cmd, e);
+                protocol::Response::new_err_response(req, e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::test_devices;
+    use serde_json::json;
+
+    fn devices() -> Devices {
+        test_devices("scene", vec![
+            json!({"name": "lamp1", "type": "lamp"}),
+            json!({"name": "lamp2", "type": "lamp"}),
+            json!({"name": "humidity1", "type": "humidity"}),
+        ])
+    }
+
+    fn handle(scenes: &mut Scenes, devices: &mut Devices, cmd: Cmd, name: &str) -> ResponseKind {
+        scenes.handle_request(devices, protocol::Request::new(cmd, name.to_owned())).resp_kind
+    }
+
+    fn results(resp_kind: ResponseKind) -> Vec<(String, Option<u16>)> {
+        let ResponseKind::Success(SuccessKind::DeviceResults(results)) = resp_kind else {
+            panic!();
+        };
+        results.into_iter()
+            .map(|res| match res.result {
+                ResponseKind::Success(_) => (res.dev_name, None),
+                ResponseKind::Err(e) => (res.dev_name, Some(e.code)),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_group_action() {
+        let mut devices = devices();
+        let config: Vec<Group> = serde_json::from_value(json!([{"name": "lights", "devices": ["lamp1", "lamp2", "sock9"]}])).unwrap();
+        let mut scenes = Scenes::new(config, Vec::new(), &devices);
+
+        let res = results(handle(&mut scenes, &mut devices, Cmd::GroupAction(Box::new(Cmd::TurnOn)), "lights"));
+        assert_eq!(res, [("lamp1".to_owned(), None), ("lamp2".to_owned(), None)]);
+        assert!(devices["lamp1"].is_on() && devices["lamp2"].is_on());
+
+        // Devices of another server are left to it
+        let members = vec!["lamp2".to_owned(), "humidity1".to_owned(), "sock1".to_owned()];
+        handle(&mut scenes, &mut devices, Cmd::DefineGroup(members), "mixed");
+        let res = results(handle(&mut scenes, &mut devices, Cmd::GroupAction(Box::new(Cmd::SetLevel(30.0))), "mixed"));
+        assert_eq!(res, [("lamp2".to_owned(), None), ("humidity1".to_owned(), Some(ErrorKind::WrongCmd.code()))]);
+
+        let res = handle(&mut scenes, &mut devices, Cmd::DefineGroup(vec!["sock1".to_owned()]), "remote");
+        assert!(matches!(res, ResponseKind::Err(e) if e.is(ErrorKind::DevNotFound)));
+        handle(&mut scenes, &mut devices, Cmd::DefineGroup(Vec::new()), "mixed");
+        let res = handle(&mut scenes, &mut devices, Cmd::GroupAction(Box::new(Cmd::TurnOff)), "mixed");
+        assert!(matches!(res, ResponseKind::Err(e) if e.is(ErrorKind::SceneNotFound)));
+    }
+
+    #[test]
+    fn test_apply_scene() {
+        let mut devices = devices();
+        let config: Vec<Scene> = serde_json::from_value(json!([{"name": "night", "items": [
+            {"dev_name": "lamp1", "cmd": "TurnOff"},
+            {"dev_name": "lamp2", "cmd": "TurnOn"},
+            {"dev_name": "lamp2", "cmd": {"SetLevel": 10.0}},
+        ]}])).unwrap();
+        let mut scenes = Scenes::new(Vec::new(), config, &devices);
+        devices.get_mut("lamp1").unwrap().execute(&Cmd::TurnOn).unwrap();
+
+        let res = results(handle(&mut scenes, &mut devices, Cmd::ApplyScene, "night"));
+        assert_eq!(res.len(), 3);
+        assert!(res.iter().all(|(_, code)| code.is_none()));
+        assert!(!devices["lamp1"].is_on() && devices["lamp2"].is_on());
+
+        let item = SceneItem {
+            dev_name: "lamp1".to_owned(),
+            cmd: Cmd::SetLevel(500.0),
+        };
+        handle(&mut scenes, &mut devices, Cmd::DefineScene(vec![item]), "bright");
+        let res = results(handle(&mut scenes, &mut devices, Cmd::ApplyScene, "bright"));
+        assert_eq!(res, [("lamp1".to_owned(), Some(ErrorKind::OutOfRange.code()))]);
+        match handle(&mut scenes, &mut devices, Cmd::GetScenes, "") {
+            ResponseKind::Success(SuccessKind::Scenes(groups, scenes)) => {
+                assert!(groups.is_empty());
+                let names: Vec<&str> = scenes.iter().map(|scene| scene.name.as_str()).collect();
+                assert_eq!(names, ["night", "bright"]);
+            }
+            _ => panic!(),
+        }
+    }
+}
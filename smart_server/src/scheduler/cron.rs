@@ -0,0 +1,157 @@
+use std::str::FromStr;
+
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDateTime, TimeZone, Timelike};
+
+/// Max search window of the next run, cron may match rarely, e.g. on 29 of February
+const MAX_SEARCH_DAYS: i64 = 5 * 366;
+
+/// Cron expression "minute hour day-of-month month day-of-week" in local time.
+/// Fields support `*`, numbers, ranges `a-b`, lists `a,b` and steps `*/n`, `a-b/n`.
+/// Day of week is 0-7, both 0 and 7 are Sunday.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Day of month and day of week are combined by "or" when both are restricted
+    is_any_day: bool,
+    is_any_weekday: bool,
+}
+
+fn parse_value(value: &str, min: u32, max: u32) -> Result<u32, String> {
+    match value.parse::<u32>() {
+        Ok(value) if (min..=max).contains(&value) => Ok(value),
+        _ => Err(format!("{value} isn't in {min}..={max}")),
+    }
+}
+
+/// Parses field into bit mask of allowed values
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+    let mut mask = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().ok().filter(|step| *step > 0).ok_or(format!("invalid step {step}"))?),
+            None => (part, 1),
+        };
+        let (from, to) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((from, to)) => (parse_value(from, min, max)?, parse_value(to, min, max)?),
+                None => {
+                    let value = parse_value(range, min, max)?;
+                    (value, if step > 1 { max } else { value })
+                }
+            },
+        };
+        if from > to {
+            return Err(format!("invalid range {range}"));
+        }
+        for value in (from..=to).step_by(step as usize) {
+            mask |= 1 << value;
+        }
+    }
+    Ok(mask)
+}
+
+impl FromStr for Cron {
+    type Err = String;
+
+    fn from_str(expr: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(format!("expected 5 fields, got {}", fields.len()));
+        };
+        let mut weekdays_mask = parse_field(weekdays, 0, 7)?;
+        if weekdays_mask & (1 << 7) != 0 {
+            weekdays_mask = (weekdays_mask | 1) & !(1 << 7);
+        }
+        Ok(Self {
+            minutes: parse_field(minutes, 0, 59)?,
+            hours: parse_field(hours, 0, 23)?,
+            days: parse_field(days, 1, 31)?,
+            months: parse_field(months, 1, 12)?,
+            weekdays: weekdays_mask,
+            is_any_day: days == "*",
+            is_any_weekday: weekdays == "*",
+        })
+    }
+}
+
+fn contains(mask: u64, value: u32) -> bool {
+    mask & (1 << value) != 0
+}
+
+impl Cron {
+    fn matches_day(&self, time: &NaiveDateTime) -> bool {
+        let day = contains(self.days, time.day());
+        let weekday = contains(self.weekdays, time.weekday().num_days_from_sunday());
+        let is_day = match (self.is_any_day, self.is_any_weekday) {
+            (true, true) => true,
+            (true, false) => weekday,
+            (false, true) => day,
+            (false, false) => day || weekday,
+        };
+        is_day && contains(self.months, time.month())
+    }
+
+    /// The first matching minute after given time
+    pub fn next_after(&self, time: &DateTime<Local>) -> Option<DateTime<Local>> {
+        let start = time.naive_local().with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+        let end = start + Duration::days(MAX_SEARCH_DAYS);
+        let mut time = start;
+        while time < end {
+            if !self.matches_day(&time) {
+                time = time.date().succ_opt()?.and_hms_opt(0, 0, 0)?;
+                continue;
+            }
+            if !contains(self.hours, time.hour()) {
+                time = time.with_minute(0)? + Duration::hours(1);
+                continue;
+            }
+            if contains(self.minutes, time.minute()) {
+                // Time skipped by daylight saving transition doesn't exist
+                if let Some(local) = Local.from_local_datetime(&time).earliest() {
+                    return Some(local);
+                }
+            }
+            time += Duration::minutes(1);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local(time: &str) -> DateTime<Local> {
+        let time = NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M").unwrap();
+        Local.from_local_datetime(&time).unwrap()
+    }
+
+    fn next(expr: &str, time: &str) -> String {
+        let cron: Cron = expr.parse().unwrap();
+        cron.next_after(&local(time)).unwrap().format("%Y-%m-%d %H:%M").to_string()
+    }
+
+    #[test]
+    fn test_next_after() {
+        // 2026-10-16 is Friday
+        assert_eq!(next("30 6 * * 1-5", "2026-10-16 06:30"), "2026-10-19 06:30");
+        assert_eq!(next("30 6 * * 1-5", "2026-10-16 06:29"), "2026-10-16 06:30");
+        assert_eq!(next("0 23 * * *", "2026-10-16 23:00"), "2026-10-17 23:00");
+        assert_eq!(next("*/15 * * * *", "2026-10-16 10:07"), "2026-10-16 10:15");
+        assert_eq!(next("0 0 29 2 *", "2026-10-16 10:00"), "2028-02-29 00:00");
+        assert_eq!(next("0 12 1 * 7", "2026-10-16 10:00"), "2026-10-18 12:00");
+        assert_eq!(next("5,10 8-9 * * *", "2026-10-16 08:10"), "2026-10-16 09:05");
+    }
+
+    #[test]
+    fn test_invalid() {
+        for expr in ["* * * *", "60 * * * *", "* 24 * * *", "* * 0 * *", "*/0 * * * *", "5-1 * * * *", "a * * * *"] {
+            assert!(expr.parse::<Cron>().is_err(), "{expr}");
+        }
+    }
+}
@@ -0,0 +1,342 @@
+mod cron;
+
+use std::fs;
+use std::path::PathBuf;
+
+use chrono::{DateTime, Local, TimeZone};
+use serde::{Deserialize, Serialize};
+use log::*;
+
+use cron::Cron;
+use super::device::Devices;
+use super::protocol::{self, Cmd, Error, ErrorKind, Schedule, ScheduleEntry, ScheduleTime, SuccessKind};
+
+/// Max count of schedules of server, so their list fits datagram
+const MAX_SCHEDULES: usize = 12;
+
+/// Source of current time, tests move it by hand
+pub trait Clock: Send {
+    fn now(&self) -> DateTime<Local>;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Local> {
+        Local::now()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    id: u32,
+    dev_name: String,
+    schedule: Schedule,
+    last_run_ms: Option<i64>,
+    /// Computed on load, missed runs of cron aren't repeated
+    #[serde(skip)]
+    next_run: Option<DateTime<Local>>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct Store {
+    next_id: u32,
+    entries: Vec<Entry>,
+}
+
+/// The next run after given time, one-shot schedule runs once even if
+/// its time passed while server was down
+fn next_run(when: &ScheduleTime, after: DateTime<Local>) -> Result<Option<DateTime<Local>>, String> {
+    match when {
+        ScheduleTime::Cron(expr) => Ok(expr.parse::<Cron>()?.next_after(&after)),
+        ScheduleTime::Once(time_ms) => match Local.timestamp_millis_opt(*time_ms).single() {
+            Some(time) => Ok(Some(time)),
+            None => Err(format!("invalid time {time_ms}")),
+        },
+    }
+}
+
+/// Timed commands on devices of server. Schedules are stored in file,
+/// so they survive restart of server.
+pub struct Scheduler {
+    path: PathBuf,
+    clock: Box<dyn Clock>,
+    store: Store,
+}
+
+impl Scheduler {
+    pub fn new(path: PathBuf, clock: Box<dyn Clock>) -> Self {
+        let mut store = match fs::read_to_string(&path) {
+            Ok(data) => match serde_json::from_str::<Store>(&data) {
+                Ok(store) => store,
+                Err(e) => {
+                    error!("Can't parse schedules {:?}: {:?}", path, e);
+                    Store::default()
+                }
+            },
+            Err(_) => {
+                info!("Schedules {:?} not found, start without schedules", path);
+                Store::default()
+            }
+        };
+        let now = clock.now();
+        store.entries.retain_mut(|entry| match next_run(&entry.schedule.when, now) {
+            Ok(next) => {
+                entry.next_run = next;
+                true
+            }
+            Err(e) => {
+                error!("Schedule {} is dropped: {e}", entry.id);
+                false
+            }
+        });
+        info!("Scheduler created with {} schedules", store.entries.len());
+        Self {
+            path,
+            clock,
+            store,
+        }
+    }
+
+    pub fn is_schedule_cmd(cmd: &Cmd) -> bool {
+        matches!(cmd, Cmd::AddSchedule(_) | Cmd::ListSchedules | Cmd::RemoveSchedule(_))
+    }
+
+    pub fn handle_request(&mut self, devices: &Devices, req: protocol::Request) -> protocol::Response {
+        let res = match &req.cmd {
+            Cmd::AddSchedule(schedule) => self.add(devices, &req.dev_name, schedule).map(SuccessKind::ScheduleAdded),
+            Cmd::ListSchedules => Ok(SuccessKind::Schedules(self.list())),
+            Cmd::RemoveSchedule(id) => self.remove(*id, &req.dev_name).map(|_| SuccessKind::Ack),
+            cmd => Err(Error::new(ErrorKind::WrongCmd).with_details(format!("{:?} isn't schedule command", cmd))),
+        };
+        match res {
+            Ok(success) => protocol::Response::new_success_response(req, success),
+            Err(e) => {
+                info!("Schedule request {:?} failed: {:?}", req.cmd, e);
+                protocol::Response::new_err_response(req, e)
+            }
+        }
+    }
+
+    fn add(&mut self, devices: &Devices, dev_name: &str, schedule: &Schedule) -> Result<u32, Error> {
+        let Some(dev) = devices.get(dev_name) else {
+            return Err(Error::new(ErrorKind::DevNotFound).with_details(dev_name.to_owned()));
+        };
+        let caps = dev.capabilities();
+        if !caps.commands.iter().chain(caps.readings.iter()).any(|cmd| cmd.same_kind(&schedule.cmd)) {
+            return Err(Error::new(ErrorKind::WrongCmd).with_details(format!("{:?} isn't supported by {dev_name}", schedule.cmd)));
+        }
+        if self.store.entries.len() >= MAX_SCHEDULES {
+            return Err(Error::new(ErrorKind::OutOfRange).with_details(format!("max {MAX_SCHEDULES} schedules")));
+        }
+        let now = self.clock.now();
+        let next = next_run(&schedule.when, now).map_err(|e| Error::new(ErrorKind::InvalidArgument).with_details(e))?;
+        match next {
+            Some(next) if next > now => {}
+            _ => return Err(Error::new(ErrorKind::OutOfRange).with_details("schedule never runs".to_owned())),
+        }
+
+        let id = self.store.next_id;
+        self.store.next_id += 1;
+        self.store.entries.push(Entry {
+            id,
+            dev_name: dev_name.to_owned(),
+            schedule: schedule.clone(),
+            last_run_ms: None,
+            next_run: next,
+        });
+        info!("Schedule {id} added: {dev_name} {:?} {:?}", schedule.cmd, schedule.when);
+        self.save();
+        Ok(id)
+    }
+
+    /// Ids are unique within server, device name tells server of schedule
+    /// if request is sent to several servers
+    fn remove(&mut self, id: u32, dev_name: &str) -> Result<(), Error> {
+        let is_match = |entry: &Entry| entry.id == id && (dev_name.is_empty() || entry.dev_name == dev_name);
+        let Some(pos) = self.store.entries.iter().position(is_match) else {
+            return Err(Error::new(ErrorKind::ScheduleNotFound).with_details(id.to_string()));
+        };
+        self.store.entries.remove(pos);
+        info!("Schedule {id} removed");
+        self.save();
+        Ok(())
+    }
+
+    fn list(&self) -> Vec<ScheduleEntry> {
+        self.store.entries.iter()
+            .map(|entry| ScheduleEntry {
+                id: entry.id,
+                dev_name: entry.dev_name.clone(),
+                schedule: entry.schedule.clone(),
+                next_run_ms: entry.next_run.map(|time| time.timestamp_millis()),
+                last_run_ms: entry.last_run_ms,
+            })
+            .collect()
+    }
+
+    /// Executes due commands. Executed one-shot schedules are removed.
+    pub fn run(&mut self, devices: &mut Devices) {
+        let now = self.clock.now();
+        let mut is_changed = false;
+        for entry in self.store.entries.iter_mut() {
+            if entry.next_run.is_none_or(|next| next > now) {
+                continue;
+            }
+            let res = match devices.get_mut(&entry.dev_name) {
+                Some(dev) => dev.execute_automated(&entry.schedule.cmd).map(|_| ()),
+                None => Err(Error::new(ErrorKind::DevNotFound)),
+            };
+            match res {
+                Ok(_) => info!("Schedule {}: {} executed {:?}", entry.id, entry.dev_name, entry.schedule.cmd),
+                Err(e) => warn!("Schedule {}: {} unable to execute {:?}: {:?}", entry.id, entry.dev_name, entry.schedule.cmd, e),
+            }
+            entry.last_run_ms = Some(now.timestamp_millis());
+            entry.next_run = match entry.schedule.when {
+                ScheduleTime::Cron(_) => next_run(&entry.schedule.when, now).ok().flatten(),
+                ScheduleTime::Once(_) => None,
+            };
+            is_changed = true;
+        }
+        if is_changed {
+            self.store.entries.retain(|entry| entry.next_run.is_some());
+            self.save();
+        }
+    }
+
+    fn save(&self) {
+        if let Some(dir) = self.path.parent() {
+            if let Err(e) = fs::create_dir_all(dir) {
+                error!("Can't create schedules storage {:?}: {:?}", dir, e);
+                return;
+            }
+        }
+        let data =
+        match serde_json::to_string_pretty(&self.store) {
+            Ok(res) => res,
+            Err(e) => {
+                error!("Can't serialize schedules: {:?}", e);
+                return;
+            }
+        };
+        if let Err(e) = fs::write(&self.path, data) {
+            error!("Can't save schedules to {:?}: {:?}", self.path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::test_devices;
+    use crate::protocol::ResponseKind;
+    use chrono::{Duration, NaiveDateTime};
+    use serde_json::json;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone)]
+    struct ManualClock(Arc<Mutex<DateTime<Local>>>);
+
+    impl ManualClock {
+        fn advance(&self, minutes: i64) {
+            *self.0.lock().unwrap() += Duration::minutes(minutes);
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now(&self) -> DateTime<Local> {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    fn clock() -> ManualClock {
+        let time = NaiveDateTime::parse_from_str("2026-10-16 10:05", "%Y-%m-%d %H:%M").unwrap();
+        ManualClock(Arc::new(Mutex::new(Local.from_local_datetime(&time).unwrap())))
+    }
+
+    fn devices() -> Devices {
+        test_devices("scheduler", vec![json!({"name": "lamp1", "type": "lamp"})])
+    }
+
+    fn storage(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("smart_server_{}_{name}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn add(scheduler: &mut Scheduler, devices: &Devices, dev_name: &str, when: ScheduleTime, cmd: Cmd) -> ResponseKind {
+        let schedule = Schedule { when, cmd: Box::new(cmd) };
+        let req = protocol::Request::new(Cmd::AddSchedule(schedule), dev_name.to_owned());
+        scheduler.handle_request(devices, req).resp_kind
+    }
+
+    #[test]
+    fn test_cron_and_once() {
+        let path = storage("cron_and_once");
+        let clock = clock();
+        let mut devices = devices();
+        let mut scheduler = Scheduler::new(path.clone(), Box::new(clock.clone()));
+
+        let on = add(&mut scheduler, &devices, "lamp1", ScheduleTime::Cron("*/10 * * * *".to_owned()), Cmd::TurnOn);
+        assert!(matches!(on, ResponseKind::Success(SuccessKind::ScheduleAdded(0))));
+        let off_at = clock.now() + Duration::minutes(7);
+        let off = add(&mut scheduler, &devices, "lamp1", ScheduleTime::Once(off_at.timestamp_millis()), Cmd::TurnOff);
+        assert!(matches!(off, ResponseKind::Success(SuccessKind::ScheduleAdded(1))));
+
+        clock.advance(4);
+        scheduler.run(&mut devices);
+        assert!(!devices["lamp1"].is_on());
+        clock.advance(1);
+        scheduler.run(&mut devices);
+        assert!(devices["lamp1"].is_on());
+        clock.advance(2);
+        scheduler.run(&mut devices);
+        assert!(!devices["lamp1"].is_on());
+
+        // One-shot schedule is gone, cron schedule is restored from storage
+        let scheduler = Scheduler::new(path.clone(), Box::new(clock.clone()));
+        let entries = scheduler.list();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].id, 0);
+        assert_eq!(entries[0].last_run_ms, Some((clock.now() - Duration::minutes(2)).timestamp_millis()));
+        assert_eq!(entries[0].next_run_ms, Some((clock.now() + Duration::minutes(8)).timestamp_millis()));
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_invalid_schedules() {
+        let path = storage("invalid");
+        let clock = clock();
+        let devices = devices();
+        let mut scheduler = Scheduler::new(path.clone(), Box::new(clock.clone()));
+        let every_minute = || ScheduleTime::Cron("* * * * *".to_owned());
+        let past = ScheduleTime::Once((clock.now() - Duration::minutes(1)).timestamp_millis());
+        let cases = [
+            (ScheduleTime::Cron("61 * * * *".to_owned()), "lamp1", Cmd::TurnOn, ErrorKind::InvalidArgument),
+            (every_minute(), "lamp2", Cmd::TurnOn, ErrorKind::DevNotFound),
+            (every_minute(), "lamp1", Cmd::Lock, ErrorKind::WrongCmd),
+            (every_minute(), "lamp1", Cmd::ListSchedules, ErrorKind::WrongCmd),
+            (past, "lamp1", Cmd::TurnOn, ErrorKind::OutOfRange),
+        ];
+        for (when, dev_name, cmd, kind) in cases {
+            match add(&mut scheduler, &devices, dev_name, when, cmd) {
+                ResponseKind::Err(e) => assert!(e.is(kind), "{:?}", e),
+                _ => panic!(),
+            }
+        }
+        assert!(scheduler.list().is_empty());
+        assert!(!path.exists());
+
+        add(&mut scheduler, &devices, "lamp1", every_minute(), Cmd::TurnOn);
+        for (id, dev_name, is_removed) in [(1, "lamp1", false), (0, "lamp2", false), (0, "lamp1", true)] {
+            let req = protocol::Request::new(Cmd::RemoveSchedule(id), dev_name.to_owned());
+            match scheduler.handle_request(&devices, req).resp_kind {
+                ResponseKind::Err(e) => assert!(!is_removed && e.is(ErrorKind::ScheduleNotFound)),
+                ResponseKind::Success(SuccessKind::Ack) => assert!(is_removed),
+                _ => panic!(),
+            }
+        }
+        assert!(scheduler.list().is_empty());
+        let _ = fs::remove_file(path);
+    }
+}
@@ -1,26 +1,26 @@
 use std::io::{ErrorKind, Write};
 use std::path::Path;
-use std::fs;
-use std::sync::mpsc::TryRecvError;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use super::err_house;
-use super::device::{Device, generate_device_emulator};
-use serde_json::Value;
-use std::collections::HashMap;
-use std::net::TcpListener;
+use super::device::SharedDevices;
+use std::net::{TcpListener, TcpStream};
 use std::thread::{self, JoinHandle};
 use std::sync::mpsc::Receiver;
-use super::transport_layer::{TranportPack, TypePack};
+use super::transport_layer::TranportPack;
 use super::console_server::{Service, ConsoleCmd};
 use super::protocol;
-use bincode;
+use super::request_handler;
+use super::house::House;
+use super::metrics::SharedMetrics;
 use log::*;
 
 const SERVER_ADDR: &str = "127.0.0.1:444";
+const TRANSPORT: &str = "tcp";
 
 pub struct TcpServer {
-    devices: HashMap<String, Device>,
+    house: House,
+    metrics: SharedMetrics,
     rx: Option<Receiver<ConsoleCmd>>,
 }
 
@@ -29,38 +29,19 @@ impl Service for TcpServer {
         self.rx = Some(rx);
         self.start()
     }
+
+    fn rx(&self) -> &Receiver<ConsoleCmd> {
+        self.rx.as_ref().unwrap()
+    }
 }
 
 impl TcpServer {
-    pub fn new(config_path: &Path) -> Self {
-        let config_json_str =
-        match fs::read_to_string(config_path) {
-            Ok(res) => res,
-            Err(e) => {
-                error!("Can't read config from {:?}: {:?}", config_path, e);
-                panic!();
-            }
-        };
-        let config_json: Value =
-        match serde_json::from_str(&config_json_str){
-            Ok(res) => res,
-            Err(e) => {
-                error!("Can't parse config json {}: {:?}", config_json_str, e);
-                panic!();
-            }
-        };
-
-        let tcp_config = config_json.get("tcp").expect("Wrong input config");
-
-        let mut devices: HashMap<String, Device> = HashMap::new();
-        for dev_info in tcp_config["devices"].as_array().expect("Wrong input config: devices isn't array") {
-            let dev_name = dev_info["name"].as_str().expect("Wrong input config: name isn't string");
-            let dev_type = dev_info["type"].as_str().expect("Wrong input config: type isn't string");
-            devices.insert(dev_name.to_owned(), generate_device_emulator(dev_type).expect("Wrong input config: unknown device type"));
-        }
+    pub fn new(config_path: &Path, metrics: SharedMetrics) -> Self {
+        let house = House::new(config_path, TRANSPORT);
         info!("TcpServer created");
         Self {
-            devices,
+            house,
+            metrics,
             rx: None,
         }
     }
@@ -69,20 +50,9 @@ impl TcpServer {
         "TcpServer"
     }
 
-    fn get_cmd(&self) -> Option<ConsoleCmd>{
-        match self.rx.as_ref().unwrap().try_recv(){
-            Ok(cmd) => {
-                Some(cmd)
-            }
-            Err(e) => {
-                if let TryRecvError::Disconnected = e{
-                    error!("Channel disconnected");
-                    panic!();
-                }else{
-                    None
-                }
-            }
-        }
+    /// Devices of server shared with services automating them
+    pub fn devices(&self) -> SharedDevices {
+        self.house.devices()
     }
 
     fn start(mut self) -> thread::JoinHandle<()> {
@@ -95,14 +65,26 @@ impl TcpServer {
                     panic!();
                 }
             };
+            if let Err(e) = listener.set_nonblocking(true){
+                error!("Can't set nonblocking listener: {e}");
+                panic!();
+            }
 
             'outer: loop{
+                if let Some(ConsoleCmd::Exit) = self.get_cmd() {
+                    break 'outer;
+                }
+                self.house.update();
+
                 let mut tcp_stream =
                 match listener.accept() {
                     Ok((res, _)) => res,
                     Err(e) => {
                         match e.kind() {
-                            ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted => continue,
+                            ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted => {
+                                thread::sleep(Duration::from_millis(100));
+                                continue;
+                            }
                             _ => {
                                 error!("Invalid listerner");
                                 break;
@@ -111,15 +93,23 @@ impl TcpServer {
                     }
                 };
 
+                self.metrics.lock().unwrap().set_connected(TRANSPORT, 1);
+                if let Err(e) = tcp_stream.set_nonblocking(false){
+                    error!("Can't set blocking stream: {e}");
+                    panic!();
+                }
                 if let Err(e) = tcp_stream.set_read_timeout(Some(Duration::from_millis(100))){
                     error!("Can't set read timeout: {e}");
                     panic!();
                 }
                 loop {
-                    if let Some(cmd) = self.get_cmd(){
-                        match cmd {
-                            ConsoleCmd::Exit => break 'outer,
-                        }
+                    if let Some(ConsoleCmd::Exit) = self.get_cmd() {
+                        break 'outer;
+                    }
+                    self.house.update();
+                    if let Ok((other_stream, addr)) = listener.accept() {
+                        info!("Client {addr} is rejected, server is busy");
+                        Self::reject_busy(other_stream);
                     }
 
                     let req =
@@ -142,18 +132,46 @@ impl TcpServer {
                             continue;
                         }
                     };
-                    let mut pack = TranportPack::new(TypePack::Simple, resp).serialize();
+                    let pack =
+                    match TranportPack::from_payload(resp).serialize(){
+                        Ok(res) => res,
+                        Err(e) => {
+                            error!("Can't send response: {e}");
+                            continue;
+                        }
+                    };
 
-                    if let Err(e) = tcp_stream.write_all(&mut pack){
+                    if let Err(e) = tcp_stream.write_all(&pack){
                         info!("Connection closed: {:?}", e);
                         break;
                     }
                 }
+                self.metrics.lock().unwrap().set_connected(TRANSPORT, 0);
             }
+            self.house.store_state();
         }
         )
     }
 
+    /// Server serves one client at a time, request of another client gets busy error
+    fn reject_busy(mut tcp_stream: TcpStream) {
+        if tcp_stream.set_nonblocking(false).is_err() || tcp_stream.set_read_timeout(Some(Duration::from_millis(100))).is_err() {
+            return;
+        }
+        let Ok(pack) = TranportPack::from_reader(&mut tcp_stream) else {
+            return;
+        };
+        let Ok(req) = bincode::deserialize::<protocol::Request>(&pack.into_payload()) else {
+            return;
+        };
+        let resp = protocol::Response::new_err_response(req, protocol::ErrorKind::Busy.into());
+        if let Ok(pack) = bincode::serialize(&resp).map(TranportPack::from_payload) {
+            if let Ok(pack) = pack.serialize() {
+                let _ = tcp_stream.write_all(&pack);
+            }
+        }
+    }
+
     fn handle_request(&mut self, req: &[u8]) -> Result<Vec<u8>, err_house::Err> {
         let req: protocol::Request =
         match bincode::deserialize(req){
@@ -164,74 +182,10 @@ impl TcpServer {
             }
         };
 
-        let resp =
-        match req.cmd {
-            protocol::Cmd::GetListDevices => {
-                let mut devices = Vec::new();
-                for (name, dev) in self.devices.iter() {
-                    devices.push(protocol::Device::new(name.to_owned(), dev.get_type_device()));
-                }
-                protocol::Response::new_success_response(req, protocol::SuccessKind::ListDev(devices))
-            }
-            protocol::Cmd::TurnOn => {
-                if let Some(dev) = self.devices.get_mut(&req.dev_name) {
-                    dev.turn_on();
-                    info!("Device: {} is turned on", req.dev_name);
-                    protocol::Response::new_success_response(req, protocol::SuccessKind::Ack)
-                }else{
-                    info!("Device: {} not found", req.dev_name);
-                    protocol::Response::new_err_response(req, protocol::ErrorKind::DevNotFound)
-                }
-            }
-
-            protocol::Cmd::TurnOff => {
-                if let Some(dev) = self.devices.get_mut(&req.dev_name) {
-                    dev.turn_off();
-                    info!("Device: {} is turned off", req.dev_name);
-                    protocol::Response::new_success_response(req, protocol::SuccessKind::Ack)
-                }else{
-                    info!("Device: {} not found", req.dev_name);
-                    protocol::Response::new_err_response(req, protocol::ErrorKind::DevNotFound)
-                }
-            }
-
-            protocol::Cmd::Power => {
-                if let Some(dev) = self.devices.get_mut(&req.dev_name) {
-                    match dev {
-                        Device::Socket(sock) => {
-                            let cur_power = sock.get_power();
-                            info!("Smart socket {} power is {cur_power}", req.dev_name);
-                            protocol::Response::new_success_response(req, protocol::SuccessKind::Power(cur_power))
-                        }
-                        Device::Therm(_) => {
-                            info!("Smart therm unable get power");
-                            protocol::Response::new_err_response(req, protocol::ErrorKind::WrongCmd)
-                        }
-                    }
-                }else{
-                    info!("Device: {} not found", req.dev_name);
-                    protocol::Response::new_err_response(req, protocol::ErrorKind::DevNotFound)
-                }
-            }
-            protocol::Cmd::Temperature => {
-                if let Some(dev) = self.devices.get_mut(&req.dev_name) {
-                    match dev {
-                        Device::Socket(_) => {
-                            info!("Smart socket unable get temperature");
-                            protocol::Response::new_err_response(req, protocol::ErrorKind::WrongCmd)
-                        }
-                        Device::Therm(therm) => {
-                            let cur_temp = therm.get_temperature();
-                            info!("Smart therm {} temperature is {cur_temp}", req.dev_name);
-                            protocol::Response::new_success_response(req, protocol::SuccessKind::Temp(cur_temp))
-                        }
-                    }
-                }else{
-                    info!("Device: {} not found", req.dev_name);
-                    protocol::Response::new_err_response(req, protocol::ErrorKind::DevNotFound)
-                }
-            }
-        };
+        let started = Instant::now();
+        let resp = self.house.handle_request(req);
+        let resp = request_handler::bound_response(resp, u16::MAX as usize);
+        self.metrics.lock().unwrap().record(TRANSPORT, &resp, started.elapsed());
         let res =
         match bincode::serialize(&resp){
             Ok(val) => val,
@@ -1,26 +1,28 @@
 use std::io::Cursor;
 use std::path::Path;
-use std::{fs, io};
-use std::sync::mpsc::TryRecvError;
-use std::time::Duration;
+use std::io;
+use std::time::{Duration, Instant};
 
 use super::err_house;
-use super::device::{Device, generate_device_emulator};
-use serde_json::Value;
-use std::collections::HashMap;
+use super::device::SharedDevices;
 use std::net::UdpSocket;
 use std::thread::{self, JoinHandle};
 use std::sync::mpsc::Receiver;
-use super::transport_layer::{self, TranportPack};
+use super::transport_layer::{TranportPack, MAX_DATAGRAM_PAYLOAD};
 use super::console_server::{Service, ConsoleCmd};
 use super::protocol;
-use bincode;
+use super::request_handler;
+use super::house::House;
+use super::metrics::SharedMetrics;
+use chrono::Local;
 use log::*;
 
 const SERVER_ADDR: &str = "127.0.0.1:4444";
+const TRANSPORT: &str = "udp";
 
 pub struct UdpServer {
-    devices: HashMap<String, Device>,
+    house: House,
+    metrics: SharedMetrics,
     rx: Option<Receiver<ConsoleCmd>>,
 }
 
@@ -29,38 +31,19 @@ impl Service for UdpServer {
         self.rx = Some(rx);
         self.start()
     }
+
+    fn rx(&self) -> &Receiver<ConsoleCmd> {
+        self.rx.as_ref().unwrap()
+    }
 }
 
 impl UdpServer {
-    pub fn new(config_path: &Path) -> Self {
-        let config_json_str =
-        match fs::read_to_string(config_path) {
-            Ok(res) => res,
-            Err(e) => {
-                error!("Can't read config from {:?}: {:?}", config_path, e);
-                panic!();
-            }
-        };
-        let config_json: Value =
-        match serde_json::from_str(&config_json_str){
-            Ok(res) => res,
-            Err(e) => {
-                error!("Can't parse config json {}: {:?}", config_json_str, e);
-                panic!();
-            }
-        };
-
-        let tcp_config = config_json.get("udp").expect("Wrong input config");
-
-        let mut devices: HashMap<String, Device> = HashMap::new();
-        for dev_info in tcp_config["devices"].as_array().expect("Wrong input config: devices isn't array") {
-            let dev_name = dev_info["name"].as_str().expect("Wrong input config: name isn't string");
-            let dev_type = dev_info["type"].as_str().expect("Wrong input config: type isn't string");
-            devices.insert(dev_name.to_owned(), generate_device_emulator(dev_type).expect("Wrong input config: unknown device type"));
-        }
+    pub fn new(config_path: &Path, metrics: SharedMetrics) -> Self {
+        let house = House::new(config_path, TRANSPORT);
         info!("UdpServer created");
         Self {
-            devices,
+            house,
+            metrics,
             rx: None,
         }
     }
@@ -69,20 +52,9 @@ impl UdpServer {
         "UdpServer"
     }
 
-    fn get_cmd(&self) -> Option<ConsoleCmd>{
-        match self.rx.as_ref().unwrap().try_recv(){
-            Ok(cmd) => {
-                Some(cmd)
-            }
-            Err(e) => {
-                if let TryRecvError::Disconnected = e{
-                    error!("Channel disconnected");
-                    panic!();
-                }else{
-                    None
-                }
-            }
-        }
+    /// Devices of server shared with services automating them
+    pub fn devices(&self) -> SharedDevices {
+        self.house.devices()
     }
 
     fn start(mut self) -> thread::JoinHandle<()> {
@@ -102,11 +74,10 @@ impl UdpServer {
             }
 
             loop{
-                if let Some(cmd) = self.get_cmd() {
-                    match cmd {
-                        ConsoleCmd::Exit => break,
-                    }
+                if let Some(ConsoleCmd::Exit) = self.get_cmd() {
+                    break;
                 }
+                self.house.update();
 
                 let mut req = vec![0u8; 1400];
                 let (cnt_bytes, remote_addr) =
@@ -116,7 +87,7 @@ impl UdpServer {
                     }
                     Err(e) => {
                         match e.kind(){
-                            io::ErrorKind::TimedOut => {
+                            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => {
                                 continue;
                             }
                             _ => {
@@ -128,6 +99,7 @@ impl UdpServer {
                 };
 
                 req.shrink_to(cnt_bytes);
+                self.metrics.lock().unwrap().client_seen(TRANSPORT, remote_addr, Local::now());
                 let resp = 
                 match self.handle_request(&req){
                     Ok(res) => res,
@@ -137,11 +109,19 @@ impl UdpServer {
                     }
                 };
 
-                let mut resp = TranportPack::new(transport_layer::TypePack::Simple, resp).serialize();
-                if let Err(e) = sock.send_to(&mut resp, remote_addr){
+                let resp =
+                match TranportPack::from_payload(resp).serialize(){
+                    Ok(res) => res,
+                    Err(e) => {
+                        error!("Can't send response: {e}");
+                        continue;
+                    }
+                };
+                if let Err(e) = sock.send_to(&resp, remote_addr){
                     info!("Remote host unavailable: {:?}", e);
                 }
             }
+            self.house.store_state();
         }
         )
     }
@@ -158,74 +138,10 @@ impl UdpServer {
             }
         };
 
-        let resp =
-        match req.cmd {
-            protocol::Cmd::GetListDevices => {
-                let mut devices = Vec::new();
-                for (name, dev) in self.devices.iter() {
-                    devices.push(protocol::Device::new(name.to_owned(), dev.get_type_device()));
-                }
-                protocol::Response::new_success_response(req, protocol::SuccessKind::ListDev(devices))
-            }
-            protocol::Cmd::TurnOn => {
-                if let Some(dev) = self.devices.get_mut(&req.dev_name) {
-                    dev.turn_on();
-                    info!("Device: {} is turned on", req.dev_name);
-                    protocol::Response::new_success_response(req, protocol::SuccessKind::Ack)
-                }else{
-                    info!("Device: {} not found", req.dev_name);
-                    protocol::Response::new_err_response(req, protocol::ErrorKind::DevNotFound)
-                }
-            }
-
-            protocol::Cmd::TurnOff => {
-                if let Some(dev) = self.devices.get_mut(&req.dev_name) {
-                    dev.turn_off();
-                    info!("Device: {} is turned off", req.dev_name);
-                    protocol::Response::new_success_response(req, protocol::SuccessKind::Ack)
-                }else{
-                    info!("Device: {} not found", req.dev_name);
-                    protocol::Response::new_err_response(req, protocol::ErrorKind::DevNotFound)
-                }
-            }
-
-            protocol::Cmd::Power => {
-                if let Some(dev) = self.devices.get_mut(&req.dev_name) {
-                    match dev {
-                        Device::Socket(sock) => {
-                            let cur_power = sock.get_power();
-                            info!("Smart socket {} power is {cur_power}", req.dev_name);
-                            protocol::Response::new_success_response(req, protocol::SuccessKind::Power(cur_power))
-                        }
-                        Device::Therm(_) => {
-                            info!("Smart therm unable get power");
-                            protocol::Response::new_err_response(req, protocol::ErrorKind::WrongCmd)
-                        }
-                    }
-                }else{
-                    info!("Device: {} not found", req.dev_name);
-                    protocol::Response::new_err_response(req, protocol::ErrorKind::DevNotFound)
-                }
-            }
-            protocol::Cmd::Temperature => {
-                if let Some(dev) = self.devices.get_mut(&req.dev_name) {
-                    match dev {
-                        Device::Socket(_) => {
-                            info!("Smart socket unable get temperature");
-                            protocol::Response::new_err_response(req, protocol::ErrorKind::WrongCmd)
-                        }
-                        Device::Therm(therm) => {
-                            let cur_temp = therm.get_temperature();
-                            info!("Smart therm {} temperature is {cur_temp}", req.dev_name);
-                            protocol::Response::new_success_response(req, protocol::SuccessKind::Temp(cur_temp))
-                        }
-                    }
-                }else{
-                    info!("Device: {} not found", req.dev_name);
-                    protocol::Response::new_err_response(req, protocol::ErrorKind::DevNotFound)
-                }
-            }
-        };
+        let started = Instant::now();
+        let resp = self.house.handle_request(req);
+        let resp = request_handler::bound_response(resp, MAX_DATAGRAM_PAYLOAD);
+        self.metrics.lock().unwrap().record(TRANSPORT, &resp, started.elapsed());
         let res =
         match bincode::serialize(&resp){
             Ok(val) => val,
@@ -0,0 +1,412 @@
+use std::fmt::Display;
+
+use chrono::{DateTime, Duration, Local};
+use serde::Deserialize;
+use log::*;
+
+use super::device::{Devices, Reading, SOCKET_TYPE, THERM_TYPE};
+use super::protocol::{self, Cmd, Error, ErrorKind, Quality, SuccessKind, ThermostatMode, ThermostatState};
+
+/// Thermostat reads therm once per period
+const CONTROL_PERIOD_SECS: i64 = 1;
+const MIN_SETPOINT: f64 = 5.0; // C
+const MAX_SETPOINT: f64 = 35.0; // C
+
+fn default_mode() -> ThermostatMode {
+    ThermostatMode::Auto
+}
+
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Control {
+    /// Heater turns on below setpoint - band / 2 and off above setpoint + band / 2
+    Hysteresis { band: f64 },
+    /// Heater is on for part of cycle given by output of PID controller in [0, 1]
+    Pid { kp: f64, ki: f64, kd: f64, cycle_secs: u64 },
+}
+
+impl Control {
+    /// Gains and band are non-negative, PID cycle is at least a second
+    fn is_valid(&self) -> bool {
+        match *self {
+            Control::Hysteresis { band } => band >= 0.0,
+            Control::Pid { kp, ki, kd, cycle_secs } => kp >= 0.0 && ki >= 0.0 && kd >= 0.0 && cycle_secs > 0,
+        }
+    }
+}
+
+impl Display for Control {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Control::Hysteresis { band } => write!(f, "hysteresis {band}"),
+            Control::Pid { kp, ki, kd, cycle_secs } => write!(f, "pid {kp}/{ki}/{kd}, cycle {cycle_secs} s"),
+        }
+    }
+}
+
+/// Thermostat from config, e.g.
+/// `{"name": "living", "therm": "therm1", "heater": "heater1", "setpoint": 21.0,
+///   "control": {"kind": "hysteresis", "band": 1.0}, "min_on_secs": 60}`
+#[derive(Deserialize, Clone)]
+pub struct ThermostatConfig {
+    pub name: String,
+    pub therm: String,
+    /// Socket heater is plugged in
+    pub heater: String,
+    pub control: Control,
+    pub setpoint: f64,
+    #[serde(default = "default_mode")]
+    pub mode: ThermostatMode,
+    /// Heater isn't switched by controller more often, mode changes apply at once
+    #[serde(default)]
+    pub min_on_secs: u64,
+    #[serde(default)]
+    pub min_off_secs: u64,
+}
+
+#[derive(Default)]
+struct PidState {
+    integral: f64,
+    last_temp: Option<(DateTime<Local>, f64)>,
+    cycle_start: Option<DateTime<Local>>,
+    /// Output latched at start of cycle, so heater switches at most twice per cycle
+    cycle_output: f64,
+}
+
+pub struct Thermostat {
+    config: ThermostatConfig,
+    mode: ThermostatMode,
+    setpoint: f64,
+    temperature: Option<f64>,
+    is_heating: bool,
+    last_switch: Option<DateTime<Local>>,
+    /// Heater refused the last switch, e.g. it's shed by power budget
+    is_refused: bool,
+    pid: PidState,
+    next_update: Option<DateTime<Local>>,
+}
+
+impl Thermostat {
+    pub fn new(config: ThermostatConfig) -> Self {
+        Self {
+            mode: config.mode,
+            setpoint: config.setpoint,
+            config,
+            temperature: None,
+            is_heating: false,
+            last_switch: None,
+            is_refused: false,
+            pid: PidState::default(),
+            next_update: None,
+        }
+    }
+
+    fn pid_demand(&mut self, temp: f64, now: DateTime<Local>) -> bool {
+        let Control::Pid { kp, ki, kd, cycle_secs } = self.config.control else {
+            return false;
+        };
+        let error = self.setpoint - temp;
+        let mut derivative = 0.0;
+        if let Some((last, last_temp)) = self.pid.last_temp {
+            let dt = (now - last).num_milliseconds() as f64 / 1000.0;
+            if dt > 0.0 {
+                self.pid.integral += error * dt;
+                derivative = -(temp - last_temp) / dt;
+            }
+        }
+        // Integral alone never asks for more than full power, so it doesn't wind up
+        if ki > 0.0 {
+            self.pid.integral = self.pid.integral.clamp(-1.0 / ki, 1.0 / ki);
+        }
+        self.pid.last_temp = Some((now, temp));
+        let output = (kp * error + ki * self.pid.integral + kd * derivative).clamp(0.0, 1.0);
+
+        let cycle = Duration::seconds(cycle_secs as i64);
+        if self.pid.cycle_start.is_none_or(|start| now - start >= cycle) {
+            self.pid.cycle_start = Some(now);
+            self.pid.cycle_output = output;
+        }
+        let elapsed = self.pid.cycle_start.map_or(0.0, |start| (now - start).num_milliseconds() as f64);
+        elapsed < self.pid.cycle_output * cycle.num_milliseconds() as f64
+    }
+
+    /// Desired state of heater, none keeps heater as is
+    fn decide(&mut self, temp: Option<f64>, is_heating: bool, now: DateTime<Local>) -> Option<bool> {
+        let demand = match (self.mode, temp) {
+            (ThermostatMode::Off, _) => return Some(false),
+            (ThermostatMode::Heat, _) => return Some(true),
+            // Heater isn't left on without temperature
+            (ThermostatMode::Auto, None) => return Some(false),
+            (ThermostatMode::Auto, Some(temp)) => match self.config.control {
+                Control::Hysteresis { band } if temp < self.setpoint - band / 2.0 => true,
+                Control::Hysteresis { band } if temp > self.setpoint + band / 2.0 => false,
+                Control::Hysteresis { .. } => is_heating,
+                Control::Pid { .. } => self.pid_demand(temp, now),
+            },
+        };
+        let min_secs = if is_heating { self.config.min_on_secs } else { self.config.min_off_secs };
+        let is_held = self.last_switch.is_some_and(|last| now - last < Duration::seconds(min_secs as i64));
+        if demand == is_heating || is_held {
+            return None;
+        }
+        Some(demand)
+    }
+
+    /// Reads therm and switches heater
+    pub fn update(&mut self, devices: &mut Devices, now: DateTime<Local>) {
+        if self.next_update.is_some_and(|next| now < next) {
+            return;
+        }
+        self.next_update = Some(now + Duration::seconds(CONTROL_PERIOD_SECS));
+
+        self.temperature = match devices.get_mut(&self.config.therm).map(|therm| therm.sample(Reading::Temperature)) {
+            Some(Ok(sample)) if sample.quality != Quality::Stale => Some(sample.value),
+            _ => None,
+        };
+        let Some(heater) = devices.get_mut(&self.config.heater) else {
+            return;
+        };
+        self.is_heating = heater.is_on();
+        let Some(is_on) = self.decide(self.temperature, self.is_heating, now) else {
+            self.is_refused = false;
+            return;
+        };
+        if is_on == self.is_heating {
+            self.is_refused = false;
+            return;
+        }
+        let cmd = if is_on { Cmd::TurnOn } else { Cmd::TurnOff };
+        match heater.execute_automated(&cmd) {
+            Ok(_) => {
+                info!("Thermostat {}: heater {} is turned {}, temperature {:?}",
+                    self.config.name, self.config.heater, if is_on { "on" } else { "off" }, self.temperature);
+                self.is_heating = is_on;
+                self.last_switch = Some(now);
+                self.is_refused = false;
+            }
+            Err(e) => {
+                // Heater refuses again every period, so only the first refusal is logged
+                if !self.is_refused {
+                    warn!("Thermostat {}: heater {} unable to execute {:?}: {:?}", self.config.name, self.config.heater, cmd, e);
+                }
+                self.is_refused = true;
+            }
+        }
+    }
+
+    pub fn state(&self) -> ThermostatState {
+        ThermostatState {
+            name: self.config.name.clone(),
+            therm: self.config.therm.clone(),
+            heater: self.config.heater.clone(),
+            mode: self.mode,
+            setpoint: self.setpoint,
+            temperature: self.temperature,
+            is_heating: self.is_heating,
+            control: self.config.control.to_string(),
+        }
+    }
+
+    fn set_mode(&mut self, mode: ThermostatMode) {
+        info!("Thermostat {} mode is {:?}", self.config.name, mode);
+        self.mode = mode;
+        self.next_update = None;
+    }
+
+    fn set_setpoint(&mut self, setpoint: f64) -> Result<(), Error> {
+        if !(MIN_SETPOINT..=MAX_SETPOINT).contains(&setpoint) {
+            return Err(Error::new(ErrorKind::OutOfRange).with_details(format!("{setpoint} isn't in {MIN_SETPOINT}..={MAX_SETPOINT}")));
+        }
+        info!("Thermostat {} setpoint is {setpoint}", self.config.name);
+        self.setpoint = setpoint;
+        self.next_update = None;
+        Ok(())
+    }
+}
+
+/// Thermostats of server, their therms and heaters are devices of the same server
+pub struct Thermostats {
+    thermostats: Vec<Thermostat>,
+}
+
+impl Thermostats {
+    pub fn new(configs: Vec<ThermostatConfig>, devices: &Devices) -> Self {
+        let thermostats = configs.into_iter()
+            .filter(|config| {
+                let type_of = |name: &str| devices.get(name).map(|dev| dev.type_dev());
+                let is_valid = type_of(&config.therm) == Some(THERM_TYPE)
+                    && type_of(&config.heater) == Some(SOCKET_TYPE)
+                    && (MIN_SETPOINT..=MAX_SETPOINT).contains(&config.setpoint)
+                    && config.control.is_valid();
+                if !is_valid {
+                    error!("Thermostat {} is skipped: it needs therm, socket, setpoint in {MIN_SETPOINT}..={MAX_SETPOINT}, \
+                        non-negative gains and non-zero cycle", config.name);
+                }
+                is_valid
+            })
+            .map(Thermostat::new)
+            .collect();
+        Self { thermostats }
+    }
+
+    pub fn is_thermostat_cmd(cmd: &Cmd) -> bool {
+        matches!(cmd, Cmd::GetThermostat | Cmd::SetThermostatMode(_) | Cmd::SetSetpoint(_))
+    }
+
+    pub fn update(&mut self, devices: &mut Devices, now: DateTime<Local>) {
+        for thermostat in self.thermostats.iter_mut() {
+            thermostat.update(devices, now);
+        }
+    }
+
+    fn execute(&mut self, devices: &mut Devices, req: &protocol::Request) -> Result<SuccessKind, Error> {
+        if let (Cmd::GetThermostat, true) = (&req.cmd, req.dev_name.is_empty()) {
+            return Ok(SuccessKind::Thermostats(self.thermostats.iter().map(|thermostat| thermostat.state()).collect()));
+        }
+        let Some(thermostat) = self.thermostats.iter_mut().find(|thermostat| thermostat.config.name == req.dev_name) else {
+            return Err(Error::new(ErrorKind::DevNotFound).with_details(req.dev_name.clone()));
+        };
+        match req.cmd {
+            Cmd::SetThermostatMode(mode) => thermostat.set_mode(mode),
+            Cmd::SetSetpoint(setpoint) => thermostat.set_setpoint(setpoint)?,
+            _ => {}
+        }
+        // Response tells state after change
+        thermostat.update(devices, Local::now());
+        Ok(SuccessKind::Thermostats(vec![thermostat.state()]))
+    }
+
+    pub fn handle_request(&mut self, devices: &mut Devices, req: protocol::Request) -> protocol::Response {
+        match self.execute(devices, &req) {
+            Ok(success) => protocol::Response::new_success_response(req, success),
+            Err(e) => {
+                info!("Thermostat request {:?} failed: {:?}", req.cmd, e);
+                protocol::Response::new_err_response(req, e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::test_devices;
+    use serde_json::{json, Value};
+
+    fn thermostat(control: Value) -> Thermostat {
+        Thermostat::new(serde_json::from_value(json!({
+            "name": "living", "therm": "therm1", "heater": "heater1", "setpoint": 21.0,
+            "control": control, "min_on_secs": 60, "min_off_secs": 30
+        })).unwrap())
+    }
+
+    #[test]
+    fn test_hysteresis_and_min_times() {
+        let mut thermostat = thermostat(json!({"kind": "hysteresis", "band": 1.0}));
+        let start = Local::now();
+        let at = |secs| start + Duration::seconds(secs);
+        assert_eq!(thermostat.decide(Some(20.8), false, at(0)), None);
+        assert_eq!(thermostat.decide(Some(20.4), false, at(1)), Some(true));
+        thermostat.last_switch = Some(at(1));
+        // Heater stays on within band and for min on time
+        assert_eq!(thermostat.decide(Some(21.3), true, at(10)), None);
+        assert_eq!(thermostat.decide(Some(21.6), true, at(30)), None);
+        assert_eq!(thermostat.decide(Some(21.6), true, at(61)), Some(false));
+        thermostat.last_switch = Some(at(61));
+        assert_eq!(thermostat.decide(Some(20.0), false, at(80)), None);
+        assert_eq!(thermostat.decide(Some(20.0), false, at(91)), Some(true));
+        // Mode is applied regardless of min times
+        thermostat.last_switch = Some(at(91));
+        thermostat.set_mode(ThermostatMode::Off);
+        assert_eq!(thermostat.decide(Some(20.0), true, at(92)), Some(false));
+        assert_eq!(thermostat.decide(None, false, at(93)), Some(false));
+    }
+
+    #[test]
+    fn test_pid_duty() {
+        let mut thermostat = thermostat(json!({"kind": "pid", "kp": 0.25, "ki": 0.0, "kd": 0.0, "cycle_secs": 100}));
+        thermostat.config.min_on_secs = 0;
+        thermostat.config.min_off_secs = 0;
+        let start = Local::now();
+        let at = |secs| start + Duration::seconds(secs);
+        // Error of 2 C gives half of cycle
+        assert_eq!(thermostat.decide(Some(19.0), false, at(0)), Some(true));
+        assert_eq!(thermostat.decide(Some(19.0), true, at(49)), None);
+        assert_eq!(thermostat.decide(Some(19.0), true, at(50)), Some(false));
+        assert_eq!(thermostat.decide(Some(19.0), false, at(99)), None);
+        // Above setpoint heater stays off for the whole cycle
+        assert_eq!(thermostat.decide(Some(22.0), false, at(100)), None);
+        assert_eq!(thermostat.decide(Some(22.0), false, at(150)), None);
+    }
+
+    fn devices() -> Devices {
+        test_devices("thermostat", vec![
+            json!({"name": "therm1", "type": "therm", "model": {"kind": "gauss", "mean": 18.0, "spread": 0.0}}),
+            json!({"name": "heater1", "type": "socket"}),
+        ])
+    }
+
+    #[test]
+    fn test_refusal_is_logged_once() {
+        let mut devices = devices();
+        let mut thermostat = thermostat(json!({"kind": "hysteresis", "band": 1.0}));
+        thermostat.mode = ThermostatMode::Heat;
+        let start = Local::now();
+
+        devices.get_mut("heater1").unwrap().shed().unwrap();
+        thermostat.update(&mut devices, start);
+        assert!(thermostat.is_refused && !devices["heater1"].is_on());
+        thermostat.update(&mut devices, start + Duration::seconds(1));
+        assert!(thermostat.is_refused);
+        devices.get_mut("heater1").unwrap().unshed().unwrap();
+        thermostat.update(&mut devices, start + Duration::seconds(2));
+        assert!(!thermostat.is_refused);
+    }
+
+    #[test]
+    fn test_therm_off_turns_heater_off() {
+        let mut devices = devices();
+        let thermostat = thermostat(json!({"kind": "hysteresis", "band": 1.0}));
+        let mut thermostats = Thermostats::new(vec![thermostat.config], &devices);
+        let start = Local::now();
+
+        devices.get_mut("therm1").unwrap().execute(&Cmd::TurnOn).unwrap();
+        thermostats.update(&mut devices, start);
+        assert!(devices["heater1"].is_on());
+        devices.get_mut("therm1").unwrap().execute(&Cmd::TurnOff).unwrap();
+        thermostats.update(&mut devices, start + Duration::seconds(1));
+        assert!(!devices["heater1"].is_on());
+
+        let req = protocol::Request::new(Cmd::SetSetpoint(40.0), "living".to_owned());
+        assert!(matches!(thermostats.handle_request(&mut devices, req).resp_kind, protocol::ResponseKind::Err(e) if e.is(ErrorKind::OutOfRange)));
+        let req = protocol::Request::new(Cmd::SetThermostatMode(ThermostatMode::Heat), "living".to_owned());
+        thermostats.handle_request(&mut devices, req);
+        assert!(devices["heater1"].is_on());
+        let req = protocol::Request::new(Cmd::GetThermostat, String::new());
+        match thermostats.handle_request(&mut devices, req).resp_kind {
+            protocol::ResponseKind::Success(SuccessKind::Thermostats(states)) => {
+                assert_eq!(states.len(), 1);
+                assert_eq!((states[0].mode, states[0].temperature, states[0].is_heating), (ThermostatMode::Heat, None, true));
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_invalid_control() {
+        let devices = devices();
+        let controls = [
+            json!({"kind": "pid", "kp": 0.25, "ki": 0.01, "kd": 0.0, "cycle_secs": 0}),
+            json!({"kind": "pid", "kp": -0.25, "ki": 0.01, "kd": 0.0, "cycle_secs": 100}),
+            json!({"kind": "pid", "kp": 0.25, "ki": -0.01, "kd": 0.0, "cycle_secs": 100}),
+            json!({"kind": "pid", "kp": 0.25, "ki": 0.01, "kd": -1.0, "cycle_secs": 100}),
+            json!({"kind": "hysteresis", "band": -1.0}),
+        ];
+        for control in controls {
+            let thermostats = Thermostats::new(vec![thermostat(control.clone()).config], &devices);
+            assert!(thermostats.thermostats.is_empty(), "{control}");
+        }
+        let control = json!({"kind": "pid", "kp": 0.25, "ki": 0.01, "kd": 0.0, "cycle_secs": 100});
+        assert_eq!(Thermostats::new(vec![thermostat(control).config], &devices).thermostats.len(), 1);
+    }
+}
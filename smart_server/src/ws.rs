@@ -0,0 +1,459 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::mpsc::Receiver;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use tungstenite::{Message, WebSocket};
+use log::*;
+
+use super::console_server::{bind_listener, config_section, ConsoleCmd, Service};
+use super::device::{handle_on_owner, Reading, SharedDevices};
+use super::metrics::SharedMetrics;
+use super::protocol::{self, DeviceState, ErrorKind, Quality};
+use super::request_handler;
+use super::scheduler::Scheduler;
+use super::thermostat::Thermostats;
+use super::scene::Scenes;
+
+const TRANSPORT: &str = "ws";
+/// Readings aren't pushed more often than server loop runs
+const MIN_INTERVAL_MS: u64 = 100;
+const MAX_CLIENTS: usize = 16;
+const HANDSHAKE_TIMEOUT_MS: u64 = 1000;
+
+/// Endpoint section of config, e.g. `{"addr": "127.0.0.1:8081"}`
+#[derive(Deserialize, Clone)]
+pub struct WsConfig {
+    pub addr: String,
+}
+
+/// Subscription of client, e.g. `{"subscribe": {"devices": ["sock1"], "interval_ms": 1000}}`.
+/// Empty devices mean all devices, zero interval cancels subscription.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Debug)]
+pub struct Subscribe {
+    #[serde(default)]
+    pub devices: Vec<String>,
+    pub interval_ms: u64,
+}
+
+/// Message of client: subscription or protocol request in JSON
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ClientMsg {
+    Subscribe { subscribe: Subscribe },
+    Request(protocol::Request),
+}
+
+/// Message pushed to client
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Event {
+    /// Response of server owning device, of every server for request without device name
+    Response {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        transport: Option<&'static str>,
+        response: protocol::Response,
+    },
+    Subscribed(Subscribe),
+    Reading {
+        transport: &'static str,
+        dev_name: String,
+        reading: Reading,
+        value: f64,
+        quality: Quality,
+        timestamp_ms: i64,
+    },
+    /// State is pushed when device is turned on or off or its fault changes
+    State {
+        transport: &'static str,
+        dev_name: String,
+        state: DeviceState,
+    },
+    /// Message of client isn't understood
+    Error { message: String },
+}
+
+struct Subscription {
+    devices: Vec<String>,
+    interval: chrono::Duration,
+    next_push: DateTime<Local>,
+    /// On and fault state of devices pushed last
+    states: HashMap<String, (bool, bool)>,
+}
+
+struct Client {
+    ws: WebSocket<TcpStream>,
+    addr: SocketAddr,
+    subscription: Option<Subscription>,
+}
+
+impl Client {
+    /// Sent message stays buffered if socket would block, it's flushed later
+    fn send(&mut self, event: &Event) -> Result<(), Box<tungstenite::Error>> {
+        let text = match serde_json::to_string(event) {
+            Ok(text) => text,
+            Err(e) => {
+                error!("Can't serialize websocket event: {:?}", e);
+                return Ok(());
+            }
+        };
+        match self.ws.send(Message::Text(text)) {
+            Err(tungstenite::Error::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            res => res.map_err(Box::new),
+        }
+    }
+}
+
+/// Streams readings and state of devices of all servers to dashboards and
+/// executes protocol requests sent as JSON.
+pub struct WsServer {
+    config: Option<WsConfig>,
+    servers: Vec<(&'static str, SharedDevices)>,
+    metrics: SharedMetrics,
+    clients: Vec<Client>,
+    rx: Option<Receiver<ConsoleCmd>>,
+}
+
+impl Service for WsServer {
+    fn start_service(mut self, rx: Receiver<ConsoleCmd>) -> JoinHandle<()> {
+        self.rx = Some(rx);
+        self.start()
+    }
+
+    fn rx(&self) -> &Receiver<ConsoleCmd> {
+        self.rx.as_ref().unwrap()
+    }
+}
+
+impl WsServer {
+    /// Endpoint is disabled if config has no ws section
+    pub fn new(config_path: &Path, servers: Vec<(&'static str, SharedDevices)>, metrics: SharedMetrics) -> Self {
+        let config = config_section(config_path, "ws");
+        Self {
+            config,
+            servers,
+            metrics,
+            clients: Vec::new(),
+            rx: None,
+        }
+    }
+
+    pub fn name() -> &'static str {
+        "WsServer"
+    }
+
+    fn bind(config: &WsConfig) -> TcpListener {
+        let listener = bind_listener(&config.addr);
+        info!("Websocket endpoint is served on ws://{}", config.addr);
+        listener
+    }
+
+    fn start(mut self) -> JoinHandle<()> {
+        thread::spawn(move || {
+            let listener = self.config.as_ref().map(Self::bind);
+            loop {
+                if let Some(ConsoleCmd::Exit) = self.get_cmd() {
+                    break;
+                }
+                if let Some(listener) = listener.as_ref() {
+                    match listener.accept() {
+                        Ok((stream, addr)) => self.accept(stream, addr),
+                        Err(e) if e.kind() != io::ErrorKind::WouldBlock => {
+                            error!("Invalid listener: {:?}", e);
+                            break;
+                        }
+                        Err(_) => {}
+                    }
+                }
+                self.poll(Local::now());
+                thread::sleep(Duration::from_millis(MIN_INTERVAL_MS));
+            }
+            for client in self.clients.iter_mut() {
+                let _ = client.ws.close(None);
+                let _ = client.ws.flush();
+            }
+        })
+    }
+
+    /// Handshake is blocking, then client socket is polled without blocking
+    fn accept(&mut self, stream: TcpStream, addr: SocketAddr) {
+        if self.clients.len() >= MAX_CLIENTS {
+            info!("Websocket client {addr} is rejected, too many clients");
+            return;
+        }
+        if let Err(e) = stream.set_nonblocking(false).and_then(|_| stream.set_read_timeout(Some(Duration::from_millis(HANDSHAKE_TIMEOUT_MS)))) {
+            error!("Can't set up websocket stream: {e}");
+            return;
+        }
+        let ws = match tungstenite::accept(stream) {
+            Ok(ws) => ws,
+            Err(e) => {
+                info!("Websocket handshake with {addr} failed: {e}");
+                return;
+            }
+        };
+        if let Err(e) = ws.get_ref().set_nonblocking(true) {
+            error!("Can't set nonblocking websocket stream: {e}");
+            return;
+        }
+        info!("Websocket client {addr} connected");
+        self.clients.push(Client {
+            ws,
+            addr,
+            subscription: None,
+        });
+        self.metrics.lock().unwrap().set_connected(TRANSPORT, self.clients.len());
+    }
+
+    /// Handles messages of clients and pushes due subscriptions, closed clients are dropped
+    fn poll(&mut self, now: DateTime<Local>) {
+        let mut clients = std::mem::take(&mut self.clients);
+        clients.retain_mut(|client| match self.poll_client(client, now) {
+            Ok(()) => true,
+            Err(e) => {
+                info!("Websocket client {} disconnected: {e}", client.addr);
+                false
+            }
+        });
+        self.clients = clients;
+        self.metrics.lock().unwrap().set_connected(TRANSPORT, self.clients.len());
+    }
+
+    fn poll_client(&self, client: &mut Client, now: DateTime<Local>) -> Result<(), Box<tungstenite::Error>> {
+        loop {
+            match client.ws.read() {
+                Ok(Message::Text(text)) => self.handle_msg(client, &text, now)?,
+                Ok(Message::Close(_)) => return Err(Box::new(tungstenite::Error::ConnectionClosed)),
+                // Pings are answered by websocket itself
+                Ok(_) => {}
+                Err(tungstenite::Error::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(Box::new(e)),
+            }
+        }
+        self.push(client, now)?;
+        match client.ws.flush() {
+            Err(tungstenite::Error::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            res => res.map_err(Box::new),
+        }
+    }
+
+    fn handle_msg(&self, client: &mut Client, text: &str, now: DateTime<Local>) -> Result<(), Box<tungstenite::Error>> {
+        match serde_json::from_str(text) {
+            Ok(ClientMsg::Subscribe { subscribe }) => {
+                if subscribe.interval_ms == 0 {
+                    info!("Websocket client {} unsubscribed", client.addr);
+                    client.subscription = None;
+                } else {
+                    let interval_ms = subscribe.interval_ms.max(MIN_INTERVAL_MS);
+                    info!("Websocket client {} subscribed to {:?} every {interval_ms} ms", client.addr, subscribe.devices);
+                    client.subscription = Some(Subscription {
+                        devices: subscribe.devices.clone(),
+                        interval: chrono::Duration::milliseconds(interval_ms as i64),
+                        next_push: now,
+                        states: HashMap::new(),
+                    });
+                }
+                client.send(&Event::Subscribed(subscribe))
+            }
+            Ok(ClientMsg::Request(req)) => {
+                for (transport, response) in self.execute(req) {
+                    client.send(&Event::Response { transport, response })?;
+                }
+                Ok(())
+            }
+            Err(e) => client.send(&Event::Error {
+                message: format!("Invalid message: {e}"),
+            }),
+        }
+    }
+
+    /// Request is executed by server owning device, request without device name by every server.
+    /// Schedules, thermostats and scenes are kept by tcp and udp servers, so endpoint rejects them.
+    fn execute(&self, req: protocol::Request) -> Vec<(Option<&'static str>, protocol::Response)> {
+        let mut responses = Vec::new();
+        if Scheduler::is_schedule_cmd(&req.cmd) || Thermostats::is_thermostat_cmd(&req.cmd) || Scenes::is_scene_cmd(&req.cmd) {
+            let e = protocol::Error::new(ErrorKind::UnknownCmd)
+                .with_details(format!("{:?} is served by tcp and udp servers only", req.cmd));
+            let resp = protocol::Response::new_err_response(req, e);
+            self.metrics.lock().unwrap().record(TRANSPORT, &resp, Duration::ZERO);
+            responses.push((None, resp));
+            return responses;
+        }
+        if !req.dev_name.is_empty() {
+            let started = Instant::now();
+            let transport = self.servers.iter()
+                .find(|(_, devices)| devices.lock().unwrap().contains_key(&req.dev_name))
+                .map(|(transport, _)| *transport);
+            let resp = handle_on_owner(&self.servers, req);
+            self.metrics.lock().unwrap().record(TRANSPORT, &resp, started.elapsed());
+            responses.push((transport, resp));
+            return responses;
+        }
+        for (transport, devices) in self.servers.iter() {
+            let started = Instant::now();
+            let resp = request_handler::handle_request(&mut devices.lock().unwrap(), req.clone());
+            self.metrics.lock().unwrap().record(TRANSPORT, &resp, started.elapsed());
+            responses.push((Some(*transport), resp));
+        }
+        responses
+    }
+
+    /// Pushes readings of subscribed devices and their changed states
+    fn push(&self, client: &mut Client, now: DateTime<Local>) -> Result<(), Box<tungstenite::Error>> {
+        let Some(subscription) = client.subscription.as_mut() else {
+            return Ok(());
+        };
+        if now < subscription.next_push {
+            return Ok(());
+        }
+        subscription.next_push = now + subscription.interval;
+        let mut events = Vec::new();
+        for (transport, devices) in self.servers.iter() {
+            let mut devices = devices.lock().unwrap();
+            let mut names: Vec<String> = devices.keys()
+                .filter(|name| subscription.devices.is_empty() || subscription.devices.contains(*name))
+                .cloned()
+                .collect();
+            names.sort();
+            for name in names {
+                let dev = devices.get_mut(&name).unwrap();
+                for reading in dev.readings().iter().copied().filter(|reading| *reading != Reading::Target) {
+                    match dev.sample(reading) {
+                        Ok(sample) if sample.quality != Quality::Stale => events.push(Event::Reading {
+                            transport,
+                            dev_name: name.clone(),
+                            reading,
+                            value: sample.value,
+                            quality: sample.quality,
+                            timestamp_ms: sample.time.timestamp_millis(),
+                        }),
+                        Ok(_) => {}
+                        Err(e) => debug!("Reading {:?} of {name} is unavailable: {:?}", reading, e),
+                    }
+                }
+                let state = dev.state(now);
+                let key = (state.is_on, state.is_fault);
+                if subscription.states.insert(name.clone(), key) != Some(key) {
+                    events.push(Event::State {
+                        transport,
+                        dev_name: name,
+                        state,
+                    });
+                }
+            }
+        }
+        for event in events.iter() {
+            client.send(event)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use crate::device::test_devices;
+    use serde_json::{json, Value};
+
+    fn server() -> SharedDevices {
+        Arc::new(Mutex::new(test_devices("ws", vec![
+            json!({"name": "sock1", "type": "socket", "model": {"kind": "gauss", "mean": 2000.0, "spread": 0.0}}),
+            json!({"name": "lamp1", "type": "lamp"}),
+        ])))
+    }
+
+    fn recv(ws: &mut WebSocket<TcpStream>) -> Value {
+        match ws.read().unwrap() {
+            Message::Text(text) => serde_json::from_str(&text).unwrap(),
+            msg => panic!("Unexpected message {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn test_requests_and_subscription() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let stream = TcpStream::connect(addr).unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+            tungstenite::client(format!("ws://{addr}/"), stream).unwrap().0
+        });
+        let mut server = WsServer {
+            config: None,
+            servers: vec![("tcp", server())],
+            metrics: SharedMetrics::default(),
+            clients: Vec::new(),
+            rx: None,
+        };
+        let (stream, peer) = listener.accept().unwrap();
+        server.accept(stream, peer);
+        let mut ws = client.join().unwrap();
+        let now = Local::now();
+
+        ws.send(Message::Text(r#"{"cmd": "TurnOn", "dev_name": "sock1"}"#.to_owned())).unwrap();
+        ws.send(Message::Text(r#"{"cmd": "TurnOn", "dev_name": "sock9"}"#.to_owned())).unwrap();
+        ws.send(Message::Text(r#"{"subscribe": {"devices": ["sock1"], "interval_ms": 1000}}"#.to_owned())).unwrap();
+        ws.send(Message::Text("{}".to_owned())).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        server.poll(now);
+
+        let resp = recv(&mut ws);
+        assert_eq!((resp["event"].as_str(), resp["transport"].as_str()), (Some("response"), Some("tcp")));
+        assert_eq!(resp["response"]["resp_kind"], json!({"Success": "Ack"}));
+        let resp = recv(&mut ws);
+        assert!(resp.get("transport").is_none());
+        assert_eq!(resp["response"]["resp_kind"]["Err"]["code"], ErrorKind::DevNotFound.code());
+        assert_eq!(recv(&mut ws), json!({"event": "subscribed", "devices": ["sock1"], "interval_ms": 1000}));
+        assert_eq!(recv(&mut ws)["event"], "error");
+        let reading = recv(&mut ws);
+        assert_eq!((&reading["event"], &reading["reading"], &reading["value"]), (&json!("reading"), &json!("power"), &json!(2000.0)));
+        let state = recv(&mut ws);
+        assert_eq!((&state["event"], &state["dev_name"], &state["state"]["is_on"]), (&json!("state"), &json!("sock1"), &json!(true)));
+
+        // State is pushed again only after it changes
+        server.poll(now + chrono::Duration::seconds(1));
+        assert_eq!(recv(&mut ws)["event"], "reading");
+        server.servers[0].1.lock().unwrap().get_mut("sock1").unwrap().execute(&protocol::Cmd::TurnOff).unwrap();
+        server.poll(now + chrono::Duration::seconds(2));
+        assert_eq!(recv(&mut ws)["value"], 0.0);
+        let state = recv(&mut ws);
+        assert_eq!((&state["event"], &state["state"]["is_on"]), (&json!("state"), &json!(false)));
+
+        ws.close(None).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        server.poll(now + chrono::Duration::seconds(3));
+        assert!(server.clients.is_empty());
+    }
+
+    #[test]
+    fn test_server_cmds_rejected() {
+        let server = WsServer {
+            config: None,
+            servers: vec![("tcp", server())],
+            metrics: SharedMetrics::default(),
+            clients: Vec::new(),
+            rx: None,
+        };
+        for (cmd, dev_name) in [
+            (protocol::Cmd::ListSchedules, ""),
+            (protocol::Cmd::RemoveSchedule(1), "sock1"),
+            (protocol::Cmd::GetThermostat, ""),
+            (protocol::Cmd::SetSetpoint(21.0), "living"),
+            (protocol::Cmd::GroupAction(Box::new(protocol::Cmd::TurnOn)), "all"),
+            (protocol::Cmd::ApplyScene, "evening"),
+        ] {
+            let responses = server.execute(protocol::Request::new(cmd.clone(), dev_name.to_owned()));
+            assert_eq!(responses.len(), 1);
+            let (transport, resp) = &responses[0];
+            assert!(transport.is_none());
+            assert!(matches!(&resp.resp_kind, protocol::ResponseKind::Err(e) if e.is(ErrorKind::UnknownCmd)), "{:?}", cmd);
+        }
+        assert!(!server.servers[0].1.lock().unwrap()["sock1"].is_on());
+    }
+}